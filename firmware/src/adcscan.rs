@@ -0,0 +1,84 @@
+//! Continuous ADC scan of the analog inputs.
+//!
+//! ADC1 converts each channel in turn, over and over, and DMA2 stream 0
+//! copies the results into a buffer, so the latest reading of every channel
+//! is always there to be read without waiting on the ADC.
+use stm32f4xx_hal::pac;
+
+/// ADC channels in scan order: PC2 and PC3 on J22, PA4-PA7 on J23, then PB0
+/// and PB1 on J24.
+pub const CHANNELS: [u8; 8] = [12, 13, 4, 5, 6, 7, 8, 9];
+const SCAN_LEN: usize = CHANNELS.len();
+// The slowest sample time: the inputs are slow, and some are high
+// impedance.
+const SAMPLE_TIME_480_CYCLES: u8 = 0b111;
+
+static mut SAMPLES: [u16; SCAN_LEN] = [0; SCAN_LEN];
+
+pub struct AdcScan {
+    _adc: pac::ADC1,
+    _dma: pac::DMA2,
+}
+
+impl AdcScan {
+    /// Both peripherals' clocks must already be enabled, and the pins set to
+    /// analog mode.
+    pub fn new(adc: pac::ADC1, dma: pac::DMA2) -> Self {
+        let stream = dma.st(0);
+        stream.cr().write(|w| w.en().disabled());
+        while stream.cr().read().en().is_enabled() {}
+        stream
+            .par()
+            .write(|w| unsafe { w.pa().bits(adc.dr().as_ptr() as u32) });
+        stream
+            .m0ar()
+            .write(|w| unsafe { w.m0a().bits(core::ptr::addr_of_mut!(SAMPLES) as u32) });
+        stream.ndtr().write(|w| w.ndt().set(SCAN_LEN as u16));
+        stream.cr().write(|w| {
+            w.chsel().set(0);
+            w.msize().bits16();
+            w.psize().bits16();
+            w.minc().incremented();
+            w.circ().enabled();
+            w.dir().peripheral_to_memory();
+            w.en().enabled()
+        });
+
+        adc.cr1().write(|w| w.scan().enabled());
+        for (i, &ch) in CHANNELS.iter().enumerate() {
+            if ch < 10 {
+                adc.smpr2()
+                    .modify(|_, w| w.smp(ch).set(SAMPLE_TIME_480_CYCLES));
+            } else {
+                adc.smpr1()
+                    .modify(|_, w| w.smp(ch - 10).set(SAMPLE_TIME_480_CYCLES));
+            }
+            let i = i as u8;
+            if i < 6 {
+                adc.sqr3().modify(|_, w| unsafe { w.sq(i).bits(ch) });
+            } else {
+                adc.sqr2().modify(|_, w| unsafe { w.sq(i - 6).bits(ch) });
+            }
+        }
+        adc.sqr1().write(|w| w.l().set(SCAN_LEN as u8 - 1));
+        adc.cr2().write(|w| {
+            w.adon().enabled();
+            w.cont().continuous();
+            w.dma().enabled();
+            w.dds().continuous()
+        });
+        // Let the ADC power up before starting it.
+        cortex_m::asm::delay(1000);
+        adc.cr2().modify(|_, w| w.swstart().start());
+
+        AdcScan {
+            _adc: adc,
+            _dma: dma,
+        }
+    }
+
+    /// Latest reading of each channel, in `CHANNELS` order.
+    pub fn samples(&self) -> [u16; SCAN_LEN] {
+        unsafe { core::ptr::read_volatile(core::ptr::addr_of!(SAMPLES)) }
+    }
+}
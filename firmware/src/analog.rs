@@ -0,0 +1,296 @@
+//! Analog inputs.
+//!
+//! Each input's raw ADC reading is scaled linearly to engineering units,
+//! filtered, and checked against up to four alarm limits. Alarms are raised
+//! as soon as a limit is crossed, but only clear once the value is back
+//! inside it by the deadband, so a value sitting on a limit doesn't chatter.
+
+const MAX_AVERAGE: usize = 16;
+
+/// Linear scaling from raw ADC counts to engineering units.
+#[derive(Clone, Copy)]
+pub struct Scaling {
+    pub raw_min: u16,
+    pub raw_max: u16,
+    pub eng_min: f32,
+    pub eng_max: f32,
+}
+
+impl Scaling {
+    pub fn scale(&self, raw: u16) -> f32 {
+        let span = self.raw_max as f32 - self.raw_min as f32;
+        if span == 0.0 {
+            return self.eng_min;
+        }
+        let fraction = (raw as f32 - self.raw_min as f32) / span;
+        self.eng_min + fraction * (self.eng_max - self.eng_min)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Filter {
+    #[allow(dead_code)]
+    None,
+    /// Average of the last so many samples, up to 16.
+    MovingAverage(usize),
+    /// First-order lag with the given time constant.
+    Iir(fugit::Duration<u32, 1, 1_000>),
+}
+
+#[derive(Clone, Copy)]
+pub struct AlarmLimits {
+    pub lo_lo: Option<f32>,
+    pub lo: Option<f32>,
+    pub hi: Option<f32>,
+    pub hi_hi: Option<f32>,
+    pub deadband: f32,
+}
+
+impl AlarmLimits {
+    pub const NONE: AlarmLimits = AlarmLimits {
+        lo_lo: None,
+        lo: None,
+        hi: None,
+        hi_hi: None,
+        deadband: 0.0,
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub enum AlarmLevel {
+    LoLo,
+    Lo,
+    #[default]
+    Normal,
+    Hi,
+    HiHi,
+}
+
+impl AlarmLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlarmLevel::LoLo => "LoLo",
+            AlarmLevel::Lo => "Lo",
+            AlarmLevel::Normal => "Normal",
+            AlarmLevel::Hi => "Hi",
+            AlarmLevel::HiHi => "HiHi",
+        }
+    }
+}
+
+impl AlarmLimits {
+    // Level for `value`, with the limits moved towards normal by `deadband`.
+    fn level(&self, value: f32, deadband: f32) -> AlarmLevel {
+        let above = |limit: Option<f32>| limit.is_some_and(|l| value >= l - deadband);
+        let below = |limit: Option<f32>| limit.is_some_and(|l| value <= l + deadband);
+        if above(self.hi_hi) {
+            AlarmLevel::HiHi
+        } else if above(self.hi) {
+            AlarmLevel::Hi
+        } else if below(self.lo_lo) {
+            AlarmLevel::LoLo
+        } else if below(self.lo) {
+            AlarmLevel::Lo
+        } else {
+            AlarmLevel::Normal
+        }
+    }
+
+    /// New alarm level for `value`, given the current one.
+    pub fn evaluate(&self, value: f32, current: AlarmLevel) -> AlarmLevel {
+        let entering = self.level(value, 0.0);
+        let holding = self.level(value, self.deadband);
+        if current > AlarmLevel::Normal && entering >= AlarmLevel::Normal {
+            entering.max(current.min(holding))
+        } else if current < AlarmLevel::Normal && entering <= AlarmLevel::Normal {
+            entering.min(current.max(holding))
+        } else {
+            entering
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct AnalogDef {
+    pub name: &'static str,
+    pub scaling: Scaling,
+    pub filter: Filter,
+    pub alarms: AlarmLimits,
+}
+
+pub struct AnalogInput {
+    def: AnalogDef,
+    samples: [f32; MAX_AVERAGE],
+    sample_count: usize,
+    sample_index: usize,
+    value: Option<f32>,
+    level: AlarmLevel,
+    last_update: Option<i64>,
+}
+
+impl From<AnalogDef> for AnalogInput {
+    fn from(def: AnalogDef) -> Self {
+        AnalogInput {
+            def,
+            samples: [0.0; MAX_AVERAGE],
+            sample_count: 0,
+            sample_index: 0,
+            value: None,
+            level: AlarmLevel::Normal,
+            last_update: None,
+        }
+    }
+}
+
+impl AnalogInput {
+    pub fn update(&mut self, raw: u16, now: i64) {
+        let sample = self.def.scaling.scale(raw);
+        let elapsed_ms = match self.last_update.replace(now) {
+            Some(last) => (now - last).max(0) as f32,
+            None => 0.0,
+        };
+        let value = match (self.def.filter, self.value) {
+            (Filter::MovingAverage(n), _) => {
+                let n = n.clamp(1, MAX_AVERAGE);
+                self.samples[self.sample_index] = sample;
+                self.sample_index = (self.sample_index + 1) % n;
+                self.sample_count = (self.sample_count + 1).min(n);
+                self.samples[..self.sample_count].iter().sum::<f32>() / self.sample_count as f32
+            }
+            (Filter::Iir(time), Some(value)) => {
+                let alpha = (elapsed_ms / time.ticks().max(1) as f32).min(1.0);
+                value + (sample - value) * alpha
+            }
+            (Filter::Iir(_), None) | (Filter::None, _) => sample,
+        };
+        self.value = Some(value);
+        self.level = self.def.alarms.evaluate(value, self.level);
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.def.name
+    }
+
+    /// Filtered value in engineering units; zero until the first reading.
+    pub fn value(&self) -> f32 {
+        self.value.unwrap_or(0.0)
+    }
+
+    #[allow(dead_code)]
+    pub fn alarm(&self) -> AlarmLevel {
+        self.level
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        self.level.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU32;
+
+    const BAR: Scaling = Scaling {
+        raw_min: 0,
+        raw_max: 4095,
+        eng_min: 0.0,
+        eng_max: 10.0,
+    };
+
+    const LIMITS: AlarmLimits = AlarmLimits {
+        lo_lo: Some(2.0),
+        lo: Some(4.0),
+        hi: Some(8.0),
+        hi_hi: Some(9.0),
+        deadband: 0.5,
+    };
+
+    fn input(filter: Filter) -> AnalogInput {
+        AnalogInput::from(AnalogDef {
+            name: "P",
+            scaling: BAR,
+            filter,
+            alarms: LIMITS,
+        })
+    }
+
+    #[test]
+    fn test_scaling() {
+        assert_eq!(BAR.scale(0), 0.0);
+        assert_eq!(BAR.scale(4095), 10.0);
+        // Offset and inverted spans work too, e.g. 4-20mA across 250R.
+        let offset = Scaling {
+            raw_min: 819,
+            raw_max: 4095,
+            eng_min: 100.0,
+            eng_max: 0.0,
+        };
+        assert_eq!(offset.scale(819), 100.0);
+        assert_eq!(offset.scale(4095), 0.0);
+        assert!(offset.scale(0) > 100.0);
+    }
+
+    #[test]
+    fn test_alarm_levels() {
+        let n = AlarmLevel::Normal;
+        assert_eq!(LIMITS.evaluate(5.0, n), AlarmLevel::Normal);
+        assert_eq!(LIMITS.evaluate(8.0, n), AlarmLevel::Hi);
+        assert_eq!(LIMITS.evaluate(9.5, n), AlarmLevel::HiHi);
+        assert_eq!(LIMITS.evaluate(4.0, n), AlarmLevel::Lo);
+        assert_eq!(LIMITS.evaluate(1.0, n), AlarmLevel::LoLo);
+    }
+
+    #[test]
+    fn test_alarm_deadband() {
+        // Hi clears only below 7.5.
+        let hi = LIMITS.evaluate(8.0, AlarmLevel::Normal);
+        assert_eq!(LIMITS.evaluate(7.6, hi), AlarmLevel::Hi);
+        assert_eq!(LIMITS.evaluate(7.5, hi), AlarmLevel::Hi);
+        assert_eq!(LIMITS.evaluate(7.4, hi), AlarmLevel::Normal);
+        // HiHi drops back to Hi below 8.5, and escalates straight away.
+        assert_eq!(LIMITS.evaluate(8.6, AlarmLevel::HiHi), AlarmLevel::HiHi);
+        assert_eq!(LIMITS.evaluate(8.4, AlarmLevel::HiHi), AlarmLevel::Hi);
+        assert_eq!(LIMITS.evaluate(9.0, hi), AlarmLevel::HiHi);
+        // Same on the low side.
+        assert_eq!(LIMITS.evaluate(4.4, AlarmLevel::Lo), AlarmLevel::Lo);
+        assert_eq!(LIMITS.evaluate(4.6, AlarmLevel::Lo), AlarmLevel::Normal);
+        assert_eq!(LIMITS.evaluate(2.4, AlarmLevel::LoLo), AlarmLevel::LoLo);
+        assert_eq!(LIMITS.evaluate(2.6, AlarmLevel::LoLo), AlarmLevel::Lo);
+        // Jumping from one side to the other isn't held up.
+        assert_eq!(LIMITS.evaluate(9.5, AlarmLevel::LoLo), AlarmLevel::HiHi);
+        assert_eq!(LIMITS.evaluate(1.0, AlarmLevel::Hi), AlarmLevel::LoLo);
+    }
+
+    #[test]
+    fn test_no_limits() {
+        assert_eq!(
+            AlarmLimits::NONE.evaluate(1e9, AlarmLevel::Normal),
+            AlarmLevel::Normal
+        );
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let mut ai = input(Filter::MovingAverage(4));
+        ai.update(0, 0);
+        assert_eq!(ai.value(), 0.0);
+        ai.update(4095, 1);
+        assert_eq!(ai.value(), 5.0);
+        for now in 2..6 {
+            ai.update(4095, now);
+        }
+        assert_eq!(ai.value(), 10.0);
+        assert_eq!(ai.alarm(), AlarmLevel::HiHi);
+        assert_eq!(ai.state_name(), "HiHi");
+    }
+
+    #[test]
+    fn test_iir() {
+        let mut ai = input(Filter::Iir(1000.millis()));
+        ai.update(2048, 0);
+        assert!((ai.value() - 5.0).abs() < 0.01);
+        ai.update(4095, 100);
+        assert!((ai.value() - 5.5).abs() < 0.01);
+    }
+}
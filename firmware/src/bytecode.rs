@@ -0,0 +1,284 @@
+//! Bytecode for user logic programs.
+//!
+//! User logic runs on a small stack machine (see `vm`), so simple machine
+//! changes can be uploaded over USB instead of rebuilt and reflashed. This
+//! module defines the program image and instruction set, and is shared with
+//! the host-side compiler, so it mustn't depend on anything else in the
+//! firmware.
+//!
+//! A program image is a 16-byte header followed by the code:
+//!
+//! | Offset | Size | Field                          |
+//! |--------|------|--------------------------------|
+//! | 0      | 4    | magic, `MAGIC`                 |
+//! | 4      | 2    | format version, `VERSION`      |
+//! | 6      | 2    | reserved, zero                 |
+//! | 8      | 4    | code length in bytes           |
+//! | 12     | 4    | CRC-32 of the code             |
+//!
+//! all little-endian. Each instruction is an opcode byte followed by its
+//! operand, if any: an index into one of the memory areas (u8), a jump
+//! target as an offset into the code (u16), or an immediate value (i32).
+//!
+//! Values on the stack are i32. Booleans are 0 or 1 when pushed, and any
+//! non-zero value counts as true when popped.
+#![allow(dead_code)]
+
+pub const MAGIC: u32 = u32::from_le_bytes(*b"HPLC");
+pub const VERSION: u16 = 1;
+pub const HEADER_LEN: usize = 16;
+
+/// Limits the device puts on a program.
+pub const MAX_CODE: usize = 2048;
+pub const STACK_DEPTH: usize = 32;
+
+/// Process image inputs (%I) and outputs (%Q).
+pub const INPUTS: usize = 16;
+pub const OUTPUTS: usize = 16;
+/// Internal bits (%M) and words (%MW).
+pub const MARKERS: usize = 64;
+pub const WORDS: usize = 64;
+/// Function block instances of each kind.
+pub const TIMERS: usize = 16;
+pub const COUNTERS: usize = 16;
+pub const EDGES: usize = 32;
+
+/// Read-only status from the built-in function blocks, by index.
+pub const SYSTEM_BITS: [&str; 12] = [
+    "SPINDLE_ON",
+    "SPINDLE_BRAKE_ON",
+    "SERVO_READY",
+    "SERVO_FAULT",
+    "SERVO_AT_SPEED",
+    "PROBE_ACTIVE",
+    "SPINDLE_PERMIT",
+    "AXES_PERMIT",
+    "AXIS0_HOMED",
+    "AXIS0_IDLE",
+    "FAN_ON",
+    "SPINDLE_HOT",
+];
+
+/// Read-only measurements, by index. Temperatures and analog values are
+/// in tenths of their unit.
+pub const SYSTEM_WORDS: [&str; 11] = [
+    "SPINDLE_RPM",
+    "SPINDLE_TEMP",
+    "CABINET_TEMP",
+    "AI1",
+    "AI2",
+    "AI3",
+    "AI4",
+    "AI5",
+    "AI6",
+    "ENCODER_POS",
+    "AXIS0_POS",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operand {
+    None,
+    Index(usize),
+    Target,
+    Immediate,
+}
+
+macro_rules! ops {
+    ($($op:ident = $code:literal, $name:literal, $operand:expr;)*) => {
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        #[repr(u8)]
+        pub enum Op {
+            $($op = $code,)*
+        }
+
+        impl Op {
+            pub fn from_u8(code: u8) -> Option<Op> {
+                match code {
+                    $($code => Some(Op::$op),)*
+                    _ => None,
+                }
+            }
+
+            /// Assembly mnemonic.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Op::$op => $name,)*
+                }
+            }
+
+            /// The operand, with the size of the memory area for indices.
+            pub fn operand(self) -> Operand {
+                match self {
+                    $(Op::$op => $operand,)*
+                }
+            }
+        }
+    };
+}
+
+ops! {
+    End = 0x00, "END", Operand::None;
+    // Push a bit: input, output, marker, system.
+    LdI = 0x01, "LD.I", Operand::Index(INPUTS);
+    LdQ = 0x02, "LD.Q", Operand::Index(OUTPUTS);
+    LdM = 0x03, "LD.M", Operand::Index(MARKERS);
+    LdS = 0x04, "LD.S", Operand::Index(SYSTEM_BITS.len());
+    // Push a word: marker word, system word, immediate.
+    LdW = 0x08, "LD.W", Operand::Index(WORDS);
+    LdSw = 0x09, "LD.SW", Operand::Index(SYSTEM_WORDS.len());
+    Push = 0x0c, "PUSH", Operand::Immediate;
+    // Pop and store.
+    StQ = 0x10, "ST.Q", Operand::Index(OUTPUTS);
+    StM = 0x11, "ST.M", Operand::Index(MARKERS);
+    StW = 0x14, "ST.W", Operand::Index(WORDS);
+    // Pop, and set or reset the bit if true.
+    SetQ = 0x18, "S.Q", Operand::Index(OUTPUTS);
+    ResetQ = 0x19, "R.Q", Operand::Index(OUTPUTS);
+    SetM = 0x1a, "S.M", Operand::Index(MARKERS);
+    ResetM = 0x1b, "R.M", Operand::Index(MARKERS);
+    // Boolean logic.
+    And = 0x20, "AND", Operand::None;
+    Or = 0x21, "OR", Operand::None;
+    Xor = 0x22, "XOR", Operand::None;
+    Not = 0x23, "NOT", Operand::None;
+    // Wrapping arithmetic; dividing by zero gives zero.
+    Add = 0x28, "ADD", Operand::None;
+    Sub = 0x29, "SUB", Operand::None;
+    Mul = 0x2a, "MUL", Operand::None;
+    Div = 0x2b, "DIV", Operand::None;
+    Mod = 0x2c, "MOD", Operand::None;
+    Neg = 0x2d, "NEG", Operand::None;
+    // Comparisons, pushing a boolean.
+    Eq = 0x30, "EQ", Operand::None;
+    Ne = 0x31, "NE", Operand::None;
+    Lt = 0x32, "LT", Operand::None;
+    Le = 0x33, "LE", Operand::None;
+    Gt = 0x34, "GT", Operand::None;
+    Ge = 0x35, "GE", Operand::None;
+    // Stack shuffling.
+    Dup = 0x38, "DUP", Operand::None;
+    Drop = 0x39, "DROP", Operand::None;
+    // Jumps; the conditional ones pop the condition.
+    Jmp = 0x40, "JMP", Operand::Target;
+    Jmpc = 0x41, "JMPC", Operand::Target;
+    Jmpcn = 0x42, "JMPCN", Operand::Target;
+    // Edge detection: pop CLK.
+    RTrig = 0x48, "R_TRIG", Operand::Index(EDGES);
+    FTrig = 0x49, "F_TRIG", Operand::Index(EDGES);
+    LdEdgeQ = 0x4a, "LD.EQ", Operand::Index(EDGES);
+    // Timers: pop PT (ms), then IN.
+    Ton = 0x50, "TON", Operand::Index(TIMERS);
+    Tof = 0x51, "TOF", Operand::Index(TIMERS);
+    Tp = 0x52, "TP", Operand::Index(TIMERS);
+    LdTimerQ = 0x53, "LD.TQ", Operand::Index(TIMERS);
+    LdTimerEt = 0x54, "LD.TET", Operand::Index(TIMERS);
+    // Counters: pop PV, then R (CTU) or LD (CTD), then CU or CD.
+    Ctu = 0x58, "CTU", Operand::Index(COUNTERS);
+    Ctd = 0x59, "CTD", Operand::Index(COUNTERS);
+    LdCounterQ = 0x5a, "LD.CQ", Operand::Index(COUNTERS);
+    LdCounterCv = 0x5b, "LD.CCV", Operand::Index(COUNTERS);
+}
+
+// An operand's never "empty", just absent, so no `is_empty`.
+#[allow(clippy::len_without_is_empty)]
+impl Operand {
+    /// Encoded size in bytes.
+    pub fn len(self) -> usize {
+        match self {
+            Operand::None => 0,
+            Operand::Index(_) => 1,
+            Operand::Target => 2,
+            Operand::Immediate => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub version: u16,
+    pub code_len: u32,
+    pub crc: u32,
+}
+
+impl Header {
+    pub fn for_code(code: &[u8]) -> Header {
+        Header {
+            version: VERSION,
+            code_len: code.len() as u32,
+            crc: crc32(code),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.code_len.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    /// None unless the magic is right; the version and CRC are left to
+    /// the caller.
+    pub fn decode(bytes: &[u8; HEADER_LEN]) -> Option<Header> {
+        let word = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        if word(0) != MAGIC {
+            return None;
+        }
+        Some(Header {
+            version: u16::from_le_bytes([bytes[4], bytes[5]]),
+            code_len: word(8),
+            crc: word(12),
+        })
+    }
+}
+
+/// Plain CRC-32 (IEEE), bitwise.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcodes_round_trip() {
+        for code in 0..=255u8 {
+            if let Some(op) = Op::from_u8(code) {
+                assert_eq!(op as u8, code);
+            }
+        }
+        assert_eq!(Op::from_u8(0xff), None);
+        assert_eq!(Op::Push.operand().len(), 4);
+        assert_eq!(Op::Jmp.operand().len(), 2);
+        assert_eq!(Op::Ton.operand(), Operand::Index(TIMERS));
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = Header::for_code(&[1, 2, 3]);
+        assert_eq!(Header::decode(&header.encode()), Some(header));
+        let mut bytes = header.encode();
+        bytes[0] ^= 1;
+        assert_eq!(Header::decode(&bytes), None);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,199 @@
+//! Pulse measurement.
+//!
+//! Turns raw timer captures and interrupt pulse counts into frequencies,
+//! speeds and pulse widths for the rest of the logic to use. The hardware
+//! side lives in main; everything here is plain arithmetic so it can be
+//! tested on the host.
+use crate::simpletimer::SimpleTimer;
+
+/// Period measurement from a timer in PWM input mode, which captures the
+/// period and high time of each cycle of the input signal.
+pub struct PeriodMeter {
+    tick_hz: u32,
+    pulses_per_rev: u32,
+    timeout: fugit::Duration<u32, 1, 1_000>,
+    capture: Option<(u32, u32)>,
+    stale: SimpleTimer,
+}
+
+impl PeriodMeter {
+    /// `tick_hz` is the capture timer's count rate. If no capture arrives
+    /// within `timeout` the signal is considered to have stopped.
+    pub fn new(tick_hz: u32, pulses_per_rev: u32, timeout: fugit::Duration<u32, 1, 1_000>) -> Self {
+        PeriodMeter {
+            tick_hz,
+            pulses_per_rev: pulses_per_rev.max(1),
+            timeout,
+            capture: None,
+            stale: SimpleTimer::start(0, timeout),
+        }
+    }
+
+    /// Record a capture of one cycle's period and high time, in ticks.
+    pub fn capture(&mut self, period_ticks: u32, width_ticks: u32, now: i64) {
+        self.capture = if period_ticks > 0 {
+            Some((period_ticks, width_ticks.min(period_ticks)))
+        } else {
+            None
+        };
+        self.stale = SimpleTimer::start(now, self.timeout);
+    }
+
+    /// The timer overflowed before the next edge: the signal is slower than
+    /// we can measure, so call it stopped.
+    pub fn overrange(&mut self) {
+        self.capture = None;
+    }
+
+    pub fn update(&mut self, now: i64) {
+        if self.stale.expired(now) {
+            self.capture = None;
+        }
+    }
+
+    pub fn frequency_hz(&self) -> f32 {
+        match self.capture {
+            Some((period, _)) => self.tick_hz as f32 / period as f32,
+            None => 0.0,
+        }
+    }
+
+    pub fn rpm(&self) -> f32 {
+        self.frequency_hz() * 60.0 / self.pulses_per_rev as f32
+    }
+
+    #[allow(dead_code)]
+    pub fn pulse_width_us(&self) -> u32 {
+        match self.capture {
+            Some((_, width)) => (width as u64 * 1_000_000 / self.tick_hz as u64) as u32,
+            None => 0,
+        }
+    }
+
+    /// High time as a fraction of the period.
+    #[allow(dead_code)]
+    pub fn duty(&self) -> f32 {
+        match self.capture {
+            Some((period, width)) => width as f32 / period as f32,
+            None => 0.0,
+        }
+    }
+}
+
+/// Frequency measurement by counting pulses over a fixed gate time. Better
+/// than period measurement for fast signals, or ones that only need a
+/// coarse rate such as flow sensors.
+pub struct PulseCounter {
+    gate: fugit::Duration<u32, 1, 1_000>,
+    window: SimpleTimer,
+    last_count: u32,
+    window_pulses: u32,
+    total: u32,
+    frequency: f32,
+}
+
+impl PulseCounter {
+    pub fn new(gate: fugit::Duration<u32, 1, 1_000>) -> Self {
+        PulseCounter {
+            gate,
+            window: SimpleTimer::start(0, gate),
+            last_count: 0,
+            window_pulses: 0,
+            total: 0,
+            frequency: 0.0,
+        }
+    }
+
+    /// `count` is a free-running pulse count, e.g. from an interrupt
+    /// handler; it may wrap.
+    pub fn update(&mut self, count: u32, now: i64) {
+        let pulses = count.wrapping_sub(self.last_count);
+        self.last_count = count;
+        self.window_pulses = self.window_pulses.wrapping_add(pulses);
+        self.total = self.total.wrapping_add(pulses);
+        if self.window.expired(now) {
+            let elapsed_ms = self.window.elapsed(now).ticks().max(1);
+            self.frequency = self.window_pulses as f32 * 1000.0 / elapsed_ms as f32;
+            self.window_pulses = 0;
+            self.window = SimpleTimer::start(now, self.gate);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    pub fn frequency_hz(&self) -> f32 {
+        self.frequency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU32;
+
+    #[test]
+    fn test_period_meter() {
+        let mut meter = PeriodMeter::new(100_000, 1, 500.millis());
+        assert_eq!(meter.rpm(), 0.0);
+        // 50Hz, 25% duty.
+        meter.capture(2000, 500, 0);
+        assert_eq!(meter.frequency_hz(), 50.0);
+        assert_eq!(meter.rpm(), 3000.0);
+        assert_eq!(meter.pulse_width_us(), 5000);
+        assert_eq!(meter.duty(), 0.25);
+    }
+
+    #[test]
+    fn test_period_meter_pulses_per_rev() {
+        let mut meter = PeriodMeter::new(100_000, 4, 500.millis());
+        meter.capture(2000, 500, 0);
+        assert_eq!(meter.rpm(), 750.0);
+    }
+
+    #[test]
+    fn test_period_meter_timeout() {
+        let mut meter = PeriodMeter::new(100_000, 1, 500.millis());
+        meter.capture(2000, 500, 100);
+        meter.update(599);
+        assert_eq!(meter.frequency_hz(), 50.0);
+        meter.update(600);
+        assert_eq!(meter.frequency_hz(), 0.0);
+        assert_eq!(meter.pulse_width_us(), 0);
+    }
+
+    #[test]
+    fn test_period_meter_overrange() {
+        let mut meter = PeriodMeter::new(100_000, 1, 500.millis());
+        meter.capture(2000, 500, 0);
+        meter.overrange();
+        assert_eq!(meter.rpm(), 0.0);
+    }
+
+    #[test]
+    fn test_pulse_counter() {
+        let mut counter = PulseCounter::new(1000.millis());
+        let mut count = 0u32;
+        for now in 0..=2000 {
+            // 20Hz.
+            if now % 50 == 49 {
+                count += 1;
+            }
+            counter.update(count, now);
+        }
+        assert_eq!(counter.frequency_hz(), 20.0);
+        assert_eq!(counter.total(), 40);
+    }
+
+    #[test]
+    fn test_pulse_counter_wraps() {
+        let mut counter = PulseCounter::new(1000.millis());
+        counter.update(u32::MAX - 4, 0);
+        counter.update(u32::MAX - 4, 1);
+        let base = counter.total();
+        counter.update(5, 1000);
+        assert_eq!(counter.total().wrapping_sub(base), 10);
+    }
+}
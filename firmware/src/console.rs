@@ -0,0 +1,415 @@
+//! USB serial console.
+//!
+//! A line-based command interface on the USB CDC-ACM port, mainly for
+//! uploading user programs:
+//!
+//! ```text
+//! erase                    erase the program (resets the board)
+//! erase rules              erase the rule table (resets the board)
+//! write <offset> <hex>     program up to 4 bytes at a hex offset
+//! load                     load the program from flash, stopped
+//! load rules               load the rule table from flash
+//! run / stop               start or stop the program
+//! status                   report the program state
+//! rules                    report whether the rule table loaded
+//! force <point> on|off     force an input or output, e.g. `force Q3 on`
+//! unforce <point>|all      release one force, or all of them
+//! forces                   list the forces
+//! input <input>            report an input's health, e.g. `input I3`
+//! pwm <output> <%>|auto    set a PWM output's duty, or give it back
+//! pwm freq <hz>            set the PWM frequency
+//! ```
+//!
+//! Each command gets one line back, starting `OK` or `ERR`. An upload is
+//! `erase`, then `write`s of the whole image (see `bytecode`), then `load`
+//! and `run`. A rule table is uploaded the same way, with `erase rules`,
+//! then `write`s of its text from offset 1f000 (see `program`), then `load
+//! rules`. Each erase keeps the other.
+//!
+//! The USB serial port only takes as much as fits in its buffer, so
+//! replies are queued and sent over as many scans as it takes. Received
+//! bytes are only worked through while there's room for another reply.
+use crate::force::Point;
+use heapless::{String, Vec};
+
+pub const LINE_LEN: usize = 80;
+/// The most `write` takes at once. Flash is programmed a byte at a time,
+/// at up to 100us a byte, and this is a word, as the retain journal writes
+/// per scan, so the rest of the scan still fits in a watchdog period.
+pub const MAX_WRITE: usize = 4;
+/// Long enough for the whole force table.
+pub const REPLY_LEN: usize = 200;
+/// Room for a couple of replies, with their line endings.
+const TX_LEN: usize = 2 * (REPLY_LEN + 2);
+const RX_LEN: usize = 64;
+/// PWM frequency limits, within what TIM4 can do with some resolution.
+pub const PWM_FREQ_MIN_HZ: u32 = 1;
+pub const PWM_FREQ_MAX_HZ: u32 = 100_000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Erase,
+    EraseRules,
+    Write {
+        offset: u32,
+        data: Vec<u8, MAX_WRITE>,
+    },
+    Load,
+    LoadRules,
+    Run,
+    Stop,
+    Status,
+    Rules,
+    /// Force a point on or off, or release it with `None`.
+    Force(Point, Option<bool>),
+    UnforceAll,
+    Forces,
+    Input(usize),
+    /// Set an output's PWM duty in percent, or release it with `None`.
+    Pwm(usize, Option<u8>),
+    PwmFrequency(u32),
+}
+
+pub fn parse(line: &str) -> Result<Command, &'static str> {
+    let mut words = line.split_ascii_whitespace();
+    let command = match words.next() {
+        Some("erase") => match words.next() {
+            None => Command::Erase,
+            Some("rules") => Command::EraseRules,
+            Some(_) => return Err("bad argument"),
+        },
+        Some("write") => {
+            let offset = words.next().ok_or("missing offset")?;
+            let offset = u32::from_str_radix(offset, 16).map_err(|_| "bad offset")?;
+            let hex = words.next().ok_or("missing data")?.as_bytes();
+            if hex.len() % 2 != 0 {
+                return Err("bad data");
+            }
+            if hex.len() / 2 > MAX_WRITE {
+                return Err("too much data");
+            }
+            let mut data = Vec::new();
+            for pair in hex.chunks_exact(2) {
+                let byte = core::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or("bad data")?;
+                let _ = data.push(byte);
+            }
+            Command::Write { offset, data }
+        }
+        Some("load") => match words.next() {
+            None => Command::Load,
+            Some("rules") => Command::LoadRules,
+            Some(_) => return Err("bad argument"),
+        },
+        Some("run") => Command::Run,
+        Some("stop") => Command::Stop,
+        Some("status") => Command::Status,
+        Some("rules") => Command::Rules,
+        Some("force") => {
+            let point = words.next().ok_or("missing point")?;
+            let point = Point::parse(point).ok_or("bad point")?;
+            let value = match words.next().ok_or("missing state")? {
+                "on" => true,
+                "off" => false,
+                _ => return Err("bad state"),
+            };
+            Command::Force(point, Some(value))
+        }
+        Some("unforce") => match words.next().ok_or("missing point")? {
+            "all" => Command::UnforceAll,
+            point => Command::Force(Point::parse(point).ok_or("bad point")?, None),
+        },
+        Some("forces") => Command::Forces,
+        Some("input") => match Point::parse(words.next().ok_or("missing input")?) {
+            Some(Point::Input(n)) => Command::Input(n),
+            _ => return Err("bad input"),
+        },
+        Some("pwm") => match words.next().ok_or("missing output")? {
+            "freq" => {
+                let hz = words.next().ok_or("missing frequency")?;
+                match hz.parse() {
+                    Ok(hz) if (PWM_FREQ_MIN_HZ..=PWM_FREQ_MAX_HZ).contains(&hz) => {
+                        Command::PwmFrequency(hz)
+                    }
+                    _ => return Err("bad frequency"),
+                }
+            }
+            output => {
+                let Some(Point::Output(n)) = Point::parse(output) else {
+                    return Err("bad output");
+                };
+                let duty = match words.next().ok_or("missing duty")? {
+                    "auto" => None,
+                    duty => match duty.parse() {
+                        Ok(duty) if duty <= 100 => Some(duty),
+                        _ => return Err("bad duty"),
+                    },
+                };
+                Command::Pwm(n, duty)
+            }
+        },
+        Some(_) => return Err("unknown command"),
+        None => return Err("empty line"),
+    };
+    if words.next().is_some() {
+        return Err("too many arguments");
+    }
+    Ok(command)
+}
+
+/// Gathers received bytes into lines.
+#[derive(Default)]
+pub struct LineBuffer {
+    line: String<LINE_LEN>,
+    error: Option<&'static str>,
+}
+
+impl LineBuffer {
+    /// Returns a line once one is complete. Lines that are too long, or not
+    /// text, come back as an error rather than cut short.
+    pub fn push(&mut self, byte: u8) -> Option<Result<String<LINE_LEN>, &'static str>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = core::mem::take(&mut self.line);
+                if let Some(error) = self.error.take() {
+                    Some(Err(error))
+                } else if line.is_empty() {
+                    None
+                } else {
+                    Some(Ok(line))
+                }
+            }
+            b' '..=b'~' | b'\t' => {
+                if self.line.push(byte as char).is_err() {
+                    self.error.get_or_insert("line too long");
+                }
+                None
+            }
+            _ => {
+                self.error.get_or_insert("not text");
+                None
+            }
+        }
+    }
+}
+
+/// Bytes received but not yet worked through.
+pub struct RxBuffer {
+    buf: [u8; RX_LEN],
+    len: usize,
+    pos: usize,
+}
+
+impl Default for RxBuffer {
+    fn default() -> Self {
+        RxBuffer {
+            buf: [0; RX_LEN],
+            len: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl RxBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.len
+    }
+
+    /// Refill from `read`, which returns how much it read.
+    pub fn fill<E>(&mut self, read: impl FnOnce(&mut [u8]) -> Result<usize, E>) {
+        self.len = read(&mut self.buf).unwrap_or(0);
+        self.pos = 0;
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        let byte = self.buf[..self.len].get(self.pos).copied()?;
+        self.pos += 1;
+        Some(byte)
+    }
+}
+
+/// Replies waiting to go out.
+#[derive(Default)]
+pub struct TxBuffer {
+    pending: Vec<u8, TX_LEN>,
+}
+
+impl TxBuffer {
+    /// Whether another reply is sure to fit.
+    pub fn has_room(&self) -> bool {
+        TX_LEN - self.pending.len() >= REPLY_LEN + 2
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let room = TX_LEN - self.pending.len();
+        let _ = self
+            .pending
+            .extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+
+    /// Hand as much as will go to `write`, which returns how much it took
+    /// or an error if it can't take any more just now.
+    pub fn flush<E>(&mut self, mut write: impl FnMut(&[u8]) -> Result<usize, E>) {
+        while !self.pending.is_empty() {
+            match write(&self.pending) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => {
+                    let count = count.min(self.pending.len());
+                    self.pending.copy_within(count.., 0);
+                    self.pending.truncate(self.pending.len() - count);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("erase"), Ok(Command::Erase));
+        assert_eq!(parse("erase rules"), Ok(Command::EraseRules));
+        assert_eq!(parse("  run "), Ok(Command::Run));
+        assert_eq!(parse("stop"), Ok(Command::Stop));
+        assert_eq!(parse("load"), Ok(Command::Load));
+        assert_eq!(parse("load rules"), Ok(Command::LoadRules));
+        assert_eq!(parse("status"), Ok(Command::Status));
+        assert_eq!(parse("rules"), Ok(Command::Rules));
+        assert_eq!(
+            parse("force q3 on"),
+            Ok(Command::Force(Point::Output(3), Some(true)))
+        );
+        assert_eq!(
+            parse("force I12 off"),
+            Ok(Command::Force(Point::Input(12), Some(false)))
+        );
+        assert_eq!(
+            parse("unforce Q3"),
+            Ok(Command::Force(Point::Output(3), None))
+        );
+        assert_eq!(parse("unforce all"), Ok(Command::UnforceAll));
+        assert_eq!(parse("forces"), Ok(Command::Forces));
+        assert_eq!(parse("input i3"), Ok(Command::Input(3)));
+        assert_eq!(parse("pwm Q13 40"), Ok(Command::Pwm(13, Some(40))));
+        assert_eq!(parse("pwm q14 auto"), Ok(Command::Pwm(14, None)));
+        assert_eq!(parse("pwm freq 20000"), Ok(Command::PwmFrequency(20_000)));
+        assert_eq!(
+            parse("write 1a0 00ff7E"),
+            Ok(Command::Write {
+                offset: 0x1a0,
+                data: Vec::from_slice(&[0x00, 0xff, 0x7e]).unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse(""), Err("empty line"));
+        assert_eq!(parse("format"), Err("unknown command"));
+        assert_eq!(parse("run now"), Err("too many arguments"));
+        assert_eq!(parse("load all"), Err("bad argument"));
+        assert_eq!(parse("erase all"), Err("bad argument"));
+        assert_eq!(parse("write"), Err("missing offset"));
+        assert_eq!(parse("write xyz 00"), Err("bad offset"));
+        assert_eq!(parse("write 0"), Err("missing data"));
+        assert_eq!(parse("write 0 123"), Err("bad data"));
+        assert_eq!(parse("write 0 zz"), Err("bad data"));
+        assert_eq!(parse("force"), Err("missing point"));
+        assert_eq!(parse("force Q16 on"), Err("bad point"));
+        assert_eq!(parse("force Q3"), Err("missing state"));
+        assert_eq!(parse("force Q3 1"), Err("bad state"));
+        assert_eq!(parse("unforce"), Err("missing point"));
+        assert_eq!(parse("input"), Err("missing input"));
+        assert_eq!(parse("input Q3"), Err("bad input"));
+        assert_eq!(parse("pwm"), Err("missing output"));
+        assert_eq!(parse("pwm I3 50"), Err("bad output"));
+        assert_eq!(parse("pwm Q13"), Err("missing duty"));
+        assert_eq!(parse("pwm Q13 101"), Err("bad duty"));
+        assert_eq!(parse("pwm Q13 -1"), Err("bad duty"));
+        assert_eq!(parse("pwm freq"), Err("missing frequency"));
+        assert_eq!(parse("pwm freq 0"), Err("bad frequency"));
+        assert_eq!(parse("pwm freq 1000000"), Err("bad frequency"));
+        let long = "00".repeat(MAX_WRITE + 1);
+        assert_eq!(parse(&format!("write 0 {}", long)), Err("too much data"));
+    }
+
+    fn feed(
+        buffer: &mut LineBuffer,
+        bytes: &[u8],
+    ) -> std::vec::Vec<Result<String<LINE_LEN>, &'static str>> {
+        bytes.iter().filter_map(|&b| buffer.push(b)).collect()
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(feed(&mut buffer, b"sta"), []);
+        let lines = feed(&mut buffer, b"tus\r\n\nrun\n");
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].as_ref().unwrap().as_str(), "status");
+        assert_eq!(lines[1].as_ref().unwrap().as_str(), "run");
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let mut tx = TxBuffer::default();
+        tx.push(&[b'a'; REPLY_LEN]);
+        tx.push(b"\r\n");
+        assert!(tx.has_room());
+        tx.push(b"OK\r\n");
+        assert!(!tx.has_room());
+        // A port that takes 128 bytes, then nothing until it's drained.
+        let mut sent = std::vec::Vec::new();
+        tx.flush(|bytes: &[u8]| {
+            if sent.len() % 128 == 0 && sent.len() > 0 {
+                return Err(());
+            }
+            let count = bytes.len().min(128 - sent.len() % 128);
+            sent.extend_from_slice(&bytes[..count]);
+            Ok(count)
+        });
+        assert_eq!(sent.len(), 128);
+        sent.clear();
+        tx.flush(|bytes: &[u8]| {
+            let count = bytes.len().min(128);
+            sent.extend_from_slice(&bytes[..count]);
+            Ok::<_, ()>(count)
+        });
+        assert_eq!(sent.len(), REPLY_LEN + 6 - 128);
+        assert!(sent.ends_with(b"a\r\nOK\r\n"));
+        assert!(tx.pending.is_empty());
+    }
+
+    #[test]
+    fn test_rx_buffer() {
+        let mut rx = RxBuffer::default();
+        assert!(rx.is_empty());
+        rx.fill(|buf: &mut [u8]| {
+            buf[..3].copy_from_slice(b"run");
+            Ok::<_, ()>(3)
+        });
+        assert_eq!(rx.pop(), Some(b'r'));
+        assert!(!rx.is_empty());
+        assert_eq!(rx.pop(), Some(b'u'));
+        assert_eq!(rx.pop(), Some(b'n'));
+        assert_eq!(rx.pop(), None);
+        rx.fill(|_: &mut [u8]| Err(()));
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    fn test_long_and_binary_lines() {
+        let mut buffer = LineBuffer::default();
+        let long = [b'a'; LINE_LEN + 1];
+        assert_eq!(feed(&mut buffer, &long), []);
+        assert_eq!(feed(&mut buffer, b"\n"), [Err("line too long")]);
+        assert_eq!(feed(&mut buffer, b"ru\x00n\n"), [Err("not text")]);
+        // And back to normal after.
+        let lines = feed(&mut buffer, b"run\n");
+        assert_eq!(lines[0].as_ref().unwrap().as_str(), "run");
+    }
+}
@@ -1,11 +1,13 @@
 //! Generic debouncer.
 //!
-//! Debounce inputs such as pushbuttons.
+//! Debounce inputs such as pushbuttons. Also keeps count of edges and of
+//! glitches (pulses too short to get through the filter) so noisy inputs
+//! can be spotted.
 use crate::simpletimer::SimpleTimer;
 use fugit::ExtU32;
 
 #[derive(Default)]
-enum DebounceFSMState {
+pub enum DebounceFSMState {
     #[default]
     Off,
     DebounceOn(SimpleTimer),
@@ -16,22 +18,65 @@ enum DebounceFSMState {
 pub struct Debouncer {
     state: DebounceFSMState,
     posedge_read: bool,
+    negedge_read: bool,
     holdoff_time: fugit::Duration<u32, 1, 1_000>,
     holdon_time: fugit::Duration<u32, 1, 1_000>,
+    inverted: bool,
+    rising_edges: u32,
+    falling_edges: u32,
+    glitches: u32,
 }
 
 const DEBOUNCE_ON_MS: u32 = 2;
 const DEBOUNCE_OFF_MS: u32 = 10;
 
-impl Debouncer {
-    pub fn default() -> Self {
-        Debouncer {
-            state: DebounceFSMState::Off,
-            posedge_read: false,
-            holdoff_time: DEBOUNCE_ON_MS.millis(),
-            holdon_time: DEBOUNCE_OFF_MS.millis(),
+/// Filter settings for one input.
+#[derive(Clone, Copy)]
+pub struct InputFilter {
+    pub on_ms: u32,
+    pub off_ms: u32,
+    pub inverted: bool,
+}
+
+impl InputFilter {
+    pub const DEFAULT: InputFilter = InputFilter {
+        on_ms: DEBOUNCE_ON_MS,
+        off_ms: DEBOUNCE_OFF_MS,
+        inverted: false,
+    };
+    /// No filtering, for inputs that are debounced further downstream.
+    pub const RAW: InputFilter = InputFilter {
+        on_ms: 0,
+        off_ms: 0,
+        inverted: false,
+    };
+
+    pub const fn inverted(self) -> Self {
+        InputFilter {
+            inverted: true,
+            ..self
+        }
+    }
+}
+
+impl Default for Debouncer {
+    fn default() -> Self {
+        Debouncer::new(DEBOUNCE_ON_MS.millis(), DEBOUNCE_OFF_MS.millis())
+    }
+}
+
+impl From<InputFilter> for Debouncer {
+    fn from(filter: InputFilter) -> Self {
+        let debouncer = Debouncer::new(filter.on_ms.millis(), filter.off_ms.millis());
+        if filter.inverted {
+            debouncer.inverted()
+        } else {
+            debouncer
         }
     }
+}
+
+impl Debouncer {
     pub fn new(
         holdoff_time: fugit::Duration<u32, 1, 1_000>,
         holdon_time: fugit::Duration<u32, 1, 1_000>,
@@ -39,37 +84,71 @@ impl Debouncer {
         Debouncer {
             state: DebounceFSMState::Off,
             posedge_read: false,
+            negedge_read: true,
             holdoff_time,
             holdon_time,
+            inverted: false,
+            rising_edges: 0,
+            falling_edges: 0,
+            glitches: 0,
         }
     }
+
+    /// Treat a low input as on, e.g. for fail-safe normally-closed contacts.
+    pub fn inverted(mut self) -> Self {
+        self.inverted = true;
+        self
+    }
+
+    fn rise(&mut self) {
+        self.state = DebounceFSMState::On;
+        self.posedge_read = false;
+        self.rising_edges = self.rising_edges.wrapping_add(1);
+    }
+
+    fn fall(&mut self) {
+        self.state = DebounceFSMState::Off;
+        self.negedge_read = false;
+        self.falling_edges = self.falling_edges.wrapping_add(1);
+    }
+
     pub fn update(&mut self, input: bool, now: i64) {
+        let input = input != self.inverted;
         match &self.state {
             DebounceFSMState::Off => {
                 if input {
-                    self.state =
-                        DebounceFSMState::DebounceOn(SimpleTimer::start(now, self.holdoff_time))
+                    if self.holdoff_time.ticks() == 0 {
+                        self.rise();
+                    } else {
+                        self.state =
+                            DebounceFSMState::DebounceOn(SimpleTimer::start(now, self.holdoff_time))
+                    }
                 }
             }
             DebounceFSMState::DebounceOn(timer) => {
                 if !input {
                     self.state = DebounceFSMState::Off;
+                    self.glitches = self.glitches.wrapping_add(1);
                 } else if timer.expired(now) {
-                    self.state = DebounceFSMState::On;
-                    self.posedge_read = false;
+                    self.rise();
                 }
             }
             DebounceFSMState::On => {
                 if !input {
-                    self.state =
-                        DebounceFSMState::DebounceOff(SimpleTimer::start(now, self.holdon_time))
+                    if self.holdon_time.ticks() == 0 {
+                        self.fall();
+                    } else {
+                        self.state =
+                            DebounceFSMState::DebounceOff(SimpleTimer::start(now, self.holdon_time))
+                    }
                 }
             }
             DebounceFSMState::DebounceOff(timer) => {
                 if input {
                     self.state = DebounceFSMState::On;
+                    self.glitches = self.glitches.wrapping_add(1);
                 } else if timer.expired(now) {
-                    self.state = DebounceFSMState::Off;
+                    self.fall();
                 }
             }
         }
@@ -98,4 +177,160 @@ impl Debouncer {
             }
         }
     }
+
+    /// True once after each debounced falling edge.
+    #[allow(dead_code)]
+    pub fn negedge(&mut self) -> bool {
+        match self.state {
+            DebounceFSMState::Off | DebounceFSMState::DebounceOn(_) => {
+                if self.negedge_read {
+                    false
+                } else {
+                    self.negedge_read = true;
+                    true
+                }
+            }
+            DebounceFSMState::On => false,
+            DebounceFSMState::DebounceOff(_) => false,
+        }
+    }
+
+    /// True once after each debounced edge in either direction.
+    #[allow(dead_code)]
+    pub fn anyedge(&mut self) -> bool {
+        self.posedge() | self.negedge()
+    }
+
+    #[allow(dead_code)]
+    pub fn rising_edges(&self) -> u32 {
+        self.rising_edges
+    }
+
+    #[allow(dead_code)]
+    pub fn falling_edges(&self) -> u32 {
+        self.falling_edges
+    }
+
+    /// Pulses that started an edge but didn't last long enough to pass.
+    #[allow(dead_code)]
+    pub fn glitches(&self) -> u32 {
+        self.glitches
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> &DebounceFSMState {
+        &self.state
+    }
+
+    /// Time left before the current state times out, if it has a timer.
+    #[allow(dead_code)]
+    pub fn remaining(&self, now: i64) -> Option<fugit::Duration<u32, 1, 1_000>> {
+        match &self.state {
+            DebounceFSMState::Off => None,
+            DebounceFSMState::DebounceOn(timer) => Some(timer.remaining(now)),
+            DebounceFSMState::On => None,
+            DebounceFSMState::DebounceOff(timer) => Some(timer.remaining(now)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drive(d: &mut Debouncer, input: bool, from: i64, to: i64) {
+        for now in from..=to {
+            d.update(input, now);
+        }
+    }
+
+    #[test]
+    fn test_debounce() {
+        let mut d = Debouncer::default();
+        drive(&mut d, true, 0, DEBOUNCE_ON_MS as i64 - 1);
+        assert!(!d.is_on());
+        d.update(true, DEBOUNCE_ON_MS as i64);
+        assert!(d.is_on());
+        drive(&mut d, false, 10, 10 + DEBOUNCE_OFF_MS as i64 - 1);
+        assert!(d.is_on());
+        d.update(false, 10 + DEBOUNCE_OFF_MS as i64);
+        assert!(!d.is_on());
+    }
+
+    #[test]
+    fn test_state() {
+        let mut d = Debouncer::default();
+        assert!(matches!(d.state(), DebounceFSMState::Off));
+        d.update(true, 0);
+        assert!(matches!(d.state(), DebounceFSMState::DebounceOn(_)));
+        assert_eq!(d.remaining(1).unwrap().ticks(), DEBOUNCE_ON_MS - 1);
+        drive(&mut d, true, 1, 10);
+        assert!(matches!(d.state(), DebounceFSMState::On));
+        assert_eq!(d.remaining(10), None);
+        d.update(false, 11);
+        assert!(matches!(d.state(), DebounceFSMState::DebounceOff(_)));
+        assert_eq!(d.remaining(15).unwrap().ticks(), DEBOUNCE_OFF_MS - 4);
+    }
+
+    #[test]
+    fn test_edges_read_once() {
+        let mut d = Debouncer::default();
+        assert!(!d.negedge());
+        drive(&mut d, true, 0, 10);
+        assert!(d.posedge());
+        assert!(!d.posedge());
+        assert!(!d.negedge());
+        drive(&mut d, false, 11, 30);
+        assert!(d.negedge());
+        assert!(!d.negedge());
+        assert!(!d.posedge());
+    }
+
+    #[test]
+    fn test_anyedge() {
+        let mut d = Debouncer::default();
+        drive(&mut d, true, 0, 10);
+        assert!(d.anyedge());
+        assert!(!d.anyedge());
+        drive(&mut d, false, 11, 30);
+        assert!(d.anyedge());
+        assert!(!d.anyedge());
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut d = Debouncer::default();
+        for i in 0..3 {
+            drive(&mut d, true, i * 100, i * 100 + 20);
+            drive(&mut d, false, i * 100 + 21, i * 100 + 50);
+        }
+        // A 1ms blip on, and a 1ms dropout while on.
+        d.update(true, 1000);
+        d.update(false, 1001);
+        drive(&mut d, true, 1100, 1120);
+        d.update(false, 1121);
+        d.update(true, 1122);
+        assert_eq!(d.rising_edges(), 4);
+        assert_eq!(d.falling_edges(), 3);
+        assert_eq!(d.glitches(), 2);
+    }
+
+    #[test]
+    fn test_inverted() {
+        let mut d = Debouncer::from(InputFilter::DEFAULT.inverted());
+        drive(&mut d, false, 0, 10);
+        assert!(d.is_on());
+        drive(&mut d, true, 11, 30);
+        assert!(!d.is_on());
+    }
+
+    #[test]
+    fn test_raw_passes_straight_through() {
+        let mut d = Debouncer::from(InputFilter::RAW);
+        d.update(true, 0);
+        assert!(d.is_on());
+        d.update(false, 1);
+        assert!(!d.is_on());
+        assert_eq!(d.glitches(), 0);
+    }
 }
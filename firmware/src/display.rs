@@ -0,0 +1,693 @@
+//! OLED status display.
+//!
+//! Drives a cheap 128x64 SSD1306 or SH1106 I2C OLED module showing live
+//! I/O state, the state of each FSM, measured values, uptime and the most
+//! recent event log entry. Drawing is done with `embedded-graphics` into a
+//! local framebuffer a line per mainloop iteration, and the frame is then
+//! trickled out to the display a small chunk per iteration, so we never
+//! hold off the watchdog for long.
+use crate::eventlog::Event;
+use crate::simpletimer::SimpleTimer;
+use core::fmt::Write;
+use embedded_graphics::mono_font::ascii::FONT_6X10;
+use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::pixelcolor::BinaryColor;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{Line, PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+use embedded_hal::i2c::I2c;
+use fugit::ExtU32;
+use heapless::String;
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 64;
+const PAGES: usize = HEIGHT / 8;
+
+const DISPLAY_ADDR: u8 = 0x3c;
+const DISPLAY_REFRESH_MS: u32 = 250;
+const DISPLAY_CHUNK: usize = 16;
+
+const LINE_HEIGHT: i32 = 10;
+const PAGE_LINES: usize = 5;
+const PAGE_SCROLL_MS: i64 = 3000;
+
+/// Monochrome framebuffer laid out in the controller's native page format.
+pub struct FrameBuffer {
+    buf: [u8; WIDTH * PAGES],
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        FrameBuffer {
+            buf: [0; WIDTH * PAGES],
+        }
+    }
+}
+
+impl FrameBuffer {
+    #[allow(dead_code)]
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        (self.buf[(y / 8) * WIDTH + x] >> (y % 8)) & 1 != 0
+    }
+
+    fn page(&self, page: usize) -> &[u8] {
+        &self.buf[page * WIDTH..(page + 1) * WIDTH]
+    }
+}
+
+impl OriginDimensions for FrameBuffer {
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if point.x < 0 || point.y < 0 || point.x >= WIDTH as i32 || point.y >= HEIGHT as i32 {
+                continue;
+            }
+            let (x, y) = (point.x as usize, point.y as usize);
+            let idx = (y / 8) * WIDTH + x;
+            match color {
+                BinaryColor::On => self.buf[idx] |= 1 << (y % 8),
+                BinaryColor::Off => self.buf[idx] &= !(1 << (y % 8)),
+            }
+        }
+        Ok(())
+    }
+
+    // The default implementation goes pixel by pixel, which is far too slow
+    // to do within a mainloop iteration.
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.buf.fill(match color {
+            BinaryColor::On => 0xff,
+            BinaryColor::Off => 0x00,
+        });
+        Ok(())
+    }
+}
+
+/// Supported display controllers. These are mostly command compatible, but
+/// the SH1106 has 132 columns of RAM with the visible area offset by two.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Controller {
+    Ssd1306,
+    Sh1106,
+}
+
+impl Controller {
+    fn init_sequence(&self) -> &'static [u8] {
+        match self {
+            Controller::Ssd1306 => &[
+                0xae, // Display off
+                0xd5, 0x80, // Clock divide
+                0xa8, 0x3f, // Multiplex 1/64
+                0xd3, 0x00, // Display offset
+                0x40, // Start line 0
+                0x8d, 0x14, // Charge pump on
+                0x20, 0x02, // Page addressing mode
+                0xa1, // Segment remap
+                0xc8, // COM scan reversed
+                0xda, 0x12, // COM pins
+                0x81, 0xcf, // Contrast
+                0xd9, 0xf1, // Precharge
+                0xdb, 0x40, // VCOMH deselect
+                0xa4, // Display from RAM
+                0xa6, // Non-inverted
+                0xaf, // Display on
+            ],
+            Controller::Sh1106 => &[
+                0xae, // Display off
+                0xd5, 0x80, // Clock divide
+                0xa8, 0x3f, // Multiplex 1/64
+                0xd3, 0x00, // Display offset
+                0x40, // Start line 0
+                0xad, 0x8b, // DC-DC on
+                0xa1, // Segment remap
+                0xc8, // COM scan reversed
+                0xda, 0x12, // COM pins
+                0x81, 0x80, // Contrast
+                0xd9, 0x22, // Precharge
+                0xdb, 0x35, // VCOMH deselect
+                0xa4, // Display from RAM
+                0xa6, // Non-inverted
+                0xaf, // Display on
+            ],
+        }
+    }
+
+    fn column_offset(&self) -> usize {
+        match self {
+            Controller::Ssd1306 => 0,
+            Controller::Sh1106 => 2,
+        }
+    }
+}
+
+/// Low-level I2C display driver that sends the framebuffer incrementally.
+pub struct Oled<I2C> {
+    i2c: I2C,
+    controller: Controller,
+    flushing: bool,
+    page: usize,
+    column: usize,
+}
+
+impl<I2C: I2c> Oled<I2C> {
+    pub fn new(i2c: I2C, controller: Controller) -> Self {
+        Oled {
+            i2c,
+            controller,
+            flushing: false,
+            page: 0,
+            column: 0,
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), I2C::Error> {
+        let mut cmd: heapless::Vec<u8, 32> = heapless::Vec::new();
+        let _ = cmd.push(0x00);
+        let _ = cmd.extend_from_slice(self.controller.init_sequence());
+        self.i2c.write(DISPLAY_ADDR, &cmd)
+    }
+
+    pub fn start_flush(&mut self) {
+        self.flushing = true;
+        self.page = 0;
+        self.column = 0;
+    }
+
+    pub fn is_flushing(&self) -> bool {
+        self.flushing
+    }
+
+    /// Send the next chunk of the framebuffer. Returns true once the whole
+    /// frame has been sent.
+    pub fn poll(&mut self, fb: &FrameBuffer) -> Result<bool, I2C::Error> {
+        if !self.flushing {
+            return Ok(true);
+        }
+        let col = self.column + self.controller.column_offset();
+        let set_addr = [
+            0x00,
+            0xb0 | self.page as u8,
+            (col & 0x0f) as u8,
+            0x10 | (col >> 4) as u8,
+        ];
+        let mut data = [0u8; DISPLAY_CHUNK + 1];
+        data[0] = 0x40;
+        data[1..].copy_from_slice(&fb.page(self.page)[self.column..self.column + DISPLAY_CHUNK]);
+        let result = self
+            .i2c
+            .write(DISPLAY_ADDR, &set_addr)
+            .and_then(|_| self.i2c.write(DISPLAY_ADDR, &data));
+        if let Err(e) = result {
+            // Abandon this frame; we'll try again next refresh.
+            self.flushing = false;
+            return Err(e);
+        }
+        self.column += DISPLAY_CHUNK;
+        if self.column >= WIDTH {
+            self.column = 0;
+            self.page += 1;
+            if self.page >= PAGES {
+                self.flushing = false;
+            }
+        }
+        Ok(!self.flushing)
+    }
+}
+
+/// Snapshot of a single FSM for display.
+pub struct FsmStatus {
+    pub name: &'static str,
+    pub state: &'static str,
+    pub remaining_ms: Option<u32>,
+}
+
+/// A measured value for display.
+#[derive(Clone)]
+pub struct Value {
+    pub name: &'static str,
+    pub value: f32,
+}
+
+/// Everything shown on the display, gathered once per refresh.
+pub struct Status<'a> {
+    pub now: i64,
+    pub inputs: u16,
+    pub outputs: u16,
+    pub fsms: &'a [FsmStatus],
+    pub values: &'a [Value],
+    pub last_event: Option<&'a Event>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Page {
+    #[default]
+    Io,
+    Fsm,
+    Values,
+    Events,
+}
+
+const PAGE_ORDER: [Page; 4] = [Page::Io, Page::Fsm, Page::Values, Page::Events];
+
+impl Page {
+    pub fn next(self) -> Page {
+        let idx = PAGE_ORDER.iter().position(|p| *p == self).unwrap_or(0);
+        PAGE_ORDER[(idx + 1) % PAGE_ORDER.len()]
+    }
+
+    pub fn prev(self) -> Page {
+        let idx = PAGE_ORDER.iter().position(|p| *p == self).unwrap_or(0);
+        PAGE_ORDER[(idx + PAGE_ORDER.len() - 1) % PAGE_ORDER.len()]
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            Page::Io => "I/O",
+            Page::Fsm => "FSM",
+            Page::Values => "VALUES",
+            Page::Events => "EVENTS",
+        }
+    }
+}
+
+fn format_hms(s: &mut String<24>, ms: i64) {
+    let secs = ms.max(0) / 1000;
+    let _ = write!(
+        s,
+        "{}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    );
+}
+
+/// Rounded up, in tenths below 10 s, so it never shows less than is left.
+fn format_remaining(s: &mut String<24>, ms: u32) {
+    let tenths = ms.div_ceil(100);
+    if tenths < 100 {
+        let _ = write!(s, "{}.{}s", tenths / 10, tenths % 10);
+    } else {
+        let _ = write!(s, "{}s", ms.div_ceil(1000));
+    }
+}
+
+fn format_value(s: &mut String<24>, value: &Value) {
+    if value.value.abs() >= 100.0 {
+        let _ = write!(s, "{} {:.0}", value.name, value.value);
+    } else {
+        let _ = write!(s, "{} {:.1}", value.name, value.value);
+    }
+}
+
+/// Index of the first line shown on a list page. If there are more lines
+/// than fit on the page, cycle through them a screenful at a time.
+fn page_window(count: usize, now: i64) -> usize {
+    let windows = count.div_ceil(PAGE_LINES).max(1);
+    (now.max(0) / PAGE_SCROLL_MS) as usize % windows * PAGE_LINES
+}
+
+fn draw_bits<D>(target: &mut D, y: i32, label: &str, bits: u16) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let text = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    Text::with_baseline(label, Point::new(0, y), text, Baseline::Top).draw(target)?;
+    for i in 0..16 {
+        // Group in nibbles for readability.
+        let x = 10 + i * 7 + (i / 4) * 2;
+        let cell = Rectangle::new(Point::new(x, y + 1), Size::new(6, 8));
+        let style = if (bits >> i) & 1 != 0 {
+            PrimitiveStyle::with_fill(BinaryColor::On)
+        } else {
+            PrimitiveStyle::with_stroke(BinaryColor::On, 1)
+        };
+        cell.into_styled(style).draw(target)?;
+    }
+    Ok(())
+}
+
+/// Rendering is split into parts (the header, each body line and the
+/// footer), drawn one per mainloop iteration.
+const RENDER_PARTS: usize = PAGE_LINES + 2;
+
+/// Render one part of a page of the status display. The first part clears
+/// the frame.
+fn render_part<D>(target: &mut D, page: Page, status: &Status, part: usize) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    let text = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let draw_text = |target: &mut D, s: &str, x: i32, y: i32| {
+        Text::with_baseline(s, Point::new(x, y), text, Baseline::Top)
+            .draw(target)
+            .map(|_| ())
+    };
+
+    // Header: page title and uptime.
+    if part == 0 {
+        target.clear(BinaryColor::Off)?;
+        draw_text(target, page.title(), 0, 0)?;
+        let mut uptime: String<24> = String::new();
+        format_hms(&mut uptime, status.now);
+        draw_text(target, &uptime, WIDTH as i32 - 6 * uptime.len() as i32, 0)?;
+        Line::new(Point::new(0, 10), Point::new(WIDTH as i32 - 1, 10))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(target)?;
+        return Ok(());
+    }
+
+    // Footer: latest event, on the pages with room for it.
+    if part == RENDER_PARTS - 1 {
+        if page == Page::Io {
+            if let Some(event) = status.last_event {
+                let mut line: String<24> = String::new();
+                let _ = write!(line, "{} {}", event.source, event.message);
+                draw_text(target, &line, 0, HEIGHT as i32 - LINE_HEIGHT)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let i = part - 1;
+    let y = 13 + i as i32 * LINE_HEIGHT;
+    match page {
+        Page::Io => match i {
+            0 => draw_bits(target, y, "I", status.inputs)?,
+            2 => draw_bits(target, y, "Q", status.outputs)?,
+            3 => {
+                // Up to two measured values, side by side.
+                for (n, value) in status.values.iter().take(2).enumerate() {
+                    let mut line: String<24> = String::new();
+                    format_value(&mut line, value);
+                    draw_text(target, &line, n as i32 * WIDTH as i32 / 2, y)?;
+                }
+            }
+            _ => {}
+        },
+        Page::Fsm => {
+            let first = page_window(status.fsms.len(), status.now);
+            if let Some(fsm) = status.fsms.get(first + i) {
+                let mut line: String<24> = String::new();
+                let _ = write!(line, "{:<4}{}", fsm.name, fsm.state);
+                draw_text(target, &line, 0, y)?;
+                if let Some(ms) = fsm.remaining_ms {
+                    let mut rem: String<24> = String::new();
+                    format_remaining(&mut rem, ms);
+                    draw_text(target, &rem, WIDTH as i32 - 6 * rem.len() as i32, y)?;
+                }
+            }
+        }
+        Page::Values => {
+            let first = page_window(status.values.len(), status.now);
+            if let Some(value) = status.values.get(first + i) {
+                let mut line: String<24> = String::new();
+                format_value(&mut line, value);
+                draw_text(target, &line, 0, y)?;
+            }
+        }
+        Page::Events => match (i, status.last_event) {
+            (0, Some(event)) => {
+                let mut when: String<24> = String::new();
+                format_hms(&mut when, event.time);
+                draw_text(target, &when, 0, y)?;
+            }
+            (1, Some(event)) => {
+                let mut line: String<24> = String::new();
+                let _ = write!(line, "{} {}", event.source, event.message);
+                draw_text(target, &line, 0, y)?;
+            }
+            (0, None) => draw_text(target, "(none)", 0, y)?,
+            _ => {}
+        },
+    }
+    Ok(())
+}
+
+/// Render a whole page of the status display.
+#[allow(dead_code)]
+pub fn render<D>(target: &mut D, page: Page, status: &Status) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    for part in 0..RENDER_PARTS {
+        render_part(target, page, status, part)?;
+    }
+    Ok(())
+}
+
+/// Status display: page selection, periodic redraw and incremental flush.
+pub struct StatusDisplay<I2C> {
+    oled: Oled<I2C>,
+    fb: FrameBuffer,
+    page: Page,
+    refresh: SimpleTimer,
+    /// The next part of the frame being rendered, if one is.
+    rendering: Option<usize>,
+}
+
+impl<I2C: I2c> StatusDisplay<I2C> {
+    pub fn new(oled: Oled<I2C>) -> Self {
+        StatusDisplay {
+            oled,
+            fb: FrameBuffer::default(),
+            page: Page::default(),
+            refresh: SimpleTimer::start(0, 0.millis()),
+            rendering: None,
+        }
+    }
+
+    pub fn next_page(&mut self) {
+        self.page = self.page.next();
+        self.redraw();
+    }
+
+    pub fn prev_page(&mut self) {
+        self.page = self.page.prev();
+        self.redraw();
+    }
+
+    fn redraw(&mut self) {
+        self.refresh = SimpleTimer::start(0, 0.millis());
+        self.rendering = None;
+    }
+
+    /// Returns true while a frame is due or being rendered, and `draw`
+    /// should be called.
+    pub fn wants_frame(&self, now: i64) -> bool {
+        self.rendering.is_some() || (!self.oled.is_flushing() && self.refresh.expired(now))
+    }
+
+    /// Render the next part of the frame, and start sending it once it's
+    /// complete.
+    pub fn draw(&mut self, status: &Status) {
+        let part = self.rendering.unwrap_or(0);
+        let _ = render_part(&mut self.fb, self.page, status, part);
+        if part + 1 < RENDER_PARTS {
+            self.rendering = Some(part + 1);
+        } else {
+            self.rendering = None;
+            self.oled.start_flush();
+            self.refresh = SimpleTimer::start(status.now, DISPLAY_REFRESH_MS.millis());
+        }
+    }
+
+    pub fn update(&mut self) {
+        // A missing or unhappy display shouldn't affect anything else.
+        let _ = self.oled.poll(&self.fb);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ascii(fb: &FrameBuffer) -> std::string::String {
+        let mut s = std::string::String::new();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                s.push(if fb.pixel(x, y) { '#' } else { '.' });
+            }
+            s.push('\n');
+        }
+        s
+    }
+
+    // Compare against a stored snapshot. Run with UPDATE_SNAPSHOTS=1 to
+    // regenerate them after an intentional layout change.
+    fn check_snapshot(name: &str, fb: &FrameBuffer) {
+        let path = format!("{}/snapshots/{}.txt", env!("CARGO_MANIFEST_DIR"), name);
+        let actual = ascii(fb);
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&path, &actual).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path).unwrap();
+        assert!(expected == actual, "snapshot {} differs:\n{}", name, actual);
+    }
+
+    const FSMS: [FsmStatus; 5] = [
+        FsmStatus {
+            name: "FAN",
+            state: "HoldOn",
+            remaining_ms: Some(211_200),
+        },
+        FsmStatus {
+            name: "PRB",
+            state: "Off",
+            remaining_ms: None,
+        },
+        FsmStatus {
+            name: "SPN",
+            state: "WaitBrakeOn",
+            remaining_ms: Some(400),
+        },
+        FsmStatus {
+            name: "SRV",
+            state: "Fault",
+            remaining_ms: None,
+        },
+        FsmStatus {
+            name: "RST",
+            state: "Off",
+            remaining_ms: None,
+        },
+    ];
+
+    const VALUES: [Value; 2] = [
+        Value {
+            name: "RPM",
+            value: 2999.6,
+        },
+        Value {
+            name: "FLOW",
+            value: 12.34,
+        },
+    ];
+
+    const EVENT: Event = Event {
+        time: 3_723_000,
+        source: "FAN",
+        message: "HoldOn",
+    };
+
+    fn status() -> Status<'static> {
+        Status {
+            now: 3_812_000,
+            inputs: 0b1000_0000_1001_0001,
+            outputs: 0b0000_0011_0000_0101,
+            fsms: &FSMS,
+            values: &VALUES,
+            last_event: Some(&EVENT),
+        }
+    }
+
+    #[test]
+    fn test_page_cycle() {
+        assert_eq!(Page::Io.next(), Page::Fsm);
+        assert_eq!(Page::Fsm.next(), Page::Values);
+        assert_eq!(Page::Events.next(), Page::Io);
+        assert_eq!(Page::Io.prev(), Page::Events);
+        assert_eq!(Page::Fsm.prev(), Page::Io);
+    }
+
+    #[test]
+    fn test_format_remaining() {
+        let mut s: String<24> = String::new();
+        format_remaining(&mut s, 211_200);
+        assert_eq!(s.as_str(), "212s");
+        s.clear();
+        format_remaining(&mut s, 400);
+        assert_eq!(s.as_str(), "0.4s");
+        s.clear();
+        format_remaining(&mut s, 9_801);
+        assert_eq!(s.as_str(), "9.9s");
+        s.clear();
+        format_remaining(&mut s, 9_901);
+        assert_eq!(s.as_str(), "10s");
+        s.clear();
+        format_remaining(&mut s, 10_001);
+        assert_eq!(s.as_str(), "11s");
+    }
+
+    #[test]
+    fn test_format_value() {
+        let mut s: String<24> = String::new();
+        format_value(&mut s, &VALUES[0]);
+        assert_eq!(s.as_str(), "RPM 3000");
+        s.clear();
+        format_value(&mut s, &VALUES[1]);
+        assert_eq!(s.as_str(), "FLOW 12.3");
+    }
+
+    #[test]
+    fn test_page_window() {
+        assert_eq!(page_window(0, 0), 0);
+        assert_eq!(page_window(5, 10 * PAGE_SCROLL_MS), 0);
+        assert_eq!(page_window(7, 0), 0);
+        assert_eq!(page_window(7, PAGE_SCROLL_MS), 5);
+        assert_eq!(page_window(7, 2 * PAGE_SCROLL_MS), 0);
+    }
+
+    #[test]
+    fn test_render_io_page() {
+        let mut fb = FrameBuffer::default();
+        render(&mut fb, Page::Io, &status()).unwrap();
+        check_snapshot("display_io", &fb);
+    }
+
+    #[test]
+    fn test_render_fsm_page() {
+        let mut fb = FrameBuffer::default();
+        render(&mut fb, Page::Fsm, &status()).unwrap();
+        check_snapshot("display_fsm", &fb);
+    }
+
+    #[test]
+    fn test_render_fsm_countdown() {
+        // Either side of where it stops showing tenths.
+        let fsms = [9_900, 9_901, 10_000, 10_001].map(|ms| FsmStatus {
+            name: "FAN",
+            state: "HoldOn",
+            remaining_ms: Some(ms),
+        });
+        let mut fb = FrameBuffer::default();
+        let mut st = status();
+        st.fsms = &fsms;
+        render(&mut fb, Page::Fsm, &st).unwrap();
+        check_snapshot("display_fsm_countdown", &fb);
+    }
+
+    #[test]
+    fn test_render_values_page() {
+        let mut fb = FrameBuffer::default();
+        render(&mut fb, Page::Values, &status()).unwrap();
+        check_snapshot("display_values", &fb);
+    }
+
+    #[test]
+    fn test_render_events_page() {
+        let mut fb = FrameBuffer::default();
+        render(&mut fb, Page::Events, &status()).unwrap();
+        check_snapshot("display_events", &fb);
+    }
+
+    #[test]
+    fn test_render_no_events() {
+        let mut fb = FrameBuffer::default();
+        let mut st = status();
+        st.last_event = None;
+        render(&mut fb, Page::Events, &st).unwrap();
+        check_snapshot("display_no_events", &fb);
+    }
+}
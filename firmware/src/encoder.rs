@@ -0,0 +1,186 @@
+//! Quadrature encoders.
+//!
+//! The timers' encoder mode does the actual counting in hardware, but only
+//! into a 16-bit counter. This extends that to a 32-bit position by
+//! accumulating the signed difference between successive reads, which is
+//! fine as long as we read more often than it can move 32k counts. On top
+//! of that it estimates velocity and can home the position on an index
+//! pulse, whose counter value is captured by the timer.
+use crate::simpletimer::SimpleTimer;
+use fugit::ExtU32;
+
+const VELOCITY_WINDOW_MS: u32 = 20;
+
+pub struct Encoder {
+    counts_per_rev: u32,
+    last_raw: Option<u16>,
+    // Extended count since startup; position() is relative to `home`.
+    count: i32,
+    home: i32,
+    homed: bool,
+    homing: bool,
+    velocity: f32,
+    window: SimpleTimer,
+    window_count: i32,
+}
+
+impl Encoder {
+    /// `counts_per_rev` is in quadrature counts, i.e. four times the
+    /// encoder's line count.
+    pub fn new(counts_per_rev: u32) -> Self {
+        Encoder {
+            counts_per_rev: counts_per_rev.max(1),
+            last_raw: None,
+            count: 0,
+            home: 0,
+            homed: false,
+            homing: false,
+            velocity: 0.0,
+            window: SimpleTimer::start(0, VELOCITY_WINDOW_MS.millis()),
+            window_count: 0,
+        }
+    }
+
+    /// Signed distance from the last read counter value to `raw`.
+    fn delta(&self, raw: u16) -> i32 {
+        match self.last_raw {
+            Some(last) => raw.wrapping_sub(last) as i16 as i32,
+            None => 0,
+        }
+    }
+
+    /// Feed in the hardware counter value. The first value read is taken
+    /// as position zero.
+    pub fn update(&mut self, raw: u16, now: i64) {
+        self.count = self.count.wrapping_add(self.delta(raw));
+        self.last_raw = Some(raw);
+        if self.window.expired(now) {
+            let elapsed_ms = self.window.elapsed(now).ticks().max(1);
+            let moved = self.count.wrapping_sub(self.window_count);
+            self.velocity = moved as f32 * 1000.0 / elapsed_ms as f32;
+            self.window_count = self.count;
+            self.window = SimpleTimer::start(now, VELOCITY_WINDOW_MS.millis());
+        }
+    }
+
+    /// Zero the position on the next index pulse.
+    pub fn home_on_index(&mut self) {
+        self.homing = true;
+    }
+
+    /// An index pulse was seen, with the counter value captured at the
+    /// time. Call this after `update` for the same loop.
+    pub fn index(&mut self, raw: u16) {
+        if self.homing {
+            self.home = self.count.wrapping_add(self.delta(raw));
+            self.homed = true;
+            self.homing = false;
+        }
+    }
+
+    /// Set the current position, e.g. to zero a handwheel.
+    #[allow(dead_code)]
+    pub fn set_position(&mut self, position: i32) {
+        self.home = self.count.wrapping_sub(position);
+    }
+
+    pub fn position(&self) -> i32 {
+        self.count.wrapping_sub(self.home)
+    }
+
+    pub fn is_homed(&self) -> bool {
+        self.homed
+    }
+
+    /// Velocity in counts per second.
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    #[allow(dead_code)]
+    pub fn rpm(&self) -> f32 {
+        self.velocity * 60.0 / self.counts_per_rev as f32
+    }
+
+    /// Angle within the current revolution, relative to home.
+    #[allow(dead_code)]
+    pub fn angle_deg(&self) -> f32 {
+        let counts = self.position().rem_euclid(self.counts_per_rev as i32);
+        counts as f32 * 360.0 / self.counts_per_rev as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extends_across_wrap() {
+        let mut enc = Encoder::new(400);
+        enc.update(65530, 0);
+        assert_eq!(enc.position(), 0);
+        enc.update(10, 1);
+        assert_eq!(enc.position(), 16);
+        enc.update(65500, 2);
+        assert_eq!(enc.position(), -30);
+    }
+
+    #[test]
+    fn test_extends_past_16_bits() {
+        let mut enc = Encoder::new(400);
+        let mut raw: u16 = 0;
+        enc.update(raw, 0);
+        for now in 0..100 {
+            raw = raw.wrapping_add(10_000);
+            enc.update(raw, now);
+        }
+        assert_eq!(enc.position(), 1_000_000);
+        for now in 100..300 {
+            raw = raw.wrapping_sub(10_000);
+            enc.update(raw, now);
+        }
+        assert_eq!(enc.position(), -1_000_000);
+    }
+
+    #[test]
+    fn test_velocity() {
+        let mut enc = Encoder::new(400);
+        // 2 counts per ms.
+        for now in 0..=100 {
+            enc.update((now * 2) as u16, now);
+        }
+        assert_eq!(enc.velocity(), 2000.0);
+        assert_eq!(enc.rpm(), 300.0);
+    }
+
+    #[test]
+    fn test_index_homing() {
+        let mut enc = Encoder::new(400);
+        enc.update(1000, 0);
+        // Index pulses are ignored until homing is requested.
+        enc.index(990);
+        assert!(!enc.is_homed());
+        assert_eq!(enc.position(), 0);
+        enc.home_on_index();
+        enc.update(1100, 1);
+        // Captured a little before this loop's read.
+        enc.index(1090);
+        assert!(enc.is_homed());
+        assert_eq!(enc.position(), 10);
+        // Only homes once per request.
+        enc.update(1500, 2);
+        enc.index(1490);
+        assert_eq!(enc.position(), 410);
+    }
+
+    #[test]
+    fn test_set_position_and_angle() {
+        let mut enc = Encoder::new(400);
+        enc.update(12345, 0);
+        enc.set_position(0);
+        assert_eq!(enc.position(), 0);
+        enc.update(12345 - 100, 1);
+        assert_eq!(enc.position(), -100);
+        assert_eq!(enc.angle_deg(), 270.0);
+    }
+}
@@ -0,0 +1,71 @@
+//! Event log.
+//!
+//! A small ring buffer of timestamped events, mostly FSM state changes.
+//! Oldest entries are overwritten once the log fills up.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Event {
+    pub time: i64,
+    pub source: &'static str,
+    pub message: &'static str,
+}
+
+const EVENT_LOG_LEN: usize = 32;
+
+pub struct EventLog {
+    events: [Option<Event>; EVENT_LOG_LEN],
+    next: usize,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        EventLog {
+            events: [None; EVENT_LOG_LEN],
+            next: 0,
+        }
+    }
+}
+
+impl EventLog {
+    pub fn record(&mut self, now: i64, source: &'static str, message: &'static str) {
+        self.events[self.next] = Some(Event {
+            time: now,
+            source,
+            message,
+        });
+        self.next = (self.next + 1) % EVENT_LOG_LEN;
+    }
+
+    pub fn latest(&self) -> Option<&Event> {
+        self.events[(self.next + EVENT_LOG_LEN - 1) % EVENT_LOG_LEN].as_ref()
+    }
+
+    /// Iterate over the logged events, newest first.
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = &Event> {
+        (1..=EVENT_LOG_LEN)
+            .map(move |i| &self.events[(self.next + EVENT_LOG_LEN - i) % EVENT_LOG_LEN])
+            .map_while(|e| e.as_ref())
+    }
+}
+
+/// Records an event whenever a watched state name changes.
+#[derive(Default)]
+pub struct StateWatch {
+    last: &'static str,
+}
+
+impl StateWatch {
+    pub fn update(
+        &mut self,
+        log: &mut EventLog,
+        now: i64,
+        source: &'static str,
+        state: &'static str,
+    ) {
+        if self.last != state {
+            self.last = state;
+            log.record(now, source, state);
+        }
+    }
+}
@@ -1,12 +1,19 @@
 //! Spindle fan PLC logic.
 //!
 //! This implements a simple hold-on/hold-off controller for a spindle
-//! cooling fan.
+//! cooling fan. Optionally the fan's speed can be controlled too, for a
+//! fan on a PWM output: while the fan is on it runs at a speed between a
+//! minimum and full, depending on how hard the spindle has been working
+//! recently and, if there's a temperature input, how hot it is. It's given
+//! a short kick at higher speed each time it starts, to get it turning. Past
+//! the full speed temperature the fan comes on even without the spindle,
+//! and so it does if the temperature sensor fails, at full speed.
+use crate::hysteresis::Hysteresis;
 use crate::simpletimer::SimpleTimer;
 use fugit::ExtU32;
 
 #[derive(Default)]
-enum FanFSMState {
+pub enum FanFSMState {
     #[default]
     Off,
     HoldOff(SimpleTimer),
@@ -14,16 +21,76 @@ enum FanFSMState {
     HoldOn(SimpleTimer),
 }
 
+#[derive(Clone, Copy)]
+pub struct FanSpeedConfig {
+    pub min_speed: f32,
+    pub kick_speed: f32,
+    pub kick_time: fugit::Duration<u32, 1, 1_000>,
+    /// Time constant of the spindle duty average; running continuously for
+    /// this long brings the fan most of the way to full speed.
+    pub load_time: fugit::Duration<u32, 1, 1_000>,
+    /// Temperatures at which the fan starts speeding up, and reaches full
+    /// speed.
+    pub temp_low: f32,
+    pub temp_high: f32,
+}
+
 #[derive(Default)]
 pub struct FanControl {
     state: FanFSMState,
+    speed_config: Option<FanSpeedConfig>,
+    kick: Option<SimpleTimer>,
+    load: f32,
+    temperature: Option<f32>,
+    sensor_fault: bool,
+    hot: Option<Hysteresis>,
+    last_update: Option<i64>,
 }
 
 const FAN_HOLDOFF_SECS: u32 = 60;
 const FAN_HOLDON_SECS: u32 = 300;
 
 impl FanControl {
+    /// A fan whose speed is controlled as well as whether it's on.
+    pub fn proportional(config: FanSpeedConfig) -> Self {
+        FanControl {
+            speed_config: Some(config),
+            hot: Some(Hysteresis::new(config.temp_high, config.temp_low)),
+            ..Default::default()
+        }
+    }
+
+    /// Latest reading from the temperature input, if there is one; `None`
+    /// if the sensor has failed.
+    pub fn set_temperature(&mut self, temperature: Option<f32>) {
+        self.temperature = temperature;
+        self.sensor_fault = temperature.is_none();
+        if let Some(hot) = &mut self.hot {
+            hot.update(temperature.unwrap_or(f32::INFINITY));
+        }
+    }
+
     pub fn update(&mut self, spindle_on: bool, now: i64) {
+        let was_on = self.fan_state();
+        let hot = self.hot.as_ref().is_some_and(Hysteresis::is_on);
+        self.update_state(spindle_on || hot, now);
+        if let Some(config) = &self.speed_config {
+            let elapsed_ms = match self.last_update.replace(now) {
+                Some(last) => (now - last).max(0) as f32,
+                None => 0.0,
+            };
+            let alpha = (elapsed_ms / config.load_time.ticks().max(1) as f32).min(1.0);
+            self.load += ((spindle_on as u8 as f32) - self.load) * alpha;
+            if self.fan_state() && !was_on {
+                self.kick = Some(SimpleTimer::start(now, config.kick_time));
+            }
+            if self.kick.as_ref().is_some_and(|kick| kick.expired(now)) {
+                self.kick = None;
+            }
+        }
+    }
+
+    fn update_state(&mut self, spindle_on: bool, now: i64) {
         match &self.state {
             FanFSMState::Off => {
                 if spindle_on {
@@ -63,6 +130,30 @@ impl FanControl {
         }
     }
 
+    /// Fan speed from 0.0 to 1.0. Without speed control this is just full
+    /// speed whenever the fan is on.
+    pub fn fan_speed(&self) -> f32 {
+        if !self.fan_state() {
+            return 0.0;
+        }
+        let Some(config) = &self.speed_config else {
+            return 1.0;
+        };
+        let temp_demand = match self.temperature {
+            _ if self.sensor_fault => 1.0,
+            Some(t) if config.temp_high > config.temp_low => {
+                (t - config.temp_low) / (config.temp_high - config.temp_low)
+            }
+            _ => 0.0,
+        };
+        let demand = self.load.max(temp_demand).clamp(0.0, 1.0);
+        let speed = config.min_speed + (1.0 - config.min_speed) * demand;
+        match self.kick {
+            Some(_) => speed.max(config.kick_speed),
+            None => speed,
+        }
+    }
+
     pub fn status_char(&self) -> char {
         match self.state {
             FanFSMState::Off => 'N',
@@ -71,4 +162,155 @@ impl FanControl {
             FanFSMState::HoldOn(_) => 'U',
         }
     }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            FanFSMState::Off => "Off",
+            FanFSMState::HoldOff(_) => "HoldOff",
+            FanFSMState::On => "On",
+            FanFSMState::HoldOn(_) => "HoldOn",
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> &FanFSMState {
+        &self.state
+    }
+
+    /// Time left before the current state times out, if it has a timer.
+    pub fn remaining(&self, now: i64) -> Option<fugit::Duration<u32, 1, 1_000>> {
+        match &self.state {
+            FanFSMState::Off => None,
+            FanFSMState::HoldOff(timer) => Some(timer.remaining(now)),
+            FanFSMState::On => None,
+            FanFSMState::HoldOn(timer) => Some(timer.remaining(now)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: FanSpeedConfig = FanSpeedConfig {
+        min_speed: 0.3,
+        kick_speed: 1.0,
+        kick_time: fugit::Duration::<u32, 1, 1_000>::from_ticks(2000),
+        load_time: fugit::Duration::<u32, 1, 1_000>::from_ticks(600_000),
+        temp_low: 40.0,
+        temp_high: 60.0,
+    };
+    const HOLDOFF_MS: i64 = FAN_HOLDOFF_SECS as i64 * 1000;
+    const HOLDON_MS: i64 = FAN_HOLDON_SECS as i64 * 1000;
+
+    fn run(fan: &mut FanControl, spindle_on: bool, from: i64, to: i64) {
+        for now in (from..=to).step_by(100) {
+            fan.update(spindle_on, now);
+        }
+    }
+
+    #[test]
+    fn test_on_off_is_full_speed() {
+        let mut fan = FanControl::default();
+        run(&mut fan, true, 0, HOLDOFF_MS - 100);
+        assert_eq!(fan.fan_speed(), 0.0);
+        assert!(matches!(fan.state(), FanFSMState::HoldOff(_)));
+        assert_eq!(fan.remaining(HOLDOFF_MS - 100).unwrap().ticks(), 100);
+        run(&mut fan, true, HOLDOFF_MS, HOLDOFF_MS + 10_000);
+        assert!(matches!(fan.state(), FanFSMState::On));
+        assert!(fan.fan_state());
+        assert_eq!(fan.fan_speed(), 1.0);
+    }
+
+    #[test]
+    fn test_kick_then_minimum() {
+        let mut fan = FanControl::proportional(CONFIG);
+        run(&mut fan, true, 0, HOLDOFF_MS);
+        assert!(fan.fan_state());
+        assert_eq!(fan.fan_speed(), 1.0);
+        run(&mut fan, true, HOLDOFF_MS + 100, HOLDOFF_MS + 2000);
+        // A minute's running is about a tenth of the load time.
+        let speed = fan.fan_speed();
+        assert!(speed > 0.3 && speed < 0.4, "{}", speed);
+    }
+
+    #[test]
+    fn test_speeds_up_with_load() {
+        let mut fan = FanControl::proportional(CONFIG);
+        run(&mut fan, true, 0, 3_600_000);
+        assert!(fan.fan_speed() > 0.99, "{}", fan.fan_speed());
+        // Slows during the hold-on, but keeps running.
+        run(&mut fan, false, 3_600_100, 3_600_000 + HOLDON_MS - 100);
+        assert!(fan.fan_state());
+        assert!(fan.fan_speed() < 0.9, "{}", fan.fan_speed());
+        run(
+            &mut fan,
+            false,
+            3_600_000 + HOLDON_MS,
+            3_600_000 + HOLDON_MS + 100,
+        );
+        assert_eq!(fan.fan_speed(), 0.0);
+    }
+
+    #[test]
+    fn test_temperature() {
+        let mut fan = FanControl::proportional(CONFIG);
+        run(&mut fan, true, 0, HOLDOFF_MS + 2000);
+        fan.set_temperature(Some(50.0));
+        let speed = fan.fan_speed();
+        assert!((speed - 0.65).abs() < 0.05, "{}", speed);
+        fan.set_temperature(Some(80.0));
+        assert_eq!(fan.fan_speed(), 1.0);
+    }
+
+    #[test]
+    fn test_hot_turns_fan_on() {
+        let mut fan = FanControl::proportional(CONFIG);
+        fan.set_temperature(Some(50.0));
+        run(&mut fan, false, 0, 2 * HOLDOFF_MS);
+        assert!(!fan.fan_state());
+        fan.set_temperature(Some(60.0));
+        run(&mut fan, false, 2 * HOLDOFF_MS, 3 * HOLDOFF_MS + 100);
+        assert!(fan.fan_state());
+        // Stays on down to the low threshold, then runs on for the hold-on.
+        fan.set_temperature(Some(45.0));
+        run(&mut fan, false, 3 * HOLDOFF_MS + 200, 3 * HOLDOFF_MS + 1000);
+        assert_eq!(fan.state_name(), "On");
+        fan.set_temperature(Some(39.0));
+        run(
+            &mut fan,
+            false,
+            3 * HOLDOFF_MS + 1100,
+            3 * HOLDOFF_MS + 2000,
+        );
+        assert_eq!(fan.state_name(), "HoldOn");
+        // A failed sensor runs it flat out.
+        fan.set_temperature(None);
+        run(
+            &mut fan,
+            false,
+            3 * HOLDOFF_MS + 2100,
+            3 * HOLDOFF_MS + 2100 + HOLDON_MS,
+        );
+        assert_eq!(fan.state_name(), "On");
+        assert_eq!(fan.fan_speed(), 1.0);
+        // Until it recovers.
+        fan.set_temperature(Some(30.0));
+        run(
+            &mut fan,
+            false,
+            3 * HOLDOFF_MS + 2200 + HOLDON_MS,
+            3 * HOLDOFF_MS + 2200 + 2 * HOLDON_MS,
+        );
+        assert!(!fan.fan_state());
+    }
+
+    #[test]
+    fn test_failed_sensor_starts_fan() {
+        let mut fan = FanControl::proportional(CONFIG);
+        fan.set_temperature(None);
+        run(&mut fan, false, 0, HOLDOFF_MS + 100);
+        assert!(fan.fan_state());
+        assert_eq!(fan.fan_speed(), 1.0);
+    }
 }
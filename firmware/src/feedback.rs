@@ -0,0 +1,204 @@
+//! Output feedback monitoring.
+//!
+//! Outputs are otherwise set blindly, so a shorted or overloaded driver, or
+//! a contactor that's welded or won't pull in, would go unnoticed. This
+//! compares the state an output was set to with what's read back, from the
+//! pin itself or from an input wired to an auxiliary contact, and flags a
+//! fault once they've disagreed for longer than a window. The window
+//! covers the time the output takes to settle, or the contactor to move.
+//!
+//! The fault clears as soon as they agree again; latching it, and getting
+//! to a safe state, is left to the interlocks.
+use crate::simpletimer::SimpleTimer;
+
+/// An output with an input wired back to it as feedback.
+pub struct FeedbackDef {
+    pub output: usize,
+    /// Disabled until the feedback is wired up.
+    pub input: Option<usize>,
+    pub window: fugit::Duration<u32, 1, 1_000>,
+}
+
+impl FeedbackDef {
+    /// Check the output, a bit in `outputs_set`, against its feedback input,
+    /// if it has one. True if it has just faulted.
+    pub fn update(
+        &self,
+        monitor: &mut FeedbackMonitor,
+        outputs_set: u16,
+        inputs: &[bool; 16],
+        now: i64,
+    ) -> bool {
+        match self.input {
+            Some(input) => monitor.update(outputs_set & 1 << self.output != 0, inputs[input], now),
+            None => false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub enum FeedbackFSMState {
+    #[default]
+    Ok,
+    Mismatch(SimpleTimer),
+    Fault,
+}
+
+pub struct FeedbackMonitor {
+    window: fugit::Duration<u32, 1, 1_000>,
+    state: FeedbackFSMState,
+}
+
+impl FeedbackMonitor {
+    pub fn new(window: fugit::Duration<u32, 1, 1_000>) -> Self {
+        FeedbackMonitor {
+            window,
+            state: FeedbackFSMState::Ok,
+        }
+    }
+
+    /// True if it has just faulted.
+    pub fn update(&mut self, commanded: bool, actual: bool, now: i64) -> bool {
+        let was_fault = self.fault();
+        if commanded == actual {
+            self.state = FeedbackFSMState::Ok;
+            return false;
+        }
+        match &self.state {
+            FeedbackFSMState::Ok => {
+                self.state = FeedbackFSMState::Mismatch(SimpleTimer::start(now, self.window));
+            }
+            FeedbackFSMState::Mismatch(timer) => {
+                if timer.expired(now) {
+                    self.state = FeedbackFSMState::Fault;
+                }
+            }
+            FeedbackFSMState::Fault => {}
+        }
+        self.fault() && !was_fault
+    }
+
+    pub fn fault(&self) -> bool {
+        matches!(self.state, FeedbackFSMState::Fault)
+    }
+
+    #[allow(dead_code)]
+    pub fn status_char(&self) -> char {
+        match self.state {
+            FeedbackFSMState::Ok => 'O',
+            FeedbackFSMState::Mismatch(_) => 'M',
+            FeedbackFSMState::Fault => 'F',
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            FeedbackFSMState::Ok => "Ok",
+            FeedbackFSMState::Mismatch(_) => "Mismatch",
+            FeedbackFSMState::Fault => "Fault",
+        }
+    }
+
+    /// Time left before the current state times out, if it has a timer.
+    #[allow(dead_code)]
+    pub fn remaining(&self, now: i64) -> Option<fugit::Duration<u32, 1, 1_000>> {
+        match &self.state {
+            FeedbackFSMState::Mismatch(timer) => Some(timer.remaining(now)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU32;
+
+    #[test]
+    fn test_settling() {
+        let mut monitor = FeedbackMonitor::new(100.millis());
+        monitor.update(true, false, 0);
+        assert_eq!(monitor.state_name(), "Mismatch");
+        assert_eq!(monitor.remaining(40).unwrap().ticks(), 60);
+        monitor.update(true, true, 50);
+        assert_eq!(monitor.state_name(), "Ok");
+        // The window starts again from the next mismatch.
+        monitor.update(false, true, 60);
+        monitor.update(false, true, 159);
+        assert!(!monitor.fault());
+        monitor.update(false, false, 200);
+        assert!(!monitor.fault());
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut monitor = FeedbackMonitor::new(100.millis());
+        assert!(!monitor.update(true, false, 0));
+        assert!(monitor.update(true, false, 100));
+        assert!(monitor.fault());
+        assert!(!monitor.update(true, false, 101));
+        monitor.update(true, false, 1000);
+        assert!(monitor.fault());
+        monitor.update(false, false, 1010);
+        assert!(!monitor.fault());
+    }
+
+    #[test]
+    fn test_feedback_input() {
+        let def = FeedbackDef {
+            output: 9,
+            input: Some(5),
+            window: 200.millis(),
+        };
+        let mut monitor = FeedbackMonitor::new(def.window);
+        let mut inputs = [false; 16];
+        // The contactor pulls in within the window.
+        def.update(&mut monitor, 1 << 9, &inputs, 0);
+        inputs[5] = true;
+        def.update(&mut monitor, 1 << 9, &inputs, 150);
+        assert_eq!(monitor.state_name(), "Ok");
+        // Then drops out while still commanded on.
+        inputs[5] = false;
+        assert!(!def.update(&mut monitor, 1 << 9, &inputs, 1000));
+        assert!(def.update(&mut monitor, 1 << 9, &inputs, 1200));
+        // Or is welded, on while commanded off.
+        let mut monitor = FeedbackMonitor::new(def.window);
+        inputs[5] = true;
+        def.update(&mut monitor, 0, &inputs, 0);
+        assert!(def.update(&mut monitor, 1 << 8, &inputs, 200));
+    }
+
+    #[test]
+    fn test_readback_and_input_share_window() {
+        let def = FeedbackDef {
+            output: 9,
+            input: Some(5),
+            window: 200.millis(),
+        };
+        let mut readback = FeedbackMonitor::new(def.window);
+        let mut feedback = FeedbackMonitor::new(def.window);
+        let inputs = [false; 16];
+        // Output 9 is set, but neither the pin nor the contact follows.
+        for now in (0..200).step_by(10) {
+            assert!(!readback.update(true, false, now));
+            assert!(!def.update(&mut feedback, 1 << 9, &inputs, now));
+            assert_eq!(readback.remaining(now), feedback.remaining(now));
+        }
+        assert!(readback.update(true, false, 200));
+        assert!(def.update(&mut feedback, 1 << 9, &inputs, 200));
+    }
+
+    #[test]
+    fn test_feedback_not_wired() {
+        let def = FeedbackDef {
+            output: 9,
+            input: None,
+            window: 200.millis(),
+        };
+        let mut monitor = FeedbackMonitor::new(def.window);
+        def.update(&mut monitor, 1 << 9, &[false; 16], 0);
+        def.update(&mut monitor, 1 << 9, &[false; 16], 1000);
+        assert!(!monitor.fault());
+    }
+}
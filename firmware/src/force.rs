@@ -0,0 +1,217 @@
+//! Force table, for commissioning.
+//!
+//! Forcing an input overrides its filtered state for everything that reads
+//! it, and forcing an output overrides whatever the firmware, the rules or
+//! the user logic would set it to, until the force is released. A PWM
+//! output forced on or off goes to 100% or 0% duty. Forces are lost on
+//! reset unless they're kept in retained variables (see `store` and
+//! `restore`).
+use crate::retain::Retain;
+use core::fmt;
+
+// Retained variable slots, after the maintenance statistics. Each holds a
+// mask of the forced points in the low half and their values in the high.
+const SLOT_FORCED_INPUTS: usize = 10;
+const SLOT_FORCED_OUTPUTS: usize = 11;
+
+const INPUT_NAMES: [&str; 16] = [
+    "I0", "I1", "I2", "I3", "I4", "I5", "I6", "I7", "I8", "I9", "I10", "I11", "I12", "I13", "I14",
+    "I15",
+];
+const OUTPUT_NAMES: [&str; 16] = [
+    "Q0", "Q1", "Q2", "Q3", "Q4", "Q5", "Q6", "Q7", "Q8", "Q9", "Q10", "Q11", "Q12", "Q13", "Q14",
+    "Q15",
+];
+
+/// An input or output, by the same names the rules use.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Point {
+    Input(usize),
+    Output(usize),
+}
+
+impl Point {
+    pub fn parse(name: &str) -> Option<Point> {
+        let find = |names: &[&str; 16]| names.iter().position(|n| n.eq_ignore_ascii_case(name));
+        find(&INPUT_NAMES)
+            .map(Point::Input)
+            .or_else(|| find(&OUTPUT_NAMES).map(Point::Output))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Point::Input(n) => INPUT_NAMES[n],
+            Point::Output(n) => OUTPUT_NAMES[n],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+struct Forced {
+    mask: u16,
+    values: u16,
+}
+
+impl Forced {
+    fn get(&self, n: usize) -> Option<bool> {
+        (self.mask & 1 << n != 0).then_some(self.values & 1 << n != 0)
+    }
+
+    fn set(&mut self, n: usize, value: Option<bool>) {
+        self.mask &= !(1 << n);
+        self.values &= !(1 << n);
+        if let Some(value) = value {
+            self.mask |= 1 << n;
+            self.values |= (value as u16) << n;
+        }
+    }
+
+    fn word(&self) -> u32 {
+        self.mask as u32 | (self.values as u32) << 16
+    }
+
+    fn from_word(word: u32) -> Self {
+        let mask = word as u16;
+        Forced {
+            mask,
+            values: (word >> 16) as u16 & mask,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Forces {
+    inputs: Forced,
+    outputs: Forced,
+}
+
+impl Forces {
+    /// The forced state of a point, if it's forced.
+    pub fn get(&self, point: Point) -> Option<bool> {
+        match point {
+            Point::Input(n) => self.inputs.get(n),
+            Point::Output(n) => self.outputs.get(n),
+        }
+    }
+
+    /// Force a point on or off, or release it with `None`.
+    pub fn set(&mut self, point: Point, value: Option<bool>) {
+        match point {
+            Point::Input(n) => self.inputs.set(n, value),
+            Point::Output(n) => self.outputs.set(n, value),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Forces::default();
+    }
+
+    pub fn input(&self, n: usize) -> Option<bool> {
+        self.inputs.get(n)
+    }
+
+    pub fn output(&self, n: usize) -> Option<bool> {
+        self.outputs.get(n)
+    }
+
+    /// The duty for a PWM output, full on or off if it's forced.
+    pub fn output_duty(&self, n: usize, duty: f32) -> f32 {
+        match self.outputs.get(n) {
+            Some(true) => 1.0,
+            Some(false) => 0.0,
+            None => duty,
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.inputs.mask != 0 || self.outputs.mask != 0
+    }
+
+    pub fn restore(&mut self, retain: &Retain) {
+        self.inputs = Forced::from_word(retain.get(SLOT_FORCED_INPUTS));
+        self.outputs = Forced::from_word(retain.get(SLOT_FORCED_OUTPUTS));
+    }
+
+    pub fn store(&self, retain: &mut Retain) {
+        retain.set(SLOT_FORCED_INPUTS, self.inputs.word());
+        retain.set(SLOT_FORCED_OUTPUTS, self.outputs.word());
+    }
+}
+
+/// The forced points, e.g. `I3=1 Q5=0`, or `none`.
+impl fmt::Display for Forces {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.active() {
+            return f.write_str("none");
+        }
+        let points = (0..16).map(Point::Input).chain((0..16).map(Point::Output));
+        let mut separator = "";
+        for point in points {
+            if let Some(value) = self.get(point) {
+                write!(f, "{}{}={}", separator, point.name(), value as u8)?;
+                separator = " ";
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_points() {
+        assert_eq!(Point::parse("I0"), Some(Point::Input(0)));
+        assert_eq!(Point::parse("q15"), Some(Point::Output(15)));
+        assert_eq!(Point::parse("Q16"), None);
+        assert_eq!(Point::parse("I01"), None);
+        assert_eq!(Point::parse("X1"), None);
+        assert_eq!(Point::Output(12).name(), "Q12");
+    }
+
+    #[test]
+    fn test_forces() {
+        let mut forces = Forces::default();
+        assert!(!forces.active());
+        assert_eq!(forces.to_string(), "none");
+        forces.set(Point::Input(3), Some(true));
+        forces.set(Point::Output(5), Some(true));
+        forces.set(Point::Output(5), Some(false));
+        forces.set(Point::Output(15), Some(true));
+        assert!(forces.active());
+        assert_eq!(forces.input(3), Some(true));
+        assert_eq!(forces.input(4), None);
+        assert_eq!(forces.output(5), Some(false));
+        assert_eq!(forces.to_string(), "I3=1 Q5=0 Q15=1");
+        forces.set(Point::Input(3), None);
+        forces.set(Point::Output(15), None);
+        assert_eq!(forces.to_string(), "Q5=0");
+        forces.clear();
+        assert!(!forces.active());
+    }
+
+    #[test]
+    fn test_pwm_output() {
+        let mut forces = Forces::default();
+        assert_eq!(forces.output_duty(13, 0.4), 0.4);
+        forces.set(Point::Output(13), Some(true));
+        forces.set(Point::Output(14), Some(false));
+        assert_eq!(forces.output_duty(13, 0.4), 1.0);
+        assert_eq!(forces.output_duty(14, 0.4), 0.0);
+        forces.set(Point::Output(13), None);
+        assert_eq!(forces.output_duty(13, 0.4), 0.4);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut retain = Retain::new([0; crate::retain::RETAIN_SLOTS], 0);
+        let mut forces = Forces::default();
+        forces.set(Point::Input(0), Some(false));
+        forces.set(Point::Output(7), Some(true));
+        forces.store(&mut retain);
+        let mut restored = Forces::default();
+        restored.restore(&retain);
+        assert_eq!(restored.to_string(), "I0=0 Q7=1");
+    }
+}
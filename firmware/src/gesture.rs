@@ -0,0 +1,259 @@
+//! Pushbutton gesture recognition.
+//!
+//! Turns a debounced button into one of a few mutually exclusive gestures,
+//! so a single button can carry several functions without e.g. a long press
+//! also counting as a click:
+//!
+//! - Click: a short press, reported once the double-click window has passed.
+//! - DoubleClick: two short presses in quick succession, reported on release.
+//! - LongPress: held past the long-press time, reported on release.
+//! - Hold: held past the hold time, reported then and repeatedly after that
+//!   until released. A hold doesn't also report a LongPress.
+//!
+//! Whether the button is being held past the long-press time can also be
+//! read as a level, for things that should last as long as the press.
+use crate::debounce::Debouncer;
+use crate::simpletimer::SimpleTimer;
+use fugit::ExtU32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Gesture {
+    Click,
+    DoubleClick,
+    LongPress,
+    Hold,
+}
+
+#[derive(Default)]
+pub enum GestureFSMState {
+    #[default]
+    Idle,
+    Pressed(SimpleTimer),
+    WaitSecond(SimpleTimer),
+    SecondPress,
+    LongPressed(SimpleTimer),
+    Holding(SimpleTimer),
+}
+
+pub struct ButtonGesture {
+    state: GestureFSMState,
+    button: Debouncer,
+    long_press_time: fugit::Duration<u32, 1, 1_000>,
+    hold_time: fugit::Duration<u32, 1, 1_000>,
+    gesture: Option<Gesture>,
+}
+
+const DOUBLE_CLICK_MS: u32 = 300;
+const HOLD_REPEAT_MS: u32 = 500;
+
+impl ButtonGesture {
+    /// `hold_time` is measured from the start of the press; if it's no
+    /// longer than `long_press_time`, a long press goes straight to a hold.
+    pub fn new(
+        long_press_time: fugit::Duration<u32, 1, 1_000>,
+        hold_time: fugit::Duration<u32, 1, 1_000>,
+    ) -> Self {
+        ButtonGesture {
+            state: GestureFSMState::Idle,
+            button: Debouncer::default(),
+            long_press_time,
+            hold_time,
+            gesture: None,
+        }
+    }
+
+    pub fn update(&mut self, input: bool, now: i64) {
+        self.button.update(input, now);
+        let pressed = self.button.is_on();
+        match &self.state {
+            GestureFSMState::Idle => {
+                if pressed {
+                    self.state =
+                        GestureFSMState::Pressed(SimpleTimer::start(now, self.long_press_time));
+                }
+            }
+            GestureFSMState::Pressed(timer) => {
+                if !pressed {
+                    self.state = GestureFSMState::WaitSecond(SimpleTimer::start(
+                        now,
+                        DOUBLE_CLICK_MS.millis(),
+                    ));
+                } else if timer.expired(now) {
+                    let until_hold = self
+                        .hold_time
+                        .checked_sub(self.long_press_time)
+                        .unwrap_or(0.millis());
+                    self.state = GestureFSMState::LongPressed(SimpleTimer::start(now, until_hold));
+                }
+            }
+            GestureFSMState::WaitSecond(timer) => {
+                if pressed {
+                    self.state = GestureFSMState::SecondPress;
+                } else if timer.expired(now) {
+                    self.gesture = Some(Gesture::Click);
+                    self.state = GestureFSMState::Idle;
+                }
+            }
+            GestureFSMState::SecondPress => {
+                if !pressed {
+                    self.gesture = Some(Gesture::DoubleClick);
+                    self.state = GestureFSMState::Idle;
+                }
+            }
+            GestureFSMState::LongPressed(timer) => {
+                if !pressed {
+                    self.gesture = Some(Gesture::LongPress);
+                    self.state = GestureFSMState::Idle;
+                } else if timer.expired(now) {
+                    self.gesture = Some(Gesture::Hold);
+                    self.state =
+                        GestureFSMState::Holding(SimpleTimer::start(now, HOLD_REPEAT_MS.millis()));
+                }
+            }
+            GestureFSMState::Holding(timer) => {
+                if !pressed {
+                    self.state = GestureFSMState::Idle;
+                } else if timer.expired(now) {
+                    self.gesture = Some(Gesture::Hold);
+                    self.state =
+                        GestureFSMState::Holding(SimpleTimer::start(now, HOLD_REPEAT_MS.millis()));
+                }
+            }
+        }
+    }
+
+    /// The most recently recognised gesture. Each gesture is only returned
+    /// once.
+    pub fn gesture(&mut self) -> Option<Gesture> {
+        self.gesture.take()
+    }
+
+    /// True while the button has been held past the long-press time.
+    pub fn long_held(&self) -> bool {
+        matches!(
+            self.state,
+            GestureFSMState::LongPressed(_) | GestureFSMState::Holding(_)
+        )
+    }
+
+    #[allow(dead_code)]
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            GestureFSMState::Idle => "Idle",
+            GestureFSMState::Pressed(_) => "Pressed",
+            GestureFSMState::WaitSecond(_) => "WaitSecond",
+            GestureFSMState::SecondPress => "SecondPress",
+            GestureFSMState::LongPressed(_) => "LongPressed",
+            GestureFSMState::Holding(_) => "Holding",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG_PRESS_MS: i64 = 2000;
+    const HOLD_MS: i64 = 5000;
+
+    fn button() -> ButtonGesture {
+        ButtonGesture::new((LONG_PRESS_MS as u32).millis(), (HOLD_MS as u32).millis())
+    }
+
+    // Drive the button with `input` from `from` to `to` inclusive, one
+    // millisecond at a time, collecting any gestures.
+    fn drive(b: &mut ButtonGesture, input: bool, from: i64, to: i64) -> Vec<(i64, Gesture)> {
+        let mut out = Vec::new();
+        for now in from..=to {
+            b.update(input, now);
+            if let Some(g) = b.gesture() {
+                out.push((now, g));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_click() {
+        let mut b = button();
+        assert!(drive(&mut b, true, 0, 100).is_empty());
+        let events = drive(&mut b, false, 101, 1000);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].1, Gesture::Click);
+        // Reported after the double-click window, not on release.
+        assert!(events[0].0 >= 101 + DOUBLE_CLICK_MS as i64);
+    }
+
+    #[test]
+    fn test_double_click() {
+        let mut b = button();
+        drive(&mut b, true, 0, 100);
+        drive(&mut b, false, 101, 200);
+        assert!(drive(&mut b, true, 201, 300).is_empty());
+        let events = drive(&mut b, false, 301, 2000);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].1, Gesture::DoubleClick);
+    }
+
+    #[test]
+    fn test_slow_second_click_is_two_clicks() {
+        let mut b = button();
+        drive(&mut b, true, 0, 100);
+        let mut events = drive(&mut b, false, 101, 1000);
+        events.extend(drive(&mut b, true, 1001, 1100));
+        events.extend(drive(&mut b, false, 1101, 2000));
+        assert_eq!(
+            events.iter().map(|e| e.1).collect::<Vec<_>>(),
+            [Gesture::Click, Gesture::Click]
+        );
+    }
+
+    #[test]
+    fn test_long_press() {
+        let mut b = button();
+        assert!(drive(&mut b, true, 0, LONG_PRESS_MS + 100).is_empty());
+        assert!(b.long_held());
+        let events = drive(&mut b, false, LONG_PRESS_MS + 101, LONG_PRESS_MS + 1000);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].1, Gesture::LongPress);
+        assert!(!b.long_held());
+    }
+
+    #[test]
+    fn test_long_held_level() {
+        let mut b = button();
+        drive(&mut b, true, 0, LONG_PRESS_MS - 1);
+        assert!(!b.long_held());
+        drive(&mut b, true, LONG_PRESS_MS, HOLD_MS + 1000);
+        assert!(b.long_held());
+        drive(&mut b, false, HOLD_MS + 1001, HOLD_MS + 1100);
+        assert!(!b.long_held());
+    }
+
+    #[test]
+    fn test_hold_time_shorter_than_long_press() {
+        let mut b = ButtonGesture::new(2000.millis(), 1000.millis());
+        let events = drive(&mut b, true, 0, 2100);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].1, Gesture::Hold);
+    }
+
+    #[test]
+    fn test_hold_repeats() {
+        let mut b = button();
+        let events = drive(&mut b, true, 0, HOLD_MS + 2 * HOLD_REPEAT_MS as i64 + 100);
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|e| e.1 == Gesture::Hold));
+        // No long press or click when released after a hold.
+        assert!(drive(&mut b, false, HOLD_MS + 1101, HOLD_MS + 2000).is_empty());
+        assert_eq!(b.state_name(), "Idle");
+    }
+
+    #[test]
+    fn test_bounce_ignored() {
+        let mut b = button();
+        // A 1ms glitch doesn't get past the debouncer.
+        b.update(true, 0);
+        assert!(drive(&mut b, false, 1, 1000).is_empty());
+    }
+}
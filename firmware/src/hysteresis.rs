@@ -0,0 +1,52 @@
+//! Analog threshold with hysteresis.
+//!
+//! Turns a measured value into an on/off signal that doesn't chatter when
+//! the value sits close to the threshold.
+
+pub struct Hysteresis {
+    on_above: f32,
+    off_below: f32,
+    state: bool,
+}
+
+impl Hysteresis {
+    /// Switches on once the value reaches `on_above`, and off again once it
+    /// falls below `off_below`.
+    pub const fn new(on_above: f32, off_below: f32) -> Self {
+        Hysteresis {
+            on_above,
+            off_below,
+            state: false,
+        }
+    }
+
+    pub fn update(&mut self, value: f32) -> bool {
+        if value >= self.on_above {
+            self.state = true;
+        } else if value < self.off_below {
+            self.state = false;
+        }
+        self.state
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hysteresis() {
+        let mut h = Hysteresis::new(10.0, 8.0);
+        assert!(!h.update(9.0));
+        assert!(h.update(10.0));
+        assert!(h.update(9.0));
+        assert!(h.update(8.0));
+        assert!(!h.update(7.9));
+        assert!(!h.update(9.9));
+        assert!(!h.is_on());
+    }
+}
@@ -0,0 +1,281 @@
+//! Input health monitoring.
+//!
+//! A broken wire or a failing sensor often shows up as an input that never
+//! changes, or one that toggles constantly. This keeps statistics on an
+//! input's unfiltered state (changes in the last minute, time since the
+//! last change and the longest on pulse) and flags it as chattering or
+//! stuck against its limits. A single pulse that's too short is put down
+//! to noise; it takes several in a minute to count as chattering.
+//!
+//! Chattering clears once the input has been within its limits for a
+//! minute, and stuck as soon as it changes.
+use fugit::Duration;
+
+/// Changes are counted in buckets, to give a rolling count over a minute.
+const BUCKET_MS: i64 = 10_000;
+const BUCKETS: usize = 6;
+
+#[derive(Clone, Copy)]
+pub struct InputHealthDef {
+    /// Chattering if it changes more often than this in a minute.
+    pub max_changes_per_min: Option<u32>,
+    /// Chattering if it's on or off for less than this, for signals that
+    /// can't physically change that quickly.
+    pub min_pulse: Option<Duration<u32, 1, 1_000>>,
+    /// Chattering only once there are more pulses shorter than `min_pulse`
+    /// than this in a minute.
+    pub max_short_pulses_per_min: u32,
+    /// Stuck if it doesn't change for this long, for signals that should.
+    pub max_stable: Option<Duration<u32, 1, 1_000>>,
+}
+
+impl InputHealthDef {
+    pub const NONE: InputHealthDef = InputHealthDef {
+        max_changes_per_min: None,
+        min_pulse: None,
+        max_short_pulses_per_min: 0,
+        max_stable: None,
+    };
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputHealthFSMState {
+    #[default]
+    Ok,
+    Chattering,
+    Stuck,
+}
+
+pub struct InputHealth {
+    def: InputHealthDef,
+    state: InputHealthFSMState,
+    /// The input's state and when it was first seen.
+    last: Option<bool>,
+    started: i64,
+    last_change: Option<i64>,
+    changes: [u32; BUCKETS],
+    short_pulses: [u32; BUCKETS],
+    bucket: usize,
+    bucket_start: i64,
+    max_pulse_ms: u32,
+}
+
+impl InputHealth {
+    pub fn new(def: InputHealthDef) -> Self {
+        InputHealth {
+            def,
+            state: InputHealthFSMState::Ok,
+            last: None,
+            started: 0,
+            last_change: None,
+            changes: [0; BUCKETS],
+            short_pulses: [0; BUCKETS],
+            bucket: 0,
+            bucket_start: 0,
+            max_pulse_ms: 0,
+        }
+    }
+
+    pub fn update(&mut self, input: bool, now: i64) {
+        let Some(last) = self.last else {
+            self.last = Some(input);
+            self.started = now;
+            self.bucket_start = now;
+            return;
+        };
+
+        let elapsed_buckets = (now - self.bucket_start) / BUCKET_MS;
+        for _ in 0..elapsed_buckets.min(BUCKETS as i64) {
+            self.bucket = (self.bucket + 1) % BUCKETS;
+            self.changes[self.bucket] = 0;
+            self.short_pulses[self.bucket] = 0;
+        }
+        self.bucket_start += elapsed_buckets * BUCKET_MS;
+
+        if input != last {
+            // The time before the first change isn't a pulse.
+            if let Some(last_change) = self.last_change {
+                let pulse = now - last_change;
+                if last {
+                    self.max_pulse_ms = self.max_pulse_ms.max(pulse as u32);
+                }
+                if self
+                    .def
+                    .min_pulse
+                    .is_some_and(|min| pulse < min.ticks() as i64)
+                {
+                    self.short_pulses[self.bucket] += 1;
+                }
+            }
+            self.changes[self.bucket] += 1;
+            self.last_change = Some(now);
+            self.last = Some(input);
+        }
+
+        let chattering = self
+            .def
+            .max_changes_per_min
+            .is_some_and(|max| self.changes_per_min() > max)
+            || self.short_pulses.iter().sum::<u32>() > self.def.max_short_pulses_per_min;
+        let stuck = self
+            .def
+            .max_stable
+            .is_some_and(|max| self.since_change(now) >= max);
+        self.state = if chattering {
+            InputHealthFSMState::Chattering
+        } else if stuck {
+            InputHealthFSMState::Stuck
+        } else {
+            InputHealthFSMState::Ok
+        };
+    }
+
+    pub fn alarm(&self) -> bool {
+        self.state != InputHealthFSMState::Ok
+    }
+
+    /// Changes over roughly the last minute.
+    pub fn changes_per_min(&self) -> u32 {
+        self.changes.iter().sum()
+    }
+
+    /// Time since the input last changed, or since it was first seen.
+    pub fn since_change(&self, now: i64) -> Duration<u32, 1, 1_000> {
+        let since = now - self.last_change.unwrap_or(self.started);
+        Duration::<u32, 1, 1_000>::from_ticks(since.clamp(0, u32::MAX as i64) as u32)
+    }
+
+    /// The longest the input has been on for.
+    pub fn max_pulse(&self) -> Duration<u32, 1, 1_000> {
+        Duration::<u32, 1, 1_000>::from_ticks(self.max_pulse_ms)
+    }
+
+    #[allow(dead_code)]
+    pub fn status_char(&self) -> char {
+        match self.state {
+            InputHealthFSMState::Ok => 'O',
+            InputHealthFSMState::Chattering => 'C',
+            InputHealthFSMState::Stuck => 'S',
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            InputHealthFSMState::Ok => "Ok",
+            InputHealthFSMState::Chattering => "Chattering",
+            InputHealthFSMState::Stuck => "Stuck",
+        }
+    }
+
+    /// Time left before the input counts as stuck, if it's checked for that.
+    #[allow(dead_code)]
+    pub fn remaining(&self, now: i64) -> Option<Duration<u32, 1, 1_000>> {
+        let max = self.def.max_stable?;
+        let since = self.since_change(now);
+        Some(if since < max {
+            max - since
+        } else {
+            Duration::<u32, 1, 1_000>::from_ticks(0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU32;
+
+    /// Toggle the input every `period` ms from `start` until `end`.
+    fn toggle(health: &mut InputHealth, start: i64, end: i64, period: i64) -> i64 {
+        let mut now = start;
+        while now < end {
+            health.update((now - start) / period % 2 == 1, now);
+            now += 1;
+        }
+        now
+    }
+
+    #[test]
+    fn test_statistics() {
+        let mut health = InputHealth::new(InputHealthDef::NONE);
+        health.update(false, 0);
+        health.update(true, 100);
+        health.update(false, 350);
+        health.update(true, 400);
+        health.update(false, 450);
+        assert_eq!(health.changes_per_min(), 4);
+        assert_eq!(health.max_pulse().ticks(), 250);
+        assert_eq!(health.since_change(1450).ticks(), 1000);
+        // The changes age out after a minute.
+        health.update(false, 61_000);
+        assert_eq!(health.changes_per_min(), 0);
+        assert!(!health.alarm());
+    }
+
+    #[test]
+    fn test_chattering() {
+        let mut health = InputHealth::new(InputHealthDef {
+            max_changes_per_min: Some(100),
+            ..InputHealthDef::NONE
+        });
+        // 10 changes a second, settling after 20 s.
+        let now = toggle(&mut health, 0, 20_000, 100);
+        assert_eq!(health.state_name(), "Chattering");
+        health.update(false, now + 30_000);
+        assert!(health.alarm());
+        health.update(false, now + 61_000);
+        assert_eq!(health.state_name(), "Ok");
+    }
+
+    #[test]
+    fn test_short_pulse() {
+        let mut health = InputHealth::new(InputHealthDef {
+            min_pulse: Some(5.millis()),
+            ..InputHealthDef::NONE
+        });
+        health.update(false, 0);
+        health.update(true, 1000);
+        health.update(false, 1010);
+        assert!(!health.alarm());
+        health.update(true, 1012);
+        assert_eq!(health.state_name(), "Chattering");
+        health.update(true, 61_012);
+        assert!(!health.alarm());
+    }
+
+    #[test]
+    fn test_short_pulse_spike() {
+        let mut health = InputHealth::new(InputHealthDef {
+            min_pulse: Some(5.millis()),
+            max_short_pulses_per_min: 2,
+            ..InputHealthDef::NONE
+        });
+        health.update(false, 0);
+        health.update(true, 1000);
+        // A single spike, and another a while later, are put down to noise.
+        health.update(false, 1001);
+        health.update(true, 20_000);
+        health.update(false, 20_001);
+        assert!(!health.alarm());
+        // But a third within the minute isn't.
+        health.update(true, 30_000);
+        health.update(false, 30_002);
+        assert_eq!(health.state_name(), "Chattering");
+    }
+
+    #[test]
+    fn test_stuck() {
+        let mut health = InputHealth::new(InputHealthDef {
+            max_stable: Some(10.secs()),
+            ..InputHealthDef::NONE
+        });
+        health.update(true, 0);
+        health.update(true, 9999);
+        assert_eq!(health.remaining(9999).unwrap().ticks(), 1);
+        assert!(!health.alarm());
+        health.update(true, 10_000);
+        assert_eq!(health.state_name(), "Stuck");
+        health.update(false, 10_500);
+        assert!(!health.alarm());
+    }
+}
@@ -0,0 +1,216 @@
+//! Machine interlocks.
+//!
+//! A table of named permissive conditions, each of which blocks one or
+//! more actuators while it is tripped. Latching interlocks (E-stop, door
+//! and the like) stay tripped until they have cleared and a reset has been
+//! seen, so that e.g. closing a door doesn't restart a spindle the CNC is
+//! still asking for. Non-latching interlocks (probe active, servo not ready)
+//! simply block while their condition holds.
+//!
+//! The first latching interlock to trip since the last reset is recorded,
+//! so the operator can see what actually caused a stop rather than
+//! everything that fell over afterwards.
+
+pub type ActuatorMask = u8;
+
+pub const ACTUATOR_SPINDLE: ActuatorMask = 1 << 0;
+pub const ACTUATOR_MANUAL_BRAKE: ActuatorMask = 1 << 1;
+pub const ACTUATOR_AXES: ActuatorMask = 1 << 2;
+
+pub struct InterlockDef {
+    pub name: &'static str,
+    /// Disabled interlocks never trip, e.g. for inputs that aren't wired.
+    pub enabled: bool,
+    pub latching: bool,
+    pub blocks: ActuatorMask,
+}
+
+pub struct Interlocks<const N: usize> {
+    defs: [InterlockDef; N],
+    tripped: [bool; N],
+    first_out: Option<usize>,
+}
+
+impl<const N: usize> Interlocks<N> {
+    pub fn new(defs: [InterlockDef; N]) -> Self {
+        Interlocks {
+            defs,
+            tripped: [false; N],
+            first_out: None,
+        }
+    }
+
+    /// `conditions[i]` is true while interlock `i`'s fault condition is
+    /// present. `reset` clears latched interlocks whose condition has gone.
+    pub fn update(&mut self, conditions: [bool; N], reset: bool) {
+        for (i, def) in self.defs.iter().enumerate() {
+            let present = def.enabled && conditions[i];
+            if present {
+                if def.latching && !self.tripped[i] && self.first_out.is_none() {
+                    self.first_out = Some(i);
+                }
+                self.tripped[i] = true;
+            } else if !def.latching || reset {
+                self.tripped[i] = false;
+            }
+        }
+        if reset && !self.any_latched() {
+            self.first_out = None;
+        }
+    }
+
+    fn any_latched(&self) -> bool {
+        self.defs
+            .iter()
+            .zip(self.tripped.iter())
+            .any(|(def, tripped)| def.latching && *tripped)
+    }
+
+    /// True if nothing is blocking any of the given actuators.
+    pub fn permit(&self, actuators: ActuatorMask) -> bool {
+        !self
+            .defs
+            .iter()
+            .zip(self.tripped.iter())
+            .any(|(def, tripped)| *tripped && def.blocks & actuators != 0)
+    }
+
+    pub fn first_out(&self) -> Option<&'static str> {
+        self.first_out.map(|i| self.defs[i].name)
+    }
+
+    /// Names of everything currently tripped.
+    #[allow(dead_code)]
+    pub fn tripped(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.defs
+            .iter()
+            .zip(self.tripped.iter())
+            .filter(|(_, tripped)| **tripped)
+            .map(|(def, _)| def.name)
+    }
+
+    /// A short summary for status displays and the event log.
+    pub fn state_name(&self) -> &'static str {
+        match self.first_out() {
+            Some(name) => name,
+            None if self.tripped.iter().any(|t| *t) => "Blocked",
+            None => "Ok",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ESTOP: usize = 0;
+    const DOOR: usize = 1;
+    const PROBE: usize = 2;
+    const AIR: usize = 3;
+
+    fn interlocks() -> Interlocks<4> {
+        Interlocks::new([
+            InterlockDef {
+                name: "EStop",
+                enabled: true,
+                latching: true,
+                blocks: ACTUATOR_SPINDLE | ACTUATOR_MANUAL_BRAKE,
+            },
+            InterlockDef {
+                name: "DoorOpen",
+                enabled: true,
+                latching: true,
+                blocks: ACTUATOR_SPINDLE,
+            },
+            InterlockDef {
+                name: "ProbeActive",
+                enabled: true,
+                latching: false,
+                blocks: ACTUATOR_SPINDLE,
+            },
+            InterlockDef {
+                name: "AirLow",
+                enabled: false,
+                latching: true,
+                blocks: ACTUATOR_SPINDLE,
+            },
+        ])
+    }
+
+    fn cond(tripped: &[usize]) -> [bool; 4] {
+        let mut c = [false; 4];
+        for i in tripped {
+            c[*i] = true;
+        }
+        c
+    }
+
+    #[test]
+    fn test_all_clear() {
+        let mut il = interlocks();
+        il.update(cond(&[]), false);
+        assert!(il.permit(ACTUATOR_SPINDLE));
+        assert!(il.permit(ACTUATOR_MANUAL_BRAKE));
+        assert_eq!(il.state_name(), "Ok");
+    }
+
+    #[test]
+    fn test_non_latching_clears_itself() {
+        let mut il = interlocks();
+        il.update(cond(&[PROBE]), false);
+        assert!(!il.permit(ACTUATOR_SPINDLE));
+        assert!(il.permit(ACTUATOR_MANUAL_BRAKE));
+        assert_eq!(il.first_out(), None);
+        assert_eq!(il.state_name(), "Blocked");
+        il.update(cond(&[]), false);
+        assert!(il.permit(ACTUATOR_SPINDLE));
+    }
+
+    #[test]
+    fn test_latching_needs_reset() {
+        let mut il = interlocks();
+        il.update(cond(&[DOOR]), false);
+        il.update(cond(&[]), false);
+        assert!(!il.permit(ACTUATOR_SPINDLE));
+        il.update(cond(&[]), true);
+        assert!(il.permit(ACTUATOR_SPINDLE));
+        assert_eq!(il.state_name(), "Ok");
+    }
+
+    #[test]
+    fn test_reset_while_present_does_nothing() {
+        let mut il = interlocks();
+        il.update(cond(&[ESTOP]), false);
+        il.update(cond(&[ESTOP]), true);
+        assert!(!il.permit(ACTUATOR_SPINDLE));
+        assert_eq!(il.first_out(), Some("EStop"));
+    }
+
+    #[test]
+    fn test_first_out() {
+        let mut il = interlocks();
+        il.update(cond(&[PROBE]), false);
+        il.update(cond(&[PROBE, DOOR]), false);
+        il.update(cond(&[PROBE, DOOR, ESTOP]), false);
+        assert_eq!(il.first_out(), Some("DoorOpen"));
+        assert_eq!(
+            il.tripped().collect::<Vec<_>>(),
+            ["EStop", "DoorOpen", "ProbeActive"]
+        );
+        // Reset with the E-stop still held keeps the original first-out.
+        il.update(cond(&[ESTOP]), true);
+        assert_eq!(il.first_out(), Some("DoorOpen"));
+        il.update(cond(&[]), true);
+        assert_eq!(il.first_out(), None);
+        il.update(cond(&[ESTOP]), false);
+        assert_eq!(il.first_out(), Some("EStop"));
+    }
+
+    #[test]
+    fn test_disabled_never_trips() {
+        let mut il = interlocks();
+        il.update(cond(&[AIR]), false);
+        assert!(il.permit(ACTUATOR_SPINDLE));
+        assert_eq!(il.state_name(), "Ok");
+    }
+}
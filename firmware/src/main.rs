@@ -6,37 +6,404 @@ use panic_halt as _;
 
 use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
 
 use stm32f4xx_hal as hal;
 
 use core::cell::{Cell, RefCell};
+use core::fmt::Write;
 
-use hal::gpio::{ErasedPin, Input, Output, PinState, PushPull, Speed};
+use hal::flash::{FlashExt, LockedFlash};
+use hal::gpio::{gpioe, Edge, ErasedPin, Input, Output, PinState, PushPull, Speed};
+use hal::otg_fs::{UsbBus, USB};
 use hal::pac;
 use hal::pac::interrupt;
 use hal::pac::rcc::cfgr::MCO2;
 use hal::prelude::*;
-use hal::rcc::Config;
-use hal::timer::{CounterUs, Event};
+use hal::qei::Qei;
+use hal::rcc::{Config, Enable, Reset};
+use hal::timer::{CounterUs, Event, Flag, Timer};
 use hal::watchdog::IndependentWatchdog;
+use usb_device::prelude::*;
 
+mod adcscan;
+mod analog;
+mod bytecode;
+mod capture;
+mod console;
 mod debounce;
+mod display;
+mod encoder;
+mod eventlog;
 mod fan;
+mod feedback;
+mod force;
+mod gesture;
+mod hysteresis;
+mod input_health;
+mod interlock;
+mod maintenance;
+mod manual_brake;
 mod morse;
+mod motion;
+mod pid;
 mod probe;
+mod program;
+mod pwm;
+mod retain;
+mod retentive;
+mod rules;
+mod servo;
 mod servo_reset;
 mod simpletimer;
 mod spindle;
-use debounce::Debouncer;
-use fan::FanControl;
+mod stepgen;
+mod temperature;
+mod vm;
+use adcscan::AdcScan;
+use analog::{AlarmLimits, AnalogDef, AnalogInput, Filter, Scaling};
+use capture::{PeriodMeter, PulseCounter};
+use console::{Command, LineBuffer, RxBuffer, TxBuffer};
+use debounce::{Debouncer, InputFilter};
+use display::{Controller, FsmStatus, Oled, Status, StatusDisplay, Value};
+use encoder::Encoder;
+use eventlog::{EventLog, StateWatch};
+use fan::{FanControl, FanSpeedConfig};
+use feedback::{FeedbackDef, FeedbackMonitor};
+use force::{Forces, Point};
+use gesture::{ButtonGesture, Gesture};
+use hysteresis::Hysteresis;
+use input_health::{InputHealth, InputHealthDef};
+use interlock::{InterlockDef, Interlocks, ACTUATOR_AXES, ACTUATOR_MANUAL_BRAKE, ACTUATOR_SPINDLE};
+use maintenance::{MaintenanceStats, ServiceIntervals};
+use manual_brake::ManualBrakeControl;
 use morse::Morse;
-use probe::ProbeControl;
+use motion::{Axis, AxisConfig, MotionFSMState};
+use pid::{Pid, PidConfig};
+use probe::{LowBatteryEscalation, ProbeControl, ProbeFSMState};
+use program::{
+    Erase, ERASE_REQUEST, ERASE_RULES_REQUEST, PROGRAM_OFFSET, PROGRAM_SIZE, RULES_SIZE,
+};
+use pwm::PwmOutput;
+use retain::{BackupRegisters, FlashJournal, JournalError, Retain, JOURNAL_OFFSET, JOURNAL_SIZE};
+use rules::{RuleError, Rules};
+use servo::ServoControl;
 use servo_reset::ServoResetControl;
-use spindle::SpindleControl;
+use spindle::{SpindleControl, SpindleFSMState};
+use stepgen::{StepGen, AXES};
+use temperature::{NtcConfig, TemperatureSensor};
+use vm::{ProcessImage, Vm, VmFSMState};
 //use simpletimer::SimpleTimer;
 
 const LONG_PRESS_HOLDOFF_MS: u32 = 2000;
 const LONG_PRESS_HOLDON_MS: u32 = 10;
+const BUTTON_HOLD_MS: u32 = 5000;
+const SPINDLE_BRAKING_TIMEOUT_MS: u32 = 3000;
+// A low probe battery is a warning for a shift, then an error until it's
+// changed. The shift is only counted since power up.
+const PROBE_LOW_BATTERY: LowBatteryEscalation = LowBatteryEscalation {
+    grace: Some(fugit::Duration::<u32, 1, 1_000>::from_ticks(
+        8 * 3600 * 1000,
+    )),
+    max_cycles: None,
+};
+const MANUAL_BRAKE_TIMEOUT_SECS: u32 = 120;
+const SERVO_GEAR: u8 = 0;
+const SERVICE_ACK_HOLDOFF_MS: u32 = 5000;
+// The watchdog period, and what it's let out to while the retain journal's
+// sector is erased, which takes up to a couple of seconds.
+const WATCHDOG_MS: u32 = 1;
+const JOURNAL_COMPACT_WATCHDOG_MS: u32 = 5000;
+// Spindle speed from the encoder index pulse, and coolant flow from the flow
+// sensor's pulse output.
+const SPINDLE_PULSES_PER_REV: u32 = 1;
+const SPINDLE_MIN_INDEX_HZ: u32 = 1;
+const COOLANT_FLOW_GATE_MS: u32 = 1000;
+const COOLANT_FLOW_MIN_HZ: f32 = 5.0;
+const COOLANT_FLOW_STARTUP_MS: u32 = 5000;
+// Quadrature encoder, e.g. a 100 line MPG handwheel.
+const ENCODER_COUNTS_PER_REV: u32 = 400;
+// Step/direction axes on the high-speed outputs. Axis 0 follows the MPG
+// once it's been homed.
+const AXIS_CONFIG: AxisConfig = AxisConfig {
+    max_velocity: 10_000.0,
+    acceleration: 50_000.0,
+    jerk: Some(2_000_000.0),
+    homing_velocity: 2000.0,
+    backoff_velocity: 200.0,
+};
+const MPG_STEPS_PER_COUNT: i32 = 10;
+// PWM outputs on TIM4. The spindle speed reference goes through a
+// PWM-to-0-10V converter, and ramps to avoid jerking the spindle. The
+// frequency, and either duty, can be changed from the console. Only
+// GP12-GP15 are on a timer that's free (see `pwm`), and GP12 and GP15 are
+// the servo gear and brake release, so these are the only PWM outputs.
+const PWM_FREQ_HZ: u32 = 1000;
+const PWM_OUTPUTS: [usize; 2] = [OUT_SPINDLE_SPEED_REF, OUT_FAN_SPEED];
+const FAN_SPEED: FanSpeedConfig = FanSpeedConfig {
+    min_speed: 0.3,
+    kick_speed: 1.0,
+    kick_time: fugit::Duration::<u32, 1, 1_000>::from_ticks(2000),
+    load_time: fugit::Duration::<u32, 1, 1_000>::from_ticks(600_000),
+    temp_low: 40.0,
+    temp_high: 60.0,
+};
+const SPINDLE_SPEED_REF_DUTY: f32 = 0.5;
+const SPINDLE_SPEED_RAMP_MS: u32 = 2000;
+// Closed-loop trim of the spindle speed reference against the measured
+// speed, once the servo reports it's at speed. Off until the speed sensor
+// is fitted and the gains are tuned.
+const SPINDLE_SPEED_RPM: f32 = 6000.0;
+const SPINDLE_SPEED_TRIM: Option<PidConfig> = None;
+// Spindle and cabinet temperatures, from 10k NTC thermistors on the ADC.
+// The spindle over-temperature interlock also trips if its sensor fails.
+const NTC: NtcConfig = NtcConfig {
+    r25: 10_000.0,
+    beta: 3950.0,
+    r_series: 10_000.0,
+    full_scale: 4095,
+};
+const TEMP_FILTER_MS: u32 = 2000;
+const TEMP_FAULT_MS: u32 = 1000;
+const SPINDLE_MAX_TEMP_C: f32 = 70.0;
+const SPINDLE_TEMP_HYSTERESIS_C: f32 = 5.0;
+// General analog inputs, scanned along with the temperatures: an air
+// pressure transducer (0-10 bar over the ADC range) on PA4, and the rest
+// read as plain volts.
+const ANALOG_INPUTS: [AnalogDef; 6] = [
+    AnalogDef {
+        name: "AIR",
+        scaling: Scaling {
+            raw_min: 0,
+            raw_max: 4095,
+            eng_min: 0.0,
+            eng_max: 10.0,
+        },
+        filter: Filter::Iir(fugit::Duration::<u32, 1, 1_000>::from_ticks(500)),
+        alarms: AlarmLimits {
+            lo_lo: Some(4.0),
+            lo: Some(5.0),
+            hi: None,
+            hi_hi: None,
+            deadband: 0.3,
+        },
+    },
+    volts_input("AI2"),
+    volts_input("AI3"),
+    volts_input("AI4"),
+    volts_input("AI5"),
+    volts_input("AI6"),
+];
+
+const fn volts_input(name: &'static str) -> AnalogDef {
+    AnalogDef {
+        name,
+        scaling: Scaling {
+            raw_min: 0,
+            raw_max: 4095,
+            eng_min: 0.0,
+            eng_max: 3.3,
+        },
+        filter: Filter::MovingAverage(8),
+        alarms: AlarmLimits::NONE,
+    }
+}
+
+// Isolated inputs. The E-stop chain, door switch and air pressure switch are
+// fail-safe (high when healthy), so they're inverted to read as on when
+// tripped.
+const IN_ESTOP: usize = 0;
+const IN_DOOR_OPEN: usize = 1;
+const IN_PROBE_LOWBATT: usize = 2;
+const IN_PROBE_ALARM: usize = 3;
+const IN_PROBE_ENABLE: usize = 4;
+const IN_AIR_LOW: usize = 5;
+const IN_SPINDLE_RUN: usize = 7;
+const IN_CABINET_BUTTON: usize = 8;
+const IN_AXIS_HOME: usize = 10;
+const IN_SERVO_NO_FAULT: usize = 11;
+const IN_SERVO_RESET: usize = 12;
+const IN_SERVO_READY: usize = 13;
+const IN_SERVO_AT_SPEED: usize = 14;
+const IN_SERVO_ZERO_SPEED: usize = 15;
+
+// Outputs driven by the firmware. The rest, bar the two PWM outputs, are
+// free for the rules and the user logic program.
+const OUT_FAN_RUN: usize = 0;
+const OUT_PROBE_POWER: usize = 1;
+const OUT_PROBE_DETECT: usize = 2;
+const OUT_SERVO_RESET: usize = 8;
+const OUT_SPINDLE_RUN: usize = 9;
+const OUT_SERVO_BRAKE: usize = 10;
+const OUT_SERVO_GEAR: [usize; 2] = [11, 12];
+const OUT_SPINDLE_SPEED_REF: usize = 13;
+const OUT_FAN_SPEED: usize = 14;
+const OUT_SPINDLE_BRAKE_RELEASE: usize = 15;
+
+// Output feedback. Every output pin is read back against what it was set
+// to, and the spindle contactor and brake release can have an auxiliary
+// contact wired back to an input. The pins are push-pull, so reading them
+// back only catches a short hard enough to overpower the driver; an open
+// load or a failed relay takes the feedback inputs. None can be wired yet:
+// every isolated input is taken, I6 and I9 by the coolant flow meter and
+// the spindle index, so only the readback is checked until one's freed.
+const READBACK_WINDOW_MS: u32 = 20;
+const OUTPUT_FEEDBACK: [FeedbackDef; 2] = [
+    FeedbackDef {
+        output: OUT_SPINDLE_RUN,
+        input: None,
+        window: fugit::Duration::<u32, 1, 1_000>::from_ticks(200),
+    },
+    FeedbackDef {
+        output: OUT_SPINDLE_BRAKE_RELEASE,
+        input: None,
+        window: fugit::Duration::<u32, 1, 1_000>::from_ticks(200),
+    },
+];
+
+// Per-input filtering. Inputs that are debounced further downstream (the
+// servo's no-fault output and the cabinet button) and pulse inputs are
+// passed through raw.
+const INPUT_FILTERS: [InputFilter; 16] = [
+    InputFilter::DEFAULT.inverted(), // 0: E-stop
+    InputFilter::DEFAULT.inverted(), // 1: door open
+    InputFilter::DEFAULT,            // 2: probe low battery
+    InputFilter::DEFAULT,            // 3: probe alarm
+    InputFilter::DEFAULT,            // 4: probe enable
+    InputFilter::DEFAULT.inverted(), // 5: air pressure low
+    InputFilter::RAW,                // 6: coolant flow pulses
+    InputFilter::DEFAULT,            // 7: spindle run
+    InputFilter::RAW,                // 8: cabinet button
+    InputFilter::RAW,                // 9: spindle index pulse
+    InputFilter::DEFAULT,            // 10: axis 0 home switch
+    InputFilter::RAW,                // 11: servo no fault
+    InputFilter::DEFAULT,            // 12: servo reset
+    InputFilter::DEFAULT,            // 13: servo ready
+    InputFilter::DEFAULT,            // 14: servo at speed
+    InputFilter::DEFAULT,            // 15: servo zero speed
+];
+
+// Input health checks, on the unfiltered inputs. Only signals from
+// electronics are checked for chattering, as switches bounce. The probe
+// alarm latches the InputFault interlock, so it takes a few short pulses
+// in a minute, not one spike, to count.
+const INPUT_HEALTH: [InputHealthDef; 16] = {
+    let mut checks = [InputHealthDef::NONE; 16];
+    checks[IN_PROBE_LOWBATT] = InputHealthDef {
+        max_changes_per_min: Some(60),
+        ..InputHealthDef::NONE
+    };
+    checks[IN_PROBE_ALARM] = InputHealthDef {
+        max_changes_per_min: Some(120),
+        min_pulse: Some(fugit::Duration::<u32, 1, 1_000>::from_ticks(2)),
+        max_short_pulses_per_min: 3,
+        max_stable: None,
+    };
+    checks[IN_SERVO_NO_FAULT] = InputHealthDef {
+        max_changes_per_min: Some(60),
+        ..InputHealthDef::NONE
+    };
+    checks
+};
+
+const SERVICE_INTERVALS: ServiceIntervals = ServiceIntervals {
+    spindle_hours: Some(500),
+    fan_hours: Some(2000),
+    spindle_starts: None,
+    brake_engagements: Some(50_000),
+};
+
+// Machine interlocks, in the order their conditions are passed in. Those
+// on sensors that may not be fitted are disabled until they're wired up.
+const INTERLOCKS: [InterlockDef; 9] = [
+    InterlockDef {
+        name: "EStop",
+        enabled: false,
+        latching: true,
+        blocks: ACTUATOR_SPINDLE | ACTUATOR_MANUAL_BRAKE | ACTUATOR_AXES,
+    },
+    InterlockDef {
+        name: "DoorOpen",
+        enabled: false,
+        latching: true,
+        blocks: ACTUATOR_SPINDLE,
+    },
+    InterlockDef {
+        name: "AirLow",
+        enabled: false,
+        latching: true,
+        blocks: ACTUATOR_SPINDLE,
+    },
+    InterlockDef {
+        name: "CoolantLow",
+        enabled: false,
+        latching: true,
+        blocks: ACTUATOR_SPINDLE,
+    },
+    InterlockDef {
+        name: "ServoNotReady",
+        enabled: true,
+        latching: false,
+        blocks: ACTUATOR_SPINDLE,
+    },
+    InterlockDef {
+        name: "ProbeActive",
+        enabled: true,
+        latching: false,
+        blocks: ACTUATOR_SPINDLE,
+    },
+    InterlockDef {
+        name: "SpindleHot",
+        enabled: false,
+        latching: true,
+        blocks: ACTUATOR_SPINDLE,
+    },
+    InterlockDef {
+        name: "OutputFault",
+        enabled: true,
+        latching: true,
+        blocks: ACTUATOR_SPINDLE | ACTUATOR_MANUAL_BRAKE | ACTUATOR_AXES,
+    },
+    InterlockDef {
+        name: "InputFault",
+        enabled: true,
+        latching: true,
+        blocks: ACTUATOR_SPINDLE,
+    },
+];
+
+// Whether forces set from the console survive a reset. Best left off
+// except while commissioning.
+const PERSIST_FORCES: bool = false;
+// Simple I/O mappings on the spare outputs (see `rules`), taking
+// precedence over the user logic program. These are the built-in ones,
+// for when none have been uploaded: a stack light, green while the spindle
+// runs and red while it's held off.
+const RULES: &str = "
+    Q6 = SPINDLE_ON
+    Q7 = NOT SPINDLE_PERMIT OR SERVO_FAULT
+";
+
+/// The rule table uploaded to flash, or the built-in one if there isn't one.
+fn load_rules<F: ReadNorFlash>(flash: &mut F, free_outputs: u16) -> Result<Rules, RuleError> {
+    let mut buf = [0u8; RULES_SIZE as usize];
+    let text = match program::read_rules(flash, &mut buf) {
+        Ok(Some(bytes)) => rules::text(bytes)?,
+        Ok(None) => RULES,
+        Err(_) => {
+            return Err(RuleError {
+                line: 0,
+                message: "flash error",
+            })
+        }
+    };
+    Rules::parse(
+        text,
+        free_outputs,
+        LONG_PRESS_HOLDOFF_MS.millis(),
+        BUTTON_HOLD_MS.millis(),
+    )
+}
 
 // TIM5 is configured to provide a monotonic 1kHz tick, exposed via a mutex-
 // protected integer.
@@ -66,17 +433,49 @@ fn TIM5() {
     let _ = tim.wait();
 }
 
+// The coolant flow sensor's pulses are counted on an EXTI interrupt.
+static G_COOLANT_PULSES: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+static G_COOLANT_PIN: Mutex<RefCell<Option<gpioe::PE6<Input>>>> = Mutex::new(RefCell::new(None));
+
+#[interrupt]
+fn EXTI9_5() {
+    static mut PIN: Option<gpioe::PE6<Input>> = None;
+    let pin = PIN.get_or_insert_with(|| {
+        cortex_m::interrupt::free(|cs| G_COOLANT_PIN.borrow(cs).replace(None).unwrap())
+    });
+    pin.clear_interrupt_pending_bit();
+    cortex_m::interrupt::free(|cs| {
+        let pulses = G_COOLANT_PULSES.borrow(cs);
+        pulses.set(pulses.get().wrapping_add(1));
+    });
+}
+
+// TIM2 generates the step pulses; its compare interrupts schedule each edge.
+static G_STEPGEN: Mutex<RefCell<Option<StepGen>>> = Mutex::new(RefCell::new(None));
+
+#[interrupt]
+fn TIM2() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(stepgen) = G_STEPGEN.borrow(cs).borrow_mut().as_mut() {
+            stepgen.on_interrupt();
+        }
+    });
+}
+
 #[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     let dp = pac::Peripherals::take().unwrap();
     let _cp = cortex_m::peripheral::Peripherals::take().unwrap();
-    let mut rcc = dp
-        .RCC
-        .freeze(Config::hse(25.MHz()).hclk(25.MHz()).sysclk(100.MHz()));
+    let mut rcc = dp.RCC.freeze(
+        Config::hse(25.MHz())
+            .hclk(25.MHz())
+            .sysclk(96.MHz())
+            .require_pll48clk(),
+    );
 
-    let _gpioa = dp.GPIOA.split(&mut rcc);
-    let _gpiob = dp.GPIOB.split(&mut rcc);
+    let gpioa = dp.GPIOA.split(&mut rcc);
+    let gpiob = dp.GPIOB.split(&mut rcc);
     let gpioc = dp.GPIOC.split(&mut rcc);
     let gpiod = dp.GPIOD.split(&mut rcc);
     let gpioe = dp.GPIOE.split(&mut rcc);
@@ -119,29 +518,44 @@ fn main() -> ! {
         Some(gpiod.pd10.into_push_pull_output().speed(Speed::Low).erase()),
         Some(gpiod.pd11.into_push_pull_output().speed(Speed::Low).erase()),
         Some(gpiod.pd12.into_push_pull_output().speed(Speed::Low).erase()),
-        Some(gpiod.pd13.into_push_pull_output().speed(Speed::Low).erase()),
-        Some(gpiod.pd14.into_push_pull_output().speed(Speed::Low).erase()),
+        None, // PD13 and PD14 are PWM, see below.
+        None,
         Some(gpiod.pd15.into_push_pull_output().speed(Speed::Low).erase()),
     ];
 
-    let mut inputs: [Option<ErasedPin<Input>>; 16] = [
-        Some(gpioe.pe0.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe1.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe2.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe3.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe4.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe5.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe6.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe7.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe8.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe9.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe10.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe11.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe12.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe13.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe14.internal_pull_down(true).into_input().erase()),
-        Some(gpioe.pe15.internal_pull_down(true).into_input().erase()),
+    // PWM outputs on GP13-GP14. TIM4's frequency can be changed at runtime
+    // with `set_period`; duties are kept as fractions so they survive that.
+    // A duty set from the console overrides the firmware's until released.
+    let (mut pwm_timer, (_, pwm_ch2, pwm_ch3, _)) = dp.TIM4.pwm_hz(PWM_FREQ_HZ.Hz(), &mut rcc);
+    let mut pwm_duty: [Option<f32>; 16] = [None; 16];
+
+    // Isolated inputs. These are read a port at a time in the mainloop, so
+    // the pins are only needed here to configure them.
+    let _inputs: [ErasedPin<Input>; 14] = [
+        gpioe.pe0.internal_pull_down(true).into_input().erase(),
+        gpioe.pe1.internal_pull_down(true).into_input().erase(),
+        gpioe.pe2.internal_pull_down(true).into_input().erase(),
+        gpioe.pe3.internal_pull_down(true).into_input().erase(),
+        gpioe.pe4.internal_pull_down(true).into_input().erase(),
+        gpioe.pe5.internal_pull_down(true).into_input().erase(),
+        gpioe.pe7.internal_pull_down(true).into_input().erase(),
+        gpioe.pe8.internal_pull_down(true).into_input().erase(),
+        gpioe.pe10.internal_pull_down(true).into_input().erase(),
+        gpioe.pe11.internal_pull_down(true).into_input().erase(),
+        gpioe.pe12.internal_pull_down(true).into_input().erase(),
+        gpioe.pe13.internal_pull_down(true).into_input().erase(),
+        gpioe.pe14.internal_pull_down(true).into_input().erase(),
+        gpioe.pe15.internal_pull_down(true).into_input().erase(),
     ];
+    let mut coolant_flow_in = gpioe.pe6.internal_pull_down(true).into_input();
+    let spindle_index_in = gpioe.pe9.internal_pull_down(true);
+    let mut input_filters = INPUT_FILTERS.map(Debouncer::from);
+    let mut input_health = INPUT_HEALTH.map(InputHealth::new);
+
+    // Micro-switches. USER1 shares a pin with BOOT1.
+    let user1 = gpiob.pb2.internal_pull_down(true).into_input();
+    let user2 = gpioc.pc0.internal_pull_down(true).into_input();
+    let user3 = gpioc.pc1.internal_pull_down(true).into_input();
 
     // Enable to expose sysclk on MCO2.
     if false {
@@ -149,100 +563,921 @@ fn main() -> ! {
         gpioc.pc9.into_alternate::<0>().set_speed(Speed::VeryHigh);
     }
 
-    let _no_fault_in = inputs[11].take().unwrap();
-    let _servo_do1 = inputs[13].take().unwrap();
-    let _servo_do6 = inputs[14].take().unwrap();
-    let _servo_do5 = inputs[15].take().unwrap();
-
-    let mut _servo_di4 = gp_outputs[10].take().unwrap();
-    let mut _servo_di5 = gp_outputs[11].take().unwrap();
-    let mut _servo_dicw64 = gp_outputs[12].take().unwrap();
+    // Spindle servo monitoring and gear selection.
+    let mut servo_control = ServoControl::default();
+    let mut servo_status_morse = Morse::default();
+    servo_control.select_gear(SERVO_GEAR);
 
     // Spindle fan control.
-    let spindle_run = inputs[7].take().unwrap();
-    let mut fan_run = gp_outputs[0].take().unwrap();
-    let mut fan_control = FanControl::default();
+    let mut fan_speed_out = pwm_ch3.with(gpiod.pd14);
+    fan_speed_out.enable();
+    let mut fan_control = FanControl::proportional(FAN_SPEED);
     let mut fan_status_led = leds[0].take().unwrap();
     let mut fan_status_morse = Morse::default();
 
     // Wireless touch probe control.
-    let probe_enable = inputs[4].take().unwrap();
-    let probe_alarm = inputs[3].take().unwrap();
-    let probe_lowbatt = inputs[2].take().unwrap();
-    let mut probe_power = gp_outputs[1].take().unwrap();
-    let mut probe_detect = gp_outputs[2].take().unwrap();
-    let mut probe_control = ProbeControl::default();
+    let mut probe_control = ProbeControl::with_low_battery_escalation(PROBE_LOW_BATTERY);
     let mut probe_status_led = leds[1].take().unwrap();
     let mut probe_status_morse = Morse::default();
 
+    // Cabinet button. A click toggles the manual brake release, and the
+    // servo is reset for as long as it's held past the long-press time.
+    let mut cabinet_button_gesture =
+        ButtonGesture::new(LONG_PRESS_HOLDOFF_MS.millis(), BUTTON_HOLD_MS.millis());
+
     // Manual brake control.
-    let cabinet_button = inputs[8].take().unwrap();
-    let mut cabinet_button_debouncer = Debouncer::default();
-    let mut manual_brake_state: bool = false;
+    let mut manual_brake_control = ManualBrakeControl::new(MANUAL_BRAKE_TIMEOUT_SECS.secs());
 
     // Servo reset signal control.
-    let servo_reset_in = inputs[12].take().unwrap();
-    let mut servo_reset_out = gp_outputs[8].take().unwrap();
     let mut servo_reset_control = ServoResetControl::default();
-    let mut cabinet_button_longpress = Debouncer::new(
-        LONG_PRESS_HOLDOFF_MS.millis(),
-        LONG_PRESS_HOLDON_MS.millis(),
+
+    // Machine interlocks.
+    let mut interlocks = Interlocks::new(INTERLOCKS);
+
+    // Spindle speed, measured by TIM1 in PWM input mode on the encoder
+    // index pulse. Only overflows should raise an update event, as the
+    // counter is reset by every edge.
+    let timclk = rcc.clocks.timclk2().raw();
+    let mut spindle_index =
+        Timer::new(dp.TIM1, &mut rcc).pwm_input(SPINDLE_MIN_INDEX_HZ.Hz(), spindle_index_in);
+    unsafe { (*pac::TIM1::ptr()).cr1().modify(|_, w| w.urs().set_bit()) };
+    let spindle_index_psc = unsafe { (*pac::TIM1::ptr()).psc().read().bits() };
+    let mut spindle_speed = PeriodMeter::new(
+        timclk / (spindle_index_psc + 1),
+        SPINDLE_PULSES_PER_REV,
+        (1000 / SPINDLE_MIN_INDEX_HZ).millis(),
+    );
+
+    // Coolant flow, counted by the EXTI interrupt.
+    let mut syscfg = dp.SYSCFG.constrain(&mut rcc);
+    let mut exti = dp.EXTI;
+    coolant_flow_in.make_interrupt_source(&mut syscfg);
+    coolant_flow_in.trigger_on_edge(&mut exti, Edge::Rising);
+    coolant_flow_in.enable_interrupt(&mut exti);
+    cortex_m::interrupt::free(|cs| *G_COOLANT_PIN.borrow(cs).borrow_mut() = Some(coolant_flow_in));
+    unsafe {
+        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::EXTI9_5);
+    }
+    let mut coolant_flow = PulseCounter::new(COOLANT_FLOW_GATE_MS.millis());
+    let mut coolant_flow_ok = Hysteresis::new(COOLANT_FLOW_MIN_HZ, COOLANT_FLOW_MIN_HZ * 0.8);
+    // Give the flow time to build up after the spindle starts.
+    let mut coolant_flow_expected = Debouncer::new(COOLANT_FLOW_STARTUP_MS.millis(), 0.millis());
+
+    // Quadrature encoder on the spare header J22, for an MPG handwheel or a
+    // spindle orientation encoder. TIM3 counts A (PC6) and B (PC7) in
+    // encoder mode and captures the count on the index pulse (PC8).
+    let encoder_qei = Qei::new(dp.TIM3, (gpioc.pc6, gpioc.pc7), &mut rcc);
+    let _encoder_index_in = gpioc.pc8.into_alternate::<2>();
+    unsafe {
+        let tim3 = &*pac::TIM3::ptr();
+        tim3.ccmr2_input().modify(|_, w| w.cc3s().ti3());
+        tim3.ccer().modify(|_, w| w.cc3e().set_bit());
+    }
+    let mut encoder = Encoder::new(ENCODER_COUNTS_PER_REV);
+    encoder.home_on_index();
+
+    // Step/direction axes on the high-speed outputs.
+    let _step_outs = (
+        gpioa.pa0.into_alternate::<1>(),
+        gpioa.pa2.into_alternate::<1>(),
+    );
+    let mut dir_outs: [ErasedPin<Output<PushPull>>; AXES] = [
+        gpioa.pa1.into_push_pull_output().erase(),
+        gpioa.pa3.into_push_pull_output().erase(),
+    ];
+    pac::TIM2::enable(&mut rcc);
+    pac::TIM2::reset(&mut rcc);
+    let stepgen = StepGen::new(dp.TIM2, rcc.clocks.timclk1().raw());
+    cortex_m::interrupt::free(|cs| *G_STEPGEN.borrow(cs).borrow_mut() = Some(stepgen));
+    unsafe {
+        cortex_m::peripheral::NVIC::unmask(pac::Interrupt::TIM2);
+    }
+    let mut axes = [Axis::new(AXIS_CONFIG), Axis::new(AXIS_CONFIG)];
+    let mut mpg_last = 0;
+
+    let mut spindle_speed_out = pwm_ch2.with(gpiod.pd13);
+    spindle_speed_out.enable();
+    let mut spindle_speed_ref = PwmOutput::new(SPINDLE_SPEED_RAMP_MS.millis(), 0.0);
+    let mut spindle_speed_trim = SPINDLE_SPEED_TRIM.map(Pid::new);
+
+    // Analog inputs on the spare headers, scanned continuously: the
+    // temperature sensors on J22, then the general inputs.
+    let _analog_pins = (
+        gpioc.pc2.into_analog(),
+        gpioc.pc3.into_analog(),
+        gpioa.pa4.into_analog(),
+        gpioa.pa5.into_analog(),
+        gpioa.pa6.into_analog(),
+        gpioa.pa7.into_analog(),
+        gpiob.pb0.into_analog(),
+        gpiob.pb1.into_analog(),
+    );
+    pac::ADC1::enable(&mut rcc);
+    pac::DMA2::enable(&mut rcc);
+    let adc_scan = AdcScan::new(dp.ADC1, dp.DMA2);
+    let mut analog_inputs = ANALOG_INPUTS.map(AnalogInput::from);
+    let mut analog_watches: [StateWatch; ANALOG_INPUTS.len()] = Default::default();
+    let mut spindle_temp = TemperatureSensor::new(TEMP_FILTER_MS.millis(), TEMP_FAULT_MS.millis());
+    let mut cabinet_temp = TemperatureSensor::new(TEMP_FILTER_MS.millis(), TEMP_FAULT_MS.millis());
+    let mut spindle_temp_high = Hysteresis::new(
+        SPINDLE_MAX_TEMP_C,
+        SPINDLE_MAX_TEMP_C - SPINDLE_TEMP_HYSTERESIS_C,
     );
 
     // Spindle control.
-    let mut spindle_run_out = gp_outputs[9].take().unwrap();
-    let mut spindle_brake_release_out = gp_outputs[15].take().unwrap();
-    let mut spindle_control = SpindleControl::default();
+    let mut spindle_control =
+        SpindleControl::with_active_braking(SPINDLE_BRAKING_TIMEOUT_MS.millis());
+
+    // Status display on the spare I2C header, plus its page buttons.
+    let i2c = dp.I2C1.i2c((gpiob.pb8, gpiob.pb7), 400.kHz(), &mut rcc);
+    let mut oled = Oled::new(i2c, Controller::Ssd1306);
+    let _ = oled.init();
+    let mut status_display = StatusDisplay::new(oled);
+    let mut page_next_debouncer = Debouncer::default();
+    let mut page_prev_debouncer = Debouncer::default();
+
+    // Event log.
+    let mut event_log = EventLog::default();
+    let mut fan_watch = StateWatch::default();
+    let mut probe_watch = StateWatch::default();
+    let mut spindle_watch = StateWatch::default();
+    let mut servo_watch = StateWatch::default();
+    let mut servo_reset_watch = StateWatch::default();
+    let mut interlock_watch = StateWatch::default();
+    let mut manual_brake_watch = StateWatch::default();
+    let mut encoder_watch = StateWatch::default();
+    let mut axis_watch = StateWatch::default();
+    let mut spindle_temp_watch = StateWatch::default();
+    let mut cabinet_temp_watch = StateWatch::default();
+    let mut speed_trim_watch = StateWatch::default();
+
+    // Retained variables, restored from the backup registers if they survived
+    // or the flash journal otherwise. The journal is compacted now, before
+    // the watchdog is started, if it's getting full, and after that only
+    // with the machine at rest.
+    pac::PWR::enable(&mut rcc);
+    dp.PWR.cr().modify(|_, w| w.dbp().set_bit());
+    let mut backup = BackupRegisters::new(dp.RTC);
+    let mut flash = LockedFlash::new(dp.FLASH);
+    let mut journal = FlashJournal::new(JOURNAL_OFFSET, JOURNAL_SIZE);
+    let journal_image = journal.load(&mut flash).ok().flatten();
+    let retain_image = backup.load().or(journal_image).unwrap_or_default();
+    if journal.nearly_full() {
+        let _ = journal.compact(&mut flash.unlocked(), &retain_image);
+    }
+    let mut retain = Retain::new(retain_image, 0);
+    let mut journal_compact_failed = false;
+
+    // Maintenance statistics. Holding USER1 acknowledges a service.
+    let mut maintenance = MaintenanceStats::new(SERVICE_INTERVALS);
+    maintenance.restore(&retain, 0);
+    let mut maintenance_watch = StateWatch::default();
+    let mut service_ack_debouncer = Debouncer::new(
+        SERVICE_ACK_HOLDOFF_MS.millis(),
+        LONG_PRESS_HOLDON_MS.millis(),
+    );
+
+    // User logic, loaded from flash and started if there's a valid program.
+    // Erasing it has to happen now too, so the console asks for it across a
+    // reset.
+    let mut vm = Vm::default();
+    let mut vm_watch = StateWatch::default();
+    let erase = match backup.take_boot_request() {
+        ERASE_REQUEST => Some(Erase::Program),
+        ERASE_RULES_REQUEST => Some(Erase::Rules),
+        _ => None,
+    };
+    if let Some(which) = erase {
+        let _ = program::erase(&mut flash.unlocked(), which);
+        let source = if which == Erase::Program {
+            "PRG"
+        } else {
+            "RUL"
+        };
+        event_log.record(0, source, "Erased");
+    }
+    match program::load(&mut flash, &mut vm) {
+        Ok(()) => vm.start(),
+        Err(err) => event_log.record(0, "PRG", err.name()),
+    }
+
+    // Rule table, for the outputs left over after all the above. The user
+    // logic program still drives any a rule doesn't.
+    let builtin_outputs = [
+        OUT_FAN_RUN,
+        OUT_PROBE_POWER,
+        OUT_PROBE_DETECT,
+        OUT_SERVO_RESET,
+        OUT_SPINDLE_RUN,
+        OUT_SERVO_BRAKE,
+        OUT_SERVO_GEAR[0],
+        OUT_SERVO_GEAR[1],
+        OUT_SPINDLE_BRAKE_RELEASE,
+    ];
+    let free_outputs = gp_outputs
+        .iter()
+        .enumerate()
+        .filter(|(n, out)| out.is_some() && !builtin_outputs.contains(n))
+        .fold(0u16, |mask, (n, _)| mask | 1 << n);
+    let rules = load_rules(&mut flash, free_outputs);
+    if let Err(err) = &rules {
+        event_log.record(0, "RUL", err.message);
+    }
+    let mut rules_error = rules.as_ref().err().copied();
+    let mut rules = rules.unwrap_or_default();
+
+    // Force table, for commissioning. Forces are cleared by a reset unless
+    // they're set to persist.
+    let mut forces = Forces::default();
+    if PERSIST_FORCES {
+        forces.restore(&retain);
+        if forces.active() {
+            event_log.record(0, "FRC", "Restored");
+        }
+    }
+
+    // Console on the USB port.
+    let usb = USB::new(
+        (dp.OTG_FS_GLOBAL, dp.OTG_FS_DEVICE, dp.OTG_FS_PWRCLK),
+        (gpioa.pa11, gpioa.pa12),
+        &rcc.clocks,
+    );
+    let usb_bus = UsbBus::new(
+        usb,
+        cortex_m::singleton!(: [u32; 1024] = [0; 1024]).unwrap(),
+    );
+    let mut serial = usbd_serial::SerialPort::new(&usb_bus);
+    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+        .device_class(usbd_serial::USB_CLASS_CDC)
+        .strings(&[StringDescriptors::default()
+            .manufacturer("HandyPLC")
+            .product("HandyPLC console")
+            .serial_number("1")])
+        .unwrap()
+        .build();
+    let mut console_line = LineBuffer::default();
+    let mut console_rx = RxBuffer::default();
+    let mut console_tx = TxBuffer::default();
+    let mut reset_at: Option<i64> = None;
 
     // Mainloop.
     let mut heartbeat = leds[2].take().unwrap();
+    let mut outputs = [false; 16];
+    let mut readback: [FeedbackMonitor; 16] =
+        core::array::from_fn(|_| FeedbackMonitor::new(READBACK_WINDOW_MS.millis()));
+    let mut feedback = OUTPUT_FEEDBACK.map(|def| FeedbackMonitor::new(def.window));
     let mut now_ms: i64 = 0;
-    watchdog.start(1.millis());
+    watchdog.start(WATCHDOG_MS.millis());
     loop {
         cortex_m::interrupt::free(|cs| {
             now_ms = G_NOW.borrow(cs).get();
         });
-        heartbeat.set_state(PinState::from(((now_ms / 2000) & 1) == 0));
 
-        let spindle_on = spindle_run.is_high();
+        // Input filtering.
+        let raw_inputs = unsafe { (*pac::GPIOE::ptr()).idr().read().bits() };
+        for (i, filter) in input_filters.iter_mut().enumerate() {
+            filter.update(raw_inputs & (1 << i) != 0, now_ms);
+        }
+        for (i, health) in input_health.iter_mut().enumerate() {
+            let before = health.state_name();
+            let raw = (raw_inputs & (1 << i) != 0) != INPUT_FILTERS[i].inverted;
+            health.update(raw, now_ms);
+            if health.state_name() != before {
+                event_log.record(now_ms, Point::Input(i).name(), health.state_name());
+            }
+        }
+        let inputs: [bool; 16] =
+            core::array::from_fn(|i| forces.input(i).unwrap_or(input_filters[i].is_on()));
+        let input = |i: usize| inputs[i];
+        let spindle_on = input(IN_SPINDLE_RUN);
+
+        // Pulse measurement.
+        let flags = spindle_index.flags();
+        if flags.contains(Flag::Update) {
+            spindle_speed.overrange();
+            spindle_index.clear_flags(Flag::Update | Flag::C1);
+        } else if flags.contains(Flag::C1) {
+            spindle_speed.capture(
+                spindle_index.get_period_clocks() as u32,
+                spindle_index.get_duty_cycle_clocks() as u32,
+                now_ms,
+            );
+            spindle_index.clear_flags(Flag::C1);
+        }
+        spindle_speed.update(now_ms);
+        encoder.update(encoder_qei.count(), now_ms);
+        // Reading the captured count clears the capture flag.
+        let tim3 = unsafe { &*pac::TIM3::ptr() };
+        if tim3.sr().read().cc3if().bit_is_set() {
+            encoder.index(tim3.ccr3().read().bits() as u16);
+        }
+        let coolant_pulses = cortex_m::interrupt::free(|cs| G_COOLANT_PULSES.borrow(cs).get());
+        coolant_flow.update(coolant_pulses, now_ms);
+        let coolant_low = !coolant_flow_ok.update(coolant_flow.frequency_hz());
+        coolant_flow_expected.update(spindle_control.spindle_on(), now_ms);
+
+        // Temperatures and analog inputs.
+        let samples = adc_scan.samples();
+        spindle_temp.update(NTC.celsius(samples[0]), now_ms);
+        cabinet_temp.update(NTC.celsius(samples[1]), now_ms);
+        for (ai, &raw) in analog_inputs.iter_mut().zip(&samples[2..]) {
+            ai.update(raw, now_ms);
+        }
+        let spindle_hot = match spindle_temp.celsius() {
+            Some(celsius) => spindle_temp_high.update(celsius),
+            None => true,
+        };
+
+        // Servo monitoring.
+        servo_control.update(
+            input(IN_SERVO_NO_FAULT),
+            input(IN_SERVO_READY),
+            input(IN_SERVO_ZERO_SPEED),
+            input(IN_SERVO_AT_SPEED),
+            now_ms,
+        );
+        for (&n, state) in OUT_SERVO_GEAR.iter().zip(servo_control.gear_outputs()) {
+            outputs[n] = state;
+        }
+
+        // Heartbeat. While the servo isn't ready this flashes the servo
+        // status instead, and it blinks quickly while maintenance is due.
+        // Anything forced overrides both with a rapid flicker.
+        if forces.active() {
+            heartbeat.set_state(PinState::from(((now_ms / 100) & 1) == 0));
+        } else if servo_control.ready() {
+            let heartbeat_ms = if maintenance.maintenance_due(now_ms) {
+                250
+            } else {
+                2000
+            };
+            heartbeat.set_state(PinState::from(((now_ms / heartbeat_ms) & 1) == 0));
+        } else {
+            servo_status_morse.set_char(servo_control.status_char());
+            servo_status_morse.update(now_ms);
+            heartbeat.set_state(PinState::from(servo_status_morse.output()));
+        }
 
         // Fan control FSM.
+        fan_control.set_temperature(spindle_temp.celsius());
         fan_control.update(spindle_on, now_ms);
-        fan_run.set_state(PinState::from(fan_control.fan_state()));
+        outputs[OUT_FAN_RUN] = fan_control.fan_state();
+        fan_speed_out.set_duty(pwm::compare(
+            forces.output_duty(
+                OUT_FAN_SPEED,
+                pwm_duty[OUT_FAN_SPEED].unwrap_or(fan_control.fan_speed()),
+            ),
+            fan_speed_out.get_max_duty(),
+        ));
         fan_status_morse.set_char(fan_control.status_char());
         fan_status_morse.update(now_ms);
         fan_status_led.set_state(PinState::from(fan_status_morse.output()));
 
         // Probe control FSM.
         probe_control.update(
-            probe_enable.is_high(),
-            probe_alarm.is_high(),
-            probe_lowbatt.is_high(),
+            input(IN_PROBE_ENABLE),
+            input(IN_PROBE_ALARM),
+            input(IN_PROBE_LOWBATT),
             now_ms,
         );
-        probe_power.set_state(PinState::from(probe_control.probe_power()));
-        probe_detect.set_state(PinState::from(probe_control.probe_detect()));
+        outputs[OUT_PROBE_POWER] = probe_control.probe_power();
+        outputs[OUT_PROBE_DETECT] = probe_control.probe_detect();
         probe_status_morse.set_char(probe_control.status_char());
         probe_status_morse.update(now_ms);
         probe_status_led.set_state(PinState::from(probe_status_morse.output()));
 
+        // Cabinet button.
+        cabinet_button_gesture.update(input(IN_CABINET_BUTTON), now_ms);
+        let cabinet_gesture = cabinet_button_gesture.gesture();
+
         // Servo reset control FSM.
-        cabinet_button_longpress.update(cabinet_button.is_high(), now_ms);
-        let reset_asserted = servo_reset_in.is_high() || cabinet_button_longpress.is_on();
+        let reset_asserted = input(IN_SERVO_RESET) || cabinet_button_gesture.long_held();
         servo_reset_control.update(reset_asserted, now_ms);
-        servo_reset_out.set_state(PinState::from(servo_reset_control.reset_state()));
+        outputs[OUT_SERVO_RESET] = servo_reset_control.reset_state();
+
+        // Machine interlocks. Output faults are from the last scan's check.
+        let output_fault = readback.iter().chain(&feedback).any(|m| m.fault());
+        interlocks.update(
+            [
+                input(IN_ESTOP),
+                input(IN_DOOR_OPEN),
+                input(IN_AIR_LOW),
+                coolant_low && coolant_flow_expected.is_on(),
+                servo_control.spindle_inhibit(),
+                probe_control.spindle_inhibit(),
+                spindle_hot,
+                output_fault,
+                input_health.iter().any(|health| health.alarm()),
+            ],
+            reset_asserted,
+        );
+
+        // Spindle control FSM.
+        spindle_control.update(
+            spindle_on,
+            !interlocks.permit(ACTUATOR_SPINDLE),
+            servo_control.zero_speed(),
+            reset_asserted,
+            now_ms,
+        );
+        outputs[OUT_SPINDLE_RUN] = spindle_control.spindle_on();
+        // The trim holds while the servo isn't at speed, and resets once
+        // the spindle stops.
+        let trim = match &mut spindle_speed_trim {
+            Some(pid) => {
+                pid.set_auto(spindle_control.spindle_on() && servo_control.at_speed());
+                if !spindle_control.spindle_on() {
+                    pid.set_output(0.0);
+                }
+                pid.update(SPINDLE_SPEED_RPM, spindle_speed.rpm(), now_ms)
+            }
+            None => 0.0,
+        };
+        spindle_speed_ref.set_duty(match pwm_duty[OUT_SPINDLE_SPEED_REF] {
+            Some(duty) => duty,
+            None if spindle_control.spindle_on() => SPINDLE_SPEED_REF_DUTY + trim,
+            None => 0.0,
+        });
+        spindle_speed_ref.update(!interlocks.permit(ACTUATOR_SPINDLE), now_ms);
+        spindle_speed_out.set_duty(pwm::compare(
+            forces.output_duty(OUT_SPINDLE_SPEED_REF, spindle_speed_ref.duty()),
+            spindle_speed_out.get_max_duty(),
+        ));
+        outputs[OUT_SERVO_BRAKE] = spindle_control.servo_brake();
+
         // Manual brake control.
-        cabinet_button_debouncer.update(cabinet_button.is_high(), now_ms);
-        if cabinet_button_debouncer.posedge() {
-            manual_brake_state = !manual_brake_state;
+        manual_brake_control.update(
+            cabinet_gesture == Some(Gesture::Click),
+            matches!(spindle_control.state(), SpindleFSMState::Off),
+            spindle_on,
+            interlocks.permit(ACTUATOR_MANUAL_BRAKE),
+            now_ms,
+        );
+        let brake_release_on = !spindle_control.brake_on() || manual_brake_control.brake_release();
+        outputs[OUT_SPINDLE_BRAKE_RELEASE] = brake_release_on;
+
+        // Motion. A double click homes axis 0, after which it follows the
+        // MPG.
+        let mpg = encoder.position();
+        let mpg_moved = mpg.wrapping_sub(mpg_last);
+        mpg_last = mpg;
+        if cabinet_gesture == Some(Gesture::DoubleClick) {
+            axes[0].home();
+        }
+        if axes[0].is_homed() && mpg_moved != 0 {
+            let from = match axes[0].state() {
+                MotionFSMState::Moving(target) => *target,
+                _ => axes[0].position(),
+            };
+            axes[0].move_to(from.wrapping_add(mpg_moved * MPG_STEPS_PER_COUNT));
+        }
+        if !interlocks.permit(ACTUATOR_AXES) {
+            axes.iter_mut().for_each(Axis::stop);
+        }
+        let home_switches = [input(IN_AXIS_HOME), false];
+        for (axis, (dir_out, home_switch)) in dir_outs.iter_mut().zip(home_switches).enumerate() {
+            let steps = axes[axis].update(home_switch, now_ms);
+            if steps != 0 {
+                dir_out.set_state(PinState::from(steps > 0));
+                cortex_m::interrupt::free(|cs| {
+                    if let Some(stepgen) = G_STEPGEN.borrow(cs).borrow_mut().as_mut() {
+                        stepgen.load(axis, steps.unsigned_abs());
+                    }
+                });
+            }
         }
 
-        // Spindle control FSM.
-        let spindle_inhibit = probe_control.spindle_inhibit();
-        spindle_control.update(spindle_on, spindle_inhibit, now_ms);
-        spindle_run_out.set_state(PinState::from(spindle_control.spindle_on()));
-        let brake_release_on = !spindle_control.brake_on() || manual_brake_state;
-        spindle_brake_release_out.set_state(PinState::from(brake_release_on));
+        // User logic.
+        let mut image = ProcessImage::default();
+        for (i, bit) in image.inputs.iter_mut().enumerate() {
+            *bit = input(i);
+        }
+        image.system_bits = [
+            spindle_control.spindle_on(),
+            spindle_control.brake_on(),
+            servo_control.ready(),
+            servo_control.fault(),
+            servo_control.at_speed(),
+            probe_control.spindle_inhibit(),
+            interlocks.permit(ACTUATOR_SPINDLE),
+            interlocks.permit(ACTUATOR_AXES),
+            axes[0].is_homed(),
+            axes[0].is_idle(),
+            fan_control.fan_state(),
+            spindle_hot,
+        ];
+        let tenths = |value: f32| (value * 10.0) as i32;
+        image.system_words = [
+            spindle_speed.rpm() as i32,
+            tenths(spindle_temp.celsius().unwrap_or(0.0)),
+            tenths(cabinet_temp.celsius().unwrap_or(0.0)),
+            tenths(analog_inputs[0].value()),
+            tenths(analog_inputs[1].value()),
+            tenths(analog_inputs[2].value()),
+            tenths(analog_inputs[3].value()),
+            tenths(analog_inputs[4].value()),
+            tenths(analog_inputs[5].value()),
+            encoder.position(),
+            axes[0].position(),
+        ];
+        vm.scan(&image, now_ms);
+        rules.update(&image, now_ms);
+        // Outputs not claimed by anything above are the rules' or the
+        // program's.
+        for (n, out) in outputs.iter_mut().enumerate() {
+            if free_outputs & 1 << n != 0 {
+                *out = rules.output(n).unwrap_or_else(|| vm.output(n));
+            }
+        }
+
+        // Output feedback, of the states set last scan, which have had a
+        // scan to settle. The HAL doesn't read the level of a push-pull
+        // output, so that comes from the port's input register.
+        let outputs_set = gp_outputs
+            .iter()
+            .enumerate()
+            .filter(|(_, pin)| pin.as_ref().is_some_and(|pin| pin.is_set_high()))
+            .fold(0u16, |bits, (n, _)| bits | 1 << n);
+        let idr = unsafe { (*pac::GPIOD::ptr()).idr().read().bits() };
+        for (n, monitor) in readback.iter_mut().enumerate() {
+            if gp_outputs[n].is_some()
+                && monitor.update(outputs_set & 1 << n != 0, idr & 1 << n != 0, now_ms)
+            {
+                event_log.record(now_ms, Point::Output(n).name(), "ReadbackFault");
+            }
+        }
+        for (def, monitor) in OUTPUT_FEEDBACK.iter().zip(&mut feedback) {
+            if def.update(monitor, outputs_set, &inputs, now_ms) {
+                event_log.record(now_ms, Point::Output(def.output).name(), "FeedbackFault");
+            }
+        }
+
+        // Outputs, with any forces. The PWM outputs' are applied to their
+        // duties, above.
+        for (n, pin) in gp_outputs.iter_mut().enumerate() {
+            if let Some(pin) = pin {
+                pin.set_state(PinState::from(forces.output(n).unwrap_or(outputs[n])));
+            }
+        }
+
+        // Maintenance statistics.
+        maintenance.update(
+            spindle_control.spindle_on(),
+            spindle_control.brake_on(),
+            fan_control.fan_state(),
+            probe_control.probe_detect(),
+            matches!(probe_control.state(), ProbeFSMState::Error),
+            now_ms,
+        );
+        service_ack_debouncer.update(user1.is_high(), now_ms);
+        if service_ack_debouncer.posedge() {
+            maintenance.service_done(now_ms);
+            event_log.record(now_ms, "MNT", "ServiceDone");
+        }
+        let maintenance_state = if maintenance.maintenance_due(now_ms) {
+            "ServiceDue"
+        } else {
+            "Ok"
+        };
+        maintenance_watch.update(&mut event_log, now_ms, "MNT", maintenance_state);
+
+        // Retained variables. The backup registers are cheap to write, so
+        // they're kept current; flash is written periodically while things
+        // are changing, and once they've settled while idle, a word per scan.
+        maintenance.store(&mut retain, now_ms);
+        if PERSIST_FORCES {
+            forces.store(&mut retain);
+        }
+        retain.update(now_ms);
+        if retain.changed() {
+            backup.save(retain.image());
+            if !journal.busy() && retain.flush_due(!maintenance.is_running(), now_ms) {
+                if !journal.begin(retain.image()) {
+                    event_log.record(now_ms, "RET", "JournalFull");
+                }
+                retain.flushed(now_ms);
+            }
+        }
+        if let Err(JournalError::Flash(_)) = journal.step(&mut flash.unlocked()) {
+            event_log.record(now_ms, "RET", "FlashError");
+        }
+        // Once the journal's getting full it's compacted, but only with the
+        // machine at rest, as the erase stalls everything for a second or
+        // two. The watchdog is let out for it.
+        let machine_idle = !maintenance.is_running()
+            && matches!(spindle_control.state(), SpindleFSMState::Off)
+            && axes.iter().all(Axis::is_idle);
+        if journal.nearly_full() && machine_idle && !journal_compact_failed {
+            watchdog.start(JOURNAL_COMPACT_WATCHDOG_MS.millis());
+            let result = journal.compact(&mut flash.unlocked(), retain.image());
+            watchdog.start(WATCHDOG_MS.millis());
+            cortex_m::interrupt::free(|cs| {
+                now_ms = G_NOW.borrow(cs).get();
+            });
+            if result.is_ok() {
+                retain.flushed(now_ms);
+                event_log.record(now_ms, "RET", "Compacted");
+            } else {
+                // Don't stall every scan retrying it.
+                journal_compact_failed = true;
+                event_log.record(now_ms, "RET", "FlashError");
+            }
+        }
+
+        // Event log.
+        fan_watch.update(&mut event_log, now_ms, "FAN", fan_control.state_name());
+        probe_watch.update(&mut event_log, now_ms, "PRB", probe_control.state_name());
+        spindle_watch.update(&mut event_log, now_ms, "SPN", spindle_control.state_name());
+        servo_watch.update(&mut event_log, now_ms, "SRV", servo_control.state_name());
+        servo_reset_watch.update(
+            &mut event_log,
+            now_ms,
+            "RST",
+            servo_reset_control.state_name(),
+        );
+        manual_brake_watch.update(
+            &mut event_log,
+            now_ms,
+            "BRK",
+            manual_brake_control.state_name(),
+        );
+        let encoder_state = if encoder.is_homed() {
+            "Homed"
+        } else {
+            "NotHomed"
+        };
+        encoder_watch.update(&mut event_log, now_ms, "ENC", encoder_state);
+        axis_watch.update(&mut event_log, now_ms, "AX0", axes[0].state_name());
+        spindle_temp_watch.update(&mut event_log, now_ms, "TSP", spindle_temp.state_name());
+        cabinet_temp_watch.update(&mut event_log, now_ms, "TCB", cabinet_temp.state_name());
+        for (watch, ai) in analog_watches.iter_mut().zip(&analog_inputs) {
+            watch.update(&mut event_log, now_ms, ai.name(), ai.state_name());
+        }
+        if let Some(pid) = &spindle_speed_trim {
+            speed_trim_watch.update(&mut event_log, now_ms, "STR", pid.state_name());
+        }
+        interlock_watch.update(&mut event_log, now_ms, "ILK", interlocks.state_name());
+        vm_watch.update(&mut event_log, now_ms, "VM", vm.state_name());
+
+        // Console. Bytes received are only worked through while there's
+        // room for the reply, which goes out as fast as the port takes it.
+        if usb_dev.poll(&mut [&mut serial]) && console_rx.is_empty() {
+            console_rx.fill(|buf| serial.read(buf));
+        }
+        while console_tx.has_room() {
+            let Some(byte) = console_rx.pop() else {
+                break;
+            };
+            let Some(line) = console_line.push(byte) else {
+                continue;
+            };
+            let mut reply: heapless::String<{ console::REPLY_LEN }> = heapless::String::new();
+            let result = line.and_then(|line| console::parse(&line));
+            let _ = match result {
+                Err(err) => write!(reply, "ERR {}", err),
+                Ok(Command::Erase) => {
+                    // Give the reply a moment to go out first.
+                    backup.set_boot_request(ERASE_REQUEST);
+                    reset_at = Some(now_ms + 100);
+                    write!(reply, "OK resetting to erase")
+                }
+                Ok(Command::EraseRules) => {
+                    backup.set_boot_request(ERASE_RULES_REQUEST);
+                    reset_at = Some(now_ms + 100);
+                    write!(reply, "OK resetting to erase")
+                }
+                Ok(Command::Write { offset, data }) => {
+                    let mut existing = [0u8; console::MAX_WRITE];
+                    let existing = &mut existing[..data.len()];
+                    if offset as usize + data.len() > PROGRAM_SIZE as usize {
+                        write!(reply, "ERR out of range")
+                    } else if ReadNorFlash::read(&mut flash, PROGRAM_OFFSET + offset, existing)
+                        .is_err()
+                        || existing.iter().any(|&b| b != 0xff)
+                    {
+                        write!(reply, "ERR not erased")
+                    } else {
+                        watchdog.feed();
+                        match flash.unlocked().write(PROGRAM_OFFSET + offset, &data) {
+                            Ok(()) => write!(reply, "OK"),
+                            Err(_) => write!(reply, "ERR flash error"),
+                        }
+                    }
+                }
+                Ok(Command::Load) => {
+                    // Reading and checking the whole program takes a while.
+                    watchdog.feed();
+                    match program::load(&mut flash, &mut vm) {
+                        Ok(()) => write!(reply, "OK"),
+                        Err(err) => write!(reply, "ERR {}", err.name()),
+                    }
+                }
+                Ok(Command::LoadRules) => {
+                    // A bad table leaves the rules as they were.
+                    watchdog.feed();
+                    match load_rules(&mut flash, free_outputs) {
+                        Ok(loaded) => {
+                            rules = loaded;
+                            rules_error = None;
+                            write!(reply, "OK {} rules", rules.len())
+                        }
+                        Err(err) => write!(reply, "ERR line {}: {}", err.line, err.message),
+                    }
+                }
+                Ok(Command::Run) => {
+                    vm.start();
+                    match vm.state() {
+                        VmFSMState::NoProgram => write!(reply, "ERR no program"),
+                        _ => write!(reply, "OK"),
+                    }
+                }
+                Ok(Command::Stop) => {
+                    vm.stop();
+                    write!(reply, "OK")
+                }
+                Ok(Command::Status) => match vm.state() {
+                    VmFSMState::Fault(fault) => write!(reply, "OK Fault {:?}", fault),
+                    _ => write!(reply, "OK {} {} bytes", vm.state_name(), vm.code_len()),
+                },
+                Ok(Command::Rules) => match rules_error {
+                    Some(err) => write!(reply, "ERR line {}: {}", err.line, err.message),
+                    None => write!(reply, "OK {} rules", rules.len()),
+                },
+                Ok(Command::Force(point, value)) => {
+                    forces.set(point, value);
+                    let message = match value {
+                        Some(true) => "ForcedOn",
+                        Some(false) => "ForcedOff",
+                        None => "Released",
+                    };
+                    event_log.record(now_ms, point.name(), message);
+                    write!(reply, "OK")
+                }
+                Ok(Command::UnforceAll) => {
+                    forces.clear();
+                    event_log.record(now_ms, "FRC", "ReleasedAll");
+                    write!(reply, "OK")
+                }
+                Ok(Command::Forces) => write!(reply, "OK {}", forces),
+                Ok(Command::Input(n)) => {
+                    let health = &input_health[n];
+                    write!(
+                        reply,
+                        "OK {} {}/min, {} ms since change, longest on {} ms",
+                        health.state_name(),
+                        health.changes_per_min(),
+                        health.since_change(now_ms).ticks(),
+                        health.max_pulse().ticks(),
+                    )
+                }
+                Ok(Command::Pwm(n, duty)) => {
+                    if PWM_OUTPUTS.contains(&n) {
+                        pwm_duty[n] = duty.map(|percent| percent as f32 / 100.0);
+                        write!(reply, "OK")
+                    } else {
+                        write!(reply, "ERR not PWM, only Q13 and Q14 are")
+                    }
+                }
+                Ok(Command::PwmFrequency(hz)) => {
+                    pwm_timer.set_period(hz.Hz());
+                    write!(reply, "OK {} Hz", pwm_timer.get_period().raw())
+                }
+            };
+            let _ = reply.push_str("\r\n");
+            console_tx.push(reply.as_bytes());
+        }
+        console_tx.flush(|bytes| serial.write(bytes));
+        if reset_at.is_some_and(|at| now_ms >= at) {
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+
+        // Status display.
+        page_next_debouncer.update(user2.is_high(), now_ms);
+        if page_next_debouncer.posedge() {
+            status_display.next_page();
+        }
+        page_prev_debouncer.update(user3.is_high(), now_ms);
+        if page_prev_debouncer.posedge() {
+            status_display.prev_page();
+        }
+        if status_display.wants_frame(now_ms) {
+            // Show the filtered inputs, and the outputs as they were set
+            // (the feedback check has been and gone, so that's this scan's).
+            let inputs = (0..16).fold(0u16, |bits, i| bits | (input(i) as u16) << i);
+            let outputs = gp_outputs
+                .iter()
+                .enumerate()
+                .filter(|(_, pin)| pin.as_ref().is_some_and(|pin| pin.is_set_high()))
+                .fold(0u16, |bits, (n, _)| bits | 1 << n);
+            let fsms = [
+                FsmStatus {
+                    name: "FAN",
+                    state: fan_control.state_name(),
+                    remaining_ms: fan_control.remaining(now_ms).map(|d| d.ticks()),
+                },
+                FsmStatus {
+                    name: "PRB",
+                    state: probe_control.state_name(),
+                    remaining_ms: probe_control.remaining(now_ms).map(|d| d.ticks()),
+                },
+                FsmStatus {
+                    name: "SPN",
+                    state: spindle_control.state_name(),
+                    remaining_ms: spindle_control.remaining(now_ms).map(|d| d.ticks()),
+                },
+                FsmStatus {
+                    name: "SRV",
+                    state: servo_control.state_name(),
+                    remaining_ms: servo_control.remaining(now_ms).map(|d| d.ticks()),
+                },
+                FsmStatus {
+                    name: "RST",
+                    state: servo_reset_control.state_name(),
+                    remaining_ms: servo_reset_control.remaining(now_ms).map(|d| d.ticks()),
+                },
+                FsmStatus {
+                    name: "BRK",
+                    state: manual_brake_control.state_name(),
+                    remaining_ms: manual_brake_control.remaining(now_ms).map(|d| d.ticks()),
+                },
+                FsmStatus {
+                    name: "AX0",
+                    state: axes[0].state_name(),
+                    remaining_ms: None,
+                },
+                FsmStatus {
+                    name: "ILK",
+                    state: interlocks.state_name(),
+                    remaining_ms: None,
+                },
+                FsmStatus {
+                    name: "VM",
+                    state: vm.state_name(),
+                    remaining_ms: None,
+                },
+                FsmStatus {
+                    name: "RET",
+                    state: journal.state_name(),
+                    remaining_ms: None,
+                },
+            ];
+            let mut values: heapless::Vec<Value, 16> = heapless::Vec::new();
+            values
+                .extend_from_slice(&[
+                    Value {
+                        name: "RPM",
+                        value: spindle_speed.rpm(),
+                    },
+                    Value {
+                        name: "SREF%",
+                        value: spindle_speed_ref.duty() * 100.0,
+                    },
+                    Value {
+                        name: "FAN%",
+                        value: fan_control.fan_speed() * 100.0,
+                    },
+                    Value {
+                        name: "TSPN",
+                        value: spindle_temp.celsius().unwrap_or(f32::NAN),
+                    },
+                    Value {
+                        name: "TCAB",
+                        value: cabinet_temp.celsius().unwrap_or(f32::NAN),
+                    },
+                    Value {
+                        name: "FLOW",
+                        value: coolant_flow.frequency_hz(),
+                    },
+                    Value {
+                        name: "ENC",
+                        value: encoder.position() as f32,
+                    },
+                    Value {
+                        name: "ENC/s",
+                        value: encoder.velocity(),
+                    },
+                    Value {
+                        name: "AX0",
+                        value: axes[0].position() as f32,
+                    },
+                ])
+                .unwrap();
+            for ai in &analog_inputs {
+                let _ = values.push(Value {
+                    name: ai.name(),
+                    value: ai.value(),
+                });
+            }
+            status_display.draw(&Status {
+                now: now_ms,
+                inputs,
+                outputs,
+                fsms: &fsms,
+                values: &values,
+                last_event: event_log.latest(),
+            });
+        }
+        status_display.update();
 
         watchdog.feed();
     }
@@ -0,0 +1,281 @@
+//! Maintenance statistics.
+//!
+//! Totals up spindle and fan run time and counts spindle starts, brake
+//! engagements and probe activity, all retained across power cycles.
+//! Each counter may have a service interval; once that much has accumulated
+//! since the last service, maintenance is flagged as due until the service
+//! is acknowledged.
+use crate::retain::Retain;
+use crate::retentive::{LongDuration, RetentiveTimer};
+
+// Retained variable slots.
+const SLOT_SPINDLE_RUN_SECS: usize = 0;
+const SLOT_FAN_RUN_SECS: usize = 1;
+const SLOT_SPINDLE_STARTS: usize = 2;
+const SLOT_BRAKE_ENGAGEMENTS: usize = 3;
+const SLOT_PROBE_ACTIVATIONS: usize = 4;
+const SLOT_PROBE_ERRORS: usize = 5;
+// Counter values at the last service.
+const SLOT_SPINDLE_SERVICE_SECS: usize = 6;
+const SLOT_FAN_SERVICE_SECS: usize = 7;
+const SLOT_SPINDLE_SERVICE_STARTS: usize = 8;
+const SLOT_BRAKE_SERVICE_ENGAGEMENTS: usize = 9;
+
+/// Service intervals. `None` disables the check for that counter.
+#[derive(Clone, Copy, Default)]
+pub struct ServiceIntervals {
+    pub spindle_hours: Option<u32>,
+    pub fan_hours: Option<u32>,
+    pub spindle_starts: Option<u32>,
+    pub brake_engagements: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct MaintenanceStats {
+    intervals: ServiceIntervals,
+    spindle_runtime: RetentiveTimer,
+    fan_runtime: RetentiveTimer,
+    spindle_starts: u32,
+    brake_engagements: u32,
+    probe_activations: u32,
+    probe_errors: u32,
+    service: [u32; 4],
+    spindle_was_running: bool,
+    brake_was_on: bool,
+    probe_was_active: bool,
+    probe_was_error: bool,
+}
+
+fn secs(timer: &RetentiveTimer, now: i64) -> u32 {
+    (timer.elapsed(now).ticks() / 1000) as u32
+}
+
+impl MaintenanceStats {
+    pub fn new(intervals: ServiceIntervals) -> Self {
+        MaintenanceStats {
+            intervals,
+            // The brake starts out engaged; don't count that.
+            brake_was_on: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn restore(&mut self, retain: &Retain, now: i64) {
+        let spindle_secs = retain.get(SLOT_SPINDLE_RUN_SECS) as u64;
+        self.spindle_runtime
+            .restore(LongDuration::secs(spindle_secs), now);
+        let fan_secs = retain.get(SLOT_FAN_RUN_SECS) as u64;
+        self.fan_runtime.restore(LongDuration::secs(fan_secs), now);
+        self.spindle_starts = retain.get(SLOT_SPINDLE_STARTS);
+        self.brake_engagements = retain.get(SLOT_BRAKE_ENGAGEMENTS);
+        self.probe_activations = retain.get(SLOT_PROBE_ACTIVATIONS);
+        self.probe_errors = retain.get(SLOT_PROBE_ERRORS);
+        self.service = [
+            retain.get(SLOT_SPINDLE_SERVICE_SECS),
+            retain.get(SLOT_FAN_SERVICE_SECS),
+            retain.get(SLOT_SPINDLE_SERVICE_STARTS),
+            retain.get(SLOT_BRAKE_SERVICE_ENGAGEMENTS),
+        ];
+    }
+
+    pub fn store(&self, retain: &mut Retain, now: i64) {
+        retain.set(SLOT_SPINDLE_RUN_SECS, secs(&self.spindle_runtime, now));
+        retain.set(SLOT_FAN_RUN_SECS, secs(&self.fan_runtime, now));
+        retain.set(SLOT_SPINDLE_STARTS, self.spindle_starts);
+        retain.set(SLOT_BRAKE_ENGAGEMENTS, self.brake_engagements);
+        retain.set(SLOT_PROBE_ACTIVATIONS, self.probe_activations);
+        retain.set(SLOT_PROBE_ERRORS, self.probe_errors);
+        retain.set(SLOT_SPINDLE_SERVICE_SECS, self.service[0]);
+        retain.set(SLOT_FAN_SERVICE_SECS, self.service[1]);
+        retain.set(SLOT_SPINDLE_SERVICE_STARTS, self.service[2]);
+        retain.set(SLOT_BRAKE_SERVICE_ENGAGEMENTS, self.service[3]);
+    }
+
+    pub fn update(
+        &mut self,
+        spindle_running: bool,
+        brake_on: bool,
+        fan_on: bool,
+        probe_active: bool,
+        probe_error: bool,
+        now: i64,
+    ) {
+        self.spindle_runtime.update(spindle_running, now);
+        self.fan_runtime.update(fan_on, now);
+        if spindle_running && !self.spindle_was_running {
+            self.spindle_starts = self.spindle_starts.wrapping_add(1);
+        }
+        if brake_on && !self.brake_was_on {
+            self.brake_engagements = self.brake_engagements.wrapping_add(1);
+        }
+        if probe_active && !self.probe_was_active {
+            self.probe_activations = self.probe_activations.wrapping_add(1);
+        }
+        if probe_error && !self.probe_was_error {
+            self.probe_errors = self.probe_errors.wrapping_add(1);
+        }
+        self.spindle_was_running = spindle_running;
+        self.brake_was_on = brake_on;
+        self.probe_was_active = probe_active;
+        self.probe_was_error = probe_error;
+    }
+
+    /// True while any of the run-time counters are accumulating.
+    pub fn is_running(&self) -> bool {
+        self.spindle_runtime.is_running() || self.fan_runtime.is_running()
+    }
+
+    fn counters(&self, now: i64) -> [u32; 4] {
+        [
+            secs(&self.spindle_runtime, now),
+            secs(&self.fan_runtime, now),
+            self.spindle_starts,
+            self.brake_engagements,
+        ]
+    }
+
+    fn limits(&self) -> [Option<u32>; 4] {
+        [
+            self.intervals.spindle_hours.map(|h| h.saturating_mul(3600)),
+            self.intervals.fan_hours.map(|h| h.saturating_mul(3600)),
+            self.intervals.spindle_starts,
+            self.intervals.brake_engagements,
+        ]
+    }
+
+    pub fn maintenance_due(&self, now: i64) -> bool {
+        self.counters(now)
+            .iter()
+            .zip(self.service.iter())
+            .zip(self.limits().iter())
+            .any(|((count, service), limit)| match limit {
+                Some(limit) => count.wrapping_sub(*service) >= *limit,
+                None => false,
+            })
+    }
+
+    /// Acknowledge a service, restarting all the service intervals.
+    pub fn service_done(&mut self, now: i64) {
+        self.service = self.counters(now);
+    }
+
+    #[allow(dead_code)]
+    pub fn spindle_hours(&self, now: i64) -> u32 {
+        secs(&self.spindle_runtime, now) / 3600
+    }
+
+    #[allow(dead_code)]
+    pub fn fan_hours(&self, now: i64) -> u32 {
+        secs(&self.fan_runtime, now) / 3600
+    }
+
+    #[allow(dead_code)]
+    pub fn spindle_starts(&self) -> u32 {
+        self.spindle_starts
+    }
+
+    #[allow(dead_code)]
+    pub fn brake_engagements(&self) -> u32 {
+        self.brake_engagements
+    }
+
+    #[allow(dead_code)]
+    pub fn probe_activations(&self) -> u32 {
+        self.probe_activations
+    }
+
+    #[allow(dead_code)]
+    pub fn probe_errors(&self) -> u32 {
+        self.probe_errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retain::RETAIN_SLOTS;
+
+    const HOUR: i64 = 3_600_000;
+
+    #[test]
+    fn test_counts_edges() {
+        let mut stats = MaintenanceStats::new(ServiceIntervals::default());
+        stats.update(false, true, false, false, false, 0);
+        stats.update(true, false, true, false, false, 10);
+        stats.update(true, false, true, false, false, 20);
+        stats.update(false, true, true, true, false, 30);
+        stats.update(false, true, false, true, true, 40);
+        stats.update(false, true, false, false, false, 50);
+        stats.update(false, true, false, true, false, 60);
+        assert_eq!(stats.spindle_starts(), 1);
+        assert_eq!(stats.brake_engagements(), 1);
+        assert_eq!(stats.probe_activations(), 2);
+        assert_eq!(stats.probe_errors(), 1);
+    }
+
+    #[test]
+    fn test_run_hours() {
+        let mut stats = MaintenanceStats::new(ServiceIntervals::default());
+        stats.update(true, false, true, false, false, 0);
+        stats.update(false, true, true, false, false, 2 * HOUR);
+        stats.update(false, true, false, false, false, 3 * HOUR);
+        assert_eq!(stats.spindle_hours(10 * HOUR), 2);
+        assert_eq!(stats.fan_hours(10 * HOUR), 3);
+        assert!(!stats.is_running());
+    }
+
+    #[test]
+    fn test_service_due_and_acknowledge() {
+        let mut stats = MaintenanceStats::new(ServiceIntervals {
+            spindle_hours: Some(1),
+            ..Default::default()
+        });
+        stats.update(true, false, false, false, false, 0);
+        assert!(!stats.maintenance_due(HOUR - 1000));
+        assert!(stats.maintenance_due(HOUR));
+        stats.service_done(HOUR);
+        assert!(!stats.maintenance_due(HOUR));
+        assert!(stats.maintenance_due(2 * HOUR));
+    }
+
+    #[test]
+    fn test_start_count_interval() {
+        let mut stats = MaintenanceStats::new(ServiceIntervals {
+            spindle_starts: Some(2),
+            ..Default::default()
+        });
+        stats.update(true, false, false, false, false, 0);
+        stats.update(false, true, false, false, false, 10);
+        assert!(!stats.maintenance_due(10));
+        stats.update(true, false, false, false, false, 20);
+        assert!(stats.maintenance_due(20));
+    }
+
+    #[test]
+    fn test_store_and_restore() {
+        let mut stats = MaintenanceStats::new(ServiceIntervals {
+            fan_hours: Some(5),
+            ..Default::default()
+        });
+        stats.update(true, false, true, true, false, 0);
+        stats.update(false, true, true, false, false, 4 * HOUR);
+        stats.service_done(4 * HOUR);
+        stats.update(false, true, true, false, false, 6 * HOUR);
+        let mut retain = Retain::new([0; RETAIN_SLOTS], 0);
+        stats.store(&mut retain, 6 * HOUR);
+
+        let mut restored = MaintenanceStats::new(ServiceIntervals {
+            fan_hours: Some(5),
+            ..Default::default()
+        });
+        restored.restore(&retain, 0);
+        assert_eq!(restored.spindle_hours(0), 4);
+        assert_eq!(restored.fan_hours(0), 6);
+        assert_eq!(restored.spindle_starts(), 1);
+        assert_eq!(restored.brake_engagements(), 1);
+        assert_eq!(restored.probe_activations(), 1);
+        assert!(!restored.maintenance_due(0));
+        restored.update(false, true, true, false, false, 0);
+        assert!(restored.maintenance_due(3 * HOUR));
+    }
+}
@@ -0,0 +1,147 @@
+//! Manual spindle brake release.
+//!
+//! The cabinet button toggles the spindle brake off so the spindle can be
+//! turned by hand, e.g. for tool changes. This is only allowed while the
+//! spindle controller is idle, and the brake re-engages by itself after a
+//! timeout, on a second press, or as soon as the spindle is asked to run.
+use crate::simpletimer::SimpleTimer;
+
+#[derive(Default)]
+pub enum ManualBrakeFSMState {
+    #[default]
+    Engaged,
+    Released(SimpleTimer),
+}
+
+pub struct ManualBrakeControl {
+    state: ManualBrakeFSMState,
+    timeout: fugit::Duration<u32, 1, 1_000>,
+}
+
+impl ManualBrakeControl {
+    pub fn new(timeout: fugit::Duration<u32, 1, 1_000>) -> Self {
+        ManualBrakeControl {
+            state: ManualBrakeFSMState::Engaged,
+            timeout,
+        }
+    }
+
+    /// `toggle` is a button press; `spindle_idle` is true while the spindle
+    /// controller is stopped with its brake on; `permitted` comes from the
+    /// interlocks.
+    pub fn update(
+        &mut self,
+        toggle: bool,
+        spindle_idle: bool,
+        spindle_requested: bool,
+        permitted: bool,
+        now: i64,
+    ) {
+        match &self.state {
+            ManualBrakeFSMState::Engaged => {
+                if toggle && spindle_idle && !spindle_requested && permitted {
+                    self.state =
+                        ManualBrakeFSMState::Released(SimpleTimer::start(now, self.timeout));
+                }
+            }
+            ManualBrakeFSMState::Released(timer) => {
+                if toggle || !spindle_idle || spindle_requested || !permitted || timer.expired(now)
+                {
+                    self.state = ManualBrakeFSMState::Engaged;
+                }
+            }
+        }
+    }
+
+    pub fn brake_release(&self) -> bool {
+        match self.state {
+            ManualBrakeFSMState::Engaged => false,
+            ManualBrakeFSMState::Released(_) => true,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn status_char(&self) -> char {
+        match self.state {
+            ManualBrakeFSMState::Engaged => 'E',
+            ManualBrakeFSMState::Released(_) => 'M',
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            ManualBrakeFSMState::Engaged => "Engaged",
+            ManualBrakeFSMState::Released(_) => "Released",
+        }
+    }
+
+    /// Time left before the current state times out, if it has a timer.
+    pub fn remaining(&self, now: i64) -> Option<fugit::Duration<u32, 1, 1_000>> {
+        match &self.state {
+            ManualBrakeFSMState::Engaged => None,
+            ManualBrakeFSMState::Released(timer) => Some(timer.remaining(now)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU32;
+
+    const TIMEOUT_MS: u32 = 60_000;
+
+    fn released() -> ManualBrakeControl {
+        let mut brake = ManualBrakeControl::new(TIMEOUT_MS.millis());
+        brake.update(true, true, false, true, 0);
+        assert!(brake.brake_release());
+        brake
+    }
+
+    #[test]
+    fn test_toggle() {
+        let mut brake = released();
+        brake.update(false, true, false, true, 10);
+        assert!(brake.brake_release());
+        brake.update(true, true, false, true, 20);
+        assert_eq!(brake.state_name(), "Engaged");
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut brake = released();
+        brake.update(false, true, false, true, TIMEOUT_MS as i64 - 1);
+        assert!(brake.brake_release());
+        assert_eq!(brake.remaining(TIMEOUT_MS as i64 - 1).unwrap().ticks(), 1);
+        brake.update(false, true, false, true, TIMEOUT_MS as i64);
+        assert!(!brake.brake_release());
+    }
+
+    #[test]
+    fn test_refused_unless_idle() {
+        let mut brake = ManualBrakeControl::new(TIMEOUT_MS.millis());
+        brake.update(true, false, false, true, 0);
+        assert!(!brake.brake_release());
+        brake.update(true, true, true, true, 10);
+        assert!(!brake.brake_release());
+        brake.update(true, true, false, false, 20);
+        assert!(!brake.brake_release());
+    }
+
+    #[test]
+    fn test_cancelled_by_run_request() {
+        let mut brake = released();
+        brake.update(false, true, true, true, 10);
+        assert!(!brake.brake_release());
+        // The request going away again doesn't bring the release back.
+        brake.update(false, true, false, true, 20);
+        assert!(!brake.brake_release());
+    }
+
+    #[test]
+    fn test_cancelled_by_interlock() {
+        let mut brake = released();
+        brake.update(false, true, false, false, 10);
+        assert!(!brake.brake_release());
+    }
+}
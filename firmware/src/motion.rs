@@ -0,0 +1,447 @@
+//! Motion planning for step/direction axes.
+//!
+//! Each axis is planned once per millisecond tick. A trapezoidal profile
+//! works out the velocity for the tick from the current move, limited by
+//! the axis's acceleration, and integrates it into a planned position that
+//! lands exactly on the target. For S-curve profiles the planned position
+//! is then smoothed by a moving average as long as it takes to reach full
+//! acceleration at the jerk limit, which turns each change in acceleration
+//! into a ramp without moving the end point. The result is the whole number
+//! of steps to emit over the tick, which the step generator spreads evenly
+//! across it.
+//!
+//! Positions are in steps, velocities in steps/s, and so on.
+use libm::{ceilf, sqrtf};
+
+#[derive(Clone, Copy)]
+pub struct AxisConfig {
+    pub max_velocity: f32,
+    pub acceleration: f32,
+    /// Jerk limit for S-curve profiles; `None` for trapezoidal.
+    pub jerk: Option<f32>,
+    /// Speed when seeking the home switch, and backing off it again.
+    pub homing_velocity: f32,
+    pub backoff_velocity: f32,
+}
+
+#[derive(Default)]
+pub enum MotionFSMState {
+    #[default]
+    Idle,
+    Moving(i32),
+    Jogging(f32),
+    Stopping,
+    HomingSeek,
+    HomingBackOff,
+}
+
+// Longest tick we'll plan for in one go, should the mainloop stall.
+const MAX_TICK_MS: i64 = 10;
+// Longest S-curve smoothing, in ticks.
+const MAX_SMOOTHING: usize = 100;
+
+pub struct Axis {
+    config: AxisConfig,
+    state: MotionFSMState,
+    // Trapezoidal profile.
+    planned: i32,
+    fraction: f32,
+    velocity: f32,
+    // S-curve smoothing of the planned position.
+    window: [i32; MAX_SMOOTHING],
+    window_len: usize,
+    window_index: usize,
+    window_sum: i64,
+    settling: usize,
+    position: i32,
+    homed: bool,
+    last_update: Option<i64>,
+}
+
+impl Axis {
+    pub fn new(config: AxisConfig) -> Self {
+        let window_len = match config.jerk {
+            Some(jerk) => ceilf(config.acceleration / jerk * 1000.0) as usize,
+            None => 1,
+        };
+        Axis {
+            config,
+            state: MotionFSMState::Idle,
+            planned: 0,
+            fraction: 0.0,
+            velocity: 0.0,
+            window: [0; MAX_SMOOTHING],
+            window_len: window_len.clamp(1, MAX_SMOOTHING),
+            window_index: 0,
+            window_sum: 0,
+            settling: 0,
+            position: 0,
+            homed: false,
+            last_update: None,
+        }
+    }
+
+    pub fn move_to(&mut self, target: i32) {
+        self.state = MotionFSMState::Moving(target);
+    }
+
+    /// Run at a constant (signed) velocity until stopped.
+    #[allow(dead_code)]
+    pub fn jog(&mut self, velocity: f32) {
+        let max = self.config.max_velocity;
+        self.state = MotionFSMState::Jogging(velocity.clamp(-max, max));
+    }
+
+    /// Decelerate to a stop.
+    pub fn stop(&mut self) {
+        if !matches!(self.state, MotionFSMState::Idle) {
+            self.state = MotionFSMState::Stopping;
+        }
+    }
+
+    /// Seek the home switch in the negative direction, then back off it
+    /// slowly and call that position zero.
+    pub fn home(&mut self) {
+        self.homed = false;
+        self.state = MotionFSMState::HomingSeek;
+    }
+
+    // Velocity to aim for this tick.
+    fn target_velocity(&self, home_switch: bool, dt: f32) -> f32 {
+        let c = &self.config;
+        match self.state {
+            MotionFSMState::Idle | MotionFSMState::Stopping => 0.0,
+            MotionFSMState::Moving(target) => {
+                let remaining = (target - self.planned) as f32 - self.fraction;
+                // Fastest speed from which we can still stop in time,
+                // slowing by `dv` each tick.
+                let dv = c.acceleration * dt;
+                let stopping = (sqrtf(dv * dv + 8.0 * c.acceleration * remaining.abs()) - dv) / 2.0;
+                stopping.min(c.max_velocity).copysign(remaining)
+            }
+            MotionFSMState::Jogging(velocity) => velocity,
+            MotionFSMState::HomingSeek => {
+                if home_switch {
+                    0.0
+                } else {
+                    -c.homing_velocity
+                }
+            }
+            MotionFSMState::HomingBackOff => c.backoff_velocity,
+        }
+    }
+
+    fn halt(&mut self) {
+        self.velocity = 0.0;
+        self.fraction = 0.0;
+        self.state = MotionFSMState::Idle;
+    }
+
+    // Move the origin to the planned position. Any smoothing still to do
+    // carries on from where it was, so no steps are emitted for the move.
+    fn rezero(&mut self) {
+        let offset = self.planned;
+        self.planned = 0;
+        self.position -= offset;
+        for planned in &mut self.window[..self.window_len] {
+            *planned -= offset;
+        }
+        self.window_sum -= offset as i64 * self.window_len as i64;
+    }
+
+    // Advance the trapezoidal profile by `dt` seconds.
+    fn plan(&mut self, home_switch: bool, dt: f32) {
+        let target = self.target_velocity(home_switch, dt);
+        let dv_max = self.config.acceleration * dt;
+        self.velocity += (target - self.velocity).clamp(-dv_max, dv_max);
+        self.fraction += self.velocity * dt;
+        let mut steps = self.fraction as i32;
+        self.fraction -= steps as f32;
+
+        match self.state {
+            MotionFSMState::Moving(target) => {
+                // Stop dead on the target rather than overshoot it.
+                let remaining = target - self.planned;
+                if (remaining == 0 || (steps != 0 && (steps > 0) == (remaining > 0)))
+                    && steps.unsigned_abs() >= remaining.unsigned_abs()
+                {
+                    steps = remaining;
+                    self.halt();
+                }
+            }
+            MotionFSMState::Stopping => {
+                if self.velocity == 0.0 {
+                    self.halt();
+                }
+            }
+            MotionFSMState::HomingSeek => {
+                if home_switch && self.velocity == 0.0 {
+                    self.state = MotionFSMState::HomingBackOff;
+                }
+            }
+            MotionFSMState::HomingBackOff => {
+                if !home_switch {
+                    self.homed = true;
+                    self.halt();
+                    self.rezero();
+                    return;
+                }
+            }
+            MotionFSMState::Idle | MotionFSMState::Jogging(_) => {}
+        }
+        self.planned += steps;
+    }
+
+    /// Plan the next tick and return the signed number of steps to emit.
+    pub fn update(&mut self, home_switch: bool, now: i64) -> i32 {
+        let Some(last) = self.last_update.replace(now) else {
+            return 0;
+        };
+        let dt_ms = (now - last).clamp(0, MAX_TICK_MS);
+        if dt_ms == 0 {
+            return 0;
+        }
+        self.plan(home_switch, dt_ms as f32 / 1000.0);
+        // After planning, in case homing has moved the origin.
+        let before = self.position;
+
+        // Moving average of the planned position.
+        let n = self.window_len;
+        self.window_sum += self.planned as i64 - self.window[self.window_index] as i64;
+        self.window[self.window_index] = self.planned;
+        self.window_index = (self.window_index + 1) % n;
+        if self.planned != self.position {
+            self.settling = n;
+        }
+        self.settling = self.settling.saturating_sub(1);
+        self.position = (self.window_sum + n as i64 / 2).div_euclid(n as i64) as i32;
+        self.position - before
+    }
+
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    /// Planned velocity, ahead of the actual velocity by up to the S-curve
+    /// smoothing time.
+    #[allow(dead_code)]
+    pub fn velocity(&self) -> f32 {
+        self.velocity
+    }
+
+    /// Whether the axis has finished moving.
+    #[allow(dead_code)]
+    pub fn is_idle(&self) -> bool {
+        matches!(self.state, MotionFSMState::Idle) && self.settling == 0
+    }
+
+    pub fn is_homed(&self) -> bool {
+        self.homed
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            MotionFSMState::Idle => "Idle",
+            MotionFSMState::Moving(_) => "Moving",
+            MotionFSMState::Jogging(_) => "Jogging",
+            MotionFSMState::Stopping => "Stopping",
+            MotionFSMState::HomingSeek => "HomingSeek",
+            MotionFSMState::HomingBackOff => "HomingBackOff",
+        }
+    }
+
+    pub fn state(&self) -> &MotionFSMState {
+        &self.state
+    }
+}
+
+/// Timer ticks between evenly spaced steps, for `steps` steps over a
+/// period of `period_ticks`.
+pub fn step_interval(steps: u32, period_ticks: u32) -> Option<u32> {
+    period_ticks.checked_div(steps).map(|ticks| ticks.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRAPEZOID: AxisConfig = AxisConfig {
+        max_velocity: 10_000.0,
+        acceleration: 50_000.0,
+        jerk: None,
+        homing_velocity: 2000.0,
+        backoff_velocity: 200.0,
+    };
+
+    // Scaled up so that the profile's shape isn't lost in rounding to
+    // whole steps: 20 steps/ms^2, reached in 10ms.
+    const FAST: AxisConfig = AxisConfig {
+        max_velocity: 1_000_000.0,
+        acceleration: 20_000_000.0,
+        jerk: None,
+        homing_velocity: 2000.0,
+        backoff_velocity: 200.0,
+    };
+
+    const FAST_SCURVE: AxisConfig = AxisConfig {
+        jerk: Some(2_000_000_000.0),
+        ..FAST
+    };
+
+    // Run the axis from `start` until it goes idle, returning the position
+    // after each tick.
+    fn run(axis: &mut Axis, start: i64, limit: i64) -> Vec<i32> {
+        let mut trace = Vec::new();
+        axis.update(false, start);
+        for now in start + 1..start + limit {
+            let before = axis.position();
+            let steps = axis.update(false, now);
+            assert_eq!(axis.position(), before + steps);
+            trace.push(axis.position());
+            if axis.is_idle() {
+                break;
+            }
+        }
+        trace
+    }
+
+    fn diff(values: &[i32]) -> Vec<i32> {
+        values.windows(2).map(|w| w[1] - w[0]).collect()
+    }
+
+    fn max_abs(values: &[i32]) -> i32 {
+        values.iter().map(|v| v.abs()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn test_trapezoid_move() {
+        let mut axis = Axis::new(TRAPEZOID);
+        axis.move_to(20_000);
+        let trace = run(&mut axis, 0, 10_000);
+        assert!(axis.is_idle());
+        assert_eq!(axis.position(), 20_000);
+        assert!(trace.iter().all(|p| (0..=20_000).contains(p)));
+        // Never more than 10 steps/ms.
+        assert!(max_abs(&diff(&trace)) <= 10);
+        // 2s at full speed, plus 0.2s each way ramping.
+        assert!((2150..2300).contains(&trace.len()), "{}", trace.len());
+    }
+
+    #[test]
+    fn test_acceleration_limit() {
+        let mut axis = Axis::new(FAST);
+        axis.move_to(1_000_000);
+        let trace = run(&mut axis, 0, 10_000);
+        assert_eq!(axis.position(), 1_000_000);
+        let accel = diff(&diff(&trace));
+        // 20 steps/ms^2, give or take rounding, apart from stopping dead at
+        // the end.
+        assert!(max_abs(&accel[..accel.len() - 1]) <= 21, "{:?}", accel);
+        // Without a jerk limit, acceleration switches on and off at once.
+        assert!(max_abs(&diff(&accel)) >= 19);
+    }
+
+    #[test]
+    fn test_scurve_move() {
+        let mut axis = Axis::new(FAST_SCURVE);
+        axis.move_to(1_000_000);
+        let trace = run(&mut axis, 0, 10_000);
+        assert_eq!(axis.position(), 1_000_000);
+        assert!(trace.iter().all(|p| (0..=1_000_000).contains(p)));
+        let accel = diff(&diff(&trace));
+        assert!(max_abs(&accel) <= 21, "{:?}", accel);
+        // 2 steps/ms^3, plus rounding.
+        assert!(max_abs(&diff(&accel)) <= 6, "{:?}", diff(&accel));
+    }
+
+    #[test]
+    fn test_short_move_is_triangular() {
+        let mut axis = Axis::new(TRAPEZOID);
+        axis.move_to(-500);
+        let trace = run(&mut axis, 0, 10_000);
+        assert_eq!(axis.position(), -500);
+        assert!(trace.iter().all(|p| (-500..=0).contains(p)));
+        // Peaks at sqrt(a * d), well short of full speed.
+        assert!(max_abs(&diff(&trace)) <= 5);
+    }
+
+    #[test]
+    fn test_retarget_reverses() {
+        let mut axis = Axis::new(FAST_SCURVE);
+        axis.move_to(1_000_000);
+        for now in 0..50 {
+            axis.update(false, now);
+        }
+        assert!(axis.velocity() > 0.0);
+        axis.move_to(0);
+        let trace = run(&mut axis, 50, 10_000);
+        assert_eq!(axis.position(), 0);
+        assert!(trace.iter().all(|p| *p >= 0));
+        let accel = diff(&diff(&trace));
+        assert!(max_abs(&diff(&accel)) <= 6, "{:?}", diff(&accel));
+    }
+
+    #[test]
+    fn test_jog_and_stop() {
+        let mut axis = Axis::new(TRAPEZOID);
+        axis.jog(-3000.0);
+        for now in 0..1000 {
+            axis.update(false, now);
+        }
+        assert_eq!(axis.velocity(), -3000.0);
+        assert_eq!(axis.state_name(), "Jogging");
+        axis.stop();
+        run(&mut axis, 1000, 1000);
+        assert!(axis.is_idle());
+        assert_eq!(axis.velocity(), 0.0);
+    }
+
+    #[test]
+    fn test_jog_clamped() {
+        let mut axis = Axis::new(TRAPEZOID);
+        axis.jog(1e9);
+        for now in 0..1000 {
+            axis.update(false, now);
+        }
+        assert_eq!(axis.velocity(), TRAPEZOID.max_velocity);
+    }
+
+    #[test]
+    fn test_homing() {
+        let mut axis = Axis::new(FAST_SCURVE);
+        axis.home();
+        let max_steps = ceilf(FAST_SCURVE.max_velocity / 1000.0) as i32;
+        let mut now = 0;
+        // The switch is on at or below -5000.
+        let mut actual = 0;
+        while !axis.is_idle() && now < 100_000 {
+            let switch = actual <= -5000;
+            let steps = axis.update(switch, now);
+            assert!(steps.abs() <= max_steps, "{} steps at {}", steps, now);
+            actual += steps;
+            now += 1;
+        }
+        assert!(axis.is_homed());
+        // Zero is where the switch released, without moving to get there.
+        assert!((-5000..-4990).contains(&actual), "{}", actual);
+        assert_eq!(axis.position(), 0);
+        assert_eq!(axis.velocity(), 0.0);
+    }
+
+    #[test]
+    fn test_stalled_loop_is_capped() {
+        let mut axis = Axis::new(TRAPEZOID);
+        axis.jog(10_000.0);
+        axis.update(false, 0);
+        let steps = axis.update(false, 1000);
+        // Planned as a single 10ms tick.
+        assert!(steps <= 5, "{}", steps);
+    }
+
+    #[test]
+    fn test_step_interval() {
+        assert_eq!(step_interval(0, 1000), None);
+        assert_eq!(step_interval(4, 1000), Some(250));
+        assert_eq!(step_interval(5000, 1000), Some(1));
+    }
+}
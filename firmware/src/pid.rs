@@ -0,0 +1,374 @@
+//! PID controller.
+//!
+//! A plain f32 PID, run once per scan with the elapsed time taken from the
+//! tick. The derivative acts on the measurement rather than the error, so a
+//! setpoint step doesn't kick the output, and the integral stops growing
+//! while the output is at a limit, so it doesn't wind up and overshoot once
+//! the output comes back off it.
+//!
+//! In manual the output is set directly and the controller just tracks the
+//! measurement; on switching to auto the integral is preset so the output
+//! carries on from where it was, without a bump.
+
+#[derive(Clone, Copy)]
+pub struct PidConfig {
+    pub kp: f32,
+    /// Integral gain, per second.
+    pub ki: f32,
+    /// Derivative gain, in seconds.
+    pub kd: f32,
+    pub output_min: f32,
+    pub output_max: f32,
+}
+
+pub struct Pid {
+    config: PidConfig,
+    auto: bool,
+    // Set on going into auto: preset the integral on the next update.
+    transfer: bool,
+    integral: f32,
+    output: f32,
+    last_measurement: Option<f32>,
+    last_update: Option<i64>,
+}
+
+impl Pid {
+    /// Starts in manual, with the output at zero or the nearest limit.
+    pub fn new(config: PidConfig) -> Self {
+        Pid {
+            config,
+            auto: false,
+            transfer: false,
+            integral: 0.0,
+            output: 0.0f32.clamp(config.output_min, config.output_max),
+            last_measurement: None,
+            last_update: None,
+        }
+    }
+
+    pub fn set_auto(&mut self, auto: bool) {
+        if auto && !self.auto {
+            self.transfer = true;
+        }
+        self.auto = auto;
+    }
+
+    /// Sets the output while in manual; ignored in auto.
+    pub fn set_output(&mut self, output: f32) {
+        if !self.auto {
+            self.output = output.clamp(self.config.output_min, self.config.output_max);
+        }
+    }
+
+    pub fn update(&mut self, setpoint: f32, measurement: f32, now: i64) -> f32 {
+        let dt = match self.last_update.replace(now) {
+            Some(last) => (now - last).max(0) as f32 / 1000.0,
+            None => 0.0,
+        };
+        let last_measurement = self.last_measurement.replace(measurement);
+        if !self.auto {
+            return self.output;
+        }
+
+        let PidConfig {
+            kp,
+            ki,
+            kd,
+            output_min,
+            output_max,
+        } = self.config;
+        let error = setpoint - measurement;
+        let p = kp * error;
+        let d = match last_measurement {
+            Some(last) if dt > 0.0 => -kd * (measurement - last) / dt,
+            _ => 0.0,
+        };
+        if self.transfer {
+            self.transfer = false;
+            self.integral = self.output - p - d;
+        } else {
+            let integral = self.integral + ki * error * dt;
+            // Only let the integral move further into a limit if the
+            // output isn't already there.
+            self.integral = if p + integral + d > output_max {
+                integral.min(self.integral.max(output_max - p - d))
+            } else if p + integral + d < output_min {
+                integral.max(self.integral.min(output_min - p - d))
+            } else {
+                integral
+            };
+        }
+        self.output = (p + self.integral + d).clamp(output_min, output_max);
+        self.output
+    }
+
+    #[allow(dead_code)]
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    #[allow(dead_code)]
+    pub fn is_auto(&self) -> bool {
+        self.auto
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        if !self.auto {
+            "Manual"
+        } else if self.output <= self.config.output_min || self.output >= self.config.output_max {
+            "Limit"
+        } else {
+            "Auto"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P_ONLY: PidConfig = PidConfig {
+        kp: 2.0,
+        ki: 0.0,
+        kd: 0.0,
+        output_min: -100.0,
+        output_max: 100.0,
+    };
+
+    // In auto from a standing start, so the bumpless transfer has nothing
+    // to carry over.
+    fn auto(config: PidConfig) -> Pid {
+        let mut pid = Pid::new(config);
+        pid.set_auto(true);
+        pid.update(0.0, 0.0, 0);
+        pid
+    }
+
+    fn approx(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn test_starts_manual_at_zero() {
+        let mut pid = Pid::new(P_ONLY);
+        assert!(!pid.is_auto());
+        assert_eq!(pid.state_name(), "Manual");
+        assert_eq!(pid.update(10.0, 0.0, 0), 0.0);
+        let pid = Pid::new(PidConfig {
+            output_min: 0.2,
+            ..P_ONLY
+        });
+        assert_eq!(pid.output(), 0.2);
+    }
+
+    #[test]
+    fn test_proportional() {
+        let mut pid = auto(P_ONLY);
+        assert_eq!(pid.update(10.0, 7.0, 1), 6.0);
+        assert_eq!(pid.update(10.0, 12.0, 2), -4.0);
+        assert_eq!(pid.state_name(), "Auto");
+    }
+
+    #[test]
+    fn test_integral() {
+        let mut pid = auto(PidConfig {
+            kp: 0.0,
+            ki: 0.5,
+            ..P_ONLY
+        });
+        pid.update(1.0, 0.0, 0);
+        // 0.5/s * error 1 for 1s.
+        for now in (10..=1000).step_by(10) {
+            pid.update(1.0, 0.0, now);
+        }
+        assert!(approx(pid.output(), 0.5), "{}", pid.output());
+        // Holds with no error, and unwinds with negative error.
+        pid.update(0.0, 0.0, 2000);
+        assert!(approx(pid.output(), 0.5));
+        pid.update(0.0, 1.0, 3000);
+        assert!(approx(pid.output(), 0.0), "{}", pid.output());
+    }
+
+    #[test]
+    fn test_derivative_on_measurement() {
+        let mut pid = auto(PidConfig {
+            kp: 0.0,
+            kd: 0.1,
+            ..P_ONLY
+        });
+        // A setpoint step doesn't kick.
+        assert_eq!(pid.update(100.0, 0.0, 10), 0.0);
+        // The measurement rising 1 in 10ms is 100/s, opposed at 0.1s.
+        assert!(approx(pid.update(100.0, 1.0, 20), -10.0));
+        assert!(approx(pid.update(100.0, 1.0, 30), 0.0));
+    }
+
+    #[test]
+    fn test_first_update_and_zero_dt() {
+        let mut pid = auto(PidConfig {
+            kp: 1.0,
+            ki: 10.0,
+            kd: 10.0,
+            ..P_ONLY
+        });
+        // No elapsed time: no integration or derivative, just P.
+        assert_eq!(pid.update(5.0, 0.0, 0), 5.0);
+        assert_eq!(pid.update(5.0, 1.0, 0), 4.0);
+        // Time going backwards counts as none passing.
+        assert_eq!(pid.update(5.0, 1.0, -5), 4.0);
+    }
+
+    #[test]
+    fn test_output_limits() {
+        let mut pid = auto(PidConfig {
+            output_min: -5.0,
+            output_max: 5.0,
+            ..P_ONLY
+        });
+        assert_eq!(pid.update(100.0, 0.0, 1), 5.0);
+        assert_eq!(pid.state_name(), "Limit");
+        assert_eq!(pid.update(-100.0, 0.0, 2), -5.0);
+        assert_eq!(pid.update(1.0, 0.0, 3), 2.0);
+    }
+
+    #[test]
+    fn test_anti_windup() {
+        let config = PidConfig {
+            kp: 1.0,
+            ki: 1.0,
+            kd: 0.0,
+            output_min: 0.0,
+            output_max: 10.0,
+        };
+        let mut pid = auto(config);
+        pid.update(100.0, 0.0, 0);
+        // A long time saturated...
+        for now in (100..=60_000).step_by(100) {
+            assert_eq!(pid.update(100.0, 0.0, now), 10.0);
+        }
+        // ...and the output comes off the limit as soon as the error
+        // reverses, rather than after unwinding a minute's worth.
+        let out = pid.update(0.0, 1.0, 60_100);
+        assert!(out < 10.0, "{}", out);
+        // The same at the bottom.
+        for now in (60_200..=120_000).step_by(100) {
+            assert_eq!(pid.update(-100.0, 0.0, now), 0.0);
+        }
+        let out = pid.update(1.0, 0.0, 120_100);
+        assert!(out > 0.0, "{}", out);
+    }
+
+    #[test]
+    fn test_windup_held_not_dumped() {
+        // A large P term pushing the output into a limit doesn't throw
+        // away integral already built up.
+        let mut pid = auto(PidConfig {
+            kp: 1.0,
+            ki: 1.0,
+            kd: 0.0,
+            output_min: -10.0,
+            output_max: 10.0,
+        });
+        pid.update(4.0, 0.0, 0);
+        pid.update(4.0, 0.0, 1000);
+        assert!(approx(pid.output(), 8.0));
+        assert_eq!(pid.update(100.0, 0.0, 1001), 10.0);
+        let out = pid.update(4.0, 0.0, 1002);
+        assert!((out - 8.0).abs() < 0.01, "{}", out);
+    }
+
+    #[test]
+    fn test_bumpless_transfer() {
+        let mut pid = Pid::new(PidConfig {
+            kp: 1.0,
+            ki: 1.0,
+            kd: 1.0,
+            ..P_ONLY
+        });
+        pid.set_output(30.0);
+        pid.update(50.0, 10.0, 0);
+        pid.update(50.0, 10.0, 100);
+        // Into auto with a large error: the output carries on from manual.
+        pid.set_auto(true);
+        assert!(approx(pid.update(50.0, 10.0, 200), 30.0));
+        // And then moves smoothly from there.
+        let out = pid.update(50.0, 10.0, 300);
+        assert!(approx(out, 34.0), "{}", out);
+        // Back to manual holds the last output until told otherwise.
+        pid.set_auto(false);
+        assert!(approx(pid.update(0.0, 100.0, 400), 34.0));
+        pid.set_output(500.0);
+        assert_eq!(pid.output(), 100.0);
+    }
+
+    #[test]
+    fn test_set_output_ignored_in_auto() {
+        let mut pid = auto(P_ONLY);
+        pid.set_output(50.0);
+        assert_eq!(pid.output(), 0.0);
+        // Switching to auto when already there isn't another transfer.
+        pid.update(10.0, 0.0, 1);
+        pid.set_auto(true);
+        assert_eq!(pid.update(10.0, 0.0, 2), 20.0);
+    }
+
+    #[test]
+    fn test_reverse_acting() {
+        // Negative gains for e.g. cooling: output rises as it gets hot.
+        let mut pid = auto(PidConfig {
+            kp: -2.0,
+            ki: 0.0,
+            kd: 0.0,
+            output_min: 0.0,
+            output_max: 100.0,
+        });
+        pid.update(0.0, 0.0, 0);
+        assert_eq!(pid.update(30.0, 40.0, 1), 20.0);
+        assert_eq!(pid.update(30.0, 20.0, 2), 0.0);
+    }
+
+    #[test]
+    fn test_closed_loop_settles() {
+        // First-order plant with a 1s time constant and a gain of 2, run
+        // at the 1ms scan tick.
+        let mut pid = auto(PidConfig {
+            kp: 1.0,
+            ki: 2.0,
+            kd: 0.01,
+            output_min: 0.0,
+            output_max: 100.0,
+        });
+        let mut y = 0.0f32;
+        let mut peak = 0.0f32;
+        for now in 0..20_000 {
+            let u = pid.update(50.0, y, now);
+            y += (2.0 * u - y) * 0.001;
+            peak = peak.max(y);
+        }
+        assert!((y - 50.0).abs() < 0.1, "{}", y);
+        assert!(peak < 60.0, "{}", peak);
+    }
+
+    #[test]
+    fn test_closed_loop_saturated_no_overshoot_from_windup() {
+        // The output limit is barely enough to reach the setpoint, so it
+        // sits at the limit for most of the rise. Without anti-windup the
+        // integral would build up all that time and overshoot badly.
+        let mut pid = auto(PidConfig {
+            kp: 0.5,
+            ki: 5.0,
+            kd: 0.0,
+            output_min: 0.0,
+            output_max: 30.0,
+        });
+        let mut y = 0.0f32;
+        let mut peak = 0.0f32;
+        for now in 0..30_000 {
+            let u = pid.update(50.0, y, now);
+            y += (2.0 * u - y) * 0.001;
+            peak = peak.max(y);
+        }
+        assert!((y - 50.0).abs() < 0.1, "{}", y);
+        assert!(peak < 52.0, "{}", peak);
+    }
+}
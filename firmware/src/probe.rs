@@ -11,26 +11,105 @@
 //! is a little finesse around the other signals becoming valid. Also, we
 //! want to disable the spindle signal whenever the probe is active to
 //! prevent stupid accident.
+//!
+//! A low battery is only a warning, as the probe usually has plenty of
+//! life left in it: the probe stays usable, but shows a different status.
+//! It can optionally be made an error once the battery has been low for a
+//! while, or for a number of probe cycles, so it does get changed. The
+//! battery has to read good for a while before that starts over, so a
+//! battery that recovers briefly under a light load doesn't reset it. None
+//! of this is retained, so a reset also starts it over.
 use crate::simpletimer::SimpleTimer;
 use fugit::ExtU32;
 
 #[derive(Default)]
-enum ProbeFSMState {
+pub enum ProbeFSMState {
     #[default]
     Off,
     WaitReady(SimpleTimer),
     Active,
+    LowBattery,
     Error,
 }
 
+/// When a low battery becomes an error. `None` disables either limit.
+#[derive(Clone, Copy, Default)]
+pub struct LowBatteryEscalation {
+    /// Time since the battery was first reported low.
+    pub grace: Option<fugit::Duration<u32, 1, 1_000>>,
+    /// Probe cycles started with the battery low.
+    pub max_cycles: Option<u32>,
+}
+
 #[derive(Default)]
 pub struct ProbeControl {
     state: ProbeFSMState,
+    escalation: LowBatteryEscalation,
+    /// Since the battery was first seen low, and the probe cycles since.
+    /// Both reset once it's been seen good for `LOW_BATTERY_CLEAR_MS`.
+    low_battery_since: Option<i64>,
+    low_battery_cycles: u32,
+    battery_good_since: Option<i64>,
 }
 
 const PROBE_WAIT_MS: u32 = 500;
+const LOW_BATTERY_CLEAR_MS: i64 = 60_000;
 
 impl ProbeControl {
+    pub fn with_low_battery_escalation(escalation: LowBatteryEscalation) -> Self {
+        ProbeControl {
+            escalation,
+            ..Default::default()
+        }
+    }
+
+    /// Where a ready probe should be, given its alarm and battery signals.
+    /// `new_cycle` is set for a probe that's just become ready.
+    fn ready(
+        &mut self,
+        probe_alarm: bool,
+        probe_lowbatt: bool,
+        new_cycle: bool,
+        now: i64,
+    ) -> ProbeFSMState {
+        if !probe_lowbatt {
+            let good_since = *self.battery_good_since.get_or_insert(now);
+            if now - good_since >= LOW_BATTERY_CLEAR_MS {
+                self.low_battery_since = None;
+                self.low_battery_cycles = 0;
+            }
+        } else {
+            self.battery_good_since = None;
+            self.low_battery_since.get_or_insert(now);
+            if new_cycle {
+                self.low_battery_cycles += 1;
+            }
+        }
+        if self.error(probe_alarm, probe_lowbatt, now) {
+            ProbeFSMState::Error
+        } else if probe_lowbatt {
+            ProbeFSMState::LowBattery
+        } else {
+            ProbeFSMState::Active
+        }
+    }
+
+    fn error(&self, probe_alarm: bool, probe_lowbatt: bool, now: i64) -> bool {
+        probe_alarm || (probe_lowbatt && self.escalated(now))
+    }
+
+    fn escalated(&self, now: i64) -> bool {
+        let grace_expired = match (self.escalation.grace, self.low_battery_since) {
+            (Some(grace), Some(since)) => now - since >= grace.ticks() as i64,
+            _ => false,
+        };
+        let too_many_cycles = self
+            .escalation
+            .max_cycles
+            .is_some_and(|max| self.low_battery_cycles > max);
+        grace_expired || too_many_cycles
+    }
+
     pub fn update(&mut self, probe_enable: bool, probe_alarm: bool, probe_lowbatt: bool, now: i64) {
         match &self.state {
             ProbeFSMState::Off => {
@@ -43,24 +122,20 @@ impl ProbeControl {
                 if !probe_enable {
                     self.state = ProbeFSMState::Off;
                 } else if timer.expired(now) {
-                    if probe_alarm || probe_lowbatt {
-                        self.state = ProbeFSMState::Error;
-                    } else {
-                        self.state = ProbeFSMState::Active;
-                    }
+                    self.state = self.ready(probe_alarm, probe_lowbatt, true, now);
                 }
             }
-            ProbeFSMState::Active => {
+            ProbeFSMState::Active | ProbeFSMState::LowBattery => {
                 if !probe_enable {
                     self.state = ProbeFSMState::Off;
-                } else if probe_alarm || probe_lowbatt {
-                    self.state = ProbeFSMState::Error;
+                } else {
+                    self.state = self.ready(probe_alarm, probe_lowbatt, false, now);
                 }
             }
             ProbeFSMState::Error => {
                 if !probe_enable {
                     self.state = ProbeFSMState::Off;
-                } else if !probe_alarm && !probe_lowbatt {
+                } else if !self.error(probe_alarm, probe_lowbatt, now) {
                     self.state =
                         ProbeFSMState::WaitReady(SimpleTimer::start(now, PROBE_WAIT_MS.millis()))
                 }
@@ -73,6 +148,7 @@ impl ProbeControl {
             ProbeFSMState::Off => false,
             ProbeFSMState::WaitReady(_) => true,
             ProbeFSMState::Active => true,
+            ProbeFSMState::LowBattery => true,
             ProbeFSMState::Error => true,
         }
     }
@@ -82,6 +158,7 @@ impl ProbeControl {
             ProbeFSMState::Off => false,
             ProbeFSMState::WaitReady(_) => false,
             ProbeFSMState::Active => true,
+            ProbeFSMState::LowBattery => true,
             ProbeFSMState::Error => false,
         }
     }
@@ -91,6 +168,7 @@ impl ProbeControl {
             ProbeFSMState::Off => false,
             ProbeFSMState::WaitReady(_) => true,
             ProbeFSMState::Active => true,
+            ProbeFSMState::LowBattery => true,
             ProbeFSMState::Error => true,
         }
     }
@@ -100,7 +178,163 @@ impl ProbeControl {
             ProbeFSMState::Off => 'O',
             ProbeFSMState::WaitReady(_) => 'W',
             ProbeFSMState::Active => 'A',
+            ProbeFSMState::LowBattery => 'L',
             ProbeFSMState::Error => 'X',
         }
     }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            ProbeFSMState::Off => "Off",
+            ProbeFSMState::WaitReady(_) => "WaitReady",
+            ProbeFSMState::Active => "Active",
+            ProbeFSMState::LowBattery => "LowBattery",
+            ProbeFSMState::Error => "Error",
+        }
+    }
+
+    pub fn state(&self) -> &ProbeFSMState {
+        &self.state
+    }
+
+    /// Time left before the current state times out, if it has a timer.
+    /// For a low battery, that's the grace time left.
+    pub fn remaining(&self, now: i64) -> Option<fugit::Duration<u32, 1, 1_000>> {
+        match &self.state {
+            ProbeFSMState::Off => None,
+            ProbeFSMState::WaitReady(timer) => Some(timer.remaining(now)),
+            ProbeFSMState::Active => None,
+            ProbeFSMState::LowBattery => {
+                let grace = self.escalation.grace?;
+                let since = self.low_battery_since?;
+                Some(SimpleTimer::start(since, grace).remaining(now))
+            }
+            ProbeFSMState::Error => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: i64 = PROBE_WAIT_MS as i64;
+
+    /// Enable the probe and let it come ready at time `at`.
+    fn ready(probe: &mut ProbeControl, lowbatt: bool, at: i64) {
+        probe.update(true, false, lowbatt, at - WAIT);
+        assert_eq!(probe.state_name(), "WaitReady");
+        probe.update(true, false, lowbatt, at);
+    }
+
+    #[test]
+    fn test_active() {
+        let mut probe = ProbeControl::default();
+        probe.update(false, false, false, 0);
+        assert!(!probe.probe_power());
+        assert!(matches!(probe.state(), ProbeFSMState::Off));
+        ready(&mut probe, false, 1000);
+        assert!(matches!(probe.state(), ProbeFSMState::Active));
+        assert!(probe.probe_detect() && probe.spindle_inhibit());
+        probe.update(false, false, false, 1010);
+        assert_eq!(probe.state_name(), "Off");
+    }
+
+    #[test]
+    fn test_alarm() {
+        let mut probe = ProbeControl::default();
+        ready(&mut probe, false, 1000);
+        probe.update(true, true, false, 1010);
+        assert_eq!(probe.state_name(), "Error");
+        assert!(!probe.probe_detect());
+        // Clearing goes back through WaitReady.
+        probe.update(true, false, false, 1020);
+        assert_eq!(probe.state_name(), "WaitReady");
+        probe.update(true, false, false, 1020 + WAIT);
+        assert_eq!(probe.state_name(), "Active");
+    }
+
+    #[test]
+    fn test_low_battery_warning() {
+        let mut probe = ProbeControl::default();
+        ready(&mut probe, true, 1000);
+        assert_eq!(probe.state_name(), "LowBattery");
+        assert_eq!(probe.status_char(), 'L');
+        assert!(probe.probe_detect() && probe.spindle_inhibit());
+        assert_eq!(probe.remaining(1000), None);
+        // It stays usable indefinitely without escalation.
+        probe.update(true, false, true, 10_000_000);
+        assert_eq!(probe.state_name(), "LowBattery");
+        probe.update(true, false, false, 10_000_010);
+        assert_eq!(probe.state_name(), "Active");
+        probe.update(true, false, true, 10_000_020);
+        assert_eq!(probe.state_name(), "LowBattery");
+        probe.update(true, true, true, 10_000_030);
+        assert_eq!(probe.state_name(), "Error");
+        // Only the alarm going keeps it out of Error.
+        probe.update(true, false, true, 10_000_035);
+        assert_eq!(probe.state_name(), "WaitReady");
+        probe.update(false, false, true, 10_000_040);
+        assert_eq!(probe.state_name(), "Off");
+    }
+
+    #[test]
+    fn test_low_battery_grace() {
+        let mut probe = ProbeControl::with_low_battery_escalation(LowBatteryEscalation {
+            grace: Some(60.secs()),
+            max_cycles: None,
+        });
+        ready(&mut probe, true, 1000);
+        assert_eq!(probe.remaining(31_000).unwrap().ticks(), 30_000);
+        // Turning the probe off and on doesn't restart the grace time.
+        probe.update(false, false, true, 2000);
+        ready(&mut probe, true, 3000);
+        probe.update(true, false, true, 60_999);
+        assert_eq!(probe.state_name(), "LowBattery");
+        probe.update(true, false, true, 61_000);
+        assert_eq!(probe.state_name(), "Error");
+        // A new battery clears it, once it's read good for a while.
+        probe.update(true, false, false, 62_000);
+        probe.update(true, false, false, 62_000 + WAIT);
+        assert_eq!(probe.state_name(), "Active");
+        probe.update(true, false, false, 62_000 + WAIT + LOW_BATTERY_CLEAR_MS);
+        probe.update(true, false, true, 123_000);
+        assert_eq!(probe.state_name(), "LowBattery");
+    }
+
+    #[test]
+    fn test_low_battery_recovery() {
+        let mut probe = ProbeControl::with_low_battery_escalation(LowBatteryEscalation {
+            grace: Some(60.secs()),
+            max_cycles: Some(2),
+        });
+        ready(&mut probe, true, 1000);
+        // The battery recovering briefly keeps the grace time and cycles.
+        probe.update(true, false, false, 30_000);
+        assert_eq!(probe.state_name(), "Active");
+        probe.update(true, false, true, 30_000 + LOW_BATTERY_CLEAR_MS - 1);
+        assert_eq!(probe.state_name(), "Error");
+        probe.update(false, false, true, 100_000);
+        assert_eq!(probe.remaining(100_000), None);
+        // Nor does a probe cycle with it reading good.
+        ready(&mut probe, false, 101_000);
+        probe.update(false, false, false, 101_200);
+        ready(&mut probe, true, 102_000);
+        assert_eq!(probe.state_name(), "Error");
+    }
+
+    #[test]
+    fn test_low_battery_cycles() {
+        let mut probe = ProbeControl::with_low_battery_escalation(LowBatteryEscalation {
+            grace: None,
+            max_cycles: Some(2),
+        });
+        for cycle in 0..2 {
+            ready(&mut probe, true, 1000 + cycle * 2000);
+            assert_eq!(probe.state_name(), "LowBattery");
+            probe.update(false, false, true, 2000 + cycle * 2000);
+        }
+        ready(&mut probe, true, 5000);
+        assert_eq!(probe.state_name(), "Error");
+    }
 }
@@ -0,0 +1,283 @@
+//! User program storage.
+//!
+//! The program image (see `bytecode`) lives in flash sector 6, which
+//! memory.x keeps the linker out of. It's uploaded over the USB console a
+//! few bytes at a time into the erased sector; as with the retain journal,
+//! erasing takes far longer than the watchdog allows, so the console asks
+//! for it across a reset and it's done at boot, before the watchdog is
+//! started.
+//!
+//! The rule table (see `rules`) is kept as text at the end of the same
+//! sector, so it's uploaded the same way. There's no other sector to spare
+//! for it, so erasing either the program or the rule table copies the
+//! other out of the sector first and writes it back afterwards. A power
+//! cut in between loses that too.
+use crate::bytecode::{crc32, Header, HEADER_LEN, MAX_CODE, VERSION};
+use crate::vm::{LoadError, Vm};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+/// Flash sector 6, relative to the start of flash.
+pub const PROGRAM_OFFSET: u32 = 0x4_0000;
+pub const PROGRAM_SIZE: u32 = 0x2_0000;
+
+/// Boot requests, left in a backup register, to erase the program or the
+/// rule table.
+pub const ERASE_REQUEST: u32 = 0x4552_4153;
+pub const ERASE_RULES_REQUEST: u32 = 0x4552_5255;
+
+/// The rule table's text, relative to the program, up to the first erased
+/// byte.
+pub const RULES_OFFSET: u32 = PROGRAM_SIZE - RULES_SIZE;
+pub const RULES_SIZE: u32 = 0x1000;
+const _: () = assert!(HEADER_LEN + MAX_CODE <= RULES_OFFSET as usize);
+const _: () = assert!(HEADER_LEN + MAX_CODE <= RULES_SIZE as usize);
+
+/// What an erase clears; the other is kept.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Erase {
+    Program,
+    Rules,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProgramError<E> {
+    Empty,
+    BadHeader,
+    BadVersion,
+    TooLong,
+    Checksum,
+    Load(LoadError),
+    Flash(E),
+}
+
+impl<E> ProgramError<E> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgramError::Empty => "Empty",
+            ProgramError::BadHeader => "BadHeader",
+            ProgramError::BadVersion => "BadVersion",
+            ProgramError::TooLong => "TooLong",
+            ProgramError::Checksum => "Checksum",
+            ProgramError::Load(_) => "BadCode",
+            ProgramError::Flash(_) => "FlashError",
+        }
+    }
+}
+
+/// Read the program from flash, check it, and load it into the VM, which
+/// is left stopped. On failure the VM keeps whatever it had.
+pub fn load<F: ReadNorFlash>(flash: &mut F, vm: &mut Vm) -> Result<(), ProgramError<F::Error>> {
+    let mut bytes = [0u8; HEADER_LEN];
+    flash
+        .read(PROGRAM_OFFSET, &mut bytes)
+        .map_err(ProgramError::Flash)?;
+    if bytes == [0xff; HEADER_LEN] {
+        return Err(ProgramError::Empty);
+    }
+    let header = Header::decode(&bytes).ok_or(ProgramError::BadHeader)?;
+    if header.version != VERSION {
+        return Err(ProgramError::BadVersion);
+    }
+    let len = header.code_len as usize;
+    if len > MAX_CODE {
+        return Err(ProgramError::TooLong);
+    }
+    let mut code = [0u8; MAX_CODE];
+    flash
+        .read(PROGRAM_OFFSET + HEADER_LEN as u32, &mut code[..len])
+        .map_err(ProgramError::Flash)?;
+    if crc32(&code[..len]) != header.crc {
+        return Err(ProgramError::Checksum);
+    }
+    vm.load(&code[..len]).map_err(ProgramError::Load)
+}
+
+/// Erase the program or the rule table, keeping the other. This erases
+/// the whole sector, so it's only for use before the watchdog starts.
+pub fn erase<F: NorFlash>(flash: &mut F, which: Erase) -> Result<(), F::Error> {
+    let (offset, len) = match which {
+        Erase::Program => (RULES_OFFSET, RULES_SIZE as usize),
+        Erase::Rules => (0, HEADER_LEN + MAX_CODE),
+    };
+    let mut kept = [0u8; RULES_SIZE as usize];
+    let kept = &mut kept[..len];
+    flash.read(PROGRAM_OFFSET + offset, kept)?;
+    flash.erase(PROGRAM_OFFSET, PROGRAM_OFFSET + PROGRAM_SIZE)?;
+    // Anything past the last programmed byte is erased already.
+    let used = kept
+        .iter()
+        .rposition(|&b| b != 0xff)
+        .map_or(0, |last| last + 1);
+    flash.write(PROGRAM_OFFSET + offset, &kept[..used])
+}
+
+/// Read the rule table's text from flash, or `None` if there isn't one.
+pub fn read_rules<'a, F: ReadNorFlash>(
+    flash: &mut F,
+    buf: &'a mut [u8; RULES_SIZE as usize],
+) -> Result<Option<&'a [u8]>, F::Error> {
+    flash.read(PROGRAM_OFFSET + RULES_OFFSET, buf)?;
+    let len = buf.iter().position(|&b| b == 0xff).unwrap_or(buf.len());
+    Ok((len > 0).then_some(&buf[..len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Op;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind};
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct MockError;
+
+    impl NorFlashError for MockError {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    // Just the program sector.
+    struct MockFlash {
+        mem: Vec<u8>,
+    }
+
+    impl MockFlash {
+        fn with_image(image: &[u8]) -> Self {
+            let mut mem = vec![0xff; PROGRAM_SIZE as usize];
+            mem[..image.len()].copy_from_slice(image);
+            MockFlash { mem }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = MockError;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), MockError> {
+            let at = (offset - PROGRAM_OFFSET) as usize;
+            bytes.copy_from_slice(&self.mem[at..at + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.mem.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = PROGRAM_SIZE as usize;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), MockError> {
+            let (from, to) = (
+                (from - PROGRAM_OFFSET) as usize,
+                (to - PROGRAM_OFFSET) as usize,
+            );
+            self.mem[from..to].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), MockError> {
+            let at = (offset - PROGRAM_OFFSET) as usize;
+            for (mem, byte) in self.mem[at..at + bytes.len()].iter_mut().zip(bytes) {
+                *mem &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn image(code: &[u8]) -> Vec<u8> {
+        let mut image = Header::for_code(code).encode().to_vec();
+        image.extend_from_slice(code);
+        image
+    }
+
+    const CODE: [u8; 4] = [Op::LdI as u8, 0, Op::StQ as u8, 0];
+
+    #[test]
+    fn test_load() {
+        let mut vm = Vm::default();
+        let mut flash = MockFlash::with_image(&image(&CODE));
+        assert_eq!(load(&mut flash, &mut vm), Ok(()));
+        assert_eq!(vm.state_name(), "Stopped");
+        assert_eq!(vm.code_len(), CODE.len());
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut vm = Vm::default();
+        let mut flash = MockFlash::with_image(&[]);
+        assert_eq!(load(&mut flash, &mut vm), Err(ProgramError::Empty));
+        assert_eq!(vm.state_name(), "NoProgram");
+    }
+
+    #[test]
+    fn test_bad_images() {
+        let mut vm = Vm::default();
+        let good = image(&CODE);
+
+        let mut bad = good.clone();
+        bad[0] = 0;
+        let mut flash = MockFlash::with_image(&bad);
+        assert_eq!(load(&mut flash, &mut vm), Err(ProgramError::BadHeader));
+
+        let mut bad = good.clone();
+        bad[4] = 99;
+        let mut flash = MockFlash::with_image(&bad);
+        assert_eq!(load(&mut flash, &mut vm), Err(ProgramError::BadVersion));
+
+        let mut bad = good.clone();
+        bad[8..12].copy_from_slice(&(MAX_CODE as u32 + 1).to_le_bytes());
+        let mut flash = MockFlash::with_image(&bad);
+        assert_eq!(load(&mut flash, &mut vm), Err(ProgramError::TooLong));
+
+        // Torn upload: the end of the code never made it.
+        let mut flash = MockFlash::with_image(&good[..good.len() - 1]);
+        assert_eq!(load(&mut flash, &mut vm), Err(ProgramError::Checksum));
+
+        let mut flash = MockFlash::with_image(&image(&[0xfe]));
+        assert_eq!(
+            load(&mut flash, &mut vm),
+            Err(ProgramError::Load(LoadError::BadOpcode(0)))
+        );
+        assert_eq!(vm.state_name(), "NoProgram");
+    }
+
+    #[test]
+    fn test_read_rules() {
+        let mut buf = [0; RULES_SIZE as usize];
+        let mut flash = MockFlash::with_image(&image(&CODE));
+        assert_eq!(read_rules(&mut flash, &mut buf), Ok(None));
+        let text = b"Q6 = SPINDLE_ON\n";
+        let at = RULES_OFFSET as usize;
+        flash.mem[at..at + text.len()].copy_from_slice(text);
+        assert_eq!(read_rules(&mut flash, &mut buf), Ok(Some(&text[..])));
+        // The program still loads alongside it.
+        let mut vm = Vm::default();
+        assert_eq!(load(&mut flash, &mut vm), Ok(()));
+    }
+
+    #[test]
+    fn test_erase_keeps_the_other() {
+        let mut buf = [0; RULES_SIZE as usize];
+        let text = b"Q6 = SPINDLE_ON\n";
+        let at = RULES_OFFSET as usize;
+        let mut flash = MockFlash::with_image(&image(&CODE));
+        flash.mem[at..at + text.len()].copy_from_slice(text);
+        erase(&mut flash, Erase::Program).unwrap();
+        let mut vm = Vm::default();
+        assert_eq!(load(&mut flash, &mut vm), Err(ProgramError::Empty));
+        assert_eq!(read_rules(&mut flash, &mut buf), Ok(Some(&text[..])));
+
+        let mut flash = MockFlash::with_image(&image(&CODE));
+        flash.mem[at..at + text.len()].copy_from_slice(text);
+        erase(&mut flash, Erase::Rules).unwrap();
+        assert_eq!(read_rules(&mut flash, &mut buf), Ok(None));
+        assert_eq!(load(&mut flash, &mut vm), Ok(()));
+        // With nothing else there, a new rule table can go straight in.
+        assert!(flash.mem[at..].iter().all(|&b| b == 0xff));
+    }
+}
@@ -0,0 +1,163 @@
+//! PWM outputs.
+//!
+//! Outputs wired to a timer channel can be driven as PWM instead of plain
+//! on/off: HS1-HS4 are on TIM2 CH1-CH4 (but TIM2 is normally the step
+//! generator's), and GP12-GP15 on TIM4 CH1-CH4 (PD12-PD15). Channels on the
+//! same timer share its frequency.
+//!
+//! This handles the duty side: the requested duty is approached at a
+//! limited rate so that e.g. a speed reference doesn't step, and on a fault
+//! the output goes straight to a safe duty, ramping back from there once
+//! the fault clears.
+
+pub struct PwmOutput {
+    ramp_time: fugit::Duration<u32, 1, 1_000>,
+    safe_duty: f32,
+    target: f32,
+    duty: f32,
+    faulted: bool,
+    last_update: Option<i64>,
+}
+
+impl PwmOutput {
+    /// `ramp_time` is how long a change from 0 to 100% duty takes; zero
+    /// for no ramping. Duties are fractions, 0.0 to 1.0.
+    pub fn new(ramp_time: fugit::Duration<u32, 1, 1_000>, safe_duty: f32) -> Self {
+        let safe_duty = safe_duty.clamp(0.0, 1.0);
+        PwmOutput {
+            ramp_time,
+            safe_duty,
+            target: safe_duty,
+            duty: safe_duty,
+            faulted: false,
+            last_update: None,
+        }
+    }
+
+    pub fn set_duty(&mut self, duty: f32) {
+        self.target = duty.clamp(0.0, 1.0);
+    }
+
+    pub fn update(&mut self, fault: bool, now: i64) {
+        let elapsed_ms = match self.last_update.replace(now) {
+            Some(last) => (now - last).max(0) as f32,
+            None => 0.0,
+        };
+        self.faulted = fault;
+        if fault {
+            self.duty = self.safe_duty;
+        } else if self.ramp_time.ticks() == 0 {
+            self.duty = self.target;
+        } else {
+            let step = elapsed_ms / self.ramp_time.ticks() as f32;
+            self.duty += (self.target - self.duty).clamp(-step, step);
+        }
+    }
+
+    pub fn duty(&self) -> f32 {
+        self.duty
+    }
+
+    /// Compare register value for the current duty; see `compare`.
+    #[allow(dead_code)]
+    pub fn compare(&self, max_duty: u16) -> u16 {
+        compare(self.duty, max_duty)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_faulted(&self) -> bool {
+        self.faulted
+    }
+
+    #[allow(dead_code)]
+    pub fn state_name(&self) -> &'static str {
+        if self.faulted {
+            "Fault"
+        } else if self.duty != self.target {
+            "Ramping"
+        } else {
+            "Steady"
+        }
+    }
+}
+
+/// Compare register value for `duty`, given the timer's maximum duty as
+/// reported by the HAL, where 0 means 2^16.
+pub fn compare(duty: f32, max_duty: u16) -> u16 {
+    let max = match max_duty {
+        0 => 65536.0,
+        max => max as f32,
+    };
+    (duty.clamp(0.0, 1.0) * max + 0.5).min(u16::MAX as f32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU32;
+
+    #[test]
+    fn test_ramps() {
+        let mut pwm = PwmOutput::new(1000.millis(), 0.0);
+        pwm.update(false, 0);
+        pwm.set_duty(0.5);
+        pwm.update(false, 100);
+        assert!((pwm.duty() - 0.1).abs() < 1e-6);
+        assert_eq!(pwm.state_name(), "Ramping");
+        pwm.update(false, 1000);
+        assert_eq!(pwm.duty(), 0.5);
+        assert_eq!(pwm.state_name(), "Steady");
+        pwm.set_duty(0.0);
+        pwm.update(false, 1250);
+        assert!((pwm.duty() - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_no_ramp() {
+        let mut pwm = PwmOutput::new(0.millis(), 0.0);
+        pwm.set_duty(0.75);
+        pwm.update(false, 0);
+        assert_eq!(pwm.duty(), 0.75);
+    }
+
+    #[test]
+    fn test_duty_clamped() {
+        let mut pwm = PwmOutput::new(0.millis(), 0.0);
+        pwm.set_duty(1.5);
+        pwm.update(false, 0);
+        assert_eq!(pwm.duty(), 1.0);
+        pwm.set_duty(-1.0);
+        pwm.update(false, 1);
+        assert_eq!(pwm.duty(), 0.0);
+    }
+
+    #[test]
+    fn test_fault_goes_safe_at_once() {
+        let mut pwm = PwmOutput::new(1000.millis(), 0.2);
+        assert_eq!(pwm.duty(), 0.2);
+        pwm.set_duty(1.0);
+        pwm.update(false, 0);
+        pwm.update(false, 1000);
+        assert_eq!(pwm.duty(), 1.0);
+        pwm.update(true, 1001);
+        assert_eq!(pwm.duty(), 0.2);
+        assert_eq!(pwm.state_name(), "Fault");
+        // Ramps back up once the fault clears.
+        pwm.update(false, 1101);
+        assert!((pwm.duty() - 0.3).abs() < 1e-6);
+        assert!(!pwm.is_faulted());
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut pwm = PwmOutput::new(0.millis(), 0.0);
+        pwm.set_duty(0.25);
+        pwm.update(false, 0);
+        assert_eq!(pwm.compare(1000), 250);
+        assert_eq!(pwm.compare(0), 16384);
+        pwm.set_duty(1.0);
+        pwm.update(false, 1);
+        assert_eq!(pwm.compare(1000), 1000);
+        assert_eq!(pwm.compare(0), u16::MAX);
+    }
+}
@@ -0,0 +1,529 @@
+//! Retained variables.
+//!
+//! A handful of 32-bit values (run-time counters and the like) that need
+//! to survive resets and power cycles. They are mirrored into the RTC
+//! backup registers on every change, which covers watchdog and software
+//! resets (and power loss too, if an RTC battery is ever fitted), and
+//! written periodically to a journal in the last flash sector, which
+//! covers power loss.
+//!
+//! The journal appends fixed-size records to the sector and the newest
+//! valid record wins when loading. Flash is programmed a byte at a time,
+//! at up to 100us a byte, so a record is written a word per scan with its
+//! magic word last; it isn't valid until it's complete. Erasing a 128K
+//! sector stalls the CPU for a second or two, far longer than the watchdog
+//! allows, so the sector is compacted at boot before the watchdog is
+//! started, or with the machine at rest and the watchdog let out. If it
+//! fills up before then, `full` says so, and records are dropped until it
+//! has been compacted; the backup registers still have the latest.
+use crate::simpletimer::SimpleTimer;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use fugit::ExtU32;
+use stm32f4xx_hal::pac;
+
+pub const RETAIN_SLOTS: usize = 16;
+pub type RetainImage = [u32; RETAIN_SLOTS];
+
+/// Flash sector 7, relative to the start of flash. memory.x keeps the
+/// linker out of it.
+pub const JOURNAL_OFFSET: u32 = 0x6_0000;
+pub const JOURNAL_SIZE: u32 = 0x2_0000;
+
+const RECORD_MAGIC: u32 = 0x5254_4e31;
+const RECORD_WORDS: usize = RETAIN_SLOTS + 3;
+const RECORD_BYTES: u32 = (RECORD_WORDS * 4) as u32;
+const ERASED: u32 = 0xffff_ffff;
+/// Bytes written per `step`: a word, within the watchdog period.
+const WRITE_CHUNK: usize = 4;
+
+const FLASH_SAVE_SECS: u32 = 600;
+/// How long the image has to stay the same before an idle flush.
+const FLASH_SETTLE_SECS: u32 = 10;
+
+fn checksum(words: &[u32]) -> u32 {
+    // Plain CRC-32 (IEEE), bitwise. Speed isn't a concern here.
+    let mut crc = 0xffff_ffffu32;
+    for word in words {
+        for byte in word.to_le_bytes() {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+    }
+    !crc
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JournalError<E> {
+    Full,
+    Flash(E),
+}
+
+/// A record on its way to flash.
+struct Pending {
+    at: u32,
+    seq: u32,
+    bytes: [u8; RECORD_BYTES as usize],
+    written: usize,
+}
+
+pub struct FlashJournal {
+    offset: u32,
+    size: u32,
+    next: u32,
+    seq: u32,
+    pending: Option<Pending>,
+}
+
+impl FlashJournal {
+    pub const fn new(offset: u32, size: u32) -> Self {
+        FlashJournal {
+            offset,
+            size,
+            next: 0,
+            seq: 0,
+            pending: None,
+        }
+    }
+
+    /// Scan the journal, returning the newest valid record and noting where
+    /// the next one should be written.
+    pub fn load<F: ReadNorFlash>(
+        &mut self,
+        flash: &mut F,
+    ) -> Result<Option<RetainImage>, F::Error> {
+        let mut newest = None;
+        self.next = 0;
+        self.seq = 0;
+        while self.next + RECORD_BYTES <= self.size {
+            let mut bytes = [0u8; RECORD_BYTES as usize];
+            flash.read(self.offset + self.next, &mut bytes)?;
+            let mut words = [0u32; RECORD_WORDS];
+            for (word, chunk) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+                *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            }
+            if words.iter().all(|&word| word == ERASED) {
+                break;
+            }
+            // Skip over records torn by a power failure.
+            if words[0] == RECORD_MAGIC
+                && checksum(&words[..RECORD_WORDS - 1]) == words[RECORD_WORDS - 1]
+            {
+                let mut image = [0; RETAIN_SLOTS];
+                image.copy_from_slice(&words[2..2 + RETAIN_SLOTS]);
+                self.seq = words[1];
+                newest = Some(image);
+            }
+            self.next += RECORD_BYTES;
+        }
+        Ok(newest)
+    }
+
+    /// Start writing a record, to be finished by calling `step` until it
+    /// returns true. Any record still being written is abandoned. False if
+    /// the journal is full.
+    pub fn begin(&mut self, image: &RetainImage) -> bool {
+        if self.full() {
+            return false;
+        }
+        let mut words = [0u32; RECORD_WORDS];
+        words[0] = RECORD_MAGIC;
+        words[1] = self.seq.wrapping_add(1);
+        words[2..2 + RETAIN_SLOTS].copy_from_slice(image);
+        words[RECORD_WORDS - 1] = checksum(&words[..RECORD_WORDS - 1]);
+        let mut bytes = [0u8; RECORD_BYTES as usize];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(words.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        // Whatever happens, don't write over this slot again.
+        self.pending = Some(Pending {
+            at: self.next,
+            seq: words[1],
+            bytes,
+            written: 0,
+        });
+        self.next += RECORD_BYTES;
+        true
+    }
+
+    /// Write the next part of the record, returning true once it's done.
+    pub fn step<F: NorFlash>(&mut self, flash: &mut F) -> Result<bool, JournalError<F::Error>> {
+        let Some(mut pending) = self.pending.take() else {
+            return Ok(true);
+        };
+        // Everything after the magic word first, then the magic word.
+        let from = (pending.written + WRITE_CHUNK) % RECORD_BYTES as usize;
+        flash
+            .write(
+                self.offset + pending.at + from as u32,
+                &pending.bytes[from..from + WRITE_CHUNK],
+            )
+            .map_err(JournalError::Flash)?;
+        pending.written += WRITE_CHUNK;
+        if pending.written < RECORD_BYTES as usize {
+            self.pending = Some(pending);
+            return Ok(false);
+        }
+        self.seq = pending.seq;
+        Ok(true)
+    }
+
+    pub fn busy(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Write a whole record at once, for use before the watchdog starts.
+    pub fn save<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        image: &RetainImage,
+    ) -> Result<(), JournalError<F::Error>> {
+        if !self.begin(image) {
+            return Err(JournalError::Full);
+        }
+        while !self.step(flash)? {}
+        Ok(())
+    }
+
+    /// Erase the journal and start it afresh with a single record.
+    pub fn compact<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        image: &RetainImage,
+    ) -> Result<(), JournalError<F::Error>> {
+        flash
+            .erase(self.offset, self.offset + self.size)
+            .map_err(JournalError::Flash)?;
+        self.next = 0;
+        self.pending = None;
+        self.save(flash, image)
+    }
+
+    /// True once three quarters of the journal has been used.
+    pub fn nearly_full(&self) -> bool {
+        self.next >= self.size / 4 * 3
+    }
+
+    /// True once there's no room for another record.
+    pub fn full(&self) -> bool {
+        self.next + RECORD_BYTES > self.size
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        if self.full() {
+            "Full"
+        } else if self.nearly_full() {
+            "NearlyFull"
+        } else {
+            "Ok"
+        }
+    }
+}
+
+/// RTC backup registers: a magic word, the slots, then a checksum.
+pub struct BackupRegisters {
+    rtc: pac::RTC,
+}
+
+const BACKUP_MAGIC: u32 = 0x424b_5031;
+// The register after the magic, slots and checksum.
+const BOOT_REQUEST_REGISTER: usize = RETAIN_SLOTS + 2;
+
+impl BackupRegisters {
+    /// Backup domain writes need the PWR clock and DBP set; the caller is
+    /// expected to have done that.
+    pub fn new(rtc: pac::RTC) -> Self {
+        BackupRegisters { rtc }
+    }
+
+    pub fn load(&self) -> Option<RetainImage> {
+        let mut words = [0u32; RETAIN_SLOTS + 2];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = self.rtc.bkpr(i).read().bits();
+        }
+        if words[0] != BACKUP_MAGIC
+            || checksum(&words[..RETAIN_SLOTS + 1]) != words[RETAIN_SLOTS + 1]
+        {
+            return None;
+        }
+        let mut image = [0; RETAIN_SLOTS];
+        image.copy_from_slice(&words[1..RETAIN_SLOTS + 1]);
+        Some(image)
+    }
+
+    /// Take a request left for the next boot, e.g. to do something that
+    /// can't be done with the watchdog running. Zero if there's none.
+    pub fn take_boot_request(&mut self) -> u32 {
+        let request = self.rtc.bkpr(BOOT_REQUEST_REGISTER).read().bits();
+        self.rtc
+            .bkpr(BOOT_REQUEST_REGISTER)
+            .write(|w| unsafe { w.bits(0) });
+        request
+    }
+
+    pub fn set_boot_request(&mut self, request: u32) {
+        self.rtc
+            .bkpr(BOOT_REQUEST_REGISTER)
+            .write(|w| unsafe { w.bits(request) });
+    }
+
+    pub fn save(&mut self, image: &RetainImage) {
+        let mut words = [0u32; RETAIN_SLOTS + 2];
+        words[0] = BACKUP_MAGIC;
+        words[1..RETAIN_SLOTS + 1].copy_from_slice(image);
+        words[RETAIN_SLOTS + 1] = checksum(&words[..RETAIN_SLOTS + 1]);
+        for (i, word) in words.iter().enumerate() {
+            self.rtc.bkpr(i).write(|w| unsafe { w.bits(*word) });
+        }
+    }
+}
+
+/// The retained image itself, tracking when it needs to go to flash.
+pub struct Retain {
+    image: RetainImage,
+    saved: RetainImage,
+    flush: SimpleTimer,
+    /// The image as of the last update, and a timer since it last changed.
+    seen: RetainImage,
+    settle: SimpleTimer,
+}
+
+impl Retain {
+    pub fn new(image: RetainImage, now: i64) -> Self {
+        Retain {
+            image,
+            saved: image,
+            flush: SimpleTimer::start(now, FLASH_SAVE_SECS.secs()),
+            seen: image,
+            settle: SimpleTimer::start(now, FLASH_SETTLE_SECS.secs()),
+        }
+    }
+
+    pub fn get(&self, slot: usize) -> u32 {
+        self.image[slot]
+    }
+
+    pub fn set(&mut self, slot: usize, value: u32) {
+        self.image[slot] = value;
+    }
+
+    pub fn image(&self) -> &RetainImage {
+        &self.image
+    }
+
+    pub fn changed(&self) -> bool {
+        self.image != self.saved
+    }
+
+    /// Note any changes made this scan.
+    pub fn update(&mut self, now: i64) {
+        if self.image != self.seen {
+            self.seen = self.image;
+            self.settle = SimpleTimer::start(now, FLASH_SETTLE_SECS.secs());
+        }
+    }
+
+    /// True when the image has changed and it's been a while since the last
+    /// flash write, or, when `idle`, once it has stopped changing.
+    pub fn flush_due(&self, idle: bool, now: i64) -> bool {
+        self.changed() && (self.flush.expired(now) || idle && self.settle.expired(now))
+    }
+
+    pub fn flushed(&mut self, now: i64) {
+        self.saved = self.image;
+        self.flush = SimpleTimer::start(now, FLASH_SAVE_SECS.secs());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind};
+
+    const SECTOR: usize = 256;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct MockError;
+
+    impl NorFlashError for MockError {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    // RAM-backed flash that, like the real thing, can only clear bits.
+    struct MockFlash {
+        mem: [u8; SECTOR],
+        longest_write: usize,
+    }
+
+    impl MockFlash {
+        fn new() -> Self {
+            MockFlash {
+                mem: [0xff; SECTOR],
+                longest_write: 0,
+            }
+        }
+    }
+
+    impl ErrorType for MockFlash {
+        type Error = MockError;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), MockError> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.mem[offset..offset + bytes.len()]);
+            Ok(())
+        }
+        fn capacity(&self) -> usize {
+            SECTOR
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = SECTOR;
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), MockError> {
+            self.mem[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), MockError> {
+            self.longest_write = self.longest_write.max(bytes.len());
+            for (i, b) in bytes.iter().enumerate() {
+                self.mem[offset as usize + i] &= *b;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_empty_journal() {
+        let mut flash = MockFlash::new();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), None);
+        assert!(!journal.nearly_full());
+    }
+
+    #[test]
+    fn test_newest_record_wins() {
+        let mut flash = MockFlash::new();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        journal.save(&mut flash, &[1; RETAIN_SLOTS]).unwrap();
+        journal.save(&mut flash, &[2; RETAIN_SLOTS]).unwrap();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), Some([2; RETAIN_SLOTS]));
+        journal.save(&mut flash, &[3; RETAIN_SLOTS]).unwrap();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), Some([3; RETAIN_SLOTS]));
+    }
+
+    #[test]
+    fn test_torn_record_skipped() {
+        let mut flash = MockFlash::new();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        journal.save(&mut flash, &[1; RETAIN_SLOTS]).unwrap();
+        journal.save(&mut flash, &[2; RETAIN_SLOTS]).unwrap();
+        // Corrupt the second record's payload.
+        flash.mem[RECORD_BYTES as usize + 8] = 0;
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), Some([1; RETAIN_SLOTS]));
+        // New records go after the torn one.
+        journal.save(&mut flash, &[4; RETAIN_SLOTS]).unwrap();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), Some([4; RETAIN_SLOTS]));
+    }
+
+    #[test]
+    fn test_written_in_steps() {
+        let mut flash = MockFlash::new();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        journal.save(&mut flash, &[1; RETAIN_SLOTS]).unwrap();
+        assert!(journal.begin(&[2; RETAIN_SLOTS]));
+        let mut steps = 1;
+        while !journal.step(&mut flash).unwrap() {
+            steps += 1;
+            // Until the magic word goes in last, it's the old record.
+            let mut reloaded = FlashJournal::new(0, SECTOR as u32);
+            assert_eq!(reloaded.load(&mut flash).unwrap(), Some([1; RETAIN_SLOTS]));
+        }
+        assert!(!journal.busy());
+        assert_eq!(steps, RECORD_WORDS);
+        assert_eq!(flash.longest_write, WRITE_CHUNK);
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), Some([2; RETAIN_SLOTS]));
+    }
+
+    #[test]
+    fn test_interrupted_record_skipped() {
+        let mut flash = MockFlash::new();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        journal.save(&mut flash, &[1; RETAIN_SLOTS]).unwrap();
+        assert!(journal.begin(&[2; RETAIN_SLOTS]));
+        journal.step(&mut flash).unwrap();
+        // Power lost part way through.
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), Some([1; RETAIN_SLOTS]));
+        journal.save(&mut flash, &[3; RETAIN_SLOTS]).unwrap();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), Some([3; RETAIN_SLOTS]));
+    }
+
+    #[test]
+    fn test_full_and_compact() {
+        let mut flash = MockFlash::new();
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        let capacity = SECTOR as u32 / RECORD_BYTES;
+        for i in 0..capacity {
+            journal.save(&mut flash, &[i; RETAIN_SLOTS]).unwrap();
+        }
+        assert!(journal.nearly_full());
+        assert!(journal.full());
+        assert_eq!(journal.state_name(), "Full");
+        assert_eq!(
+            journal.save(&mut flash, &[99; RETAIN_SLOTS]),
+            Err(JournalError::Full)
+        );
+        journal.compact(&mut flash, &[7; RETAIN_SLOTS]).unwrap();
+        assert!(!journal.nearly_full());
+        assert!(!journal.full());
+        assert_eq!(journal.state_name(), "Ok");
+        let mut journal = FlashJournal::new(0, SECTOR as u32);
+        assert_eq!(journal.load(&mut flash).unwrap(), Some([7; RETAIN_SLOTS]));
+    }
+
+    #[test]
+    fn test_flush_due() {
+        let mut retain = Retain::new([0; RETAIN_SLOTS], 0);
+        retain.set(0, 5);
+        retain.update(0);
+        assert!(retain.changed());
+        assert!(!retain.flush_due(false, 1000));
+        assert!(retain.flush_due(false, FLASH_SAVE_SECS as i64 * 1000));
+        retain.flushed(FLASH_SAVE_SECS as i64 * 1000);
+        assert!(!retain.changed());
+        assert_eq!(retain.get(0), 5);
+    }
+
+    #[test]
+    fn test_idle_flush_settles() {
+        const SETTLE_MS: i64 = FLASH_SETTLE_SECS as i64 * 1000;
+        let mut retain = Retain::new([0; RETAIN_SLOTS], 0);
+        // A counter ticking over every second never settles.
+        for now in (0..5 * SETTLE_MS).step_by(1000) {
+            retain.set(0, now as u32);
+            retain.update(now);
+            assert!(!retain.flush_due(true, now));
+        }
+        let stopped = 5 * SETTLE_MS - 1000;
+        retain.update(stopped + SETTLE_MS - 1);
+        assert!(!retain.flush_due(true, stopped + SETTLE_MS - 1));
+        assert!(retain.flush_due(true, stopped + SETTLE_MS));
+    }
+}
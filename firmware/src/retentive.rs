@@ -0,0 +1,163 @@
+//! Retentive on-delay timer (TONR).
+//!
+//! Unlike `SimpleTimer`, this only accumulates time while it is enabled
+//! and holds its value while paused, so it can total up run time across
+//! many start/stop cycles. The accumulated value can be saved and restored
+//! via the `retain` module so that it survives power cycles.
+use fugit::Duration;
+
+pub type LongDuration = Duration<u64, 1, 1_000>;
+
+#[derive(Default)]
+pub struct RetentiveTimer {
+    accumulated: u64,
+    running_since: Option<i64>,
+    preset: Option<LongDuration>,
+}
+
+impl RetentiveTimer {
+    #[allow(dead_code)]
+    pub const fn new(preset: LongDuration) -> Self {
+        RetentiveTimer {
+            accumulated: 0,
+            running_since: None,
+            preset: Some(preset),
+        }
+    }
+
+    /// TONR behaviour: run while enabled, hold while not.
+    pub fn update(&mut self, enable: bool, now: i64) {
+        if enable {
+            self.resume(now);
+        } else {
+            self.pause(now);
+        }
+    }
+
+    pub fn resume(&mut self, now: i64) {
+        if self.running_since.is_none() {
+            self.running_since = Some(now);
+        }
+    }
+
+    pub fn pause(&mut self, now: i64) {
+        if let Some(since) = self.running_since.take() {
+            self.accumulated += (now - since).max(0) as u64;
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running_since.is_some()
+    }
+
+    /// Clear the accumulated time. A running timer keeps running from zero.
+    #[allow(dead_code)]
+    pub fn reset(&mut self, now: i64) {
+        self.accumulated = 0;
+        if self.running_since.is_some() {
+            self.running_since = Some(now);
+        }
+    }
+
+    pub fn elapsed(&self, now: i64) -> LongDuration {
+        let running = match self.running_since {
+            Some(since) => (now - since).max(0) as u64,
+            None => 0,
+        };
+        LongDuration::from_ticks(self.accumulated + running)
+    }
+
+    /// Restore a previously saved accumulated value, e.g. after power-up.
+    pub fn restore(&mut self, elapsed: LongDuration, now: i64) {
+        self.accumulated = elapsed.ticks();
+        if self.running_since.is_some() {
+            self.running_since = Some(now);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn set_preset(&mut self, preset: Option<LongDuration>) {
+        self.preset = preset;
+    }
+
+    /// True once the accumulated time reaches the preset.
+    #[allow(dead_code)]
+    pub fn done(&self, now: i64) -> bool {
+        match self.preset {
+            Some(preset) => self.elapsed(now) >= preset,
+            None => false,
+        }
+    }
+
+    /// Time left until the preset is reached, if there is one.
+    #[allow(dead_code)]
+    pub fn remaining(&self, now: i64) -> Option<LongDuration> {
+        self.preset.map(|preset| {
+            LongDuration::from_ticks(preset.ticks().saturating_sub(self.elapsed(now).ticks()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU64;
+
+    #[test]
+    fn test_accumulates_only_while_enabled() {
+        let mut timer = RetentiveTimer::default();
+        timer.update(true, 1000);
+        timer.update(true, 1500);
+        assert_eq!(timer.elapsed(1500).ticks(), 500);
+        timer.update(false, 1500);
+        timer.update(false, 9000);
+        assert_eq!(timer.elapsed(9000).ticks(), 500);
+        timer.update(true, 10000);
+        assert_eq!(timer.elapsed(10250).ticks(), 750);
+    }
+
+    #[test]
+    fn test_preset_and_remaining() {
+        let mut timer = RetentiveTimer::new(1.secs());
+        assert_eq!(timer.remaining(0), Some(1.secs()));
+        timer.update(true, 0);
+        timer.update(false, 600);
+        assert!(!timer.done(600));
+        assert_eq!(timer.remaining(5000).unwrap().ticks(), 400);
+        timer.update(true, 7000);
+        assert!(!timer.done(7399));
+        assert!(timer.done(7400));
+        assert_eq!(timer.remaining(8000).unwrap().ticks(), 0);
+    }
+
+    #[test]
+    fn test_no_preset_never_done() {
+        let mut timer = RetentiveTimer::default();
+        timer.update(true, 0);
+        assert!(!timer.done(i64::MAX / 2));
+        assert_eq!(timer.remaining(100), None);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut timer = RetentiveTimer::default();
+        timer.update(true, 0);
+        timer.reset(300);
+        assert!(timer.is_running());
+        assert_eq!(timer.elapsed(500).ticks(), 200);
+        timer.update(false, 500);
+        timer.reset(600);
+        assert!(!timer.is_running());
+        assert_eq!(timer.elapsed(700).ticks(), 0);
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut timer = RetentiveTimer::default();
+        timer.restore(3600.secs(), 0);
+        assert_eq!(timer.elapsed(100).ticks(), 3_600_000);
+        timer.update(true, 100);
+        timer.restore(10.secs(), 200);
+        assert_eq!(timer.elapsed(300).ticks(), 10_100);
+    }
+}
@@ -0,0 +1,524 @@
+//! Boolean rule table for simple I/O mappings.
+//!
+//! Pass-throughs and interlocks that don't warrant a module of their own
+//! are written as text, one rule per line, each setting an output from an
+//! expression:
+//!
+//! ```text
+//! # Servo reset from the button, or a long press on the cabinet button.
+//! Q8 = I12 OR LONGPRESS(I8)
+//! Q3 = I1 OR (Q3 AND NOT I2)   # latched until I2
+//! ```
+//!
+//! The terms are the filtered inputs `I0`-`I15`, the outputs driven by
+//! rules `Q0`-`Q15`, the built-in status bits by name (the same names as
+//! the user logic VM's, e.g. `SPINDLE_ON`), `TRUE` and `FALSE`, and
+//! `CLICK`, `DOUBLECLICK`, `LONGPRESS` or `HOLD` of an input, which are
+//! true for the one scan the gesture is recognised in. NOT binds tightest,
+//! then AND, XOR and OR. Names are case-insensitive, and `#` starts a
+//! comment.
+//!
+//! Rules are evaluated in order every scan, so an output read before the
+//! rule that drives it has its value from the previous scan.
+//!
+//! The table is checked when it's parsed, so a typo is reported (with its
+//! line) up front rather than quietly leaving an output off. It's uploaded
+//! to flash over the console (see `program`), or failing that the built-in
+//! one is used.
+use crate::bytecode::SYSTEM_BITS;
+use crate::gesture::{ButtonGesture, Gesture};
+use crate::vm::ProcessImage;
+use heapless::Vec;
+
+pub const MAX_RULES: usize = 16;
+/// Terms and operators in one rule.
+pub const MAX_TERMS: usize = 24;
+pub const MAX_GESTURES: usize = 4;
+const IO_COUNT: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Term {
+    Input(u8),
+    Output(u8),
+    System(u8),
+    Const(bool),
+    /// A gesture on the input watched by a detector slot.
+    Gesture(u8, Gesture),
+    Not,
+    And,
+    Xor,
+    Or,
+}
+
+struct Rule {
+    line: usize,
+    output: u8,
+    /// In reverse Polish order.
+    terms: Vec<Term, MAX_TERMS>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RuleError {
+    /// 1-based, or 0 if it isn't about any one line.
+    pub line: usize,
+    pub message: &'static str,
+}
+
+pub struct Rules {
+    rules: Vec<Rule, MAX_RULES>,
+    /// One detector per input that has gestures in the table, and the
+    /// gesture it recognised this scan.
+    gestures: Vec<(u8, ButtonGesture, Option<Gesture>), MAX_GESTURES>,
+    outputs: [bool; IO_COUNT],
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            rules: Vec::new(),
+            gestures: Vec::new(),
+            outputs: [false; IO_COUNT],
+        }
+    }
+}
+
+/// A rule table's text from raw bytes, reporting the line of anything
+/// that isn't text.
+pub fn text(bytes: &[u8]) -> Result<&str, RuleError> {
+    core::str::from_utf8(bytes).map_err(|err| {
+        let valid = &bytes[..err.valid_up_to()];
+        RuleError {
+            line: valid.iter().filter(|&&b| b == b'\n').count() + 1,
+            message: "not text",
+        }
+    })
+}
+
+impl Rules {
+    /// Parse and check a rule table. Only outputs in `free_outputs` (a bit
+    /// per output) can be driven; the rest belong to something else.
+    /// Gestures are recognised with the given long press and hold times.
+    pub fn parse(
+        text: &str,
+        free_outputs: u16,
+        long_press_time: fugit::Duration<u32, 1, 1_000>,
+        hold_time: fugit::Duration<u32, 1, 1_000>,
+    ) -> Result<Rules, RuleError> {
+        let mut rules = Rules::default();
+        for (n, line) in text.lines().enumerate() {
+            let line_no = n + 1;
+            let error = |message| RuleError {
+                line: line_no,
+                message,
+            };
+            let text = line.split('#').next().unwrap_or("");
+            let mut parser = Parser {
+                tokens: Tokens { rest: text },
+                terms: Vec::new(),
+                gestures: &mut rules.gestures,
+                long_press_time,
+                hold_time,
+            };
+            let Some(first) = parser.tokens.next() else {
+                continue;
+            };
+            let output = match io_index(first, 'Q') {
+                Some(Ok(n)) => n,
+                Some(Err(message)) => return Err(error(message)),
+                None => return Err(error("expected an output, as in Q3 = ...")),
+            };
+            if free_outputs & (1 << output) == 0 {
+                return Err(error("output is already used by the firmware"));
+            }
+            if rules.rules.iter().any(|r| r.output == output) {
+                return Err(error("output is already driven by another rule"));
+            }
+            if parser.tokens.next() != Some("=") {
+                return Err(error("expected '=' after the output"));
+            }
+            parser.or().map_err(error)?;
+            if parser.tokens.next().is_some() {
+                return Err(error("unexpected text after the rule"));
+            }
+            let terms = parser.terms;
+            rules
+                .rules
+                .push(Rule {
+                    line: line_no,
+                    output,
+                    terms,
+                })
+                .map_err(|_| error("too many rules"))?;
+        }
+        // Outputs can only be read back if a rule drives them.
+        for rule in &rules.rules {
+            for term in &rule.terms {
+                if let Term::Output(n) = *term {
+                    if !rules.rules.iter().any(|r| r.output == n) {
+                        return Err(RuleError {
+                            line: rule.line,
+                            message: "output read isn't driven by any rule",
+                        });
+                    }
+                }
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn update(&mut self, image: &ProcessImage, now: i64) {
+        for (input, detector, gesture) in self.gestures.iter_mut() {
+            detector.update(image.inputs[*input as usize], now);
+            *gesture = detector.gesture();
+        }
+        for rule in &self.rules {
+            let mut stack = [false; MAX_TERMS];
+            let mut depth = 0;
+            for term in &rule.terms {
+                let value = match *term {
+                    Term::Input(n) => image.inputs[n as usize],
+                    Term::Output(n) => self.outputs[n as usize],
+                    Term::System(n) => image.system_bits[n as usize],
+                    Term::Const(value) => value,
+                    Term::Gesture(slot, gesture) => self.gestures[slot as usize].2 == Some(gesture),
+                    Term::Not => !stack[depth - 1],
+                    Term::And | Term::Xor | Term::Or => {
+                        depth -= 1;
+                        let (a, b) = (stack[depth - 1], stack[depth]);
+                        match term {
+                            Term::And => a && b,
+                            Term::Xor => a != b,
+                            _ => a || b,
+                        }
+                    }
+                };
+                if !matches!(term, Term::Not | Term::And | Term::Xor | Term::Or) {
+                    depth += 1;
+                }
+                stack[depth - 1] = value;
+            }
+            self.outputs[rule.output as usize] = stack[0];
+        }
+    }
+
+    /// The output's state, if a rule drives it.
+    pub fn output(&self, n: usize) -> Option<bool> {
+        self.rules
+            .iter()
+            .any(|rule| rule.output as usize == n)
+            .then(|| self.outputs[n])
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+/// `I3` or `Q12` style names: None if it isn't one at all, or an error if
+/// it's out of range.
+fn io_index(word: &str, area: char) -> Option<Result<u8, &'static str>> {
+    let mut chars = word.chars();
+    if !chars.next()?.eq_ignore_ascii_case(&area) {
+        return None;
+    }
+    let number = chars.as_str();
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(match number.parse::<usize>() {
+        Ok(n) if n < IO_COUNT => Ok(n as u8),
+        _ => Err(if area == 'I' {
+            "no such input; they're I0 to I15"
+        } else {
+            "no such output; they're Q0 to Q15"
+        }),
+    })
+}
+
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        let len = match rest.bytes().next()? {
+            b'(' | b')' | b'=' => 1,
+            _ => rest
+                .find(|c: char| c.is_whitespace() || "()=".contains(c))
+                .unwrap_or(rest.len()),
+        };
+        Some(&rest[..len])
+    }
+
+    fn next(&mut self) -> Option<&'a str> {
+        let token = self.peek()?;
+        let rest = self.rest.trim_start();
+        self.rest = &rest[token.len()..];
+        Some(token)
+    }
+
+    fn eat(&mut self, keyword: &str) -> bool {
+        if self.peek().is_some_and(|t| t.eq_ignore_ascii_case(keyword)) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct Parser<'a, 'g> {
+    tokens: Tokens<'a>,
+    terms: Vec<Term, MAX_TERMS>,
+    gestures: &'g mut Vec<(u8, ButtonGesture, Option<Gesture>), MAX_GESTURES>,
+    long_press_time: fugit::Duration<u32, 1, 1_000>,
+    hold_time: fugit::Duration<u32, 1, 1_000>,
+}
+
+type ParseResult = Result<(), &'static str>;
+
+impl Parser<'_, '_> {
+    fn emit(&mut self, term: Term) -> ParseResult {
+        self.terms.push(term).map_err(|_| "rule is too long")
+    }
+
+    fn binary(
+        &mut self,
+        keyword: &str,
+        op: Term,
+        operand: fn(&mut Self) -> ParseResult,
+    ) -> ParseResult {
+        operand(self)?;
+        while self.tokens.eat(keyword) {
+            operand(self)?;
+            self.emit(op)?;
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> ParseResult {
+        self.binary("OR", Term::Or, Self::xor)
+    }
+
+    fn xor(&mut self) -> ParseResult {
+        self.binary("XOR", Term::Xor, Self::and)
+    }
+
+    fn and(&mut self) -> ParseResult {
+        self.binary("AND", Term::And, Self::not)
+    }
+
+    fn not(&mut self) -> ParseResult {
+        if self.tokens.eat("NOT") {
+            self.not()?;
+            return self.emit(Term::Not);
+        }
+        self.term()
+    }
+
+    fn term(&mut self) -> ParseResult {
+        let word = self.tokens.next().ok_or("expected a term")?;
+        if word == "(" {
+            self.or()?;
+            if !self.tokens.eat(")") {
+                return Err("expected ')'");
+            }
+            return Ok(());
+        }
+        let term = if let Some(n) = io_index(word, 'I') {
+            Term::Input(n?)
+        } else if let Some(n) = io_index(word, 'Q') {
+            Term::Output(n?)
+        } else if word.eq_ignore_ascii_case("TRUE") {
+            Term::Const(true)
+        } else if word.eq_ignore_ascii_case("FALSE") {
+            Term::Const(false)
+        } else if let Some(n) = SYSTEM_BITS
+            .iter()
+            .position(|s| s.eq_ignore_ascii_case(word))
+        {
+            Term::System(n as u8)
+        } else if let Some(gesture) = gesture(word) {
+            self.gesture(gesture)?
+        } else {
+            return Err("unknown name");
+        };
+        self.emit(term)
+    }
+
+    /// `LONGPRESS(I8)` and the like, sharing a detector per input.
+    fn gesture(&mut self, gesture: Gesture) -> Result<Term, &'static str> {
+        let input = match (self.tokens.next(), self.tokens.next(), self.tokens.next()) {
+            (Some("("), Some(word), Some(")")) => match io_index(word, 'I') {
+                Some(n) => n?,
+                None => return Err("gestures are of an input, as in LONGPRESS(I8)"),
+            },
+            _ => return Err("gestures are of an input, as in LONGPRESS(I8)"),
+        };
+        let slot = match self.gestures.iter().position(|g| g.0 == input) {
+            Some(slot) => slot,
+            None => {
+                let detector = ButtonGesture::new(self.long_press_time, self.hold_time);
+                self.gestures
+                    .push((input, detector, None))
+                    .map_err(|_| "gestures on too many inputs")?;
+                self.gestures.len() - 1
+            }
+        };
+        Ok(Term::Gesture(slot as u8, gesture))
+    }
+}
+
+fn gesture(word: &str) -> Option<Gesture> {
+    [
+        ("CLICK", Gesture::Click),
+        ("DOUBLECLICK", Gesture::DoubleClick),
+        ("LONGPRESS", Gesture::LongPress),
+        ("HOLD", Gesture::Hold),
+    ]
+    .into_iter()
+    .find(|(name, _)| name.eq_ignore_ascii_case(word))
+    .map(|(_, gesture)| gesture)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU32;
+
+    const ALL_FREE: u16 = 0xffff;
+
+    fn parse(text: &str) -> Result<Rules, RuleError> {
+        Rules::parse(text, ALL_FREE, 2000.millis(), 5000.millis())
+    }
+
+    fn image(inputs: &[usize], system_bits: &[usize]) -> ProcessImage {
+        let mut image = ProcessImage::default();
+        for &n in inputs {
+            image.inputs[n] = true;
+        }
+        for &n in system_bits {
+            image.system_bits[n] = true;
+        }
+        image
+    }
+
+    #[test]
+    fn test_logic() {
+        let mut rules = parse(
+            "q0 = I1 AND NOT I2 OR I3   # comment
+             Q1 = NOT (I1 OR I2)
+             Q2 = I1 XOR I2 AND I3
+             Q3 = spindle_on AND TRUE",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 4);
+        let mut check = |inputs: &[usize], expected: [bool; 4]| {
+            rules.update(&image(inputs, &[0]), 0);
+            let outputs = [0, 1, 2, 3].map(|n| rules.output(n).unwrap());
+            assert_eq!(outputs, expected, "inputs {:?}", inputs);
+        };
+        check(&[], [false, true, false, true]);
+        check(&[1], [true, false, true, true]);
+        check(&[1, 2], [false, false, true, true]);
+        check(&[1, 2, 3], [true, false, false, true]);
+        assert_eq!(rules.output(4), None);
+    }
+
+    #[test]
+    fn test_latch() {
+        let mut rules = parse("Q3 = I1 OR (Q3 AND NOT I2)").unwrap();
+        rules.update(&image(&[], &[]), 0);
+        assert_eq!(rules.output(3), Some(false));
+        rules.update(&image(&[1], &[]), 1);
+        rules.update(&image(&[], &[]), 2);
+        assert_eq!(rules.output(3), Some(true));
+        rules.update(&image(&[2], &[]), 3);
+        assert_eq!(rules.output(3), Some(false));
+    }
+
+    #[test]
+    fn test_gestures() {
+        let mut rules = parse("Q8 = I12 OR LONGPRESS(I8)\nQ9 = CLICK(i8)").unwrap();
+        assert_eq!(rules.gestures.len(), 1);
+        let mut long_presses = 0;
+        let mut clicks = 0;
+        for now in 0..4000 {
+            let inputs: &[usize] = if now < 2500 { &[8] } else { &[] };
+            rules.update(&image(inputs, &[]), now);
+            long_presses += rules.output(8).unwrap() as u32;
+            clicks += rules.output(9).unwrap() as u32;
+        }
+        assert_eq!((long_presses, clicks), (1, 0));
+        rules.update(&image(&[12], &[]), 4000);
+        assert_eq!(rules.output(8), Some(true));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| parse(text).err().map(|e| (e.line, e.message));
+        assert_eq!(error("\n\n# nothing\n"), None);
+        assert_eq!(
+            error("Q1 = I1\nQ1 = I2"),
+            Some((2, "output is already driven by another rule"))
+        );
+        assert_eq!(
+            error("I1 = Q1"),
+            Some((1, "expected an output, as in Q3 = ..."))
+        );
+        assert_eq!(
+            error("Q16 = I1"),
+            Some((1, "no such output; they're Q0 to Q15"))
+        );
+        assert_eq!(
+            error("Q1 = I16"),
+            Some((1, "no such input; they're I0 to I15"))
+        );
+        assert_eq!(error("Q1 I1"), Some((1, "expected '=' after the output")));
+        assert_eq!(
+            error("Q1 = I1 ANDNOT I2"),
+            Some((1, "unexpected text after the rule"))
+        );
+        assert_eq!(error("Q1 = SPINDLE_RUN"), Some((1, "unknown name")));
+        assert_eq!(error("Q1 = (I1 OR I2"), Some((1, "expected ')'")));
+        assert_eq!(error("Q1 = I1 AND"), Some((1, "expected a term")));
+        assert_eq!(
+            error("Q1 = LONGPRESS(Q2)"),
+            Some((1, "gestures are of an input, as in LONGPRESS(I8)"))
+        );
+        assert_eq!(
+            error("Q1 = Q2 AND I1"),
+            Some((1, "output read isn't driven by any rule"))
+        );
+        let long = ["I1"; MAX_TERMS].join(" OR ");
+        assert_eq!(
+            error(&format!("Q1 = {}", long)),
+            Some((1, "rule is too long"))
+        );
+        assert_eq!(
+            Rules::parse("Q8 = I1", !(1 << 8), 2000.millis(), 5000.millis()).err(),
+            Some(RuleError {
+                line: 1,
+                message: "output is already used by the firmware"
+            })
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(text(b"Q1 = I1\n"), Ok("Q1 = I1\n"));
+        assert_eq!(
+            text(b"Q1 = I1\nQ2 = \xc0I2\n"),
+            Err(RuleError {
+                line: 2,
+                message: "not text"
+            })
+        );
+    }
+}
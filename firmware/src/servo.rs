@@ -0,0 +1,213 @@
+//! Spindle servo monitoring and mode selection.
+//!
+//! Watches the servo's no-fault output and its status outputs (ready,
+//! zero-speed, at-speed) and uses them to inhibit the spindle whenever the
+//! servo isn't ready to run. Also drives the servo's digital inputs used
+//! for mode/gear selection, which the servo only honours at standstill, so
+//! a newly requested gear is held back until the spindle is stopped.
+use crate::simpletimer::SimpleTimer;
+use fugit::ExtU32;
+
+#[derive(Default)]
+pub enum ServoFSMState {
+    #[default]
+    NotReady,
+    Ready,
+    FaultPending(SimpleTimer),
+    Fault,
+}
+
+#[derive(Default)]
+pub struct ServoControl {
+    state: ServoFSMState,
+    zero_speed: bool,
+    at_speed: bool,
+    gear: u8,
+    requested_gear: u8,
+}
+
+// Ignore very brief glitches on the fault line.
+const FAULT_DEBOUNCE_MS: u32 = 5;
+
+pub const SERVO_GEARS: u8 = 4;
+
+impl ServoControl {
+    pub fn update(
+        &mut self,
+        no_fault: bool,
+        ready: bool,
+        zero_speed: bool,
+        at_speed: bool,
+        now: i64,
+    ) {
+        self.zero_speed = zero_speed;
+        self.at_speed = at_speed;
+        match &self.state {
+            ServoFSMState::NotReady => {
+                if !no_fault {
+                    self.state = ServoFSMState::FaultPending(SimpleTimer::start(
+                        now,
+                        FAULT_DEBOUNCE_MS.millis(),
+                    ));
+                } else if ready {
+                    self.state = ServoFSMState::Ready;
+                }
+            }
+            ServoFSMState::Ready => {
+                if !no_fault {
+                    self.state = ServoFSMState::FaultPending(SimpleTimer::start(
+                        now,
+                        FAULT_DEBOUNCE_MS.millis(),
+                    ));
+                } else if !ready {
+                    self.state = ServoFSMState::NotReady;
+                }
+            }
+            ServoFSMState::FaultPending(timer) => {
+                if no_fault {
+                    self.state = ServoFSMState::NotReady;
+                } else if timer.expired(now) {
+                    self.state = ServoFSMState::Fault;
+                }
+            }
+            ServoFSMState::Fault => {
+                // The servo latches its own faults and needs a reset to
+                // clear them, so just follow it.
+                if no_fault {
+                    self.state = ServoFSMState::NotReady;
+                }
+            }
+        }
+        // Gear changes only happen with the spindle stopped.
+        if self.requested_gear != self.gear && (zero_speed || !self.ready()) {
+            self.gear = self.requested_gear;
+        }
+    }
+
+    pub fn select_gear(&mut self, gear: u8) {
+        self.requested_gear = gear % SERVO_GEARS;
+    }
+
+    /// Servo digital input states for the selected gear.
+    pub fn gear_outputs(&self) -> [bool; 2] {
+        [self.gear & 1 != 0, self.gear & 2 != 0]
+    }
+
+    #[allow(dead_code)]
+    pub fn gear(&self) -> u8 {
+        self.gear
+    }
+
+    pub fn ready(&self) -> bool {
+        matches!(self.state, ServoFSMState::Ready)
+    }
+
+    #[allow(dead_code)]
+    pub fn fault(&self) -> bool {
+        matches!(self.state, ServoFSMState::Fault)
+    }
+
+    pub fn zero_speed(&self) -> bool {
+        self.zero_speed
+    }
+
+    #[allow(dead_code)]
+    pub fn at_speed(&self) -> bool {
+        self.at_speed
+    }
+
+    pub fn spindle_inhibit(&self) -> bool {
+        match self.state {
+            ServoFSMState::NotReady => true,
+            ServoFSMState::Ready => false,
+            // Still pending; the glitch may be real.
+            ServoFSMState::FaultPending(_) => true,
+            ServoFSMState::Fault => true,
+        }
+    }
+
+    pub fn status_char(&self) -> char {
+        match self.state {
+            ServoFSMState::NotReady => 'N',
+            ServoFSMState::Ready => 'R',
+            ServoFSMState::FaultPending(_) => 'P',
+            ServoFSMState::Fault => 'F',
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            ServoFSMState::NotReady => "NotReady",
+            ServoFSMState::Ready => "Ready",
+            ServoFSMState::FaultPending(_) => "FaultPending",
+            ServoFSMState::Fault => "Fault",
+        }
+    }
+
+    /// Time left before the current state times out, if it has a timer.
+    pub fn remaining(&self, now: i64) -> Option<fugit::Duration<u32, 1, 1_000>> {
+        match &self.state {
+            ServoFSMState::NotReady => None,
+            ServoFSMState::Ready => None,
+            ServoFSMState::FaultPending(timer) => Some(timer.remaining(now)),
+            ServoFSMState::Fault => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_allows_spindle() {
+        let mut servo = ServoControl::default();
+        assert!(servo.spindle_inhibit());
+        servo.update(true, true, true, false, 0);
+        assert!(servo.ready());
+        assert!(!servo.spindle_inhibit());
+        servo.update(true, false, true, false, 10);
+        assert_eq!(servo.state_name(), "NotReady");
+        assert!(servo.spindle_inhibit());
+    }
+
+    #[test]
+    fn test_fault_glitch_ignored() {
+        let mut servo = ServoControl::default();
+        servo.update(true, true, true, false, 0);
+        servo.update(false, true, true, false, 10);
+        assert!(servo.spindle_inhibit());
+        assert_eq!(servo.remaining(11).unwrap().ticks(), FAULT_DEBOUNCE_MS - 1);
+        servo.update(true, true, true, false, 12);
+        assert!(!servo.fault());
+        servo.update(true, true, true, false, 13);
+        assert!(servo.ready());
+    }
+
+    #[test]
+    fn test_fault_follows_servo() {
+        let mut servo = ServoControl::default();
+        servo.update(true, true, true, false, 0);
+        servo.update(false, true, true, false, 10);
+        servo.update(false, true, true, false, 10 + FAULT_DEBOUNCE_MS as i64);
+        assert!(servo.fault());
+        assert_eq!(servo.status_char(), 'F');
+        servo.update(false, true, true, false, 1000);
+        assert!(servo.fault());
+        servo.update(true, true, true, false, 2000);
+        servo.update(true, true, true, false, 2001);
+        assert!(servo.ready());
+    }
+
+    #[test]
+    fn test_gear_change_waits_for_zero_speed() {
+        let mut servo = ServoControl::default();
+        servo.update(true, true, false, true, 0);
+        servo.select_gear(3);
+        servo.update(true, true, false, true, 10);
+        assert_eq!(servo.gear_outputs(), [false, false]);
+        servo.update(true, true, true, false, 20);
+        assert_eq!(servo.gear(), 3);
+        assert_eq!(servo.gear_outputs(), [true, true]);
+    }
+}
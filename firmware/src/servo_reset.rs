@@ -18,7 +18,7 @@ use fugit::ExtU32;
 use crate::simpletimer::SimpleTimer;
 
 #[derive(Default)]
-enum ServoResetFSMState {
+pub enum ServoResetFSMState {
     #[default]
     Off,
     On,
@@ -72,4 +72,46 @@ impl ServoResetControl {
             ServoResetFSMState::HoldOn(_) => 'Y',
         }
     }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            ServoResetFSMState::Off => "Off",
+            ServoResetFSMState::On => "On",
+            ServoResetFSMState::HoldOn(_) => "HoldOn",
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> &ServoResetFSMState {
+        &self.state
+    }
+
+    /// Time left before the current state times out, if it has a timer.
+    pub fn remaining(&self, now: i64) -> Option<fugit::Duration<u32, 1, 1_000>> {
+        match &self.state {
+            ServoResetFSMState::Off => None,
+            ServoResetFSMState::On => None,
+            ServoResetFSMState::HoldOn(timer) => Some(timer.remaining(now)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_holds_on_after_release() {
+        let mut reset = ServoResetControl::default();
+        reset.update(true, 0);
+        assert!(reset.reset_state());
+        assert_eq!(reset.remaining(0), None);
+        assert!(matches!(reset.state(), ServoResetFSMState::On));
+        reset.update(false, 100);
+        assert!(matches!(reset.state(), ServoResetFSMState::HoldOn(_)));
+        assert_eq!(reset.remaining(120).unwrap().ticks(), 30);
+        reset.update(false, 100 + RESET_HOLDON_MS as i64);
+        assert!(matches!(reset.state(), ServoResetFSMState::Off));
+        assert!(!reset.reset_state());
+    }
 }
@@ -2,16 +2,43 @@
 use fugit::Duration;
 
 pub struct SimpleTimer {
+    start: i64,
     expiry: i64,
 }
 
 impl SimpleTimer {
     pub const fn start(now: i64, duration: Duration<u32, 1, 1_000>) -> SimpleTimer {
-        SimpleTimer { expiry: now + duration.ticks() as i64 }
+        SimpleTimer {
+            start: now,
+            expiry: now + duration.ticks() as i64,
+        }
     }
     pub fn expired(&self, now: i64) -> bool {
         self.expiry <= now
     }
+    /// Time left until expiry; zero once expired.
+    pub fn remaining(&self, now: i64) -> Duration<u32, 1, 1_000> {
+        let remaining = (self.expiry - now).clamp(0, u32::MAX as i64);
+        Duration::<u32, 1, 1_000>::from_ticks(remaining as u32)
+    }
+    /// Time since the timer was started, capped at its duration.
+    pub fn elapsed(&self, now: i64) -> Duration<u32, 1, 1_000> {
+        let elapsed = (now.min(self.expiry) - self.start).max(0);
+        Duration::<u32, 1, 1_000>::from_ticks(elapsed as u32)
+    }
+    #[allow(dead_code)]
+    pub fn duration(&self) -> Duration<u32, 1, 1_000> {
+        Duration::<u32, 1, 1_000>::from_ticks((self.expiry - self.start) as u32)
+    }
+    /// Fraction of the duration elapsed, from 0.0 to 1.0.
+    #[allow(dead_code)]
+    pub fn progress(&self, now: i64) -> f32 {
+        let duration = self.duration().ticks();
+        if duration == 0 {
+            return 1.0;
+        }
+        self.elapsed(now).ticks() as f32 / duration as f32
+    }
 }
 
 #[cfg(test)]
@@ -67,6 +94,41 @@ mod tests {
         assert!(!timer.expired(now));
         assert!(timer.expired(now + 1));
     }
-}
 
+    #[test]
+    fn test_remaining() {
+        let now = 1000;
+        let timer = SimpleTimer::start(now, 100.millis());
+        assert_eq!(timer.remaining(now).ticks(), 100);
+        assert_eq!(timer.remaining(now + 40).ticks(), 60);
+        assert_eq!(timer.remaining(now + 100).ticks(), 0);
+        assert_eq!(timer.remaining(now + 500).ticks(), 0);
+    }
 
+    #[test]
+    fn test_elapsed() {
+        let now = 1000;
+        let timer = SimpleTimer::start(now, 100.millis());
+        assert_eq!(timer.elapsed(now).ticks(), 0);
+        assert_eq!(timer.elapsed(now + 40).ticks(), 40);
+        assert_eq!(timer.elapsed(now + 500).ticks(), 100);
+        assert_eq!(timer.duration().ticks(), 100);
+    }
+
+    #[test]
+    fn test_progress() {
+        let now = 1000;
+        let timer = SimpleTimer::start(now, 200.millis());
+        assert_eq!(timer.progress(now), 0.0);
+        assert_eq!(timer.progress(now + 50), 0.25);
+        assert_eq!(timer.progress(now + 200), 1.0);
+        assert_eq!(timer.progress(now + 1000), 1.0);
+    }
+
+    #[test]
+    fn test_zero_duration_progress() {
+        let timer = SimpleTimer::start(1000, 0.millis());
+        assert_eq!(timer.progress(1000), 1.0);
+        assert_eq!(timer.remaining(1000).ticks(), 0);
+    }
+}
@@ -1,32 +1,64 @@
 //! Spindle controller.
 //!
 //! This sequences spindle startup with brake disengagement/reengagement.
-//! In the future this will also be used to sequence active spindle braking,
-//! which for some reason doesn't work via the signals I'm currently using on
-//! my servo.
+//!
+//! Optionally, it also sequences active spindle braking: when the spindle
+//! is stopped, the servo is commanded to brake to zero speed and we wait
+//! for its zero-speed output before engaging the mechanical brake. If the
+//! servo doesn't report zero speed in time then the mechanical brake is
+//! engaged anyway and the controller latches a fault until reset. Zero
+//! speed has to hold for a little while, so a glitch on the input doesn't
+//! drop the brake onto a spindle that's still turning.
 use crate::simpletimer::SimpleTimer;
 use fugit::ExtU32;
 
 #[derive(Default)]
-enum SpindleFSMState {
+pub enum SpindleFSMState {
     #[default]
     Off,
     WaitBrakeOff(SimpleTimer),
     Running,
+    /// The braking timeout, and how long zero speed has held for.
+    Braking(SimpleTimer, Option<SimpleTimer>),
     WaitBrakeOn(SimpleTimer),
+    Fault,
 }
 
 #[derive(Default)]
 pub struct SpindleControl {
     state: SpindleFSMState,
+    active_braking_timeout: Option<fugit::Duration<u32, 1, 1_000>>,
 }
 
 const BRAKE_OFF_MS: u32 = 50;
 const BRAKE_ON_MS: u32 = 1000;
+const ZERO_SPEED_HOLD_MS: u32 = 50;
 
 impl SpindleControl {
-    pub fn update(&mut self, spindle_on: bool, spindle_inhibit: bool, now: i64) {
-        match &self.state {
+    /// Use active braking, waiting at most `timeout` for zero speed.
+    pub fn with_active_braking(timeout: fugit::Duration<u32, 1, 1_000>) -> Self {
+        SpindleControl {
+            state: SpindleFSMState::Off,
+            active_braking_timeout: Some(timeout),
+        }
+    }
+
+    fn stop(&self, now: i64) -> SpindleFSMState {
+        match self.active_braking_timeout {
+            Some(timeout) => SpindleFSMState::Braking(SimpleTimer::start(now, timeout), None),
+            None => SpindleFSMState::WaitBrakeOn(SimpleTimer::start(now, BRAKE_ON_MS.millis())),
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        spindle_on: bool,
+        spindle_inhibit: bool,
+        zero_speed: bool,
+        fault_reset: bool,
+        now: i64,
+    ) {
+        match &mut self.state {
             SpindleFSMState::Off => {
                 if spindle_on && !spindle_inhibit {
                     self.state = SpindleFSMState::WaitBrakeOff(SimpleTimer::start(
@@ -44,8 +76,24 @@ impl SpindleControl {
             }
             SpindleFSMState::Running => {
                 if !spindle_on || spindle_inhibit {
-                    self.state =
-                        SpindleFSMState::WaitBrakeOn(SimpleTimer::start(now, BRAKE_ON_MS.millis()))
+                    self.state = self.stop(now);
+                }
+            }
+            SpindleFSMState::Braking(timer, stopped) => {
+                if spindle_on && !spindle_inhibit {
+                    self.state = SpindleFSMState::Running;
+                } else if !zero_speed {
+                    if timer.expired(now) {
+                        self.state = SpindleFSMState::Fault;
+                    } else {
+                        *stopped = None;
+                    }
+                } else if let Some(hold) = stopped {
+                    if hold.expired(now) {
+                        self.state = SpindleFSMState::Off;
+                    }
+                } else {
+                    *stopped = Some(SimpleTimer::start(now, ZERO_SPEED_HOLD_MS.millis()));
                 }
             }
             SpindleFSMState::WaitBrakeOn(timer) => {
@@ -55,6 +103,12 @@ impl SpindleControl {
                     self.state = SpindleFSMState::Off;
                 }
             }
+            SpindleFSMState::Fault => {
+                // Don't let a reset restart a spindle that's still requested.
+                if fault_reset && !spindle_on {
+                    self.state = SpindleFSMState::Off;
+                }
+            }
         }
     }
 
@@ -63,7 +117,9 @@ impl SpindleControl {
             SpindleFSMState::Off => false,
             SpindleFSMState::WaitBrakeOff(_) => false,
             SpindleFSMState::Running => true,
+            SpindleFSMState::Braking(..) => false,
             SpindleFSMState::WaitBrakeOn(_) => false,
+            SpindleFSMState::Fault => false,
         }
     }
 
@@ -72,17 +128,244 @@ impl SpindleControl {
             SpindleFSMState::Off => true,
             SpindleFSMState::WaitBrakeOff(_) => false,
             SpindleFSMState::Running => false,
+            SpindleFSMState::Braking(..) => false,
             SpindleFSMState::WaitBrakeOn(_) => false,
+            SpindleFSMState::Fault => true,
         }
     }
 
+    /// Command the servo to brake to zero speed.
+    pub fn servo_brake(&self) -> bool {
+        match self.state {
+            SpindleFSMState::Off => false,
+            SpindleFSMState::WaitBrakeOff(_) => false,
+            SpindleFSMState::Running => false,
+            SpindleFSMState::Braking(..) => true,
+            SpindleFSMState::WaitBrakeOn(_) => false,
+            SpindleFSMState::Fault => false,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn fault(&self) -> bool {
+        matches!(self.state, SpindleFSMState::Fault)
+    }
+
     #[allow(dead_code)]
     pub fn status_char(&self) -> char {
         match self.state {
             SpindleFSMState::Off => 'O',
             SpindleFSMState::WaitBrakeOff(_) => 'D',
             SpindleFSMState::Running => 'R',
+            SpindleFSMState::Braking(..) => 'Z',
             SpindleFSMState::WaitBrakeOn(_) => 'B',
+            SpindleFSMState::Fault => 'X',
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            SpindleFSMState::Off => "Off",
+            SpindleFSMState::WaitBrakeOff(_) => "WaitBrakeOff",
+            SpindleFSMState::Running => "Running",
+            SpindleFSMState::Braking(..) => "Braking",
+            SpindleFSMState::WaitBrakeOn(_) => "WaitBrakeOn",
+            SpindleFSMState::Fault => "Fault",
         }
     }
+
+    pub fn state(&self) -> &SpindleFSMState {
+        &self.state
+    }
+
+    /// Time left before the current state times out, if it has a timer.
+    pub fn remaining(&self, now: i64) -> Option<fugit::Duration<u32, 1, 1_000>> {
+        match &self.state {
+            SpindleFSMState::Off => None,
+            SpindleFSMState::WaitBrakeOff(timer) => Some(timer.remaining(now)),
+            SpindleFSMState::Running => None,
+            SpindleFSMState::Braking(timer, _) => Some(timer.remaining(now)),
+            SpindleFSMState::WaitBrakeOn(timer) => Some(timer.remaining(now)),
+            SpindleFSMState::Fault => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRAKING_TIMEOUT_MS: u32 = 2000;
+
+    fn running(active: bool) -> SpindleControl {
+        let mut spindle = if active {
+            SpindleControl::with_active_braking(BRAKING_TIMEOUT_MS.millis())
+        } else {
+            SpindleControl::default()
+        };
+        spindle.update(true, false, true, false, 0);
+        spindle.update(true, false, true, false, BRAKE_OFF_MS as i64);
+        assert_eq!(spindle.state_name(), "Running");
+        spindle
+    }
+
+    fn outputs(spindle: &SpindleControl) -> (bool, bool, bool) {
+        (
+            spindle.spindle_on(),
+            spindle.brake_on(),
+            spindle.servo_brake(),
+        )
+    }
+
+    #[test]
+    fn test_startup_sequence() {
+        let mut spindle = SpindleControl::default();
+        assert_eq!(outputs(&spindle), (false, true, false));
+        spindle.update(true, false, true, false, 0);
+        assert_eq!(spindle.state_name(), "WaitBrakeOff");
+        assert_eq!(outputs(&spindle), (false, false, false));
+        spindle.update(true, false, true, false, BRAKE_OFF_MS as i64 - 1);
+        assert!(matches!(spindle.state(), SpindleFSMState::WaitBrakeOff(_)));
+        assert_eq!(
+            spindle.remaining(BRAKE_OFF_MS as i64 - 1).unwrap().ticks(),
+            1
+        );
+        spindle.update(true, false, true, false, BRAKE_OFF_MS as i64);
+        assert!(matches!(spindle.state(), SpindleFSMState::Running));
+        assert_eq!(spindle.remaining(BRAKE_OFF_MS as i64), None);
+        assert_eq!(outputs(&spindle), (true, false, false));
+    }
+
+    #[test]
+    fn test_startup_aborted() {
+        let mut spindle = SpindleControl::default();
+        spindle.update(true, false, true, false, 0);
+        spindle.update(false, false, true, false, 10);
+        assert_eq!(spindle.state_name(), "Off");
+        spindle.update(true, false, true, false, 20);
+        spindle.update(true, true, true, false, 30);
+        assert_eq!(spindle.state_name(), "Off");
+    }
+
+    #[test]
+    fn test_inhibited_start() {
+        let mut spindle = SpindleControl::default();
+        spindle.update(true, true, true, false, 0);
+        assert_eq!(spindle.state_name(), "Off");
+    }
+
+    #[test]
+    fn test_passive_stop() {
+        let mut spindle = running(false);
+        spindle.update(false, false, false, false, 100);
+        assert_eq!(spindle.state_name(), "WaitBrakeOn");
+        assert_eq!(outputs(&spindle), (false, false, false));
+        spindle.update(false, false, false, false, 100 + BRAKE_ON_MS as i64 - 1);
+        assert_eq!(spindle.state_name(), "WaitBrakeOn");
+        spindle.update(false, false, false, false, 100 + BRAKE_ON_MS as i64);
+        assert_eq!(spindle.state_name(), "Off");
+        assert_eq!(outputs(&spindle), (false, true, false));
+    }
+
+    #[test]
+    fn test_passive_restart_while_stopping() {
+        let mut spindle = running(false);
+        spindle.update(false, false, false, false, 100);
+        spindle.update(true, false, false, false, 200);
+        assert_eq!(spindle.state_name(), "Running");
+    }
+
+    #[test]
+    fn test_active_braking_reaches_zero_speed() {
+        let mut spindle = running(true);
+        spindle.update(false, false, false, false, 100);
+        assert_eq!(spindle.state_name(), "Braking");
+        assert_eq!(outputs(&spindle), (false, false, true));
+        spindle.update(false, false, false, false, 500);
+        assert_eq!(spindle.state_name(), "Braking");
+        spindle.update(false, false, true, false, 600);
+        assert_eq!(spindle.state_name(), "Braking");
+        spindle.update(false, false, true, false, 600 + ZERO_SPEED_HOLD_MS as i64);
+        assert_eq!(spindle.state_name(), "Off");
+        assert_eq!(outputs(&spindle), (false, true, false));
+    }
+
+    #[test]
+    fn test_zero_speed_glitch_ignored() {
+        let mut spindle = running(true);
+        spindle.update(false, false, false, false, 100);
+        // One scan of zero speed while the spindle's still turning.
+        spindle.update(false, false, true, false, 200);
+        spindle.update(false, false, false, false, 201);
+        spindle.update(false, false, false, false, 200 + ZERO_SPEED_HOLD_MS as i64);
+        assert_eq!(spindle.state_name(), "Braking");
+        assert!(!spindle.brake_on());
+        // The hold starts again from the next time it's seen.
+        spindle.update(false, false, true, false, 300);
+        spindle.update(
+            false,
+            false,
+            true,
+            false,
+            300 + ZERO_SPEED_HOLD_MS as i64 - 1,
+        );
+        assert_eq!(spindle.state_name(), "Braking");
+        spindle.update(false, false, true, false, 300 + ZERO_SPEED_HOLD_MS as i64);
+        assert_eq!(spindle.state_name(), "Off");
+    }
+
+    #[test]
+    fn test_active_braking_on_inhibit() {
+        let mut spindle = running(true);
+        spindle.update(true, true, false, false, 100);
+        assert_eq!(spindle.state_name(), "Braking");
+        // Still inhibited, so stays braking.
+        spindle.update(true, true, false, false, 200);
+        assert_eq!(spindle.state_name(), "Braking");
+    }
+
+    #[test]
+    fn test_active_braking_restart() {
+        let mut spindle = running(true);
+        spindle.update(false, false, false, false, 100);
+        spindle.update(true, false, false, false, 200);
+        assert_eq!(spindle.state_name(), "Running");
+        assert_eq!(outputs(&spindle), (true, false, false));
+    }
+
+    #[test]
+    fn test_active_braking_timeout_faults() {
+        let mut spindle = running(true);
+        spindle.update(false, false, false, false, 100);
+        assert_eq!(spindle.remaining(100).unwrap().ticks(), BRAKING_TIMEOUT_MS);
+        spindle.update(
+            false,
+            false,
+            false,
+            false,
+            100 + BRAKING_TIMEOUT_MS as i64 - 1,
+        );
+        assert_eq!(spindle.state_name(), "Braking");
+        spindle.update(false, false, false, false, 100 + BRAKING_TIMEOUT_MS as i64);
+        assert_eq!(spindle.state_name(), "Fault");
+        assert!(spindle.fault());
+        assert_eq!(outputs(&spindle), (false, true, false));
+    }
+
+    #[test]
+    fn test_fault_latches_until_reset() {
+        let mut spindle = running(true);
+        spindle.update(false, false, false, false, 0);
+        spindle.update(false, false, false, false, BRAKING_TIMEOUT_MS as i64);
+        assert!(spindle.fault());
+        // Zero speed arriving late doesn't clear it, nor does a run request.
+        spindle.update(false, false, true, false, 5000);
+        spindle.update(true, false, true, false, 5100);
+        assert!(spindle.fault());
+        // Nor does a reset while the spindle is still requested.
+        spindle.update(true, false, true, true, 5200);
+        assert!(spindle.fault());
+        spindle.update(false, false, true, true, 5300);
+        assert_eq!(spindle.state_name(), "Off");
+    }
 }
@@ -0,0 +1,129 @@
+//! Step pulse generation on the high-speed outputs.
+//!
+//! TIM2 free-runs at 1MHz and its output compare channels toggle the step
+//! outputs directly, so pulse timing doesn't depend on interrupt latency.
+//! Each compare interrupt just schedules the next edge. The mainloop hands
+//! over a batch of steps each tick, as planned by `motion`, and they're
+//! spread evenly over slightly less than the tick so that one batch is
+//! always finished before the next arrives.
+//!
+//! Axis 0 steps on HS1 (PA0, CH1) and axis 1 on HS3 (PA2, CH3); HS2 and HS4
+//! are their direction outputs, driven as plain GPIO by the caller.
+use crate::motion::step_interval;
+use stm32f4xx_hal::pac;
+
+pub const AXES: usize = 2;
+pub const TICK_HZ: u32 = 1_000_000;
+// Ticks to spread each millisecond's steps over.
+const BATCH_TICKS: u32 = 900;
+// Compare matches are on equality, so each edge has to be scheduled far
+// enough ahead that the counter can't already have passed it.
+const MIN_HALF_INTERVAL: u32 = 10;
+const CHANNELS: [u8; AXES] = [0, 2];
+
+#[derive(Default, Clone, Copy)]
+struct Channel {
+    toggles: u32,
+    half_interval: u32,
+}
+
+pub struct StepGen {
+    tim: pac::TIM2,
+    channels: [Channel; AXES],
+}
+
+impl StepGen {
+    /// The timer must already be enabled and reset. `timclk` is its input
+    /// clock.
+    pub fn new(tim: pac::TIM2, timclk: u32) -> Self {
+        tim.psc()
+            .write(|w| w.psc().set((timclk / TICK_HZ - 1) as u16));
+        tim.arr().write(|w| w.arr().set(u32::MAX));
+        tim.egr().write(|w| w.ug().set_bit());
+        let gen = StepGen {
+            tim,
+            channels: Default::default(),
+        };
+        for ch in CHANNELS {
+            gen.set_toggling(ch, false);
+            gen.tim.ccer().modify(|_, w| w.cce(ch).set_bit());
+        }
+        gen.tim.cr1().modify(|_, w| w.cen().set_bit());
+        gen
+    }
+
+    fn set_toggling(&self, ch: u8, toggle: bool) {
+        // Stopped channels are forced low rather than left wherever the last
+        // toggle put them.
+        if ch < 2 {
+            self.tim.ccmr1_output().modify(|_, w| {
+                let ocm = w.ocm(ch % 2);
+                if toggle {
+                    ocm.toggle()
+                } else {
+                    ocm.force_inactive()
+                }
+            });
+        } else {
+            self.tim.ccmr2_output().modify(|_, w| {
+                let ocm = w.ocm(ch % 2);
+                if toggle {
+                    ocm.toggle()
+                } else {
+                    ocm.force_inactive()
+                }
+            });
+        }
+    }
+
+    /// Queue `steps` pulses on `axis` over the next tick. Any still
+    /// outstanding from the last batch are carried into this one. Call with
+    /// interrupts disabled.
+    pub fn load(&mut self, axis: usize, steps: u32) {
+        let ch = CHANNELS[axis];
+        let busy = self.is_busy(axis);
+        let total = self.channels[axis].toggles / 2 + steps;
+        let Some(interval) = step_interval(total, BATCH_TICKS) else {
+            return;
+        };
+        let half_interval = (interval / 2).max(MIN_HALF_INTERVAL);
+        self.channels[axis] = Channel {
+            toggles: total * 2,
+            half_interval,
+        };
+        if !busy {
+            let cnt = self.tim.cnt().read().bits();
+            let ccr = self.tim.ccr(ch as usize);
+            ccr.write(|w| w.ccr().set(cnt.wrapping_add(half_interval)));
+            self.set_toggling(ch, true);
+            self.tim.sr().write(|w| unsafe { w.bits(!(1 << (ch + 1))) });
+            self.tim.dier().modify(|_, w| w.ccie(ch).set_bit());
+        }
+    }
+
+    /// Whether `axis` still has steps to emit.
+    pub fn is_busy(&self, axis: usize) -> bool {
+        self.channels[axis].toggles > 0
+    }
+
+    /// Call from the TIM2 interrupt.
+    pub fn on_interrupt(&mut self) {
+        let sr = self.tim.sr().read();
+        for (axis, ch) in CHANNELS.into_iter().enumerate() {
+            if !sr.ccif(ch).bit_is_set() {
+                continue;
+            }
+            self.tim.sr().write(|w| unsafe { w.bits(!(1 << (ch + 1))) });
+            let channel = &mut self.channels[axis];
+            channel.toggles = channel.toggles.saturating_sub(1);
+            if channel.toggles > 0 {
+                let ccr = self.tim.ccr(ch as usize);
+                let next = ccr.read().bits().wrapping_add(channel.half_interval);
+                ccr.write(|w| w.ccr().set(next));
+            } else {
+                self.tim.dier().modify(|_, w| w.ccie(ch).clear_bit());
+                self.set_toggling(ch, false);
+            }
+        }
+    }
+}
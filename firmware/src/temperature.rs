@@ -0,0 +1,190 @@
+//! Temperature sensing.
+//!
+//! NTC thermistors are read on the ADC, each in a divider with a series
+//! resistor from the ADC reference and the thermistor to ground. Readings
+//! are converted with the beta equation, filtered, and checked for open or
+//! shorted sensors; a fault has to persist for a while before it's
+//! reported, so a single bad conversion doesn't trip anything. While a
+//! sensor is faulted it has no temperature, and users of it are expected to
+//! fail safe.
+use crate::debounce::Debouncer;
+use libm::logf;
+
+const KELVIN: f32 = 273.15;
+// Readings this close to either rail mean the sensor is open or shorted.
+const OPEN_FRACTION: f32 = 0.98;
+const SHORT_FRACTION: f32 = 0.02;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SensorFault {
+    Open,
+    Short,
+}
+
+pub struct NtcConfig {
+    /// Resistance at 25C.
+    pub r25: f32,
+    pub beta: f32,
+    pub r_series: f32,
+    /// ADC reading at the reference voltage.
+    pub full_scale: u16,
+}
+
+impl NtcConfig {
+    pub fn celsius(&self, raw: u16) -> Result<f32, SensorFault> {
+        let ratio = raw as f32 / self.full_scale as f32;
+        if ratio >= OPEN_FRACTION {
+            return Err(SensorFault::Open);
+        }
+        if ratio <= SHORT_FRACTION {
+            return Err(SensorFault::Short);
+        }
+        let r = self.r_series * ratio / (1.0 - ratio);
+        let inv_t = 1.0 / (25.0 + KELVIN) + logf(r / self.r25) / self.beta;
+        Ok(1.0 / inv_t - KELVIN)
+    }
+}
+
+pub struct TemperatureSensor {
+    filter_time: fugit::Duration<u32, 1, 1_000>,
+    value: Option<f32>,
+    fault: Debouncer,
+    last_fault: SensorFault,
+    last_update: Option<i64>,
+}
+
+impl TemperatureSensor {
+    /// `filter_time` is the time constant of the reading filter, and
+    /// `fault_time` how long a fault has to persist (or clear) before it
+    /// counts.
+    pub fn new(
+        filter_time: fugit::Duration<u32, 1, 1_000>,
+        fault_time: fugit::Duration<u32, 1, 1_000>,
+    ) -> Self {
+        TemperatureSensor {
+            filter_time,
+            value: None,
+            fault: Debouncer::new(fault_time, fault_time),
+            last_fault: SensorFault::Open,
+            last_update: None,
+        }
+    }
+
+    pub fn update(&mut self, reading: Result<f32, SensorFault>, now: i64) {
+        let elapsed_ms = match self.last_update.replace(now) {
+            Some(last) => (now - last).max(0) as f32,
+            None => 0.0,
+        };
+        self.fault.update(reading.is_err(), now);
+        match reading {
+            Err(fault) => self.last_fault = fault,
+            Ok(celsius) => {
+                let alpha = (elapsed_ms / self.filter_time.ticks().max(1) as f32).min(1.0);
+                self.value = Some(match self.value {
+                    Some(value) => value + (celsius - value) * alpha,
+                    None => celsius,
+                });
+            }
+        }
+        if self.fault.is_on() {
+            self.value = None;
+        }
+    }
+
+    /// Filtered temperature, if the sensor is working.
+    pub fn celsius(&self) -> Option<f32> {
+        if self.fault.is_on() {
+            None
+        } else {
+            self.value
+        }
+    }
+
+    pub fn fault(&self) -> Option<SensorFault> {
+        if self.fault.is_on() {
+            Some(self.last_fault)
+        } else {
+            None
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match (self.fault(), self.value) {
+            (Some(SensorFault::Open), _) => "Open",
+            (Some(SensorFault::Short), _) => "Short",
+            (None, None) => "NoData",
+            (None, Some(_)) => "Ok",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fugit::ExtU32;
+
+    // A common 10k, B=3950 thermistor with a 10k series resistor.
+    const NTC: NtcConfig = NtcConfig {
+        r25: 10_000.0,
+        beta: 3950.0,
+        r_series: 10_000.0,
+        full_scale: 4095,
+    };
+
+    #[test]
+    fn test_ntc_conversion() {
+        let t = NTC.celsius(2048).unwrap();
+        assert!((t - 25.0).abs() < 0.1, "{}", t);
+        // 3.6k at about 50C.
+        let raw = (4095.0 * 3.6 / 13.6) as u16;
+        let t = NTC.celsius(raw).unwrap();
+        assert!((t - 50.0).abs() < 1.0, "{}", t);
+        // Hotter reads lower.
+        assert!(NTC.celsius(500).unwrap() > NTC.celsius(1000).unwrap());
+    }
+
+    #[test]
+    fn test_ntc_faults() {
+        assert_eq!(NTC.celsius(4095), Err(SensorFault::Open));
+        assert_eq!(NTC.celsius(4050), Err(SensorFault::Open));
+        assert_eq!(NTC.celsius(0), Err(SensorFault::Short));
+        assert_eq!(NTC.celsius(50), Err(SensorFault::Short));
+    }
+
+    #[test]
+    fn test_filtering() {
+        let mut sensor = TemperatureSensor::new(1000.millis(), 500.millis());
+        assert_eq!(sensor.state_name(), "NoData");
+        sensor.update(Ok(20.0), 0);
+        assert_eq!(sensor.celsius(), Some(20.0));
+        assert_eq!(sensor.state_name(), "Ok");
+        sensor.update(Ok(30.0), 100);
+        assert!((sensor.celsius().unwrap() - 21.0).abs() < 1e-4);
+        for now in (200..10_000).step_by(100) {
+            sensor.update(Ok(30.0), now);
+        }
+        assert!((sensor.celsius().unwrap() - 30.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_fault_must_persist() {
+        let mut sensor = TemperatureSensor::new(1000.millis(), 500.millis());
+        sensor.update(Ok(20.0), 0);
+        // A brief glitch holds the last value.
+        sensor.update(Err(SensorFault::Short), 100);
+        assert_eq!(sensor.celsius(), Some(20.0));
+        sensor.update(Ok(20.0), 200);
+        for now in (300..=800).step_by(100) {
+            sensor.update(Err(SensorFault::Open), now);
+        }
+        assert_eq!(sensor.celsius(), None);
+        assert_eq!(sensor.fault(), Some(SensorFault::Open));
+        assert_eq!(sensor.state_name(), "Open");
+        // Recovers with a fresh reading, rather than filtering from stale.
+        for now in (900..=1400).step_by(100) {
+            sensor.update(Ok(40.0), now);
+        }
+        assert_eq!(sensor.fault(), None);
+        assert_eq!(sensor.celsius(), Some(40.0));
+    }
+}
@@ -0,0 +1,902 @@
+//! User logic interpreter.
+//!
+//! Runs a bytecode program (see `bytecode`) once per scan against the
+//! process image: the filtered inputs, plus read-only status and
+//! measurements from the built-in function blocks. The program has its own
+//! outputs, markers, words, timers, counters and edge detectors.
+//!
+//! Programs are checked when loaded, so a bad opcode, operand or jump
+//! target is refused up front rather than found halfway through a scan.
+//! What can only go wrong at run time (running out of stack, or looping
+//! for longer than a scan allows) faults the program: it stops, and its
+//! outputs all go off, until it's loaded or started again.
+use crate::bytecode::{
+    Op, Operand, COUNTERS, EDGES, INPUTS, MARKERS, MAX_CODE, OUTPUTS, STACK_DEPTH, SYSTEM_BITS,
+    SYSTEM_WORDS, TIMERS, WORDS,
+};
+
+// Enough for any program without backward jumps to run to the end, while
+// bounding the time a looping one can take.
+const MAX_STEPS: usize = MAX_CODE;
+// Room to decode the longest operand past the end of the code.
+const CODE_PADDING: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LoadError {
+    TooLong,
+    BadOpcode(usize),
+    BadOperand(usize),
+    BadJump(usize),
+    Truncated(usize),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmFault {
+    StackOverflow(usize),
+    StackUnderflow(usize),
+    Runaway,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum VmFSMState {
+    #[default]
+    NoProgram,
+    Stopped,
+    Running,
+    Fault(VmFault),
+}
+
+#[derive(Default)]
+pub struct ProcessImage {
+    pub inputs: [bool; INPUTS],
+    pub system_bits: [bool; SYSTEM_BITS.len()],
+    pub system_words: [i32; SYSTEM_WORDS.len()],
+}
+
+#[derive(Clone, Copy, Default)]
+struct Timer {
+    start: Option<i64>,
+    input: bool,
+    q: bool,
+    et: i32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counter {
+    input: bool,
+    q: bool,
+    cv: i32,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Edge {
+    clk: bool,
+    q: bool,
+}
+
+pub struct Vm {
+    state: VmFSMState,
+    code: [u8; MAX_CODE + CODE_PADDING],
+    code_len: usize,
+    stack: [i32; STACK_DEPTH],
+    depth: usize,
+    outputs: [bool; OUTPUTS],
+    markers: [bool; MARKERS],
+    words: [i32; WORDS],
+    timers: [Timer; TIMERS],
+    counters: [Counter; COUNTERS],
+    edges: [Edge; EDGES],
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm {
+            state: VmFSMState::NoProgram,
+            code: [0; MAX_CODE + CODE_PADDING],
+            code_len: 0,
+            stack: [0; STACK_DEPTH],
+            depth: 0,
+            outputs: [false; OUTPUTS],
+            markers: [false; MARKERS],
+            words: [0; WORDS],
+            timers: [Timer::default(); TIMERS],
+            counters: [Counter::default(); COUNTERS],
+            edges: [Edge::default(); EDGES],
+        }
+    }
+}
+
+/// Check that every instruction decodes, with its operand in range and any
+/// jump landing on an instruction.
+pub fn verify(code: &[u8]) -> Result<(), LoadError> {
+    if code.len() > MAX_CODE {
+        return Err(LoadError::TooLong);
+    }
+    let mut starts = [false; MAX_CODE];
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = Op::from_u8(code[pc]).ok_or(LoadError::BadOpcode(pc))?;
+        starts[pc] = true;
+        let operand = op.operand();
+        if pc + 1 + operand.len() > code.len() {
+            return Err(LoadError::Truncated(pc));
+        }
+        if let Operand::Index(size) = operand {
+            if code[pc + 1] as usize >= size {
+                return Err(LoadError::BadOperand(pc));
+            }
+        }
+        pc += 1 + operand.len();
+    }
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = Op::from_u8(code[pc]).unwrap_or(Op::End);
+        if op.operand() == Operand::Target {
+            let target = u16::from_le_bytes([code[pc + 1], code[pc + 2]]) as usize;
+            // Jumping to the very end is allowed, and ends the scan.
+            if target > code.len() || (target < code.len() && !starts[target]) {
+                return Err(LoadError::BadJump(pc));
+            }
+        }
+        pc += 1 + op.operand().len();
+    }
+    Ok(())
+}
+
+impl Vm {
+    /// Replace the program, leaving it stopped. All of its memory is
+    /// cleared.
+    pub fn load(&mut self, code: &[u8]) -> Result<(), LoadError> {
+        verify(code)?;
+        *self = Vm::default();
+        self.code[..code.len()].copy_from_slice(code);
+        self.code_len = code.len();
+        self.state = VmFSMState::Stopped;
+        Ok(())
+    }
+
+    /// Start, or restart after a fault. Memory is kept.
+    pub fn start(&mut self) {
+        if self.state != VmFSMState::NoProgram {
+            self.state = VmFSMState::Running;
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if self.state != VmFSMState::NoProgram {
+            self.state = VmFSMState::Stopped;
+            self.outputs = [false; OUTPUTS];
+        }
+    }
+
+    pub fn scan(&mut self, image: &ProcessImage, now: i64) {
+        if self.state != VmFSMState::Running {
+            return;
+        }
+        if let Err(fault) = self.execute(image, now) {
+            self.state = VmFSMState::Fault(fault);
+            self.outputs = [false; OUTPUTS];
+        }
+    }
+
+    pub fn output(&self, n: usize) -> bool {
+        self.outputs.get(n).copied().unwrap_or(false)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_running(&self) -> bool {
+        self.state == VmFSMState::Running
+    }
+
+    pub fn code_len(&self) -> usize {
+        self.code_len
+    }
+
+    #[allow(dead_code)]
+    pub fn status_char(&self) -> char {
+        match self.state {
+            VmFSMState::NoProgram => '-',
+            VmFSMState::Stopped => 'S',
+            VmFSMState::Running => 'R',
+            VmFSMState::Fault(_) => 'F',
+        }
+    }
+
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            VmFSMState::NoProgram => "NoProgram",
+            VmFSMState::Stopped => "Stopped",
+            VmFSMState::Running => "Running",
+            VmFSMState::Fault(_) => "Fault",
+        }
+    }
+
+    pub fn state(&self) -> &VmFSMState {
+        &self.state
+    }
+
+    fn push(&mut self, value: i32, pc: usize) -> Result<(), VmFault> {
+        if self.depth == STACK_DEPTH {
+            return Err(VmFault::StackOverflow(pc));
+        }
+        self.stack[self.depth] = value;
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self, pc: usize) -> Result<i32, VmFault> {
+        if self.depth == 0 {
+            return Err(VmFault::StackUnderflow(pc));
+        }
+        self.depth -= 1;
+        Ok(self.stack[self.depth])
+    }
+
+    fn execute(&mut self, image: &ProcessImage, now: i64) -> Result<(), VmFault> {
+        self.depth = 0;
+        let mut pc = 0;
+        for _ in 0..MAX_STEPS {
+            if pc >= self.code_len {
+                return Ok(());
+            }
+            let at = pc;
+            // The program was verified on loading, so this can't fail.
+            let op = Op::from_u8(self.code[pc]).unwrap_or(Op::End);
+            let arg = [
+                self.code[pc + 1],
+                self.code[pc + 2],
+                self.code[pc + 3],
+                self.code[pc + 4],
+            ];
+            let n = arg[0] as usize;
+            let target = u16::from_le_bytes([arg[0], arg[1]]) as usize;
+            let immediate = i32::from_le_bytes(arg);
+            pc += 1 + op.operand().len();
+
+            match op {
+                Op::End => return Ok(()),
+                Op::LdI => self.push(image.inputs[n] as i32, at)?,
+                Op::LdQ => self.push(self.outputs[n] as i32, at)?,
+                Op::LdM => self.push(self.markers[n] as i32, at)?,
+                Op::LdS => self.push(image.system_bits[n] as i32, at)?,
+                Op::LdW => self.push(self.words[n], at)?,
+                Op::LdSw => self.push(image.system_words[n], at)?,
+                Op::Push => self.push(immediate, at)?,
+                Op::StQ => self.outputs[n] = self.pop(at)? != 0,
+                Op::StM => self.markers[n] = self.pop(at)? != 0,
+                Op::StW => self.words[n] = self.pop(at)?,
+                Op::SetQ => self.outputs[n] |= self.pop(at)? != 0,
+                Op::ResetQ => self.outputs[n] &= self.pop(at)? == 0,
+                Op::SetM => self.markers[n] |= self.pop(at)? != 0,
+                Op::ResetM => self.markers[n] &= self.pop(at)? == 0,
+                Op::Not => {
+                    let a = self.pop(at)?;
+                    self.push((a == 0) as i32, at)?;
+                }
+                Op::Neg => {
+                    let a = self.pop(at)?;
+                    self.push(a.wrapping_neg(), at)?;
+                }
+                Op::Dup => {
+                    let a = self.pop(at)?;
+                    self.push(a, at)?;
+                    self.push(a, at)?;
+                }
+                Op::Drop => {
+                    self.pop(at)?;
+                }
+                Op::And
+                | Op::Or
+                | Op::Xor
+                | Op::Add
+                | Op::Sub
+                | Op::Mul
+                | Op::Div
+                | Op::Mod
+                | Op::Eq
+                | Op::Ne
+                | Op::Lt
+                | Op::Le
+                | Op::Gt
+                | Op::Ge => {
+                    let b = self.pop(at)?;
+                    let a = self.pop(at)?;
+                    self.push(binary(op, a, b), at)?;
+                }
+                Op::Jmp => pc = target,
+                Op::Jmpc => {
+                    if self.pop(at)? != 0 {
+                        pc = target;
+                    }
+                }
+                Op::Jmpcn => {
+                    if self.pop(at)? == 0 {
+                        pc = target;
+                    }
+                }
+                Op::RTrig | Op::FTrig => {
+                    let clk = self.pop(at)? != 0;
+                    let edge = &mut self.edges[n];
+                    edge.q = if op == Op::RTrig {
+                        clk && !edge.clk
+                    } else {
+                        !clk && edge.clk
+                    };
+                    edge.clk = clk;
+                }
+                Op::LdEdgeQ => self.push(self.edges[n].q as i32, at)?,
+                Op::Ton | Op::Tof | Op::Tp => {
+                    let pt = self.pop(at)?.max(0);
+                    let input = self.pop(at)? != 0;
+                    self.timers[n].update(op, input, pt, now);
+                }
+                Op::LdTimerQ => self.push(self.timers[n].q as i32, at)?,
+                Op::LdTimerEt => self.push(self.timers[n].et, at)?,
+                Op::Ctu | Op::Ctd => {
+                    let pv = self.pop(at)?;
+                    let reset = self.pop(at)? != 0;
+                    let input = self.pop(at)? != 0;
+                    self.counters[n].update(op, input, reset, pv);
+                }
+                Op::LdCounterQ => self.push(self.counters[n].q as i32, at)?,
+                Op::LdCounterCv => self.push(self.counters[n].cv, at)?,
+            }
+        }
+        Err(VmFault::Runaway)
+    }
+}
+
+fn binary(op: Op, a: i32, b: i32) -> i32 {
+    match op {
+        Op::And => (a != 0 && b != 0) as i32,
+        Op::Or => (a != 0 || b != 0) as i32,
+        Op::Xor => ((a != 0) != (b != 0)) as i32,
+        Op::Add => a.wrapping_add(b),
+        Op::Sub => a.wrapping_sub(b),
+        Op::Mul => a.wrapping_mul(b),
+        Op::Div => a.checked_div(b).unwrap_or(0),
+        Op::Mod => a.checked_rem(b).unwrap_or(0),
+        Op::Eq => (a == b) as i32,
+        Op::Ne => (a != b) as i32,
+        Op::Lt => (a < b) as i32,
+        Op::Le => (a <= b) as i32,
+        Op::Gt => (a > b) as i32,
+        Op::Ge => (a >= b) as i32,
+        _ => 0,
+    }
+}
+
+impl Timer {
+    fn update(&mut self, op: Op, input: bool, pt: i32, now: i64) {
+        let elapsed = |start: i64| (now - start).clamp(0, i32::MAX as i64) as i32;
+        match op {
+            Op::Ton => {
+                if input {
+                    let start = *self.start.get_or_insert(now);
+                    self.et = elapsed(start).min(pt);
+                    self.q = self.et >= pt;
+                } else {
+                    self.start = None;
+                    self.et = 0;
+                    self.q = false;
+                }
+            }
+            Op::Tof => {
+                if input {
+                    self.start = None;
+                    self.et = 0;
+                    self.q = true;
+                } else if self.q {
+                    let start = *self.start.get_or_insert(now);
+                    self.et = elapsed(start).min(pt);
+                    self.q = self.et < pt;
+                }
+            }
+            _ => {
+                if input && !self.input && !self.q {
+                    self.start = Some(now);
+                }
+                if let Some(start) = self.start {
+                    self.et = elapsed(start).min(pt);
+                    self.q = self.et < pt;
+                    if !self.q && !input {
+                        self.start = None;
+                        self.et = 0;
+                    }
+                }
+            }
+        }
+        self.input = input;
+    }
+}
+
+impl Counter {
+    fn update(&mut self, op: Op, input: bool, reset: bool, pv: i32) {
+        let edge = input && !self.input;
+        self.input = input;
+        if op == Op::Ctu {
+            if reset {
+                self.cv = 0;
+            } else if edge {
+                self.cv = self.cv.saturating_add(1);
+            }
+            self.q = self.cv >= pv;
+        } else {
+            if reset {
+                self.cv = pv;
+            } else if edge {
+                self.cv = self.cv.saturating_sub(1);
+            }
+            self.q = self.cv <= 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal assembler: ops with their operand, if any.
+    enum A {
+        Op(Op),
+        N(Op, u8),
+        J(Op, u16),
+        Push(i32),
+    }
+
+    fn asm(program: &[A]) -> Vec<u8> {
+        let mut code = Vec::new();
+        for a in program {
+            match *a {
+                A::Op(op) => code.push(op as u8),
+                A::N(op, n) => code.extend([op as u8, n]),
+                A::J(op, target) => {
+                    code.push(op as u8);
+                    code.extend(target.to_le_bytes());
+                }
+                A::Push(value) => {
+                    code.push(Op::Push as u8);
+                    code.extend(value.to_le_bytes());
+                }
+            }
+        }
+        code
+    }
+
+    fn running(program: &[A]) -> Vm {
+        let mut vm = Vm::default();
+        vm.load(&asm(program)).unwrap();
+        vm.start();
+        vm
+    }
+
+    fn image(inputs: &[usize]) -> ProcessImage {
+        let mut image = ProcessImage::default();
+        for &i in inputs {
+            image.inputs[i] = true;
+        }
+        image
+    }
+
+    #[test]
+    fn test_states() {
+        let mut vm = Vm::default();
+        assert_eq!(vm.state_name(), "NoProgram");
+        vm.start();
+        assert_eq!(vm.state_name(), "NoProgram");
+        vm.load(&asm(&[A::N(Op::LdI, 0), A::N(Op::StQ, 3)]))
+            .unwrap();
+        assert_eq!(vm.state_name(), "Stopped");
+        vm.scan(&image(&[0]), 0);
+        assert!(!vm.output(3));
+        vm.start();
+        assert_eq!(vm.status_char(), 'R');
+        vm.scan(&image(&[0]), 0);
+        assert!(vm.output(3));
+        // Stopping turns the outputs off.
+        vm.stop();
+        assert!(!vm.output(3));
+    }
+
+    #[test]
+    fn test_verify() {
+        assert_eq!(verify(&[0xff]), Err(LoadError::BadOpcode(0)));
+        assert_eq!(
+            verify(&[Op::LdI as u8, INPUTS as u8]),
+            Err(LoadError::BadOperand(0))
+        );
+        assert_eq!(
+            verify(&[Op::Not as u8, Op::Push as u8, 1, 0]),
+            Err(LoadError::Truncated(1))
+        );
+        // Into the middle of the PUSH.
+        let code = asm(&[A::J(Op::Jmp, 4), A::Push(1)]);
+        assert_eq!(verify(&code), Err(LoadError::BadJump(0)));
+        let code = asm(&[A::J(Op::Jmp, 9), A::Push(1)]);
+        assert_eq!(verify(&code), Err(LoadError::BadJump(0)));
+        // To the start of an instruction, or just past the end, is fine.
+        assert_eq!(verify(&asm(&[A::J(Op::Jmp, 3), A::Push(1)])), Ok(()));
+        assert_eq!(verify(&asm(&[A::J(Op::Jmp, 8), A::Push(1)])), Ok(()));
+        assert_eq!(verify(&[0; MAX_CODE + 1]), Err(LoadError::TooLong));
+        // A refused program leaves the old one in place.
+        let mut vm = running(&[A::Push(1), A::N(Op::StQ, 0)]);
+        assert!(vm.load(&[0xff]).is_err());
+        vm.scan(&image(&[]), 0);
+        assert!(vm.output(0));
+    }
+
+    #[test]
+    fn test_logic() {
+        // Q0 = (I0 AND NOT I1) OR I2
+        let mut vm = running(&[
+            A::N(Op::LdI, 0),
+            A::N(Op::LdI, 1),
+            A::Op(Op::Not),
+            A::Op(Op::And),
+            A::N(Op::LdI, 2),
+            A::Op(Op::Or),
+            A::N(Op::StQ, 0),
+            A::N(Op::LdI, 0),
+            A::N(Op::LdI, 1),
+            A::Op(Op::Xor),
+            A::N(Op::StQ, 1),
+        ]);
+        for bits in 0..8 {
+            let inputs: Vec<usize> = (0..3).filter(|i| bits & (1 << i) != 0).collect();
+            vm.scan(&image(&inputs), 0);
+            let i = |n: usize| bits & (1 << n) != 0;
+            assert_eq!(vm.output(0), (i(0) && !i(1)) || i(2), "{}", bits);
+            assert_eq!(vm.output(1), i(0) != i(1), "{}", bits);
+        }
+    }
+
+    #[test]
+    fn test_set_reset() {
+        // Latch Q0 on I0, clear it on I1; M0 the same.
+        let mut vm = running(&[
+            A::N(Op::LdI, 0),
+            A::N(Op::SetQ, 0),
+            A::N(Op::LdI, 1),
+            A::N(Op::ResetQ, 0),
+            A::N(Op::LdI, 0),
+            A::N(Op::SetM, 0),
+            A::N(Op::LdI, 1),
+            A::N(Op::ResetM, 0),
+            A::N(Op::LdM, 0),
+            A::N(Op::StQ, 1),
+        ]);
+        vm.scan(&image(&[0]), 0);
+        vm.scan(&image(&[]), 1);
+        assert!(vm.output(0) && vm.output(1));
+        vm.scan(&image(&[1]), 2);
+        assert!(!vm.output(0) && !vm.output(1));
+    }
+
+    // Runs the program and returns what it leaves on top of the stack.
+    fn eval(program: Vec<A>) -> i32 {
+        let mut program = program;
+        program.push(A::N(Op::StW, 0));
+        let mut vm = running(&program);
+        vm.scan(&image(&[]), 0);
+        assert!(vm.is_running(), "{:?}", vm.state());
+        vm.words[0]
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let bin = |a, b, op| eval(vec![A::Push(a), A::Push(b), A::Op(op)]);
+        assert_eq!(bin(7, 3, Op::Add), 10);
+        assert_eq!(bin(7, 3, Op::Sub), 4);
+        assert_eq!(bin(7, 3, Op::Mul), 21);
+        assert_eq!(bin(7, 3, Op::Div), 2);
+        assert_eq!(bin(-7, 3, Op::Mod), -1);
+        assert_eq!(bin(7, 0, Op::Div), 0);
+        assert_eq!(bin(7, 0, Op::Mod), 0);
+        assert_eq!(bin(i32::MIN, -1, Op::Div), 0);
+        assert_eq!(bin(i32::MAX, 1, Op::Add), i32::MIN);
+        assert_eq!(eval(vec![A::Push(5), A::Op(Op::Neg)]), -5);
+        assert_eq!(bin(2, 3, Op::Lt), 1);
+        assert_eq!(bin(3, 3, Op::Le), 1);
+        assert_eq!(bin(2, 3, Op::Gt), 0);
+        assert_eq!(bin(3, 3, Op::Ge), 1);
+        assert_eq!(bin(3, 3, Op::Eq), 1);
+        assert_eq!(bin(3, 3, Op::Ne), 0);
+        // Any non-zero value is true.
+        assert_eq!(bin(5, -2, Op::And), 1);
+        assert_eq!(bin(5, 0, Op::Or), 1);
+        assert_eq!(bin(5, 1, Op::Xor), 0);
+        assert_eq!(eval(vec![A::Push(3), A::Op(Op::Not)]), 0);
+        assert_eq!(eval(vec![A::Push(3), A::Op(Op::Dup), A::Op(Op::Mul)]), 9);
+        assert_eq!(eval(vec![A::Push(3), A::Push(4), A::Op(Op::Drop)]), 3);
+    }
+
+    #[test]
+    fn test_words_and_system() {
+        // MW1 = SW0 * 2; Q0 = S1
+        let mut vm = running(&[
+            A::N(Op::LdSw, 0),
+            A::Push(2),
+            A::Op(Op::Mul),
+            A::N(Op::StW, 1),
+            A::N(Op::LdW, 1),
+            A::N(Op::StW, 2),
+            A::N(Op::LdS, 1),
+            A::N(Op::StQ, 0),
+        ]);
+        let mut image = ProcessImage::default();
+        image.system_words[0] = 21;
+        image.system_bits[1] = true;
+        vm.scan(&image, 0);
+        assert_eq!(vm.words[2], 42);
+        assert!(vm.output(0));
+    }
+
+    #[test]
+    fn test_jumps() {
+        // IF I0 THEN MW0 := 1 ELSE MW0 := 2 END_IF
+        let mut code = asm(&[
+            A::N(Op::LdI, 0),
+            A::J(Op::Jmpcn, 15),
+            A::Push(1),
+            A::N(Op::StW, 0),
+            A::J(Op::Jmp, 22),
+            A::Push(2),
+            A::N(Op::StW, 0),
+        ]);
+        assert_eq!(code.len(), 22);
+        let mut vm = Vm::default();
+        vm.load(&code).unwrap();
+        vm.start();
+        vm.scan(&image(&[0]), 0);
+        assert_eq!(vm.words[0], 1);
+        vm.scan(&image(&[]), 0);
+        assert_eq!(vm.words[0], 2);
+        // JMPC jumps on true.
+        code[2] = Op::Jmpc as u8;
+        vm.load(&code).unwrap();
+        vm.start();
+        vm.scan(&image(&[0]), 0);
+        assert_eq!(vm.words[0], 2);
+    }
+
+    #[test]
+    fn test_end_stops_scan() {
+        let mut vm = running(&[A::Op(Op::End), A::Push(1), A::N(Op::StQ, 0)]);
+        vm.scan(&image(&[]), 0);
+        assert!(!vm.output(0));
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut vm = running(&[A::Op(Op::Not)]);
+        vm.scan(&image(&[]), 0);
+        assert_eq!(*vm.state(), VmFSMState::Fault(VmFault::StackUnderflow(0)));
+        assert_eq!(vm.state_name(), "Fault");
+
+        let mut vm = running(&[A::Push(1), A::J(Op::Jmp, 0)]);
+        vm.scan(&image(&[]), 0);
+        assert_eq!(*vm.state(), VmFSMState::Fault(VmFault::StackOverflow(0)));
+
+        // Outputs go off on a fault, and stay off.
+        let mut vm = running(&[A::Push(1), A::N(Op::StQ, 0), A::J(Op::Jmp, 0)]);
+        vm.scan(&image(&[]), 0);
+        assert_eq!(*vm.state(), VmFSMState::Fault(VmFault::Runaway));
+        assert!(!vm.output(0));
+        vm.scan(&image(&[]), 1);
+        assert!(!vm.output(0));
+        // Restarting runs it again.
+        vm.start();
+        assert!(vm.is_running());
+    }
+
+    #[test]
+    fn test_edges() {
+        // Q0 = R_TRIG(I0), Q1 = F_TRIG(I0)
+        let mut vm = running(&[
+            A::N(Op::LdI, 0),
+            A::N(Op::RTrig, 0),
+            A::N(Op::LdEdgeQ, 0),
+            A::N(Op::StQ, 0),
+            A::N(Op::LdI, 0),
+            A::N(Op::FTrig, 1),
+            A::N(Op::LdEdgeQ, 1),
+            A::N(Op::StQ, 1),
+        ]);
+        let trace: Vec<(bool, bool)> = [false, true, true, false, false]
+            .iter()
+            .enumerate()
+            .map(|(t, &i)| {
+                vm.scan(&image(if i { &[0] } else { &[] }), t as i64);
+                (vm.output(0), vm.output(1))
+            })
+            .collect();
+        assert_eq!(
+            trace,
+            [
+                (false, false),
+                (true, false),
+                (false, false),
+                (false, true),
+                (false, false)
+            ]
+        );
+    }
+
+    fn timer(op: Op) -> Vm {
+        // Q0 = timer(I0, 100ms).Q, MW0 = its ET
+        running(&[
+            A::N(Op::LdI, 0),
+            A::Push(100),
+            A::N(op, 0),
+            A::N(Op::LdTimerQ, 0),
+            A::N(Op::StQ, 0),
+            A::N(Op::LdTimerEt, 0),
+            A::N(Op::StW, 0),
+        ])
+    }
+
+    fn run_timer(vm: &mut Vm, steps: &[(i64, bool)]) -> Vec<(bool, i32)> {
+        steps
+            .iter()
+            .map(|&(now, input)| {
+                vm.scan(&image(if input { &[0] } else { &[] }), now);
+                (vm.output(0), vm.words[0])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ton() {
+        let mut vm = timer(Op::Ton);
+        let trace = run_timer(
+            &mut vm,
+            &[
+                (0, false),
+                (10, true),
+                (60, true),
+                (109, true),
+                (110, true),
+                (500, true),
+                (501, false),
+                (502, true),
+            ],
+        );
+        assert_eq!(
+            trace,
+            [
+                (false, 0),
+                (false, 0),
+                (false, 50),
+                (false, 99),
+                (true, 100),
+                (true, 100),
+                (false, 0),
+                (false, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tof() {
+        let mut vm = timer(Op::Tof);
+        let trace = run_timer(
+            &mut vm,
+            &[
+                (0, false),
+                (10, true),
+                (20, false),
+                (70, false),
+                (80, true),
+                (90, false),
+                (190, false),
+            ],
+        );
+        assert_eq!(
+            trace,
+            [
+                (false, 0),
+                (true, 0),
+                (true, 0),
+                (true, 50),
+                (true, 0),
+                (true, 0),
+                (false, 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tp() {
+        let mut vm = timer(Op::Tp);
+        let trace = run_timer(
+            &mut vm,
+            &[
+                (0, true),
+                (50, false),
+                (60, true),
+                (100, true),
+                (150, true),
+                (200, false),
+                (210, true),
+            ],
+        );
+        // The pulse runs its full length regardless of the input, isn't
+        // retriggered, and a new one needs a fresh rising edge.
+        assert_eq!(
+            trace,
+            [
+                (true, 0),
+                (true, 50),
+                (true, 60),
+                (false, 100),
+                (false, 100),
+                (false, 0),
+                (true, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_counters() {
+        // CTU 0: CU=I0, R=I1, PV=3; CTD 1: CD=I0, LD=I1, PV=2
+        let mut vm = running(&[
+            A::N(Op::LdI, 0),
+            A::N(Op::LdI, 1),
+            A::Push(3),
+            A::N(Op::Ctu, 0),
+            A::N(Op::LdCounterQ, 0),
+            A::N(Op::StQ, 0),
+            A::N(Op::LdCounterCv, 0),
+            A::N(Op::StW, 0),
+            A::N(Op::LdI, 0),
+            A::N(Op::LdI, 1),
+            A::Push(2),
+            A::N(Op::Ctd, 1),
+            A::N(Op::LdCounterQ, 1),
+            A::N(Op::StQ, 1),
+            A::N(Op::LdCounterCv, 1),
+            A::N(Op::StW, 1),
+        ]);
+        vm.scan(&image(&[1]), 0);
+        assert_eq!((vm.words[0], vm.words[1]), (0, 2));
+        let pulse = |vm: &mut Vm| {
+            vm.scan(&image(&[0]), 0);
+            vm.scan(&image(&[]), 0);
+        };
+        pulse(&mut vm);
+        pulse(&mut vm);
+        assert_eq!((vm.words[0], vm.words[1]), (2, 0));
+        assert!(!vm.output(0) && vm.output(1));
+        pulse(&mut vm);
+        assert_eq!((vm.words[0], vm.words[1]), (3, -1));
+        assert!(vm.output(0));
+        // Holding the input only counts once.
+        vm.scan(&image(&[0]), 0);
+        vm.scan(&image(&[0]), 0);
+        assert_eq!(vm.words[0], 4);
+        vm.scan(&image(&[1]), 0);
+        assert_eq!((vm.words[0], vm.words[1]), (0, 2));
+    }
+
+    #[test]
+    fn test_memory_kept_between_scans_cleared_on_load() {
+        // MW0 := MW0 + 1
+        let program = [
+            A::N(Op::LdW, 0),
+            A::Push(1),
+            A::Op(Op::Add),
+            A::N(Op::StW, 0),
+        ];
+        let mut vm = running(&program);
+        for now in 0..5 {
+            vm.scan(&image(&[]), now);
+        }
+        assert_eq!(vm.words[0], 5);
+        vm.stop();
+        vm.start();
+        vm.scan(&image(&[]), 5);
+        assert_eq!(vm.words[0], 6);
+        vm.load(&asm(&program)).unwrap();
+        assert_eq!(vm.words[0], 0);
+    }
+}
@@ -0,0 +1,67 @@
+#!/usr/bin/env python3
+"""Upload a user logic program image, or a rule table, to the board over
+the USB console.
+
+Usage: upload-program.py PORT IMAGE
+       upload-program.py --rules PORT RULES
+
+The image is the header plus code, as written by handyplc-stc. The rule
+table is a text file (see firmware/src/rules.rs). Erasing either keeps
+the other. Needs pyserial.
+"""
+
+import sys
+import time
+
+import serial
+
+# As much as the console's `write` takes at once.
+CHUNK = 4
+# Where the rule table's text goes, and the room for it (see
+# firmware/src/program.rs).
+RULES_OFFSET = 0x1F000
+RULES_SIZE = 0x1000
+
+
+def command(port, line):
+    port.write((line + "\n").encode())
+    reply = port.readline().decode().strip()
+    if not reply.startswith("OK"):
+        sys.exit(f"{line.split()[0]}: {reply or 'no reply'}")
+    return reply
+
+
+def upload(path, erase, offset, data, finish):
+    with serial.Serial(path, timeout=2) as port:
+        command(port, erase)
+    # The board resets to erase, so wait for it to come back.
+    time.sleep(5)
+    with serial.Serial(path, timeout=2) as port:
+        for at in range(0, len(data), CHUNK):
+            chunk = data[at : at + CHUNK]
+            command(port, f"write {offset + at:x} {chunk.hex()}")
+        for line in finish:
+            reply = command(port, line)
+        print(reply)
+
+
+def main():
+    args = sys.argv[1:]
+    rules = args[:1] == ["--rules"]
+    if rules:
+        args = args[1:]
+    if len(args) != 2:
+        sys.exit(__doc__.strip())
+    path, data = args[0], open(args[1], "rb").read()
+
+    if rules:
+        # The table ends at the first erased byte.
+        if len(data) > RULES_SIZE or b"\xff" in data:
+            sys.exit(f"{args[1]}: not a rule table of up to {RULES_SIZE} bytes")
+        upload(path, "erase rules", RULES_OFFSET, data, ["load rules"])
+    else:
+        upload(path, "erase", 0, data, ["load", "run", "status"])
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,143 @@
+//! The parsed program.
+use crate::error::Span;
+use crate::lexer::Address;
+
+/// A name as written, with the case-folded form used to look it up.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Ident {
+    pub text: String,
+    pub key: String,
+    pub span: Span,
+}
+
+impl Ident {
+    pub fn new(text: &str, span: Span) -> Ident {
+        Ident {
+            text: text.into(),
+            key: text.to_ascii_uppercase(),
+            span,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub name: Ident,
+    pub vars: Vec<VarDecl>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug)]
+pub struct VarDecl {
+    pub name: Ident,
+    pub at: Option<(Address, Span)>,
+    pub ty: Ident,
+    pub init: Option<Expr>,
+}
+
+#[derive(Debug)]
+pub enum Stmt {
+    Assign {
+        target: Expr,
+        value: Expr,
+    },
+    Call {
+        instance: Ident,
+        args: Vec<Arg>,
+    },
+    If {
+        arms: Vec<(Expr, Vec<Stmt>)>,
+        otherwise: Vec<Stmt>,
+    },
+    Case {
+        selector: Expr,
+        arms: Vec<CaseArm>,
+        otherwise: Vec<Stmt>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Arg {
+    pub name: Ident,
+    pub value: Expr,
+}
+
+#[derive(Debug)]
+pub struct CaseArm {
+    pub labels: Vec<CaseLabel>,
+    pub body: Vec<Stmt>,
+}
+
+/// A single value has `lo == hi`.
+#[derive(Debug)]
+pub struct CaseLabel {
+    pub lo: i64,
+    pub hi: i64,
+    pub span: Span,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl BinaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "OR",
+            BinaryOp::Xor => "XOR",
+            BinaryOp::And => "AND",
+            BinaryOp::Eq => "=",
+            BinaryOp::Ne => "<>",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "MOD",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum ExprKind {
+    Bool(bool),
+    Int(i64),
+    Time(i64),
+    Var(Ident),
+    Address(Address),
+    /// A function block output, e.g. `t1.Q`.
+    Member(Ident, Ident),
+    Unary(UnaryOp, Box<Expr>),
+    /// The span is the operator's.
+    Binary(BinaryOp, Span, Box<Expr>, Box<Expr>),
+    Call(Ident, Vec<Expr>),
+}
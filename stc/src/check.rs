@@ -0,0 +1,647 @@
+//! Type checking, and everything else that can be wrong with a program
+//! that parses. Carries on after an error to report as many as it can;
+//! an expression that's already been reported has no type, and doesn't
+//! cause more errors.
+use crate::ast::*;
+use crate::error::{Error, Span};
+use crate::lexer::Address;
+use crate::symbols::{locate, FbKind, Required, Storage, Symbol, Symbols, Type};
+
+/// Type conversion functions: name, argument type, result type.
+pub const CONVERSIONS: [(&str, Type, Type); 4] = [
+    ("BOOL_TO_INT", Type::Bool, Type::Int),
+    ("INT_TO_BOOL", Type::Int, Type::Bool),
+    ("TIME_TO_INT", Type::Time, Type::Int),
+    ("INT_TO_TIME", Type::Int, Type::Time),
+];
+
+struct Checker<'a> {
+    symbols: &'a Symbols,
+    errors: Vec<Error>,
+}
+
+pub fn check(program: &Program, symbols: &Symbols) -> Vec<Error> {
+    let mut checker = Checker {
+        symbols,
+        errors: Vec::new(),
+    };
+    for var in &program.vars {
+        if let Some(init) = &var.init {
+            checker.initial_value(var, init);
+        }
+    }
+    checker.statements(&program.body);
+    checker.errors
+}
+
+impl Checker<'_> {
+    fn error(&mut self, span: Span, message: impl Into<String>) {
+        self.errors.push(Error::new(span, message));
+    }
+
+    fn initial_value(&mut self, var: &VarDecl, init: &Expr) {
+        let ty = match self.symbols.get(&var.name) {
+            Some(Symbol::Var {
+                storage: Storage::Input(_),
+                ..
+            }) => {
+                return self.error(init.span, "inputs can't have an initial value");
+            }
+            Some(Symbol::Var { ty, .. }) => ty,
+            Some(Symbol::Fb { kind, .. }) => {
+                let message = format!("a {} can't have an initial value", kind.name());
+                return self.error(init.span, message);
+            }
+            None => return,
+        };
+        if !matches!(
+            init.kind,
+            ExprKind::Bool(_) | ExprKind::Int(_) | ExprKind::Time(_)
+        ) {
+            return self.error(init.span, "initial values must be literals");
+        }
+        self.expect(
+            init,
+            ty,
+            &format!("the initial value of '{}'", var.name.text),
+        );
+    }
+
+    fn statements(&mut self, body: &[Stmt]) {
+        for stmt in body {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Assign { target, value } => {
+                let ty = self.target(target);
+                let actual = self.expr(value);
+                if let (Some(ty), Some(actual)) = (ty, actual) {
+                    if ty != actual {
+                        let what = match &target.kind {
+                            ExprKind::Var(name) => format!("'{}'", name.text),
+                            ExprKind::Address(address) => address.to_string(),
+                            _ => "the target".into(),
+                        };
+                        let message = format!(
+                            "can't assign {} to {}, which is {}",
+                            actual.name(),
+                            what,
+                            ty.name()
+                        );
+                        self.error(value.span, message);
+                    }
+                }
+            }
+            Stmt::Call { instance, args } => self.call(instance, args),
+            Stmt::If { arms, otherwise } => {
+                for (condition, body) in arms {
+                    self.expect(condition, Type::Bool, "an IF condition");
+                    self.statements(body);
+                }
+                self.statements(otherwise);
+            }
+            Stmt::Case {
+                selector,
+                arms,
+                otherwise,
+            } => {
+                self.expect(selector, Type::Int, "a CASE selector");
+                let mut seen: Vec<(i64, i64)> = Vec::new();
+                for arm in arms {
+                    for label in &arm.labels {
+                        self.case_label(label, &seen);
+                        seen.push((label.lo, label.hi));
+                    }
+                    self.statements(&arm.body);
+                }
+                self.statements(otherwise);
+            }
+        }
+    }
+
+    fn case_label(&mut self, label: &CaseLabel, seen: &[(i64, i64)]) {
+        let range = i32::MIN as i64..=i32::MAX as i64;
+        if !range.contains(&label.lo) || !range.contains(&label.hi) {
+            return self.error(label.span, "case label out of range for INT");
+        }
+        if label.lo > label.hi {
+            let message = format!("empty case range {}..{}", label.lo, label.hi);
+            return self.error(label.span, message);
+        }
+        if seen
+            .iter()
+            .any(|&(lo, hi)| label.lo <= hi && lo <= label.hi)
+        {
+            let message = if label.lo == label.hi {
+                format!("case label {} is already covered", label.lo)
+            } else {
+                format!(
+                    "case range {}..{} overlaps an earlier label",
+                    label.lo, label.hi
+                )
+            };
+            self.error(label.span, message);
+        }
+    }
+
+    /// The type of a variable that's assigned to, if it can be.
+    fn target(&mut self, target: &Expr) -> Option<Type> {
+        match &target.kind {
+            ExprKind::Var(name) => match self.symbols.get(name) {
+                Some(Symbol::Var { ty, storage }) if storage.is_writable() => Some(ty),
+                Some(Symbol::Var { storage, .. }) => {
+                    let message = match storage {
+                        Storage::Input(_) => {
+                            format!("'{}' is an input, and can't be assigned to", name.text)
+                        }
+                        _ => format!("'{}' is read-only", name.text),
+                    };
+                    self.error(name.span, message);
+                    None
+                }
+                Some(Symbol::Fb { kind, .. }) => {
+                    let message = format!(
+                        "'{}' is a {}; call it to set its inputs, as in {}({} := ...)",
+                        name.text,
+                        kind.name(),
+                        name.text,
+                        kind.inputs()[0].0
+                    );
+                    self.error(name.span, message);
+                    None
+                }
+                None => {
+                    self.undeclared(name);
+                    None
+                }
+            },
+            ExprKind::Address(address) => match self.address(*address, target.span)? {
+                Storage::Input(_) => {
+                    let message = format!("{} is an input, and can't be assigned to", address);
+                    self.error(target.span, message);
+                    None
+                }
+                _ => Some(Type::Bool),
+            },
+            ExprKind::Member(instance, output) => {
+                let message = format!(
+                    "'{}.{}' is a function block output, and can't be assigned to",
+                    instance.text, output.text
+                );
+                self.error(output.span, message);
+                None
+            }
+            _ => {
+                self.error(target.span, "expected a variable to assign to");
+                None
+            }
+        }
+    }
+
+    fn call(&mut self, instance: &Ident, args: &[Arg]) {
+        let kind = match self.symbols.get(instance) {
+            Some(Symbol::Fb { kind, .. }) => kind,
+            Some(Symbol::Var { ty, .. }) => {
+                let message = format!(
+                    "'{}' is a {}, not a function block, so can't be called",
+                    instance.text,
+                    ty.name()
+                );
+                self.error(instance.span, message);
+                return self.args(args);
+            }
+            None => {
+                self.undeclared(instance);
+                return self.args(args);
+            }
+        };
+        let inputs = kind.inputs();
+        for (n, arg) in args.iter().enumerate() {
+            let Some(&(name, ty, _)) = inputs.iter().find(|input| input.0 == arg.name.key) else {
+                let message = format!(
+                    "{} has no input '{}'; its inputs are {}",
+                    kind.name(),
+                    arg.name.text,
+                    list(inputs.iter().map(|input| input.0))
+                );
+                self.error(arg.name.span, message);
+                self.expr(&arg.value);
+                continue;
+            };
+            if args[..n].iter().any(|a| a.name.key == arg.name.key) {
+                let message = format!("input '{}' is given twice", name);
+                self.error(arg.name.span, message);
+            }
+            self.expect(
+                &arg.value,
+                ty,
+                &format!("input {} of {}", name, kind.name()),
+            );
+        }
+        for &(name, _, required) in inputs {
+            if required == Required::Yes && !args.iter().any(|arg| arg.name.key == name) {
+                let message = format!(
+                    "the call of '{}' is missing input {}; every call must give {}",
+                    instance.text,
+                    name,
+                    list(inputs.iter().filter(|i| i.2 == Required::Yes).map(|i| i.0))
+                );
+                self.error(instance.span, message);
+            }
+        }
+    }
+
+    fn args(&mut self, args: &[Arg]) {
+        for arg in args {
+            self.expr(&arg.value);
+        }
+    }
+
+    fn undeclared(&mut self, name: &Ident) {
+        self.error(name.span, format!("'{}' isn't declared", name.text));
+    }
+
+    fn address(&mut self, address: Address, span: Span) -> Option<Storage> {
+        match locate(address, span, Type::Bool) {
+            Ok(storage) => Some(storage),
+            Err(error) => {
+                self.errors.push(error);
+                None
+            }
+        }
+    }
+
+    /// Check an expression has the type a context needs.
+    fn expect(&mut self, expr: &Expr, ty: Type, context: &str) {
+        if let Some(actual) = self.expr(expr) {
+            if actual != ty {
+                let message = format!("{} must be {}, not {}", context, ty.name(), actual.name());
+                self.error(expr.span, message);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Option<Type> {
+        match &expr.kind {
+            ExprKind::Bool(_) => Some(Type::Bool),
+            ExprKind::Int(n) => {
+                if i32::try_from(*n).is_err() {
+                    self.error(expr.span, format!("{} is out of range for INT", n));
+                    return None;
+                }
+                Some(Type::Int)
+            }
+            ExprKind::Time(ms) => {
+                if i32::try_from(*ms).is_err() {
+                    self.error(
+                        expr.span,
+                        "TIME literal is too long; the most is about 24 days",
+                    );
+                    return None;
+                }
+                Some(Type::Time)
+            }
+            ExprKind::Var(name) => match self.symbols.get(name) {
+                Some(Symbol::Var { ty, .. }) => Some(ty),
+                Some(Symbol::Fb { kind, .. }) => {
+                    let message = format!(
+                        "'{}' is a {}; use one of its outputs, like {}.{}",
+                        name.text,
+                        kind.name(),
+                        name.text,
+                        kind.outputs()[0].0
+                    );
+                    self.error(name.span, message);
+                    None
+                }
+                None => {
+                    self.undeclared(name);
+                    None
+                }
+            },
+            ExprKind::Address(address) => {
+                self.address(*address, expr.span)?;
+                Some(Type::Bool)
+            }
+            ExprKind::Member(instance, output) => {
+                let kind = self.instance(instance)?;
+                let found = kind.outputs().iter().find(|o| o.0 == output.key);
+                if found.is_none() {
+                    let message = format!(
+                        "{} has no output '{}'; its outputs are {}",
+                        kind.name(),
+                        output.text,
+                        list(kind.outputs().iter().map(|o| o.0))
+                    );
+                    self.error(output.span, message);
+                }
+                found.map(|o| o.1)
+            }
+            ExprKind::Unary(op, operand) => {
+                let ty = self.expr(operand)?;
+                let ok = match op {
+                    UnaryOp::Neg => ty != Type::Bool,
+                    UnaryOp::Not => ty == Type::Bool,
+                };
+                if !ok {
+                    let (symbol, types) = match op {
+                        UnaryOp::Neg => ("-", "INT or TIME"),
+                        UnaryOp::Not => ("NOT", "BOOL"),
+                    };
+                    let message = format!("{} needs {}, not {}", symbol, types, ty.name());
+                    self.error(operand.span, message);
+                    return None;
+                }
+                Some(ty)
+            }
+            ExprKind::Binary(op, span, lhs, rhs) => {
+                let lhs = self.expr(lhs);
+                let rhs = self.expr(rhs);
+                let (lhs, rhs) = (lhs?, rhs?);
+                let result = binary_type(*op, lhs, rhs);
+                if result.is_none() {
+                    let message = format!(
+                        "can't use {} on {} and {}{}",
+                        op.symbol(),
+                        lhs.name(),
+                        rhs.name(),
+                        binary_hint(*op, lhs, rhs)
+                    );
+                    self.error(*span, message);
+                }
+                result
+            }
+            ExprKind::Call(function, args) => {
+                let Some(&(name, from, to)) = CONVERSIONS.iter().find(|c| c.0 == function.key)
+                else {
+                    let message = match self.symbols.get(function) {
+                        Some(Symbol::Fb { .. }) => format!(
+                            "function blocks are called as statements, e.g. '{}(...);', and their outputs read like {}.Q",
+                            function.text, function.text
+                        ),
+                        _ => format!(
+                            "unknown function '{}'; the functions are {}",
+                            function.text,
+                            list(CONVERSIONS.iter().map(|c| c.0))
+                        ),
+                    };
+                    self.error(function.span, message);
+                    for arg in args {
+                        self.expr(arg);
+                    }
+                    return None;
+                };
+                if args.len() != 1 {
+                    let message = format!("{} takes one argument, not {}", name, args.len());
+                    self.error(function.span, message);
+                    return Some(to);
+                }
+                self.expect(&args[0], from, &format!("the argument of {}", name));
+                Some(to)
+            }
+        }
+    }
+
+    fn instance(&mut self, name: &Ident) -> Option<FbKind> {
+        match self.symbols.get(name) {
+            Some(Symbol::Fb { kind, .. }) => Some(kind),
+            Some(Symbol::Var { ty, .. }) => {
+                let message = format!(
+                    "'{}' is a {}, not a function block, so has no outputs",
+                    name.text,
+                    ty.name()
+                );
+                self.error(name.span, message);
+                None
+            }
+            None => {
+                self.undeclared(name);
+                None
+            }
+        }
+    }
+}
+
+/// The result of a binary operator, if it works on those types.
+pub fn binary_type(op: BinaryOp, lhs: Type, rhs: Type) -> Option<Type> {
+    use Type::*;
+    match (op, lhs, rhs) {
+        (BinaryOp::And | BinaryOp::Or | BinaryOp::Xor, Bool, Bool) => Some(Bool),
+        (BinaryOp::Eq | BinaryOp::Ne, a, b) if a == b => Some(Bool),
+        (BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge, a, b)
+            if a == b && a != Bool =>
+        {
+            Some(Bool)
+        }
+        (BinaryOp::Add | BinaryOp::Sub, a, b) if a == b && a != Bool => Some(a),
+        (BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod, Int, Int) => Some(Int),
+        (BinaryOp::Mul | BinaryOp::Div, Time, Int) => Some(Time),
+        _ => None,
+    }
+}
+
+fn binary_hint(op: BinaryOp, lhs: Type, rhs: Type) -> &'static str {
+    if lhs != rhs
+        && (lhs == Type::Int || rhs == Type::Int)
+        && lhs != Type::Bool
+        && rhs != Type::Bool
+    {
+        if matches!(op, BinaryOp::Mul | BinaryOp::Div) {
+            "; put the TIME first"
+        } else {
+            "; convert with INT_TO_TIME or TIME_TO_INT"
+        }
+    } else if lhs != rhs && (lhs == Type::Bool || rhs == Type::Bool) {
+        "; convert with BOOL_TO_INT or INT_TO_BOOL"
+    } else {
+        ""
+    }
+}
+
+/// "A, B and C"
+fn list<'a>(items: impl Iterator<Item = &'a str>) -> String {
+    let items: Vec<_> = items.collect();
+    match items.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer, parser};
+
+    fn errors(source: &str) -> Vec<String> {
+        let program = parser::parse(&lexer::lex(source).unwrap()).unwrap();
+        match Symbols::declare(&program) {
+            Ok(symbols) => check(&program, &symbols),
+            Err(errors) => errors,
+        }
+        .into_iter()
+        .map(|e| e.message)
+        .collect()
+    }
+
+    fn body(statements: &str) -> Vec<String> {
+        errors(&format!(
+            "PROGRAM p
+             VAR b : BOOL; i : INT; t : TIME; q AT %QX0.1 : BOOL; x AT %IX0.2 : BOOL;
+                 tmr : TON; up : CTU; END_VAR
+             {}
+             END_PROGRAM",
+            statements
+        ))
+    }
+
+    #[test]
+    fn test_well_typed() {
+        let ok: Vec<String> = Vec::new();
+        assert_eq!(
+            body(
+                "b := x AND NOT q OR (i > 3) XOR (t <= T#1s);
+                 i := -i * 2 MOD 5 + TIME_TO_INT(t / 2) - up.CV;
+                 t := tmr.ET + INT_TO_TIME(SPINDLE_RPM);
+                 %QX0.3 := SPINDLE_ON = b;
+                 tmr(IN := b, PT := T#2s);
+                 up(CU := x, PV := 10);
+                 IF up.Q THEN q := TRUE; END_IF;
+                 CASE i OF 1..3, 5: b := FALSE; END_CASE;"
+            ),
+            ok
+        );
+    }
+
+    #[test]
+    fn test_type_errors() {
+        assert_eq!(body("b := i;"), ["can't assign INT to 'b', which is BOOL"]);
+        assert_eq!(
+            body("i := i + t;"),
+            ["can't use + on INT and TIME; convert with INT_TO_TIME or TIME_TO_INT"]
+        );
+        assert_eq!(
+            body("t := 2 * t;"),
+            ["can't use * on INT and TIME; put the TIME first"]
+        );
+        assert_eq!(
+            body("IF i THEN END_IF;"),
+            ["an IF condition must be BOOL, not INT"]
+        );
+        assert_eq!(body("b := NOT i;"), ["NOT needs BOOL, not INT"]);
+        // One error, not another for each use of the bad value.
+        assert_eq!(body("b := nope AND b OR i;"), ["'nope' isn't declared"]);
+        assert_eq!(
+            body("i := 3000000000;"),
+            ["3000000000 is out of range for INT"]
+        );
+    }
+
+    #[test]
+    fn test_assignment_errors() {
+        assert_eq!(
+            body("x := TRUE;"),
+            ["'x' is an input, and can't be assigned to"]
+        );
+        assert_eq!(
+            body("%IX0.4 := TRUE;"),
+            ["%IX0.4 is an input, and can't be assigned to"]
+        );
+        assert_eq!(body("SPINDLE_ON := TRUE;"), ["'SPINDLE_ON' is read-only"]);
+        assert_eq!(
+            body("tmr.Q := TRUE;"),
+            ["'tmr.Q' is a function block output, and can't be assigned to"]
+        );
+        assert_eq!(
+            body("b := %QX0.16;"),
+            ["there's no %QX0.16; the addresses are %QX0.0 to %QX0.15"]
+        );
+    }
+
+    #[test]
+    fn test_call_errors() {
+        assert_eq!(
+            body("tmr(IN := b);"),
+            ["the call of 'tmr' is missing input PT; every call must give IN and PT"]
+        );
+        assert_eq!(
+            body("tmr(IN := b, PT := 5, CLK := b);"),
+            [
+                "input PT of TON must be TIME, not INT",
+                "TON has no input 'CLK'; its inputs are IN and PT",
+            ]
+        );
+        assert_eq!(
+            body("tmr(IN := b, in := b, PT := t);"),
+            ["input 'IN' is given twice"]
+        );
+        assert_eq!(
+            body("b(IN := b);"),
+            ["'b' is a BOOL, not a function block, so can't be called"]
+        );
+        assert_eq!(
+            body("b := tmr;"),
+            ["'tmr' is a TON; use one of its outputs, like tmr.Q"]
+        );
+        assert_eq!(
+            body("b := up.ET > 0;"),
+            ["CTU has no output 'ET'; its outputs are Q and CV"]
+        );
+        assert_eq!(
+            body("b := SQRT(i);"),
+            ["unknown function 'SQRT'; the functions are BOOL_TO_INT, INT_TO_BOOL, TIME_TO_INT and INT_TO_TIME"]
+        );
+    }
+
+    #[test]
+    fn test_case_labels() {
+        assert_eq!(
+            body("CASE i OF 1, 2..4: ; 3: ; 5..9, 0..1: ; 7..6: ; END_CASE;"),
+            [
+                "case label 3 is already covered",
+                "case range 0..1 overlaps an earlier label",
+                "empty case range 7..6",
+            ]
+        );
+        assert_eq!(
+            body("CASE t OF 1: ; END_CASE;"),
+            ["a CASE selector must be INT, not TIME"]
+        );
+    }
+
+    #[test]
+    fn test_declaration_errors() {
+        assert_eq!(
+            errors(
+                "PROGRAM p VAR
+                 a : BOOL; A : INT; SPINDLE_ON : BOOL; c : REAL;
+                 d AT %IX0.1 : INT; e AT %QX1.0 : BOOL; f AT %QX0.0 : TON;
+                 END_VAR END_PROGRAM"
+            ),
+            [
+                "'A' is already declared on line 2",
+                "'SPINDLE_ON' is a built-in name, and can't be redeclared",
+                "unknown type 'REAL'; the types are BOOL, INT, TIME, TON, TOF, TP, R_TRIG, F_TRIG, CTU and CTD",
+                "%IX0.1 is a single bit, so can only be a BOOL",
+                "there's no %QX1.0; the addresses are %QX0.0 to %QX0.15",
+                "function blocks can't have an address",
+            ]
+        );
+        assert_eq!(
+            errors(
+                "PROGRAM p VAR
+                 a : BOOL := 1; x AT %IX0.0 : BOOL := TRUE; t : TON := T#1s; i : INT := 2 + 3;
+                 END_VAR END_PROGRAM"
+            ),
+            [
+                "the initial value of 'a' must be BOOL, not INT",
+                "inputs can't have an initial value",
+                "a TON can't have an initial value",
+                "initial values must be literals",
+            ]
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! Code generation, for a program that's been checked.
+use crate::ast::*;
+use crate::bytecode::{Op, Operand, MAX_CODE, STACK_DEPTH};
+use crate::error::{Error, Span};
+use crate::lexer::Area;
+use crate::symbols::{FbKind, Storage, Symbol, Symbols};
+
+struct Gen<'a> {
+    symbols: &'a Symbols,
+    code: Vec<u8>,
+    depth: usize,
+}
+
+pub fn generate(program: &Program, symbols: &Symbols) -> Result<Vec<u8>, Error> {
+    let mut gen = Gen {
+        symbols,
+        code: Vec::new(),
+        depth: 0,
+    };
+    if let Some(done) = symbols.first_scan_done {
+        gen.initial_values(program, done)?;
+    }
+    gen.statements(&program.body)?;
+    if gen.code.len() > MAX_CODE {
+        return Err(Error::new(
+            program.name.span,
+            format!(
+                "the program is {} bytes; the device takes at most {}",
+                gen.code.len(),
+                MAX_CODE
+            ),
+        ));
+    }
+    Ok(gen.code)
+}
+
+/// How many values an instruction pops and pushes.
+fn stack_effect(op: Op) -> (usize, usize) {
+    match op {
+        Op::End | Op::Jmp => (0, 0),
+        Op::LdI
+        | Op::LdQ
+        | Op::LdM
+        | Op::LdS
+        | Op::LdW
+        | Op::LdSw
+        | Op::Push
+        | Op::LdEdgeQ
+        | Op::LdTimerQ
+        | Op::LdTimerEt
+        | Op::LdCounterQ
+        | Op::LdCounterCv => (0, 1),
+        Op::StQ
+        | Op::StM
+        | Op::StW
+        | Op::SetQ
+        | Op::ResetQ
+        | Op::SetM
+        | Op::ResetM
+        | Op::Drop
+        | Op::Jmpc
+        | Op::Jmpcn
+        | Op::RTrig
+        | Op::FTrig => (1, 0),
+        Op::Not | Op::Neg => (1, 1),
+        Op::Dup => (1, 2),
+        Op::And
+        | Op::Or
+        | Op::Xor
+        | Op::Add
+        | Op::Sub
+        | Op::Mul
+        | Op::Div
+        | Op::Mod
+        | Op::Eq
+        | Op::Ne
+        | Op::Lt
+        | Op::Le
+        | Op::Gt
+        | Op::Ge => (2, 1),
+        Op::Ton | Op::Tof | Op::Tp => (2, 0),
+        Op::Ctu | Op::Ctd => (3, 0),
+    }
+}
+
+impl Gen<'_> {
+    /// Emit an instruction; the span is blamed if the stack overflows.
+    fn op(&mut self, op: Op, span: Span) -> Result<(), Error> {
+        let (pops, pushes) = stack_effect(op);
+        self.depth = self.depth - pops + pushes;
+        if self.depth > STACK_DEPTH {
+            return Err(Error::new(
+                span,
+                format!(
+                    "expression is too deeply nested; the device has room for {} values",
+                    STACK_DEPTH
+                ),
+            ));
+        }
+        self.code.push(op as u8);
+        Ok(())
+    }
+
+    fn op_index(&mut self, op: Op, n: u8, span: Span) -> Result<(), Error> {
+        debug_assert!(matches!(op.operand(), Operand::Index(size) if (n as usize) < size));
+        self.op(op, span)?;
+        self.code.push(n);
+        Ok(())
+    }
+
+    fn push(&mut self, value: i64, span: Span) -> Result<(), Error> {
+        self.op(Op::Push, span)?;
+        self.code.extend_from_slice(&(value as i32).to_le_bytes());
+        Ok(())
+    }
+
+    /// A jump to be patched once the target is known.
+    fn jump(&mut self, op: Op, span: Span) -> Result<usize, Error> {
+        self.op(op, span)?;
+        self.code.extend_from_slice(&[0, 0]);
+        Ok(self.code.len() - 2)
+    }
+
+    /// Point a jump here.
+    fn patch(&mut self, at: usize) {
+        let target = (self.code.len() as u16).to_le_bytes();
+        self.code[at..at + 2].copy_from_slice(&target);
+    }
+
+    /// Set the initial values on the first scan, then the marker that
+    /// says it's done.
+    fn initial_values(&mut self, program: &Program, done: u8) -> Result<(), Error> {
+        let span = program.name.span;
+        self.op_index(Op::LdM, done, span)?;
+        let skip = self.jump(Op::Jmpc, span)?;
+        for var in &program.vars {
+            if let Some(init) = &var.init {
+                self.expr(init)?;
+                self.store(var.name.span, &ExprKind::Var(var.name.clone()))?;
+            }
+        }
+        self.push(1, span)?;
+        self.op_index(Op::StM, done, span)?;
+        self.patch(skip);
+        Ok(())
+    }
+
+    fn statements(&mut self, body: &[Stmt]) -> Result<(), Error> {
+        for stmt in body {
+            self.statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<(), Error> {
+        match stmt {
+            Stmt::Assign { target, value } => {
+                self.expr(value)?;
+                self.store(target.span, &target.kind)
+            }
+            Stmt::Call { instance, args } => self.call(instance, args),
+            Stmt::If { arms, otherwise } => {
+                let mut ends = Vec::new();
+                for (n, (condition, body)) in arms.iter().enumerate() {
+                    self.expr(condition)?;
+                    let next = self.jump(Op::Jmpcn, condition.span)?;
+                    self.statements(body)?;
+                    if n + 1 < arms.len() || !otherwise.is_empty() {
+                        ends.push(self.jump(Op::Jmp, condition.span)?);
+                    }
+                    self.patch(next);
+                }
+                self.statements(otherwise)?;
+                for end in ends {
+                    self.patch(end);
+                }
+                Ok(())
+            }
+            Stmt::Case {
+                selector,
+                arms,
+                otherwise,
+            } => self.case(selector, arms, otherwise),
+        }
+    }
+
+    /// The selector stays on the stack while the labels are compared,
+    /// and is dropped on the way into the chosen arm.
+    fn case(&mut self, selector: &Expr, arms: &[CaseArm], otherwise: &[Stmt]) -> Result<(), Error> {
+        self.expr(selector)?;
+        let mut entries = Vec::new();
+        for arm in arms {
+            let mut matched = Vec::new();
+            for label in &arm.labels {
+                let span = label.span;
+                self.op(Op::Dup, span)?;
+                self.push(label.lo, span)?;
+                if label.lo == label.hi {
+                    self.op(Op::Eq, span)?;
+                    matched.push(self.jump(Op::Jmpc, span)?);
+                } else {
+                    self.op(Op::Ge, span)?;
+                    let below = self.jump(Op::Jmpcn, span)?;
+                    self.op(Op::Dup, span)?;
+                    self.push(label.hi, span)?;
+                    self.op(Op::Le, span)?;
+                    matched.push(self.jump(Op::Jmpc, span)?);
+                    self.patch(below);
+                }
+            }
+            entries.push(matched);
+        }
+        let mut ends = Vec::new();
+        let no_match = self.jump(Op::Jmp, selector.span)?;
+        let depth = self.depth;
+        for (arm, matched) in arms.iter().zip(entries) {
+            for at in matched {
+                self.patch(at);
+            }
+            self.depth = depth;
+            self.op(Op::Drop, selector.span)?;
+            self.statements(&arm.body)?;
+            ends.push(self.jump(Op::Jmp, selector.span)?);
+        }
+        self.patch(no_match);
+        self.depth = depth;
+        self.op(Op::Drop, selector.span)?;
+        self.statements(otherwise)?;
+        for end in ends {
+            self.patch(end);
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, instance: &Ident, args: &[Arg]) -> Result<(), Error> {
+        let Some(Symbol::Fb { kind, index }) = self.symbols.get(instance) else {
+            unreachable!("checked");
+        };
+        for &(name, _, _) in kind.inputs() {
+            match args.iter().find(|arg| arg.name.key == name) {
+                Some(arg) => self.expr(&arg.value)?,
+                // Only an optional input can be left out.
+                None => self.push(0, instance.span)?,
+            }
+        }
+        let op = match kind {
+            FbKind::Ton => Op::Ton,
+            FbKind::Tof => Op::Tof,
+            FbKind::Tp => Op::Tp,
+            FbKind::RTrig => Op::RTrig,
+            FbKind::FTrig => Op::FTrig,
+            FbKind::Ctu => Op::Ctu,
+            FbKind::Ctd => Op::Ctd,
+        };
+        self.op_index(op, index, instance.span)
+    }
+
+    fn store(&mut self, span: Span, target: &ExprKind) -> Result<(), Error> {
+        let storage = match target {
+            ExprKind::Var(name) => match self.symbols.get(name) {
+                Some(Symbol::Var { storage, .. }) => storage,
+                _ => unreachable!("checked"),
+            },
+            ExprKind::Address(address) if address.area == Area::Output => {
+                Storage::Output(address.bit as u8)
+            }
+            _ => unreachable!("checked"),
+        };
+        match storage {
+            Storage::Output(n) => self.op_index(Op::StQ, n, span),
+            Storage::Marker(n) => self.op_index(Op::StM, n, span),
+            Storage::Word(n) => self.op_index(Op::StW, n, span),
+            _ => unreachable!("checked"),
+        }
+    }
+
+    fn load(&mut self, storage: Storage, span: Span) -> Result<(), Error> {
+        match storage {
+            Storage::Input(n) => self.op_index(Op::LdI, n, span),
+            Storage::Output(n) => self.op_index(Op::LdQ, n, span),
+            Storage::Marker(n) => self.op_index(Op::LdM, n, span),
+            Storage::Word(n) => self.op_index(Op::LdW, n, span),
+            Storage::SystemBit(n) => self.op_index(Op::LdS, n, span),
+            Storage::SystemWord(n) => self.op_index(Op::LdSw, n, span),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<(), Error> {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Bool(b) => self.push(*b as i64, span),
+            ExprKind::Int(n) | ExprKind::Time(n) => self.push(*n, span),
+            ExprKind::Var(name) => match self.symbols.get(name) {
+                Some(Symbol::Var { storage, .. }) => self.load(storage, span),
+                _ => unreachable!("checked"),
+            },
+            ExprKind::Address(address) => {
+                let bit = address.bit as u8;
+                match address.area {
+                    Area::Input => self.load(Storage::Input(bit), span),
+                    Area::Output => self.load(Storage::Output(bit), span),
+                }
+            }
+            ExprKind::Member(instance, output) => {
+                let Some(Symbol::Fb { kind, index }) = self.symbols.get(instance) else {
+                    unreachable!("checked");
+                };
+                let op = match (kind, output.key.as_str()) {
+                    (FbKind::Ton | FbKind::Tof | FbKind::Tp, "Q") => Op::LdTimerQ,
+                    (FbKind::Ton | FbKind::Tof | FbKind::Tp, _) => Op::LdTimerEt,
+                    (FbKind::RTrig | FbKind::FTrig, _) => Op::LdEdgeQ,
+                    (FbKind::Ctu | FbKind::Ctd, "Q") => Op::LdCounterQ,
+                    (FbKind::Ctu | FbKind::Ctd, _) => Op::LdCounterCv,
+                };
+                self.op_index(op, index, span)
+            }
+            ExprKind::Unary(op, operand) => {
+                self.expr(operand)?;
+                match op {
+                    UnaryOp::Neg => self.op(Op::Neg, span),
+                    UnaryOp::Not => self.op(Op::Not, span),
+                }
+            }
+            ExprKind::Binary(op, op_span, lhs, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                let op = match op {
+                    BinaryOp::Or => Op::Or,
+                    BinaryOp::Xor => Op::Xor,
+                    BinaryOp::And => Op::And,
+                    BinaryOp::Eq => Op::Eq,
+                    BinaryOp::Ne => Op::Ne,
+                    BinaryOp::Lt => Op::Lt,
+                    BinaryOp::Le => Op::Le,
+                    BinaryOp::Gt => Op::Gt,
+                    BinaryOp::Ge => Op::Ge,
+                    BinaryOp::Add => Op::Add,
+                    BinaryOp::Sub => Op::Sub,
+                    BinaryOp::Mul => Op::Mul,
+                    BinaryOp::Div => Op::Div,
+                    BinaryOp::Mod => Op::Mod,
+                };
+                self.op(op, *op_span)
+            }
+            ExprKind::Call(function, args) => {
+                self.expr(&args[0])?;
+                // INT and TIME are both plain numbers on the device, and
+                // BOOLs are already 0 or 1, so only INT_TO_BOOL does
+                // anything.
+                if function.key == "INT_TO_BOOL" {
+                    self.push(0, span)?;
+                    self.op(Op::Ne, span)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use std::fmt::Write;
+
+use crate::bytecode::{Op, Operand};
+
+/// A listing of the code, one instruction per line with its offset.
+/// Anything that doesn't decode is shown as raw bytes.
+pub fn disassemble(code: &[u8]) -> String {
+    let mut out = String::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let Some(op) = Op::from_u8(code[pc]) else {
+            let _ = writeln!(out, "{:04x}  .byte 0x{:02x}", pc, code[pc]);
+            pc += 1;
+            continue;
+        };
+        let len = op.operand().len();
+        let Some(arg) = code.get(pc + 1..pc + 1 + len) else {
+            let _ = writeln!(out, "{:04x}  {} <truncated>", pc, op.name());
+            break;
+        };
+        let _ = match op.operand() {
+            Operand::None => writeln!(out, "{:04x}  {}", pc, op.name()),
+            Operand::Index(_) => writeln!(out, "{:04x}  {} {}", pc, op.name(), arg[0]),
+            Operand::Target => {
+                let target = u16::from_le_bytes([arg[0], arg[1]]);
+                writeln!(out, "{:04x}  {} {:04x}", pc, op.name(), target)
+            }
+            Operand::Immediate => {
+                let value = i32::from_le_bytes([arg[0], arg[1], arg[2], arg[3]]);
+                writeln!(out, "{:04x}  {} {}", pc, op.name(), value)
+            }
+        };
+        pc += 1 + len;
+    }
+    out
+}
@@ -0,0 +1,43 @@
+use std::fmt::Write;
+
+/// A position in the source, both 1-based; columns count characters.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Error {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(span: Span, message: impl Into<String>) -> Error {
+        Error {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// Render in the usual compiler style, with the offending line and a
+    /// caret under the column.
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let Span { line, col } = self.span;
+        let mut out = format!("{}:{}:{}: error: {}\n", file, line, col, self.message);
+        if let Some(text) = source.lines().nth(line.wrapping_sub(1)) {
+            let gutter = line.to_string().len();
+            let _ = writeln!(out, "{:>w$} |", "", w = gutter);
+            let _ = writeln!(out, "{} | {}", line, text);
+            // Keep tabs so the caret lines up however they're shown.
+            let pad: String = text
+                .chars()
+                .take(col.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let _ = writeln!(out, "{:>w$} | {}^", "", pad, w = gutter);
+        }
+        out
+    }
+}
@@ -0,0 +1,471 @@
+//! Splits the source into tokens.
+use crate::error::{Error, Span};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Area {
+    Input,
+    Output,
+}
+
+/// A directly represented variable, `%IX<word>.<bit>` or `%QX<word>.<bit>`.
+/// The range is checked where it's used.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Address {
+    pub area: Area,
+    pub word: u32,
+    pub bit: u32,
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let area = match self.area {
+            Area::Input => 'I',
+            Area::Output => 'Q',
+        };
+        write!(f, "%{}X{}.{}", area, self.word, self.bit)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Keyword {
+    Program,
+    EndProgram,
+    Var,
+    EndVar,
+    At,
+    If,
+    Then,
+    Elsif,
+    Else,
+    EndIf,
+    Case,
+    Of,
+    EndCase,
+    And,
+    Or,
+    Xor,
+    Not,
+    Mod,
+    True,
+    False,
+}
+
+const KEYWORDS: [(&str, Keyword); 20] = [
+    ("PROGRAM", Keyword::Program),
+    ("END_PROGRAM", Keyword::EndProgram),
+    ("VAR", Keyword::Var),
+    ("END_VAR", Keyword::EndVar),
+    ("AT", Keyword::At),
+    ("IF", Keyword::If),
+    ("THEN", Keyword::Then),
+    ("ELSIF", Keyword::Elsif),
+    ("ELSE", Keyword::Else),
+    ("END_IF", Keyword::EndIf),
+    ("CASE", Keyword::Case),
+    ("OF", Keyword::Of),
+    ("END_CASE", Keyword::EndCase),
+    ("AND", Keyword::And),
+    ("OR", Keyword::Or),
+    ("XOR", Keyword::Xor),
+    ("NOT", Keyword::Not),
+    ("MOD", Keyword::Mod),
+    ("TRUE", Keyword::True),
+    ("FALSE", Keyword::False),
+];
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Tok {
+    Ident(String),
+    Keyword(Keyword),
+    Int(i64),
+    /// In milliseconds.
+    Time(i64),
+    Address(Address),
+    Assign,
+    Colon,
+    Semi,
+    Comma,
+    Dot,
+    DotDot,
+    LParen,
+    RParen,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Amp,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eof,
+}
+
+impl Tok {
+    /// How the token is described in error messages.
+    pub fn describe(&self) -> String {
+        match self {
+            Tok::Ident(name) => format!("'{}'", name),
+            Tok::Keyword(kw) => {
+                let name = KEYWORDS.iter().find(|(_, k)| k == kw).map_or("", |k| k.0);
+                format!("'{}'", name)
+            }
+            Tok::Int(n) => format!("'{}'", n),
+            Tok::Time(_) => "a TIME literal".into(),
+            Tok::Address(address) => format!("'{}'", address),
+            Tok::Eof => "the end of the file".into(),
+            tok => format!("'{}'", tok.symbol()),
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Tok::Assign => ":=",
+            Tok::Colon => ":",
+            Tok::Semi => ";",
+            Tok::Comma => ",",
+            Tok::Dot => ".",
+            Tok::DotDot => "..",
+            Tok::LParen => "(",
+            Tok::RParen => ")",
+            Tok::Plus => "+",
+            Tok::Minus => "-",
+            Tok::Star => "*",
+            Tok::Slash => "/",
+            Tok::Amp => "&",
+            Tok::Eq => "=",
+            Tok::Ne => "<>",
+            Tok::Lt => "<",
+            Tok::Le => "<=",
+            Tok::Gt => ">",
+            Tok::Ge => ">=",
+            _ => "",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Token {
+    pub tok: Tok,
+    pub span: Span,
+    /// Just past the last character.
+    pub end: Span,
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    col: usize,
+}
+
+pub fn lex(source: &str) -> Result<Vec<Token>, Error> {
+    let mut lexer = Lexer {
+        chars: source.chars().collect(),
+        pos: 0,
+        line: 1,
+        col: 1,
+    };
+    let mut tokens = Vec::new();
+    loop {
+        lexer.skip_space()?;
+        let span = lexer.span();
+        let tok = match lexer.peek(0) {
+            None => Tok::Eof,
+            Some(_) => lexer.token()?,
+        };
+        let done = tok == Tok::Eof;
+        let end = lexer.span();
+        tokens.push(Token { tok, span, end });
+        if done {
+            return Ok(tokens);
+        }
+    }
+}
+
+impl Lexer {
+    fn span(&self) -> Span {
+        Span {
+            line: self.line,
+            col: self.col,
+        }
+    }
+
+    fn peek(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.pos + ahead).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek(0) == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_space(&mut self) -> Result<(), Error> {
+        loop {
+            match (self.peek(0), self.peek(1)) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.bump();
+                }
+                (Some('/'), Some('/')) => {
+                    while self.peek(0).is_some_and(|c| c != '\n') {
+                        self.bump();
+                    }
+                }
+                (Some('('), Some('*')) => {
+                    let start = self.span();
+                    self.bump();
+                    self.bump();
+                    while !(self.peek(0) == Some('*') && self.peek(1) == Some(')')) {
+                        if self.bump().is_none() {
+                            return Err(Error::new(start, "unterminated comment"));
+                        }
+                    }
+                    self.bump();
+                    self.bump();
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<Tok, Error> {
+        let start = self.span();
+        let c = self.bump().unwrap_or_default();
+        let tok = match c {
+            ':' if self.eat('=') => Tok::Assign,
+            ':' => Tok::Colon,
+            ';' => Tok::Semi,
+            ',' => Tok::Comma,
+            '.' if self.eat('.') => Tok::DotDot,
+            '.' => Tok::Dot,
+            '(' => Tok::LParen,
+            ')' => Tok::RParen,
+            '+' => Tok::Plus,
+            '-' => Tok::Minus,
+            '*' => Tok::Star,
+            '/' => Tok::Slash,
+            '&' => Tok::Amp,
+            '=' => Tok::Eq,
+            '<' if self.eat('>') => Tok::Ne,
+            '<' if self.eat('=') => Tok::Le,
+            '<' => Tok::Lt,
+            '>' if self.eat('=') => Tok::Ge,
+            '>' => Tok::Gt,
+            '%' => Tok::Address(self.address(start)?),
+            c if c.is_ascii_digit() => Tok::Int(self.integer(start, c)?),
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut word = String::from(c);
+                while let Some(c) = self
+                    .peek(0)
+                    .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+                {
+                    word.push(c);
+                    self.bump();
+                }
+                let upper = word.to_ascii_uppercase();
+                if (upper == "T" || upper == "TIME") && self.eat('#') {
+                    Tok::Time(self.time(start)?)
+                } else if let Some((_, kw)) = KEYWORDS.iter().find(|(k, _)| *k == upper) {
+                    Tok::Keyword(*kw)
+                } else {
+                    Tok::Ident(word)
+                }
+            }
+            c => return Err(Error::new(start, format!("unexpected character '{}'", c))),
+        };
+        Ok(tok)
+    }
+
+    fn digits(&mut self, radix: u32) -> String {
+        let mut digits = String::new();
+        while let Some(c) = self.peek(0).filter(|c| c.is_digit(radix) || *c == '_') {
+            if c != '_' {
+                digits.push(c);
+            }
+            self.bump();
+        }
+        digits
+    }
+
+    /// Decimal, or based like `16#FF` or `2#1010`.
+    fn integer(&mut self, start: Span, first: char) -> Result<i64, Error> {
+        let mut digits = String::from(first);
+        digits.push_str(&self.digits(10));
+        let mut radix = 10;
+        if self.peek(0) == Some('#') {
+            radix = match digits.as_str() {
+                "2" | "8" | "16" => digits.parse().unwrap_or(10),
+                _ => return Err(Error::new(start, "integer bases are 2, 8 and 16")),
+            };
+            self.bump();
+            digits = self.digits(radix);
+        }
+        i64::from_str_radix(&digits, radix)
+            .map_err(|_| Error::new(start, "bad or out of range integer literal"))
+    }
+
+    /// The part of a TIME literal after `T#`, e.g. `1h30m`, `250ms` or
+    /// `1.5s`, in milliseconds.
+    fn time(&mut self, start: Span) -> Result<i64, Error> {
+        const UNITS: [(&str, i64); 5] = [
+            ("ms", 1),
+            ("d", 86_400_000),
+            ("h", 3_600_000),
+            ("m", 60_000),
+            ("s", 1000),
+        ];
+        let bad = || {
+            Error::new(
+                start,
+                "bad TIME literal; write it like T#1h30m, T#2s or T#250ms",
+            )
+        };
+        let mut total: i64 = 0;
+        let mut parts = 0;
+        while self.peek(0).is_some_and(|c| c.is_ascii_digit()) {
+            let whole = self.digits(10);
+            let mut fraction = String::new();
+            if self.peek(0) == Some('.') && self.peek(1).is_some_and(|c| c.is_ascii_digit()) {
+                self.bump();
+                fraction = self.digits(10);
+            }
+            let mut unit = String::new();
+            while let Some(c) = self.peek(0).filter(|c| c.is_ascii_alphabetic()) {
+                unit.push(c.to_ascii_lowercase());
+                self.bump();
+            }
+            let scale = UNITS.iter().find(|(u, _)| *u == unit).ok_or_else(bad)?.1;
+            let whole: i64 = whole.parse().map_err(|_| bad())?;
+            let mut ms = whole.checked_mul(scale).ok_or_else(bad)?;
+            if !fraction.is_empty() {
+                let denominator = 10i64.checked_pow(fraction.len() as u32).ok_or_else(bad)?;
+                let numerator: i64 = fraction.parse().map_err(|_| bad())?;
+                let part = numerator * scale;
+                if part % denominator != 0 {
+                    return Err(Error::new(
+                        start,
+                        "TIME literals must be a whole number of ms",
+                    ));
+                }
+                ms += part / denominator;
+            }
+            total = total.checked_add(ms).ok_or_else(bad)?;
+            parts += 1;
+            if self.peek(0) == Some('_') {
+                self.bump();
+            }
+        }
+        if parts == 0 {
+            return Err(bad());
+        }
+        Ok(total)
+    }
+
+    fn address(&mut self, start: Span) -> Result<Address, Error> {
+        let bad = || Error::new(start, "bad address; write it like %IX0.7 or %QX0.9");
+        let area = match self.bump().map(|c| c.to_ascii_uppercase()) {
+            Some('I') => Area::Input,
+            Some('Q') => Area::Output,
+            Some('M') => {
+                return Err(Error::new(
+                    start,
+                    "marker addresses aren't supported; declare a variable instead",
+                ))
+            }
+            _ => return Err(bad()),
+        };
+        if self.peek(0).is_some_and(|c| c.eq_ignore_ascii_case(&'X')) {
+            self.bump();
+        }
+        let word = self.digits(10).parse().map_err(|_| bad())?;
+        if !self.eat('.') {
+            return Err(bad());
+        }
+        let bit = self.digits(10).parse().map_err(|_| bad())?;
+        Ok(Address { area, word, bit })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toks(source: &str) -> Vec<Tok> {
+        lex(source).unwrap().into_iter().map(|t| t.tok).collect()
+    }
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(
+            toks("x := a<>b; (* note *) // more\n y<=16#1F"),
+            [
+                Tok::Ident("x".into()),
+                Tok::Assign,
+                Tok::Ident("a".into()),
+                Tok::Ne,
+                Tok::Ident("b".into()),
+                Tok::Semi,
+                Tok::Ident("y".into()),
+                Tok::Le,
+                Tok::Int(31),
+                Tok::Eof,
+            ]
+        );
+        assert_eq!(
+            toks("end_if If 1..3"),
+            [
+                Tok::Keyword(Keyword::EndIf),
+                Tok::Keyword(Keyword::If),
+                Tok::Int(1),
+                Tok::DotDot,
+                Tok::Int(3),
+                Tok::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_time_literals() {
+        assert_eq!(toks("T#1h30m")[0], Tok::Time(5_400_000));
+        assert_eq!(toks("time#250MS")[0], Tok::Time(250));
+        assert_eq!(toks("t#1.5s")[0], Tok::Time(1500));
+        assert_eq!(toks("T#1m_30s")[0], Tok::Time(90_000));
+        assert!(lex("T#5").is_err());
+        assert!(lex("T#1.0005s").is_err());
+    }
+
+    #[test]
+    fn test_addresses() {
+        let address = |area, word, bit| Tok::Address(Address { area, word, bit });
+        assert_eq!(toks("%IX0.7")[0], address(Area::Input, 0, 7));
+        assert_eq!(toks("%qx0.9")[0], address(Area::Output, 0, 9));
+        assert_eq!(toks("%I1.2")[0], address(Area::Input, 1, 2));
+        assert!(lex("%IX0").is_err());
+    }
+
+    #[test]
+    fn test_positions() {
+        let tokens = lex("a\n  b").unwrap();
+        assert_eq!(tokens[1].span, Span { line: 2, col: 3 });
+        let error = lex("(* open").unwrap_err();
+        assert_eq!(error.message, "unterminated comment");
+    }
+}
@@ -0,0 +1,65 @@
+//! Structured Text compiler for the HandyPLC user logic VM.
+//!
+//! Compiles a small subset of IEC 61131-3 Structured Text to the bytecode
+//! the firmware runs (see `firmware/src/bytecode.rs`, which is shared with
+//! this crate):
+//!
+//! ```text
+//! PROGRAM conveyor
+//! VAR
+//!     start AT %IX0.0 : BOOL;
+//!     motor AT %QX0.9 : BOOL;
+//!     run_on : TOF;
+//!     delay : TIME := T#5s;
+//! END_VAR
+//!     run_on(IN := start, PT := delay);
+//!     motor := run_on.Q AND NOT SERVO_FAULT;
+//! END_PROGRAM
+//! ```
+//!
+//! - Types are BOOL, INT (32 bits on the device) and TIME (milliseconds).
+//! - Variables can be located at the inputs and outputs, `%IX0.0` to
+//!   `%IX0.15` and `%QX0.0` to `%QX0.15`. Otherwise they're allocated to
+//!   the VM's markers and words, and start at zero or their initial value.
+//! - Statements are assignment, IF/ELSIF/ELSE, CASE, and calls of the
+//!   TON, TOF, TP, R_TRIG, F_TRIG, CTU and CTD function blocks. Every input
+//!   of a call must be given, except a counter's R or LD.
+//! - The built-in function blocks' status and measurements can be read by
+//!   name, e.g. `SPINDLE_ON` or `AI1`.
+//!
+//! Names and keywords are case-insensitive, as usual for ST.
+#[path = "../../firmware/src/bytecode.rs"]
+pub mod bytecode;
+
+mod ast;
+mod check;
+mod codegen;
+mod disasm;
+mod error;
+mod lexer;
+mod parser;
+mod symbols;
+
+pub use disasm::disassemble;
+pub use error::Error;
+
+use bytecode::Header;
+
+/// Compile a program to code, or the errors that stopped it.
+pub fn compile(source: &str) -> Result<Vec<u8>, Vec<Error>> {
+    let tokens = lexer::lex(source).map_err(|e| vec![e])?;
+    let program = parser::parse(&tokens).map_err(|e| vec![e])?;
+    let symbols = symbols::Symbols::declare(&program)?;
+    let errors = check::check(&program, &symbols);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    codegen::generate(&program, &symbols).map_err(|e| vec![e])
+}
+
+/// The image to upload: a header, then the code.
+pub fn image(code: &[u8]) -> Vec<u8> {
+    let mut image = Header::for_code(code).encode().to_vec();
+    image.extend_from_slice(code);
+    image
+}
@@ -0,0 +1,68 @@
+//! handyplc-stc: compile a Structured Text program for the HandyPLC.
+//!
+//! ```text
+//! handyplc-stc [-o OUTPUT] [--listing] PROGRAM.st
+//! ```
+//!
+//! Writes the program image, ready for `firmware/upload-program.py`, to
+//! OUTPUT, or PROGRAM.bin by default. `--listing` prints the code too.
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+fn usage() -> ExitCode {
+    eprintln!("usage: handyplc-stc [-o OUTPUT] [--listing] PROGRAM.st");
+    ExitCode::from(2)
+}
+
+fn main() -> ExitCode {
+    let mut input = None;
+    let mut output = None;
+    let mut listing = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => match args.next() {
+                Some(path) => output = Some(PathBuf::from(path)),
+                None => return usage(),
+            },
+            "--listing" => listing = true,
+            _ if arg.starts_with('-') || input.is_some() => return usage(),
+            _ => input = Some(PathBuf::from(arg)),
+        }
+    }
+    let Some(input) = input else {
+        return usage();
+    };
+    let output = output.unwrap_or_else(|| input.with_extension("bin"));
+
+    let source = match std::fs::read_to_string(&input) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", input.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let code = match handyplc_stc::compile(&source) {
+        Ok(code) => code,
+        Err(errors) => {
+            for error in &errors {
+                eprint!("{}", error.render(&input.display().to_string(), &source));
+            }
+            eprintln!(
+                "{} error{}",
+                errors.len(),
+                if errors.len() == 1 { "" } else { "s" }
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    if listing {
+        print!("{}", handyplc_stc::disassemble(&code));
+    }
+    if let Err(e) = std::fs::write(&output, handyplc_stc::image(&code)) {
+        eprintln!("{}: {}", output.display(), e);
+        return ExitCode::FAILURE;
+    }
+    eprintln!("{}: {} bytes of code", output.display(), code.len());
+    ExitCode::SUCCESS
+}
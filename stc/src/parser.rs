@@ -0,0 +1,546 @@
+//! Recursive descent parser. Stops at the first error.
+use crate::ast::*;
+use crate::error::{Error, Span};
+use crate::lexer::{Keyword, Tok, Token};
+
+/// How deeply expressions and statements can nest. Every pass recurses
+/// over the tree, so this keeps them all off the end of the stack.
+const MAX_NESTING: usize = 64;
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    depth: usize,
+}
+
+pub fn parse(tokens: &[Token]) -> Result<Program> {
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+    parser.program()
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+impl Parser<'_> {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.pos].tok
+    }
+
+    fn peek_at(&self, ahead: usize) -> &Tok {
+        let last = self.tokens.len() - 1;
+        &self.tokens[(self.pos + ahead).min(last)].tok
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.pos].span
+    }
+
+    fn bump(&mut self) -> &Token {
+        let token = &self.tokens[self.pos];
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, tok: &Tok) -> bool {
+        if self.peek() == tok {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, kw: Keyword) -> bool {
+        self.eat(&Tok::Keyword(kw))
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        Error::new(
+            self.span(),
+            format!("expected {}, found {}", expected, self.peek().describe()),
+        )
+    }
+
+    /// Parse something that can contain itself, one level further in.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth == MAX_NESTING {
+            return Err(Error::new(self.span(), "nested too deeply"));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn expect(&mut self, tok: Tok, context: &str) -> Result<()> {
+        if self.eat(&tok) {
+            return Ok(());
+        }
+        let expected = format!("{} {}", Tok::describe(&tok), context);
+        let mut error = self.unexpected(expected.trim_end());
+        // A missing semicolon is missing from the end of the line before,
+        // not the start of the next.
+        if tok == Tok::Semi && self.pos > 0 {
+            error.span = self.tokens[self.pos - 1].end;
+        }
+        Err(error)
+    }
+
+    fn expect_keyword(&mut self, kw: Keyword, context: &str) -> Result<()> {
+        self.expect(Tok::Keyword(kw), context)
+    }
+
+    fn ident(&mut self, what: &str) -> Result<Ident> {
+        match self.peek().clone() {
+            Tok::Ident(name) => {
+                let span = self.span();
+                self.bump();
+                Ok(Ident::new(&name, span))
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    fn program(&mut self) -> Result<Program> {
+        self.expect_keyword(Keyword::Program, "at the start of the file")?;
+        let name = self.ident("a program name")?;
+        let mut vars = Vec::new();
+        while self.eat_keyword(Keyword::Var) {
+            while !self.eat_keyword(Keyword::EndVar) {
+                self.declaration(&mut vars)?;
+            }
+        }
+        let body = self.statements()?;
+        self.expect_keyword(Keyword::EndProgram, "")?;
+        if *self.peek() != Tok::Eof {
+            return Err(self.unexpected("the end of the file after END_PROGRAM"));
+        }
+        Ok(Program { name, vars, body })
+    }
+
+    /// `a, b : BOOL;` or `x AT %QX0.1 : BOOL := TRUE;`
+    fn declaration(&mut self, vars: &mut Vec<VarDecl>) -> Result<()> {
+        let mut names = vec![self.ident("a variable name or END_VAR")?];
+        while self.eat(&Tok::Comma) {
+            names.push(self.ident("a variable name")?);
+        }
+        let mut at = None;
+        if self.eat_keyword(Keyword::At) {
+            if names.len() > 1 {
+                return Err(Error::new(
+                    names[1].span,
+                    "only one variable can be declared with an address",
+                ));
+            }
+            let span = self.span();
+            match *self.peek() {
+                Tok::Address(address) => {
+                    self.bump();
+                    at = Some((address, span));
+                }
+                _ => return Err(self.unexpected("an address like %IX0.7")),
+            }
+        }
+        self.expect(Tok::Colon, "before the type")?;
+        let ty = self.ident("a type")?;
+        let init = if self.eat(&Tok::Assign) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.expect(Tok::Semi, "after the declaration")?;
+        for name in names {
+            vars.push(VarDecl {
+                name,
+                at,
+                ty: ty.clone(),
+                init: init.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Statements up to whatever ends the block, which is left for the
+    /// caller.
+    fn statements(&mut self) -> Result<Vec<Stmt>> {
+        let mut body = Vec::new();
+        loop {
+            match self.peek() {
+                Tok::Keyword(
+                    Keyword::EndProgram
+                    | Keyword::EndIf
+                    | Keyword::Elsif
+                    | Keyword::Else
+                    | Keyword::EndCase,
+                )
+                | Tok::Int(_)
+                | Tok::Minus
+                | Tok::Eof => return Ok(body),
+                Tok::Semi => {
+                    self.bump();
+                }
+                _ => body.push(self.statement()?),
+            }
+        }
+    }
+
+    fn statement(&mut self) -> Result<Stmt> {
+        match self.peek() {
+            Tok::Keyword(Keyword::If) => self.nested(Self::if_statement),
+            Tok::Keyword(Keyword::Case) => self.nested(Self::case_statement),
+            Tok::Ident(_) if *self.peek_at(1) == Tok::LParen => self.call_statement(),
+            Tok::Ident(_) | Tok::Address(_) => {
+                let target = self.primary()?;
+                if *self.peek() == Tok::Eq {
+                    return Err(self.unexpected("':=' (use ':=' to assign; '=' compares)"));
+                }
+                self.expect(Tok::Assign, "in the assignment")?;
+                let value = self.expression()?;
+                self.expect(Tok::Semi, "after the assignment")?;
+                Ok(Stmt::Assign { target, value })
+            }
+            _ => Err(self.unexpected("a statement")),
+        }
+    }
+
+    fn call_statement(&mut self) -> Result<Stmt> {
+        let instance = self.ident("a function block instance")?;
+        self.expect(Tok::LParen, "")?;
+        let mut args = Vec::new();
+        if !self.eat(&Tok::RParen) {
+            loop {
+                let name = self.ident("an input name, as in IN := x")?;
+                self.expect(Tok::Assign, "after the input name")?;
+                let value = self.expression()?;
+                args.push(Arg { name, value });
+                if self.eat(&Tok::RParen) {
+                    break;
+                }
+                self.expect(Tok::Comma, "or ')' in the call")?;
+            }
+        }
+        self.expect(Tok::Semi, "after the call")?;
+        Ok(Stmt::Call { instance, args })
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt> {
+        self.bump();
+        let mut arms = Vec::new();
+        loop {
+            let condition = self.expression()?;
+            self.expect_keyword(Keyword::Then, "after the condition")?;
+            arms.push((condition, self.statements()?));
+            if !self.eat_keyword(Keyword::Elsif) {
+                break;
+            }
+        }
+        let otherwise = if self.eat_keyword(Keyword::Else) {
+            self.statements()?
+        } else {
+            Vec::new()
+        };
+        self.expect_keyword(Keyword::EndIf, "")?;
+        self.expect(Tok::Semi, "after END_IF")?;
+        Ok(Stmt::If { arms, otherwise })
+    }
+
+    fn case_statement(&mut self) -> Result<Stmt> {
+        self.bump();
+        let selector = self.expression()?;
+        self.expect_keyword(Keyword::Of, "after the CASE selector")?;
+        let mut arms = Vec::new();
+        while matches!(self.peek(), Tok::Int(_) | Tok::Minus) {
+            let mut labels = Vec::new();
+            loop {
+                let span = self.span();
+                let lo = self.case_value()?;
+                let hi = if self.eat(&Tok::DotDot) {
+                    self.case_value()?
+                } else {
+                    lo
+                };
+                labels.push(CaseLabel { lo, hi, span });
+                if !self.eat(&Tok::Comma) {
+                    break;
+                }
+            }
+            self.expect(Tok::Colon, "after the case labels")?;
+            let body = self.statements()?;
+            arms.push(CaseArm { labels, body });
+        }
+        let otherwise = if self.eat_keyword(Keyword::Else) {
+            self.statements()?
+        } else {
+            Vec::new()
+        };
+        self.expect_keyword(Keyword::EndCase, "or a case label")?;
+        self.expect(Tok::Semi, "after END_CASE")?;
+        Ok(Stmt::Case {
+            selector,
+            arms,
+            otherwise,
+        })
+    }
+
+    fn case_value(&mut self) -> Result<i64> {
+        let negative = self.eat(&Tok::Minus);
+        match *self.peek() {
+            Tok::Int(n) => {
+                self.bump();
+                Ok(if negative { -n } else { n })
+            }
+            _ => Err(self.unexpected("an integer case label")),
+        }
+    }
+
+    pub fn expression(&mut self) -> Result<Expr> {
+        self.nested(|parser| parser.binary(0))
+    }
+
+    /// Operators from loosest to tightest binding; each level is left
+    /// associative.
+    fn binary(&mut self, level: usize) -> Result<Expr> {
+        const LEVELS: [&[(Tok, BinaryOp)]; 7] = [
+            &[(Tok::Keyword(Keyword::Or), BinaryOp::Or)],
+            &[(Tok::Keyword(Keyword::Xor), BinaryOp::Xor)],
+            &[
+                (Tok::Keyword(Keyword::And), BinaryOp::And),
+                (Tok::Amp, BinaryOp::And),
+            ],
+            &[(Tok::Eq, BinaryOp::Eq), (Tok::Ne, BinaryOp::Ne)],
+            &[
+                (Tok::Lt, BinaryOp::Lt),
+                (Tok::Le, BinaryOp::Le),
+                (Tok::Gt, BinaryOp::Gt),
+                (Tok::Ge, BinaryOp::Ge),
+            ],
+            &[(Tok::Plus, BinaryOp::Add), (Tok::Minus, BinaryOp::Sub)],
+            &[
+                (Tok::Star, BinaryOp::Mul),
+                (Tok::Slash, BinaryOp::Div),
+                (Tok::Keyword(Keyword::Mod), BinaryOp::Mod),
+            ],
+        ];
+        let Some(ops) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(&(_, op)) = ops.iter().find(|(tok, _)| tok == self.peek()) {
+            let op_span = self.span();
+            self.bump();
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr {
+                span: lhs.span,
+                kind: ExprKind::Binary(op, op_span, Box::new(lhs), Box::new(rhs)),
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        let span = self.span();
+        let op = match self.peek() {
+            Tok::Minus => UnaryOp::Neg,
+            Tok::Keyword(Keyword::Not) => UnaryOp::Not,
+            _ => return self.primary(),
+        };
+        self.bump();
+        // Fold negative literals, so the most negative INT can be written.
+        if op == UnaryOp::Neg {
+            if let Tok::Int(n) = *self.peek() {
+                self.bump();
+                return Ok(Expr {
+                    kind: ExprKind::Int(-n),
+                    span,
+                });
+            }
+        }
+        let operand = self.nested(Self::unary)?;
+        Ok(Expr {
+            kind: ExprKind::Unary(op, Box::new(operand)),
+            span,
+        })
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let span = self.span();
+        let kind = match self.peek().clone() {
+            Tok::Int(n) => {
+                self.bump();
+                ExprKind::Int(n)
+            }
+            Tok::Time(ms) => {
+                self.bump();
+                ExprKind::Time(ms)
+            }
+            Tok::Keyword(Keyword::True) => {
+                self.bump();
+                ExprKind::Bool(true)
+            }
+            Tok::Keyword(Keyword::False) => {
+                self.bump();
+                ExprKind::Bool(false)
+            }
+            Tok::Address(address) => {
+                self.bump();
+                ExprKind::Address(address)
+            }
+            Tok::LParen => {
+                self.bump();
+                let inner = self.expression()?;
+                self.expect(Tok::RParen, "to close the parenthesis")?;
+                return Ok(inner);
+            }
+            Tok::Ident(_) => {
+                let name = self.ident("a name")?;
+                if self.eat(&Tok::Dot) {
+                    let member = self.ident("a function block output, like Q")?;
+                    ExprKind::Member(name, member)
+                } else if self.eat(&Tok::LParen) {
+                    let mut args = Vec::new();
+                    if !self.eat(&Tok::RParen) {
+                        loop {
+                            args.push(self.expression()?);
+                            if self.eat(&Tok::RParen) {
+                                break;
+                            }
+                            self.expect(Tok::Comma, "or ')' in the call")?;
+                        }
+                    }
+                    ExprKind::Call(name, args)
+                } else {
+                    ExprKind::Var(name)
+                }
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+        Ok(Expr { kind, span })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+
+    fn parse_source(source: &str) -> Result<Program> {
+        parse(&lex(source).unwrap())
+    }
+
+    fn expression(source: &str) -> Expr {
+        let tokens = lex(source).unwrap();
+        Parser {
+            tokens: &tokens,
+            pos: 0,
+            depth: 0,
+        }
+        .expression()
+        .unwrap()
+    }
+
+    // Fully parenthesised, to check precedence.
+    fn show(expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Bool(b) => b.to_string(),
+            ExprKind::Int(n) => n.to_string(),
+            ExprKind::Time(ms) => format!("T#{}ms", ms),
+            ExprKind::Var(name) => name.text.clone(),
+            ExprKind::Address(address) => address.to_string(),
+            ExprKind::Member(a, b) => format!("{}.{}", a.text, b.text),
+            ExprKind::Unary(UnaryOp::Neg, e) => format!("(-{})", show(e)),
+            ExprKind::Unary(UnaryOp::Not, e) => format!("(NOT {})", show(e)),
+            ExprKind::Binary(op, _, a, b) => format!("({} {} {})", show(a), op.symbol(), show(b)),
+            ExprKind::Call(f, args) => {
+                let args: Vec<_> = args.iter().map(show).collect();
+                format!("{}({})", f.text, args.join(", "))
+            }
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            show(&expression("a OR b AND NOT c XOR d")),
+            "(a OR ((b AND (NOT c)) XOR d))"
+        );
+        assert_eq!(
+            show(&expression("x + 2 * y MOD 3 >= -4 = t.Q")),
+            "(((x + ((2 * y) MOD 3)) >= -4) = t.Q)"
+        );
+        assert_eq!(show(&expression("-(a - b - c)")), "(-((a - b) - c))");
+        assert_eq!(
+            show(&expression("TIME_TO_INT(T#1s) / 2")),
+            "(TIME_TO_INT(T#1000ms) / 2)"
+        );
+    }
+
+    #[test]
+    fn test_program() {
+        let program = parse_source(
+            "PROGRAM p
+             VAR a, b : BOOL; q AT %QX0.1 : BOOL := TRUE; END_VAR
+             IF a THEN q := b; ELSIF b THEN ; ELSE q := FALSE; END_IF;
+             CASE 3 OF 1, 2..4: a := TRUE; -1: b := TRUE; ELSE END_CASE;
+             t(IN := a, PT := T#1s);
+             END_PROGRAM",
+        )
+        .unwrap();
+        assert_eq!(program.name.text, "p");
+        assert_eq!(program.vars.len(), 3);
+        assert!(program.vars[2].at.is_some());
+        assert_eq!(program.body.len(), 3);
+        let Stmt::Case { arms, .. } = &program.body[1] else {
+            panic!("not a CASE");
+        };
+        assert_eq!((arms[0].labels[1].lo, arms[0].labels[1].hi), (2, 4));
+        assert_eq!(arms[1].labels[0].lo, -1);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| parse_source(source).unwrap_err().message;
+        assert_eq!(
+            error("PROGRAM p x = 1; END_PROGRAM"),
+            "expected ':=' (use ':=' to assign; '=' compares), found '='"
+        );
+        assert_eq!(
+            error("PROGRAM p IF x THEN y := 1; END_IF END_PROGRAM"),
+            "expected ';' after END_IF, found 'END_PROGRAM'"
+        );
+        assert_eq!(
+            error("PROGRAM p x := (1 + 2; END_PROGRAM"),
+            "expected ')' to close the parenthesis, found ';'"
+        );
+        assert_eq!(
+            error("PROGRAM p VAR x BOOL; END_VAR END_PROGRAM"),
+            "expected ':' before the type, found 'BOOL'"
+        );
+        assert_eq!(
+            error("PROGRAM p"),
+            "expected 'END_PROGRAM', found the end of the file"
+        );
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let error = |source: String| parse_source(&source).map(|_| ()).unwrap_err();
+        let parens = |n| format!("{}x{}", "(".repeat(n), ")".repeat(n));
+        let ifs = |n| format!("{}y := 1;{}", "IF x THEN ".repeat(n), " END_IF;".repeat(n));
+        let program = |body: String| format!("PROGRAM p {} END_PROGRAM", body);
+        assert!(parse_source(&program(format!("y := {};", parens(40)))).is_ok());
+        assert!(parse_source(&program(ifs(40))).is_ok());
+        // Far deeper than the stack would take.
+        let deep = error(program(format!("y := {};", parens(100_000))));
+        assert_eq!(deep.message, "nested too deeply");
+        assert_eq!(deep.span.col, 16 + MAX_NESTING);
+        let deep = error(program(format!("y := {}x;", "NOT ".repeat(100_000))));
+        assert_eq!(deep.message, "nested too deeply");
+        let deep = error(program(ifs(100_000)));
+        assert_eq!(deep.message, "nested too deeply");
+    }
+}
@@ -0,0 +1,340 @@
+//! Declared names, their types, and where they live in the VM.
+use std::collections::HashMap;
+
+use crate::ast::{Ident, Program};
+use crate::bytecode::{
+    COUNTERS, EDGES, INPUTS, MARKERS, OUTPUTS, SYSTEM_BITS, SYSTEM_WORDS, TIMERS, WORDS,
+};
+use crate::error::{Error, Span};
+use crate::lexer::{Address, Area};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Type {
+    Bool,
+    Int,
+    Time,
+}
+
+impl Type {
+    pub fn name(self) -> &'static str {
+        match self {
+            Type::Bool => "BOOL",
+            Type::Int => "INT",
+            Type::Time => "TIME",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Storage {
+    Input(u8),
+    Output(u8),
+    Marker(u8),
+    Word(u8),
+    SystemBit(u8),
+    SystemWord(u8),
+}
+
+impl Storage {
+    pub fn is_writable(self) -> bool {
+        matches!(
+            self,
+            Storage::Output(_) | Storage::Marker(_) | Storage::Word(_)
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FbKind {
+    Ton,
+    Tof,
+    Tp,
+    RTrig,
+    FTrig,
+    Ctu,
+    Ctd,
+}
+
+/// Whether an input must be given in every call.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Required {
+    Yes,
+    /// Defaults to FALSE.
+    No,
+}
+
+impl FbKind {
+    pub const ALL: [FbKind; 7] = [
+        FbKind::Ton,
+        FbKind::Tof,
+        FbKind::Tp,
+        FbKind::RTrig,
+        FbKind::FTrig,
+        FbKind::Ctu,
+        FbKind::Ctd,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FbKind::Ton => "TON",
+            FbKind::Tof => "TOF",
+            FbKind::Tp => "TP",
+            FbKind::RTrig => "R_TRIG",
+            FbKind::FTrig => "F_TRIG",
+            FbKind::Ctu => "CTU",
+            FbKind::Ctd => "CTD",
+        }
+    }
+
+    /// Inputs, in the order they're pushed for a call.
+    pub fn inputs(self) -> &'static [(&'static str, Type, Required)] {
+        match self {
+            FbKind::Ton | FbKind::Tof | FbKind::Tp => &[
+                ("IN", Type::Bool, Required::Yes),
+                ("PT", Type::Time, Required::Yes),
+            ],
+            FbKind::RTrig | FbKind::FTrig => &[("CLK", Type::Bool, Required::Yes)],
+            FbKind::Ctu => &[
+                ("CU", Type::Bool, Required::Yes),
+                ("R", Type::Bool, Required::No),
+                ("PV", Type::Int, Required::Yes),
+            ],
+            FbKind::Ctd => &[
+                ("CD", Type::Bool, Required::Yes),
+                ("LD", Type::Bool, Required::No),
+                ("PV", Type::Int, Required::Yes),
+            ],
+        }
+    }
+
+    pub fn outputs(self) -> &'static [(&'static str, Type)] {
+        match self {
+            FbKind::Ton | FbKind::Tof | FbKind::Tp => &[("Q", Type::Bool), ("ET", Type::Time)],
+            FbKind::RTrig | FbKind::FTrig => &[("Q", Type::Bool)],
+            FbKind::Ctu | FbKind::Ctd => &[("Q", Type::Bool), ("CV", Type::Int)],
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Symbol {
+    Var { ty: Type, storage: Storage },
+    Fb { kind: FbKind, index: u8 },
+}
+
+pub struct Symbols {
+    names: HashMap<String, Symbol>,
+    /// A marker set at the end of the first scan, if there are initial
+    /// values to set before it.
+    pub first_scan_done: Option<u8>,
+}
+
+/// Hands out the slots of one VM memory area.
+struct Allocator {
+    next: usize,
+    size: usize,
+    what: &'static str,
+}
+
+impl Allocator {
+    fn new(size: usize, what: &'static str) -> Allocator {
+        Allocator {
+            next: 0,
+            size,
+            what,
+        }
+    }
+
+    fn take(&mut self, span: Span) -> Result<u8, Error> {
+        if self.next == self.size {
+            return Err(Error::new(
+                span,
+                format!("too many {}; the device has {}", self.what, self.size),
+            ));
+        }
+        self.next += 1;
+        Ok((self.next - 1) as u8)
+    }
+}
+
+impl Symbols {
+    /// The built-in status and measurements, then the program's own
+    /// declarations.
+    pub fn declare(program: &Program) -> Result<Symbols, Vec<Error>> {
+        let mut names = HashMap::new();
+        for (n, name) in SYSTEM_BITS.iter().enumerate() {
+            let storage = Storage::SystemBit(n as u8);
+            names.insert(
+                name.to_string(),
+                Symbol::Var {
+                    ty: Type::Bool,
+                    storage,
+                },
+            );
+        }
+        for (n, name) in SYSTEM_WORDS.iter().enumerate() {
+            let storage = Storage::SystemWord(n as u8);
+            names.insert(
+                name.to_string(),
+                Symbol::Var {
+                    ty: Type::Int,
+                    storage,
+                },
+            );
+        }
+
+        let mut markers = Allocator::new(MARKERS, "BOOL variables");
+        let mut words = Allocator::new(WORDS, "INT and TIME variables");
+        let mut timers = Allocator::new(TIMERS, "timers");
+        let mut edges = Allocator::new(EDGES, "edge detectors");
+        let mut counters = Allocator::new(COUNTERS, "counters");
+
+        let mut errors = Vec::new();
+        let mut declared: HashMap<&str, Span> = HashMap::new();
+        for var in &program.vars {
+            let name = &var.name;
+            if let Some(first) = declared.get(name.key.as_str()) {
+                errors.push(Error::new(
+                    name.span,
+                    format!("'{}' is already declared on line {}", name.text, first.line),
+                ));
+                continue;
+            }
+            if names.contains_key(&name.key) {
+                errors.push(Error::new(
+                    name.span,
+                    format!(
+                        "'{}' is a built-in name, and can't be redeclared",
+                        name.text
+                    ),
+                ));
+                continue;
+            }
+            declared.insert(&name.key, name.span);
+            let symbol = match declared_type(var.at, &var.ty) {
+                Ok(Declared::Var(ty, None)) => {
+                    let slot = match ty {
+                        Type::Bool => markers.take(name.span).map(Storage::Marker),
+                        Type::Int | Type::Time => words.take(name.span).map(Storage::Word),
+                    };
+                    slot.map(|storage| Symbol::Var { ty, storage })
+                }
+                Ok(Declared::Var(ty, Some(storage))) => Ok(Symbol::Var { ty, storage }),
+                Ok(Declared::Fb(kind)) => {
+                    let allocator = match kind {
+                        FbKind::Ton | FbKind::Tof | FbKind::Tp => &mut timers,
+                        FbKind::RTrig | FbKind::FTrig => &mut edges,
+                        FbKind::Ctu | FbKind::Ctd => &mut counters,
+                    };
+                    allocator
+                        .take(name.span)
+                        .map(|index| Symbol::Fb { kind, index })
+                }
+                Err(error) => Err(error),
+            };
+            match symbol {
+                Ok(symbol) => {
+                    names.insert(name.key.clone(), symbol);
+                }
+                Err(error) => errors.push(error),
+            }
+        }
+
+        let mut first_scan_done = None;
+        if program.vars.iter().any(|var| var.init.is_some()) {
+            match markers.take(program.name.span) {
+                Ok(marker) => first_scan_done = Some(marker),
+                Err(_) => errors.push(Error::new(
+                    program.name.span,
+                    format!(
+                        "too many BOOL variables; initial values need one of the {} markers",
+                        MARKERS
+                    ),
+                )),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Symbols {
+                names,
+                first_scan_done,
+            })
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn get(&self, name: &Ident) -> Option<Symbol> {
+        self.names.get(&name.key).copied()
+    }
+}
+
+enum Declared {
+    Var(Type, Option<Storage>),
+    Fb(FbKind),
+}
+
+/// What a declaration declares, with the storage if it's located.
+fn declared_type(at: Option<(Address, Span)>, ty: &Ident) -> Result<Declared, Error> {
+    let var_type = match ty.key.as_str() {
+        "BOOL" => Some(Type::Bool),
+        "INT" => Some(Type::Int),
+        "TIME" => Some(Type::Time),
+        _ => None,
+    };
+    if let Some(ty) = var_type {
+        let storage = at
+            .map(|(address, span)| locate(address, span, ty))
+            .transpose()?;
+        return Ok(Declared::Var(ty, storage));
+    }
+    let Some(kind) = FbKind::ALL.into_iter().find(|k| k.name() == ty.key) else {
+        return Err(Error::new(
+            ty.span,
+            format!(
+                "unknown type '{}'; the types are BOOL, INT, TIME, TON, TOF, TP, R_TRIG, F_TRIG, CTU and CTD",
+                ty.text
+            ),
+        ));
+    };
+    if let Some((_, span)) = at {
+        return Err(Error::new(span, "function blocks can't have an address"));
+    }
+    Ok(Declared::Fb(kind))
+}
+
+/// The storage for a located variable.
+pub fn locate(address: Address, span: Span, ty: Type) -> Result<Storage, Error> {
+    let size = match address.area {
+        Area::Input => INPUTS,
+        Area::Output => OUTPUTS,
+    };
+    if address.word != 0 || address.bit as usize >= size {
+        let area = if address.area == Area::Input {
+            'I'
+        } else {
+            'Q'
+        };
+        return Err(Error::new(
+            span,
+            format!(
+                "there's no {}; the addresses are %{}X0.0 to %{}X0.{}",
+                address,
+                area,
+                area,
+                size - 1
+            ),
+        ));
+    }
+    if ty != Type::Bool {
+        return Err(Error::new(
+            span,
+            format!("{} is a single bit, so can only be a BOOL", address),
+        ));
+    }
+    let bit = address.bit as u8;
+    Ok(match address.area {
+        Area::Input => Storage::Input(bit),
+        Area::Output => Storage::Output(bit),
+    })
+}
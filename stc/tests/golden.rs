@@ -0,0 +1,51 @@
+//! Golden tests: each program in tests/golden compiles to the listing next
+//! to it, and each in tests/errors fails with the errors next to it. Run
+//! with UPDATE_SNAPSHOTS=1 to regenerate them after an intentional change.
+use std::path::Path;
+
+fn check_golden(dir: &str, extension: &str, output: impl Fn(&str, &str) -> String) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(dir);
+    let mut sources: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "st"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty(), "no programs in {}", dir.display());
+    for source in sources {
+        let name = source.file_name().unwrap().to_str().unwrap();
+        let actual = output(name, &std::fs::read_to_string(&source).unwrap());
+        let expected_path = source.with_extension(extension);
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(&expected_path, &actual).unwrap();
+        }
+        let expected = std::fs::read_to_string(&expected_path).unwrap_or_default();
+        assert!(expected == actual, "{} differs:\n{}", name, actual);
+    }
+}
+
+#[test]
+fn test_listings() {
+    check_golden(
+        "golden",
+        "lst",
+        |name, source| match handyplc_stc::compile(source) {
+            Ok(code) => handyplc_stc::disassemble(&code),
+            Err(errors) => panic!("{}:\n{}", name, errors[0].render(name, source)),
+        },
+    );
+}
+
+#[test]
+fn test_errors() {
+    check_golden(
+        "errors",
+        "err",
+        |name, source| match handyplc_stc::compile(source) {
+            Ok(_) => panic!("{} compiled", name),
+            Err(errors) => errors.iter().map(|e| e.render(name, source)).collect(),
+        },
+    );
+}
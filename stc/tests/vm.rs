@@ -0,0 +1,118 @@
+//! Run compiled programs on the firmware's own VM.
+#[path = "../../firmware/src/bytecode.rs"]
+mod bytecode;
+#[allow(dead_code)]
+#[path = "../../firmware/src/vm.rs"]
+mod vm;
+
+use vm::{ProcessImage, Vm};
+
+fn load(name: &str) -> Vm {
+    let path = format!("{}/tests/golden/{}.st", env!("CARGO_MANIFEST_DIR"), name);
+    let code = handyplc_stc::compile(&std::fs::read_to_string(path).unwrap()).unwrap();
+    let mut vm = Vm::default();
+    vm.load(&code).unwrap();
+    vm.start();
+    vm
+}
+
+fn inputs(on: &[usize]) -> ProcessImage {
+    let mut image = ProcessImage::default();
+    for &n in on {
+        image.inputs[n] = true;
+    }
+    image
+}
+
+#[test]
+fn test_blink() {
+    let mut vm = load("blink");
+    let on = inputs(&[0]);
+    // How long the lamp stays in each state, scanning every 10 ms.
+    let mut runs: Vec<(bool, i64)> = Vec::new();
+    for now in (0..4000).step_by(10) {
+        vm.scan(&on, now);
+        match runs.last_mut() {
+            Some((lamp, ms)) if *lamp == vm.output(8) => *ms += 10,
+            _ => runs.push((vm.output(8), 10)),
+        }
+    }
+    // 500 ms on and 1500 ms off, give or take a scan or two.
+    assert!(runs.len() >= 3);
+    for &(lamp, ms) in &runs[..3] {
+        let expected = if lamp { 500 } else { 1500 };
+        assert!((ms - expected).abs() <= 20, "{:?}", runs);
+    }
+    assert!(runs[0].0);
+    vm.scan(&inputs(&[]), 4000);
+    assert!(!vm.output(8));
+}
+
+#[test]
+fn test_conveyor() {
+    let mut vm = load("conveyor");
+    vm.scan(&inputs(&[0]), 0);
+    assert!(vm.output(9));
+    vm.scan(&inputs(&[]), 100);
+    vm.scan(&inputs(&[]), 5099);
+    assert!(vm.output(9), "runs on for the initial delay");
+    vm.scan(&inputs(&[]), 5100);
+    assert!(!vm.output(9));
+
+    let mut faulted = inputs(&[0]);
+    faulted.system_bits[3] = true;
+    vm.scan(&faulted, 6000);
+    assert!(!vm.output(9));
+}
+
+#[test]
+fn test_modes() {
+    let mut vm = load("modes");
+    let mut now = 0;
+    let mut press = |vm: &mut Vm| {
+        vm.scan(&inputs(&[3]), now);
+        vm.scan(&inputs(&[]), now + 10);
+        now += 1000;
+        (vm.output(4), vm.output(5))
+    };
+    vm.scan(&inputs(&[]), 0);
+    assert_eq!(
+        (vm.output(4), vm.output(5)),
+        (true, false),
+        "starts in mode 1"
+    );
+    assert_eq!(press(&mut vm), (false, true));
+    assert_eq!(press(&mut vm), (false, true));
+    // Mode 4 flashes both for a moment on each press.
+    assert_eq!(press(&mut vm), (true, true));
+    assert_eq!(press(&mut vm), (true, false));
+}
+
+// One scan with the input on, then one with it off.
+fn pulse(vm: &mut Vm, input: usize, now: &mut i64) {
+    vm.scan(&inputs(&[input]), *now);
+    vm.scan(&inputs(&[]), *now + 1);
+    *now += 2;
+}
+
+#[test]
+fn test_counters() {
+    let mut vm = load("counters");
+    let mut now = 0;
+    for _ in 0..99 {
+        pulse(&mut vm, 5, &mut now);
+    }
+    assert!(vm.output(11), "parts counted");
+    // Past the warning count, the full lamp flashes every scan.
+    let before = vm.output(10);
+    vm.scan(&inputs(&[]), now);
+    assert_ne!(vm.output(10), before);
+    pulse(&mut vm, 5, &mut now);
+    assert!(vm.output(10));
+    vm.scan(&inputs(&[]), now);
+    assert!(vm.output(10), "steady once full");
+    // The count resets when the reset button is let go.
+    pulse(&mut vm, 6, &mut now);
+    assert!(!vm.output(10));
+    assert!(!vm.output(11));
+}